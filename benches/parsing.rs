@@ -0,0 +1,95 @@
+use std::sync::Arc;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use loganalyzer::ingest::{LogLine, LogLineRef};
+use loganalyzer::parser::{cef::CefParser, json::JsonParser, leef::LeefParser, plain_text::PlainTextParser, registry::ParserRegistry, LogParser};
+use tokio::sync::Mutex;
+
+const BATCH_SIZE: usize = 100;
+
+fn registry() -> ParserRegistry {
+    let mut registry = ParserRegistry::new();
+    registry.register(PlainTextParser::new());
+    registry.register(JsonParser::new());
+    registry.register(CefParser::new());
+    registry.register(LeefParser::new());
+    registry
+}
+
+fn lines(template: &str) -> Vec<LogLine> {
+    (0..BATCH_SIZE)
+        .map(|i| LogLine {
+            content: template.replace("{i}", &i.to_string()),
+            source: "bench".to_string(),
+            timestamp: chrono::Utc::now()
+        })
+        .collect()
+}
+
+// what `Engine::run` used to do: one future per record parsing a clone of the line, then a clone of
+// every parsed record handed to its own task that locks the shared state. The tasks are joined so
+// their cost lands in the measurement
+async fn parse_and_spawn_per_record(parser: Arc<ParserRegistry>, batch: &[LogLine], processed: Arc<Mutex<usize>>) -> usize {
+    let futures = batch.iter()
+        .map(|log_line| {
+            let parser = parser.clone();
+            async move {
+                let log_line = log_line.clone();
+                parser.parse(&log_line)
+            }
+        })
+        .collect::<Vec<_>>();
+
+    let parsed = futures::future::join_all(futures).await
+        .into_iter()
+        .filter_map(|res| res.ok())
+        .collect::<Vec<_>>();
+
+    // the original went on to the output, the task got a copy
+    let tasks = parsed.iter()
+        .map(|parsed_log| {
+            let processed = processed.clone();
+            let log_clone = parsed_log.clone();
+            tokio::spawn(async move {
+                *processed.lock().await += log_clone.message.len();
+            })
+        })
+        .collect::<Vec<_>>();
+    futures::future::join_all(tasks).await;
+
+    parsed.len()
+}
+
+fn bench_parsing(c: &mut Criterion) {
+    let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+    let parser = Arc::new(registry());
+    let processed = Arc::new(Mutex::new(0));
+
+    let inputs = [
+        ("json", lines(r#"{"message": "request {i} served", "level": "info", "service": "api", "host": "web-1", "duration_ms": 12.5}"#)),
+        ("plain_text", lines("GET /api/items/{i} 200 12ms")),
+        ("cef", lines("CEF:0|Security|threatmanager|1.0|100|worm successfully stopped|10|src=10.0.0.{i} dst=2.1.2.2 spt=1232")),
+    ];
+
+    let mut group = c.benchmark_group("parse");
+    group.throughput(Throughput::Elements(BATCH_SIZE as u64));
+
+    for (format, batch) in &inputs {
+        group.bench_with_input(BenchmarkId::new("async_spawn_per_record", format), batch, |b, batch| {
+            b.iter(|| runtime.block_on(parse_and_spawn_per_record(parser.clone(), batch, processed.clone())))
+        });
+
+        group.bench_with_input(BenchmarkId::new("sync_owned", format), batch, |b, batch| {
+            b.iter(|| batch.iter().filter(|line| parser.parse(line).is_ok()).count())
+        });
+
+        group.bench_with_input(BenchmarkId::new("sync_borrowed", format), batch, |b, batch| {
+            b.iter(|| batch.iter().filter(|line| parser.parse_ref(&LogLineRef::from(*line)).is_ok()).count())
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_parsing);
+criterion_main!(benches);
@@ -1,119 +1,302 @@
-use std::{collections::HashMap, time::Duration};
+use std::{collections::{BTreeMap, HashMap, VecDeque}, fmt, time::Duration};
 use chrono::{DateTime, Utc};
+use serde_json::Value;
 use crate::parser::{Level, ParsedLog};
+use crate::transform::{get_path, sample::sample_rate};
 
+pub mod metrics;
+pub mod window;
+
+pub use metrics::{MetricKey, MetricSummary, DEFAULT_METRICS};
+pub use window::{WindowResult, Windowing, WINDOW_KINDS};
+
+use window::{delta, WindowState};
+
+// closed windows nobody took yet are kept up to this many, the oldest go first and are counted
+const MAX_CLOSED_WINDOWS: usize = 1000;
+
+// gets every window result as its window closes, instead of the queue
+struct WindowSink(Box<dyn FnMut(WindowResult) + Send>);
+
+impl fmt::Debug for WindowSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("WindowSink")
+    }
+}
+
+// Analytics over event time: records are grouped into windows by their timestamp, not by when
+// they arrive, so analyzing yesterday's file gives the same answers as following it live. The
+// watermark trails the latest timestamp seen by the allowed lateness; a window closes once the
+// watermark passes its end and its result goes to the window sink, or is queued for
+// `take_closed_windows` without one. Records for a window that already closed are late and only
+// counted. A record without a timestamp belongs to the latest time seen, before any timestamp it
+// is only counted as untimed
 #[derive(Debug)]
 pub struct LogAnalytics {
-    window_size: i64,
-    error_counts: HashMap<String, usize>,
-    response_times: Vec<f64>,
-    user_activity: HashMap<String, Vec<DateTime<Utc>>>,
-    resource_usage: HashMap<String, Vec<(DateTime<Utc>, f64)>>,
-    window_start: DateTime<Utc>
+    windowing: Windowing,
+    allowed_lateness: Duration,
+    // metadata paths of numbers or numeric strings, tracked as resource usage
+    metrics: Vec<String>,
+    // by start, sessions as well as fixed windows
+    open: BTreeMap<DateTime<Utc>, WindowState>,
+    closed: VecDeque<WindowResult>,
+    sink: Option<WindowSink>,
+    // queued results dropped to make room
+    evicted_windows: u64,
+    // the most recent window to close, what the getters report once nothing is open
+    last_closed: Option<WindowState>,
+    latest: Option<DateTime<Utc>>,
+    late_records: u64,
+    untimed_records: u64
 }
 
 impl LogAnalytics {
+    // tumbling windows of `window_size` seconds
     pub fn new(window_size : i64) -> Self {
-        Self { window_size, error_counts: HashMap::new(), 
-            response_times: Vec::new(),
-            user_activity: HashMap::new(),
-            resource_usage: HashMap::new(),
-            window_start: Utc::now()
+        Self {
+            windowing: Windowing::tumbling(Duration::from_secs(window_size.max(1) as u64)),
+            allowed_lateness: Duration::ZERO,
+            metrics: DEFAULT_METRICS.iter().map(|metric| metric.to_string()).collect(),
+            open: BTreeMap::new(),
+            closed: VecDeque::new(),
+            sink: None,
+            evicted_windows: 0,
+            last_closed: None,
+            latest: None,
+            late_records: 0,
+            untimed_records: 0
         }
     }
 
-    pub fn process_log(&mut self, log: ParsedLog) {
-        // prune the data
-        self.prune_old_data();
+    pub fn with_windowing(mut self, windowing : Windowing) -> Self {
+        self.windowing = windowing;
+        self
+    }
 
-        if let Some(Level::Error) = log.level {
-            let error_type = log.metadata.get("error_type")
-                .and_then(|v| v.as_str())
-                .unwrap_or("unknown")
-                .to_string();
+    // how far behind the latest timestamp records may arrive and still count
+    pub fn with_allowed_lateness(mut self, allowed_lateness : Duration) -> Self {
+        self.allowed_lateness = allowed_lateness;
+        self
+    }
 
-            *self.error_counts.entry(error_type).or_insert(0) += 1;
-        }
+    // metadata paths like `cpu_usage` or `queue.depth`, instead of `DEFAULT_METRICS`
+    pub fn with_metrics(mut self, metrics : Vec<String>) -> Self {
+        self.metrics = metrics;
+        self
+    }
 
+    pub fn set_metrics(&mut self, metrics : Vec<String>) {
+        self.metrics = metrics;
+    }
 
-        // processing user activity
-        if let Some(user_id) = log.metadata.get("userid").and_then(|v| v.as_str()) {
+    // window results go to `sink` as their windows close, nothing is queued anymore
+    pub fn set_window_sink(&mut self, sink : impl FnMut(WindowResult) + Send + 'static) {
+        self.sink = Some(WindowSink(Box::new(sink)));
+    }
 
-            self.user_activity
-                .entry(user_id.to_string()).or_default()
-                .push(log.timestamp.unwrap_or_else(|| Utc::now()));
-        }
+    // open windows keep their bounds, the ones opened from now on use the new size
+    pub fn set_window_size(&mut self, window_size : i64) {
+        self.windowing = self.windowing.with_size(Duration::from_secs(window_size.max(1) as u64));
+    }
 
+    pub fn set_windowing(&mut self, windowing : Windowing, allowed_lateness : Duration) {
+        self.windowing = windowing;
+        self.allowed_lateness = allowed_lateness;
+    }
 
-        // processing resouce usage
-        if let Some(cpu_usage) = log.metadata.get("cpu_usage").and_then(|v| v.as_str()) {
+    pub fn windowing(&self) -> Windowing {
+        self.windowing
+    }
 
-            self.resource_usage
-            .entry(cpu_usage.to_string())
-            .or_default()
-            .push((log.timestamp.unwrap_or_else(|| Utc::now()) , cpu_usage.parse::<f64>().unwrap()));
+    // windows ending at or before it are closed
+    pub fn watermark(&self) -> Option<DateTime<Utc>> {
+        self.latest.map(|latest| latest - delta(self.allowed_lateness))
+    }
+
+    pub fn process_log(&mut self, log: ParsedLog) {
+        let at = match log.timestamp.or(self.latest) {
+            Some(at) => at,
+            None => {
+                self.untimed_records += 1;
+                return;
+            }
+        };
+        if log.timestamp.is_none() {
+            self.untimed_records += 1;
         }
 
+        let windows = match self.assign(at) {
+            Some(windows) => windows,
+            None => {
+                self.late_records += 1;
+                return;
+            }
+        };
+
+        // a record kept by sampling stands for the ones dropped with it
+        let weight = 1.0 / sample_rate(&log);
+        for start in windows {
+            let window = self.open.get_mut(&start).expect("assigned windows are open");
+            Self::collect(window, &self.metrics, &log, at, weight);
+        }
 
+        self.latest = Some(self.latest.map_or(at, |latest| latest.max(at)));
+        self.close_windows();
     }
 
-    pub fn prune_old_data(&mut self) {
+    // the open windows the record goes to, opened if needed. None when they closed already
+    fn assign(&mut self, at: DateTime<Utc>) -> Option<Vec<DateTime<Utc>>> {
+        let watermark = self.watermark();
+        let closed = |end: DateTime<Utc>| watermark.is_some_and(|watermark| end <= watermark);
 
-        let current_time = Utc::now();
-        let window_start = current_time - Duration::from_secs(self.window_size as u64);
+        if let Windowing::Session { gap } = self.windowing {
+            let gap = delta(gap);
+            if closed(at + gap) {
+                return None;
+            }
+            // every session the record is within a gap of joins its own
+            let mut session = WindowState::new(at, at + gap);
+            let touching = self.open.iter()
+                .filter(|(_, window)| window.start - gap <= at && at < window.end)
+                .map(|(start, _)| *start)
+                .collect::<Vec<_>>();
+            for start in touching {
+                session.merge(self.open.remove(&start).expect("found above"));
+            }
+            let start = session.start;
+            self.open.insert(start, session);
+            return Some(vec![start]);
+        }
 
-        self.window_start = window_start;
+        let windows = self.windowing.windows(at).into_iter().filter(|(_, end)| !closed(*end)).collect::<Vec<_>>();
+        if windows.is_empty() {
+            return None;
+        }
+        Some(windows.into_iter().map(|(start, end)| {
+            self.open.entry(start).or_insert_with(|| WindowState::new(start, end));
+            start
+        }).collect())
+    }
+
+    fn collect(window: &mut WindowState, metrics: &[String], log: &ParsedLog, at: DateTime<Utc>, weight: f64) {
+        window.records += weight;
 
-        for activities in self.user_activity.values_mut() {
-            activities.retain(|&timestamp| timestamp >= window_start);
+        if let Some(Level::Error) = log.level {
+            let error_type = log.metadata.get("error_type")
+                .and_then(|v| v.as_str())
+                .unwrap_or("unknown")
+                .to_string();
+
+            *window.error_counts.entry(error_type).or_insert(0.0) += weight;
         }
 
-        for usaged in self.user_activity.values_mut() {
-            usaged.retain(|&timestamp| timestamp >= window_start);
+        // user ids are logged as strings or numbers
+        match log.metadata.get("userid") {
+            Some(Value::String(user_id)) => { window.users.insert(user_id.clone()); }
+            Some(Value::Number(user_id)) => { window.users.insert(user_id.to_string()); }
+            _ => {}
+        }
+
+        if let Some(duration_ms) = log.duration_ms {
+            window.response_times.push((duration_ms, weight));
+        }
+
+        for metric in metrics {
+            let usage = match get_path(&log.metadata, metric) {
+                Some(Value::Number(usage)) => usage.as_f64(),
+                Some(Value::String(usage)) => usage.trim().parse().ok(),
+                _ => None
+            };
+            if let Some(usage) = usage.filter(|usage: &f64| usage.is_finite()) {
+                let key = MetricKey { resource: metric.clone(), host: log.host.clone(), service: log.service_name.clone() };
+                window.resource_usage.entry(key).or_default().push(at, usage);
+            }
         }
     }
 
+    fn close_windows(&mut self) {
+        let Some(watermark) = self.watermark() else {
+            return;
+        };
+        let mut ending = self.open.iter()
+            .filter(|(_, window)| window.end <= watermark)
+            .map(|(start, window)| (window.end, *start))
+            .collect::<Vec<_>>();
+        ending.sort();
+        for (_, start) in ending {
+            let window = self.open.remove(&start).expect("found above");
+            self.close(window);
+        }
+    }
 
-    pub fn get_response_time_percentiles(&self) -> Option<(f64, f64, f64)>{
+    fn close(&mut self, window: WindowState) {
+        match &mut self.sink {
+            Some(WindowSink(sink)) => sink(window.result()),
+            None => {
+                if self.closed.len() == MAX_CLOSED_WINDOWS {
+                    self.closed.pop_front();
+                    self.evicted_windows += 1;
+                }
+                self.closed.push_back(window.result());
+            }
+        }
+        self.last_closed = Some(window);
+    }
 
-        if self.response_times.is_empty() {
-            return None;
+    // closes every open window, at the end of the input no more records can come for them
+    pub fn close_all(&mut self) {
+        let mut windows = std::mem::take(&mut self.open).into_values().collect::<Vec<_>>();
+        windows.sort_by_key(|window| (window.end, window.start));
+        for window in windows {
+            self.close(window);
         }
+    }
 
-        // get teh percentile score for the response times
-        let mut response_times = self.response_times.clone();
-        response_times.sort_by(|a,b| a.partial_cmp(b).unwrap());
+    // results of the windows that closed since the last call, in the order they closed
+    pub fn take_closed_windows(&mut self) -> Vec<WindowResult> {
+        self.closed.drain(..).collect()
+    }
 
-        let len = response_times.len();
+    // closed windows dropped from a full queue before anyone took them
+    pub fn evicted_windows(&self) -> u64 {
+        self.evicted_windows
+    }
 
-        let percentile50 = ((len as f64) * 0.50) as usize;
-        let percentile90 = ((len as f64) * 0.90) as usize;
-        let percentile99 = ((len as f64) * 0.99) as usize;
+    // records that came after their window closed
+    pub fn late_records(&self) -> u64 {
+        self.late_records
+    }
 
+    // records without a timestamp
+    pub fn untimed_records(&self) -> u64 {
+        self.untimed_records
+    }
 
-        Some((
-            response_times[percentile50],
-            response_times[percentile90],
-            response_times[percentile99]
-        ))
+    // the window the getters describe: the latest open one, or the last one to close
+    fn current(&self) -> Option<&WindowState> {
+        self.open.values().max_by_key(|window| window.end).or(self.last_closed.as_ref())
+    }
+
+    pub fn get_response_time_percentiles(&self) -> Option<(f64, f64, f64)>{
+        self.current().and_then(WindowState::percentiles)
+    }
+
+    pub fn get_error_counts(&self) -> HashMap<String, usize> {
+        self.current().map(WindowState::error_counts).unwrap_or_default()
     }
 
     pub fn get_active_users_count(&self) -> usize {
-        self.user_activity.values()
-        .filter(|activities| !activities.is_empty())
-        .count()
+        self.current().map_or(0, |window| window.users.len())
     }
 
-    pub fn get_resource_usage_trend(&self,resource : &str) -> Option<f64> {
-        self.resource_usage.get(resource)
-        .map(|usage_data| {
-            if usage_data.is_empty() {
-                return 0.0;
-            }
+    // min, max, avg, last and trend of every metric in the current window
+    pub fn get_resource_usage(&self) -> HashMap<MetricKey, MetricSummary> {
+        self.current().map(WindowState::usage).unwrap_or_default()
+    }
 
-            let sum = usage_data.iter().map(|(_ ,value)| value).sum::<f64>();
-            sum / usage_data.len() as f64
-        })
+    // change per second of a metric in the current window, e.g. "cpu_usage" of one host
+    pub fn get_resource_usage_trend(&self, key : &MetricKey) -> Option<f64> {
+        self.current()?.resource_usage.get(key)?.summary()?.trend
     }
 }
 
@@ -123,75 +306,150 @@ mod tests {
     use serde_json::json;
 
     use super::*;
-    use std::sync::{Arc, Mutex};
 
-    fn resource_usage(i : i32) -> String {
-        match i {
-            0..5 => (i*10).to_string(),
-            _ => ((i-5)*20).to_string()
+    fn at(seconds: i64, level: Level, metadata: Value) -> ParsedLog {
+        ParsedLog {
+            timestamp: DateTime::from_timestamp(1_700_000_000 + seconds, 0),
+            level: Some(level),
+            metadata,
+            ..ParsedLog::new("message".to_string())
         }
     }
 
-
-    #[tokio::test]
-    async fn test_prune_data() {
-
-        let log_analytics = Arc::new(Mutex::new(LogAnalytics::new(100)));
-        let mut parsed_logs = Vec::new();
-
-        for i in 0..10 {
-            let parsed_log = ParsedLog {
-                timestamp : Some(Utc::now()),
-                level : Some(Level::Error),
-                message: format!("message {}", i),
-                metadata: json!({
-                    "userid" : format!("userid{}", i),
-                    "cpu_usage" : resource_usage(i),
-                    "error_type" : "Something is really wrong"
-                }),
-                ..Default::default()
-            };
-
-            parsed_logs.push(parsed_log);
+    #[test]
+    fn old_records_are_windowed_by_event_time() {
+        // 1_700_000_000 starts a 100 second window
+        let mut analytics = LogAnalytics::new(100).with_allowed_lateness(Duration::from_secs(10));
+        for seconds in [0, 10, 20] {
+            analytics.process_log(at(seconds, Level::Error, json!({ "error_type": "timeout", "userid": format!("u{}", seconds), "cpu_usage": "40" })));
         }
+        // years old and still counted, the window hasn't closed
+        assert_eq!(analytics.get_error_counts().get("timeout"), Some(&3));
+        assert_eq!((analytics.get_active_users_count(), analytics.get_resource_usage_trend(&MetricKey::new("cpu_usage"))), (3, Some(0.0)));
+
+        analytics.process_log(at(65, Level::Info, json!({ "cpu_usage": 80 })));
+        analytics.process_log(at(55, Level::Error, json!({ "error_type": "timeout" })));
+        analytics.process_log(at(105, Level::Info, json!({})));
+        // within the allowed lateness, the first window is still open
+        analytics.process_log(at(95, Level::Info, json!({})));
+        assert!(analytics.take_closed_windows().is_empty());
+
+        analytics.process_log(at(112, Level::Info, json!({})));
+        let closed = analytics.take_closed_windows();
+        assert_eq!(closed.len(), 1);
+        assert_eq!((closed[0].records, closed[0].error_counts.get("timeout")), (6, Some(&4)));
+        assert_eq!(closed[0].resource_usage[&MetricKey::new("cpu_usage")].avg, 50.0);
+
+        // too late for the closed window
+        analytics.process_log(at(30, Level::Error, json!({})));
+        assert_eq!(analytics.late_records(), 1);
+        analytics.close_all();
+        assert_eq!(analytics.take_closed_windows()[0].records, 2);
+    }
 
-
-        let mut handlers = Vec::new();
-        for log in parsed_logs.into_iter() {
-
-            let la_clone = log_analytics.clone();
-
-            let handler = tokio::spawn(async move {
-                if let Ok(mut la) = la_clone.lock() {
-                    la.process_log(log);
-                }
-            });
-
-            handlers.push(handler);
+    #[test]
+    fn sliding_and_session_windows() {
+        let mut sliding = LogAnalytics::new(100).with_windowing(Windowing::Sliding { size: Duration::from_secs(100), slide: Duration::from_secs(50) });
+        sliding.process_log(at(0, Level::Info, json!({})));
+        sliding.close_all();
+        // in the window it starts and the one that started 50 seconds before
+        assert_eq!(sliding.take_closed_windows().len(), 2);
+
+        let mut sessions = LogAnalytics::new(100)
+            .with_windowing(Windowing::Session { gap: Duration::from_secs(30) })
+            .with_allowed_lateness(Duration::from_secs(60));
+        for seconds in [0, 20, 100, 40, 110] {
+            let mut log = at(seconds, Level::Info, json!({}));
+            log.duration_ms = Some(seconds as f64);
+            sessions.process_log(log);
         }
+        // 40 came late but bridged into the first session
+        sessions.close_all();
+        let closed = sessions.take_closed_windows();
+        assert_eq!(closed.iter().map(|window| window.records).collect::<Vec<_>>(), vec![3, 2]);
+        assert_eq!(closed[0].end - closed[0].start, chrono::TimeDelta::seconds(70));
+        assert_eq!(closed[1].response_time_percentiles, Some((100.0, 110.0, 110.0)));
+    }
 
-        for handler in handlers {
-            if handler.await.is_err() {
-                panic!("there is an error")
-            }
+    #[test]
+    fn metrics_are_kept_apart_per_host() {
+        let mut analytics = LogAnalytics::new(100).with_metrics(vec!["queue.depth".to_string()]);
+        for (seconds, host, depth) in [(0, "a", json!(10)), (10, "a", json!("30")), (0, "b", json!(5)), (5, "b", json!("n/a"))] {
+            let mut log = at(seconds, Level::Info, json!({ "queue": { "depth": depth } }));
+            log.host = Some(host.to_string());
+            analytics.process_log(log);
         }
 
+        let usage = analytics.get_resource_usage();
+        let a = MetricKey::new("queue.depth").with_host("a");
+        assert_eq!((usage[&a].min, usage[&a].last, usage[&a].trend), (10.0, 30.0, Some(2.0)));
+        // "n/a" isn't a number
+        assert_eq!(usage[&MetricKey::new("queue.depth").with_host("b")].samples, 1);
+        assert_eq!(analytics.get_resource_usage_trend(&a), Some(2.0));
+    }
 
-        // print log analytics
-        println!("log analytics: {:#?}", log_analytics.clone().lock().unwrap());
-
-        // get percentile count
-        if let Ok(la) = log_analytics.clone().lock() {
-            let active_user_count = la.get_active_users_count();
-            println!("active user count: {}", active_user_count);
+    #[test]
+    fn sampled_records_weigh_what_they_stand_for() {
+        let mut analytics = LogAnalytics::new(100);
+        // kept at 30%, each stands for 3.33 records and the three for 10
+        for seconds in 0..3 {
+            analytics.process_log(at(seconds, Level::Error, json!({ "error_type": "timeout", "sample_rate": 0.3 })));
+        }
+        for (duration_ms, sample_rate) in [(10.0, 0.1), (100.0, 1.0)] {
+            let mut log = at(5, Level::Info, json!({ "sample_rate": sample_rate }));
+            log.duration_ms = Some(duration_ms);
+            analytics.process_log(log);
+        }
 
-            // get resouce trend for 40.0 , need to get this test case right
+        assert_eq!(analytics.get_error_counts().get("timeout"), Some(&10));
+        // the fast one stands for 10 of 11, only the p99 is the slow one
+        assert_eq!(analytics.get_response_time_percentiles(), Some((10.0, 10.0, 100.0)));
+        analytics.close_all();
+        assert_eq!(analytics.take_closed_windows()[0].records, 21);
+    }
 
-            if let Some(trend) =  la.get_resource_usage_trend("40") {
-                println!("resource trend for 40 is: {}",trend);
-            }
+    #[test]
+    fn parsed_records_without_a_timestamp_stay_untimed() {
+        use crate::{ingest::LogLine, parser::{registry::ParserRegistry, LogParser}};
+
+        let parser = ParserRegistry::builtin();
+        let mut analytics = LogAnalytics::new(100);
+        for content in [
+            "starting up",
+            r#"{"timestamp": "2023-11-14T22:13:20Z", "level": "info", "message": "ready"}"#,
+            r#"{"level": "error", "message": "no time of its own", "error_type": "timeout"}"#,
+            "plain text has no timestamp either"
+        ] {
+            let parsed = parser.parse(&LogLine { content: content.to_string(), source: "app.log".to_string(), timestamp: Utc::now() }).unwrap();
+            analytics.process_log(parsed);
         }
 
+        // the first came before any timestamp, the other two belong to the latest one
+        assert_eq!(analytics.untimed_records(), 3);
+        analytics.close_all();
+        let closed = analytics.take_closed_windows();
+        assert_eq!((closed.len(), closed[0].records, closed[0].error_counts.get("timeout")), (1, 3, Some(&1)));
+        assert_eq!(closed[0].start, DateTime::from_timestamp(1_700_000_000, 0).unwrap());
+    }
 
+    #[test]
+    fn closed_windows_go_to_the_sink_or_are_evicted_from_the_queue() {
+        let mut queued = LogAnalytics::new(1);
+        for seconds in 0..=MAX_CLOSED_WINDOWS as i64 + 10 {
+            queued.process_log(at(seconds, Level::Info, json!({})));
+        }
+        // every record closed the window before it
+        assert_eq!((queued.take_closed_windows().len(), queued.evicted_windows()), (MAX_CLOSED_WINDOWS, 10));
+
+        let results = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut sunk = LogAnalytics::new(1);
+        let sink = results.clone();
+        sunk.set_window_sink(move |result| sink.lock().unwrap().push(result.records));
+        for seconds in 0..=MAX_CLOSED_WINDOWS as i64 + 10 {
+            sunk.process_log(at(seconds, Level::Info, json!({})));
+        }
+        sunk.close_all();
+        assert!(sunk.take_closed_windows().is_empty());
+        assert_eq!((results.lock().unwrap().len(), sunk.evicted_windows()), (MAX_CLOSED_WINDOWS + 11, 0));
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,188 @@
+use std::fmt;
+
+use chrono::{DateTime, Utc};
+
+// metadata paths tracked when no others are configured
+pub const DEFAULT_METRICS: [&str; 4] = ["cpu_usage", "memory_usage", "disk_usage", "queue_depth"];
+
+// a metric as one host of one service reports it, hosts and services are tracked apart
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct MetricKey {
+    pub resource: String,
+    pub host: Option<String>,
+    pub service: Option<String>
+}
+
+impl MetricKey {
+    pub fn new(resource: impl Into<String>) -> Self {
+        Self { resource: resource.into(), host: None, service: None }
+    }
+
+    pub fn with_host(mut self, host: impl Into<String>) -> Self {
+        self.host = Some(host.into());
+        self
+    }
+
+    pub fn with_service(mut self, service: impl Into<String>) -> Self {
+        self.service = Some(service.into());
+        self
+    }
+}
+
+impl fmt::Display for MetricKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.resource)?;
+        if let Some(host) = &self.host {
+            write!(f, " host={}", host)?;
+        }
+        if let Some(service) = &self.service {
+            write!(f, " service={}", service)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MetricSummary {
+    pub samples: usize,
+    pub min: f64,
+    pub max: f64,
+    pub avg: f64,
+    // the value with the latest timestamp
+    pub last: f64,
+    // change per second, the slope of a least squares line through the samples. None without
+    // two different timestamps
+    pub trend: Option<f64>
+}
+
+// what a summary needs of a metric's samples, kept up as they come instead of the samples
+#[derive(Debug, Clone, Default)]
+pub(crate) struct MetricAccumulator {
+    // x is seconds since the first sample to come, epoch seconds squared would lose the precision
+    origin: Option<DateTime<Utc>>,
+    samples: usize,
+    sum_x: f64,
+    sum_y: f64,
+    sum_xy: f64,
+    sum_xx: f64,
+    min: f64,
+    max: f64,
+    // the value with the latest timestamp, of equal ones the one that came last
+    last: Option<(DateTime<Utc>, f64)>
+}
+
+fn seconds(delta: chrono::TimeDelta) -> f64 {
+    delta.num_milliseconds() as f64 / 1000.0
+}
+
+impl MetricAccumulator {
+    // samples in the order they came, not necessarily by time
+    pub(crate) fn push(&mut self, at: DateTime<Utc>, value: f64) {
+        let x = seconds(at - *self.origin.get_or_insert(at));
+        if self.samples == 0 {
+            (self.min, self.max) = (value, value);
+        }
+        self.samples += 1;
+        self.sum_x += x;
+        self.sum_y += value;
+        self.sum_xy += x * value;
+        self.sum_xx += x * x;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        if self.last.is_none_or(|(last_at, _)| at >= last_at) {
+            self.last = Some((at, value));
+        }
+    }
+
+    // sessions that a record bridges become one, `other`'s x move to this origin
+    pub(crate) fn merge(&mut self, other: MetricAccumulator) {
+        let (Some(origin), Some(other_origin)) = (self.origin, other.origin) else {
+            if self.origin.is_none() {
+                *self = other;
+            }
+            return;
+        };
+        let shift = seconds(other_origin - origin);
+        let n = other.samples as f64;
+        self.samples += other.samples;
+        self.sum_xx += other.sum_xx + 2.0 * shift * other.sum_x + n * shift * shift;
+        self.sum_xy += other.sum_xy + shift * other.sum_y;
+        self.sum_x += other.sum_x + n * shift;
+        self.sum_y += other.sum_y;
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+        if let Some((at, value)) = other.last {
+            if self.last.is_none_or(|(last_at, _)| at >= last_at) {
+                self.last = Some((at, value));
+            }
+        }
+    }
+
+    pub(crate) fn summary(&self) -> Option<MetricSummary> {
+        let (_, last) = self.last?;
+        let n = self.samples as f64;
+        // least squares from the sums, sxx is 0 up to rounding when every sample has one timestamp
+        let sxx = self.sum_xx - self.sum_x * self.sum_x / n;
+        let sxy = self.sum_xy - self.sum_x * self.sum_y / n;
+        Some(MetricSummary {
+            samples: self.samples,
+            min: self.min,
+            max: self.max,
+            avg: self.sum_y / n,
+            last,
+            trend: (sxx > self.sum_xx * 1e-12).then(|| sxy / sxx)
+        })
+    }
+}
+
+impl fmt::Display for MetricSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "min={} max={} avg={:.2} last={}", self.min, self.max, self.avg, self.last)?;
+        match self.trend {
+            Some(trend) => write!(f, " trend={:+.4}/s", trend),
+            None => write!(f, " trend=-")
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn summary(samples: &[(i64, f64)]) -> Option<MetricSummary> {
+        let mut accumulator = MetricAccumulator::default();
+        for (seconds, value) in samples {
+            accumulator.push(DateTime::from_timestamp(1_700_000_000 + seconds, 0).unwrap(), *value);
+        }
+        accumulator.summary()
+    }
+
+    #[test]
+    fn summary_and_slope() {
+        // 2 per second with some noise, the last one to arrive isn't the latest
+        let noisy = summary(&[(0, 10.0), (10, 31.0), (20, 49.0), (5, 20.0)]).unwrap();
+        assert_eq!((noisy.samples, noisy.min, noisy.max, noisy.avg, noisy.last), (4, 10.0, 49.0, 27.5, 49.0));
+        assert!((noisy.trend.unwrap() - 1.9543).abs() < 1e-4, "{:?}", noisy.trend);
+
+        let flat = summary(&[(7, 3.0), (7, 5.0)]).unwrap();
+        assert_eq!((flat.last, flat.trend), (5.0, None));
+        assert!(summary(&[]).is_none());
+    }
+
+    #[test]
+    fn merged_accumulators_summarise_like_one() {
+        let at = |seconds: i64| DateTime::from_timestamp(1_700_000_000 + seconds, 0).unwrap();
+        let (mut early, mut late) = (MetricAccumulator::default(), MetricAccumulator::default());
+        early.push(at(0), 10.0);
+        early.push(at(5), 20.0);
+        late.push(at(20), 49.0);
+        late.push(at(10), 31.0);
+        late.merge(early);
+
+        let merged = late.summary().unwrap();
+        let whole = summary(&[(0, 10.0), (10, 31.0), (20, 49.0), (5, 20.0)]).unwrap();
+        assert_eq!((merged.samples, merged.min, merged.max, merged.avg, merged.last), (4, 10.0, 49.0, 27.5, 49.0));
+        assert!((merged.trend.unwrap() - whole.trend.unwrap()).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,189 @@
+use std::{collections::{HashMap, HashSet}, fmt, time::Duration};
+
+use chrono::{DateTime, TimeDelta, Utc};
+
+use super::metrics::{MetricAccumulator, MetricKey, MetricSummary};
+use crate::error::LogAnalyzerError;
+
+pub const WINDOW_KINDS: [&str; 3] = ["tumbling", "sliding", "session"];
+
+// how records are grouped into windows by their event time
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Windowing {
+    // back to back windows of `size`, aligned to the epoch, every record is in one
+    Tumbling { size: Duration },
+    // windows of `size` starting every `slide`, a record is in size / slide of them
+    Sliding { size: Duration, slide: Duration },
+    // records less than `gap` apart share a window, which closes after `gap` without records
+    Session { gap: Duration }
+}
+
+impl Windowing {
+    pub fn tumbling(size: Duration) -> Self {
+        Windowing::Tumbling { size }
+    }
+
+    // a kind from `WINDOW_KINDS`, `slide` only matters for sliding windows and defaults to `size`
+    pub fn from_name(kind: &str, size: Duration, slide: Option<Duration>) -> Result<Self, LogAnalyzerError> {
+        match kind {
+            "tumbling" => Ok(Windowing::Tumbling { size }),
+            "sliding" => Ok(Windowing::Sliding { size, slide: slide.unwrap_or(size) }),
+            "session" => Ok(Windowing::Session { gap: size }),
+            other => Err(LogAnalyzerError::Config(format!("unknown window kind '{}', expected one of {}", other, WINDOW_KINDS.join(", "))))
+        }
+    }
+
+    // the size of tumbling and sliding windows, the gap of sessions
+    pub fn size(&self) -> Duration {
+        match self {
+            Windowing::Tumbling { size } | Windowing::Sliding { size, .. } => *size,
+            Windowing::Session { gap } => *gap
+        }
+    }
+
+    pub fn with_size(self, new: Duration) -> Self {
+        match self {
+            Windowing::Tumbling { .. } => Windowing::Tumbling { size: new },
+            // a slide longer than the window would leave gaps
+            Windowing::Sliding { slide, .. } => Windowing::Sliding { size: new, slide: slide.min(new) },
+            Windowing::Session { .. } => Windowing::Session { gap: new }
+        }
+    }
+
+    // start and end of the fixed windows `at` falls into, oldest first. Sessions have none
+    pub(crate) fn windows(&self, at: DateTime<Utc>) -> Vec<(DateTime<Utc>, DateTime<Utc>)> {
+        let (size, slide) = match self {
+            Windowing::Tumbling { size } => (millis(*size), millis(*size)),
+            Windowing::Sliding { size, slide } => (millis(*size), millis(*slide)),
+            Windowing::Session { .. } => return Vec::new()
+        };
+        let at = at.timestamp_millis();
+        let last = at - at.rem_euclid(slide);
+        let mut windows = Vec::new();
+        let mut start = last;
+        while start > at - size {
+            windows.push((from_millis(start), from_millis(start + size)));
+            start -= slide;
+        }
+        windows.reverse();
+        windows
+    }
+}
+
+impl fmt::Display for Windowing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Windowing::Tumbling { size } => write!(f, "tumbling {:?}", size),
+            Windowing::Sliding { size, slide } => write!(f, "sliding {:?} every {:?}", size, slide),
+            Windowing::Session { gap } => write!(f, "session gap {:?}", gap)
+        }
+    }
+}
+
+// at least a millisecond, so windows always move forward
+fn millis(duration: Duration) -> i64 {
+    (duration.as_millis() as i64).max(1)
+}
+
+fn from_millis(millis: i64) -> DateTime<Utc> {
+    DateTime::from_timestamp_millis(millis).unwrap_or(DateTime::<Utc>::MAX_UTC)
+}
+
+pub(crate) fn delta(duration: Duration) -> TimeDelta {
+    TimeDelta::from_std(duration).unwrap_or(TimeDelta::MAX)
+}
+
+// what analytics found in one window, handed out once it closed
+#[derive(Debug, Clone, PartialEq)]
+pub struct WindowResult {
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    // records that fell into the window, sampled ones counted 1 / sample_rate times and the sum
+    // rounded
+    pub records: usize,
+    pub error_counts: HashMap<String, usize>,
+    pub active_users: usize,
+    // p50, p90 and p99 of `duration_ms`, a sampled record weighing 1 / sample_rate
+    pub response_time_percentiles: Option<(f64, f64, f64)>,
+    // per resource, host and service
+    pub resource_usage: HashMap<MetricKey, MetricSummary>
+}
+
+// a window still collecting records
+#[derive(Debug, Clone)]
+pub(crate) struct WindowState {
+    pub(crate) start: DateTime<Utc>,
+    pub(crate) end: DateTime<Utc>,
+    // weighted by 1 / sample_rate, rounded only when reported
+    pub(crate) records: f64,
+    pub(crate) error_counts: HashMap<String, f64>,
+    pub(crate) users: HashSet<String>,
+    // duration and weight
+    pub(crate) response_times: Vec<(f64, f64)>,
+    pub(crate) resource_usage: HashMap<MetricKey, MetricAccumulator>
+}
+
+impl WindowState {
+    pub(crate) fn new(start: DateTime<Utc>, end: DateTime<Utc>) -> Self {
+        Self { start, end, records: 0.0, error_counts: HashMap::new(), users: HashSet::new(), response_times: Vec::new(), resource_usage: HashMap::new() }
+    }
+
+    // sessions that a record bridges become one
+    pub(crate) fn merge(&mut self, other: WindowState) {
+        self.start = self.start.min(other.start);
+        self.end = self.end.max(other.end);
+        self.records += other.records;
+        for (error_type, count) in other.error_counts {
+            *self.error_counts.entry(error_type).or_insert(0.0) += count;
+        }
+        self.users.extend(other.users);
+        self.response_times.extend(other.response_times);
+        for (key, usage) in other.resource_usage {
+            self.resource_usage.entry(key).or_default().merge(usage);
+        }
+    }
+
+    pub(crate) fn percentiles(&self) -> Option<(f64, f64, f64)> {
+        if self.response_times.is_empty() {
+            return None;
+        }
+        let mut response_times = self.response_times.clone();
+        response_times.sort_by(|a, b| a.0.total_cmp(&b.0));
+        let total = response_times.iter().map(|(_, weight)| weight).sum::<f64>();
+        // nearest rank by weight, so the p99 of 100 equal values is the 99th and not the largest
+        let percentile = |p: f64| {
+            let rank = p * total;
+            let mut below = 0.0;
+            for (duration, weight) in &response_times {
+                below += weight;
+                if below >= rank {
+                    return *duration;
+                }
+            }
+            response_times[response_times.len() - 1].0
+        };
+        Some((percentile(0.50), percentile(0.90), percentile(0.99)))
+    }
+
+    pub(crate) fn error_counts(&self) -> HashMap<String, usize> {
+        self.error_counts.iter().map(|(error_type, count)| (error_type.clone(), count.round() as usize)).collect()
+    }
+
+    pub(crate) fn usage(&self) -> HashMap<MetricKey, MetricSummary> {
+        self.resource_usage.iter()
+            .filter_map(|(key, usage)| usage.summary().map(|summary| (key.clone(), summary)))
+            .collect()
+    }
+
+    pub(crate) fn result(&self) -> WindowResult {
+        WindowResult {
+            start: self.start,
+            end: self.end,
+            records: self.records.round() as usize,
+            error_counts: self.error_counts(),
+            active_users: self.users.len(),
+            response_time_percentiles: self.percentiles(),
+            resource_usage: self.usage()
+        }
+    }
+}
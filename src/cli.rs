@@ -0,0 +1,734 @@
+use std::{error::Error, io::{IsTerminal, Write}, path::PathBuf, process::ExitCode, sync::{Arc, Mutex}, time::Duration};
+
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use tokio::sync::broadcast;
+
+use loganalyzer::analytics::{LogAnalytics, Windowing};
+use loganalyzer::config::PipelineConfig;
+use loganalyzer::dead_letter::{self, DeadLetterStats, FileDeadLetterSink};
+use loganalyzer::engine::{overflow, shutdown, Engine, EngineEvent, OverflowPolicy, OverflowStats, SourceState, StagePolicy, SupervisionPolicy};
+use loganalyzer::error::LogAnalyzerError;
+use loganalyzer::filter::LogFilter;
+use loganalyzer::formatter::{self, LogFormatter, PlainTextFormatter};
+use loganalyzer::ingest::{file_source::FileLogSource, listener_source::ListenerLogSource, network_source::NetworkLogSource, spooled_source::SpooledSource, LogSource};
+use loganalyzer::parser::{registry::ParserRegistry, Level, ParsedLog};
+use loganalyzer::spool::SpoolConfig;
+use loganalyzer::transform::TransformCounter;
+
+// process exit codes, also listed in `--help`
+pub const EXIT_OK: u8 = 0;
+pub const EXIT_FAILURE: u8 = 1;
+pub const EXIT_USAGE: u8 = 2;
+pub const EXIT_INPUT: u8 = 3;
+pub const EXIT_DEAD_LETTERS: u8 = 4;
+
+#[derive(Debug, Parser)]
+#[command(name = "loganalyzer", version, about = "Parse, filter, convert and analyze log streams")]
+#[command(after_help = "Exit codes:\n  0  success\n  1  runtime failure\n  2  invalid usage\n  3  an input could not be opened or connected to\n  4  finished, but some records failed to parse")]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Parse the given inputs and print every record
+    Analyze {
+        /// Files, or addresses with --source-type network
+        #[arg(required = true)]
+        inputs: Vec<String>,
+
+        #[arg(long, value_enum, default_value_t = SourceType::File)]
+        source_type: SourceType,
+
+        #[command(flatten)]
+        pipeline: PipelineArgs,
+
+        #[command(flatten)]
+        output: OutputArgs,
+    },
+
+    /// Follow files as they grow, like `tail -f`
+    Tail {
+        #[arg(required = true)]
+        files: Vec<PathBuf>,
+
+        /// Read the existing content first instead of starting at the end
+        #[arg(long)]
+        from_start: bool,
+
+        #[command(flatten)]
+        pipeline: PipelineArgs,
+
+        #[command(flatten)]
+        output: OutputArgs,
+    },
+
+    /// Print a summary of the inputs instead of the records
+    Stats {
+        #[arg(required = true)]
+        inputs: Vec<String>,
+
+        #[arg(long, value_enum, default_value_t = SourceType::File)]
+        source_type: SourceType,
+
+        #[command(flatten)]
+        pipeline: PipelineArgs,
+    },
+
+    /// Rewrite the inputs in another format
+    Convert {
+        #[arg(required = true)]
+        inputs: Vec<String>,
+
+        #[arg(long, value_enum, default_value_t = SourceType::File)]
+        source_type: SourceType,
+
+        /// Target format
+        #[arg(long, value_enum)]
+        to: OutputFormat,
+
+        /// Layout for --to template
+        #[arg(long)]
+        template: Option<String>,
+
+        /// Write to this file instead of stdout
+        #[arg(long, short = 'O')]
+        out: Option<PathBuf>,
+
+        #[command(flatten)]
+        pipeline: PipelineArgs,
+    },
+
+    /// Accept newline delimited records from TCP clients
+    Serve {
+        #[arg(long, default_value = "127.0.0.1:8888")]
+        listen: String,
+
+        #[command(flatten)]
+        pipeline: PipelineArgs,
+
+        #[command(flatten)]
+        output: OutputArgs,
+    },
+
+    /// Run the pipeline described by a configuration file
+    Run {
+        #[arg(long, short = 'c')]
+        config: PathBuf,
+
+        /// Apply changes to the file (or SIGHUP) without restarting, until Ctrl-C
+        #[arg(long)]
+        watch: bool,
+    },
+
+    /// Validate a configuration file without starting anything
+    Check {
+        #[arg(long, short = 'c')]
+        config: PathBuf,
+    },
+
+    /// Re-parse a dead-letter file, e.g. after fixing the parser configuration
+    Replay {
+        dead_letter_file: PathBuf,
+
+        /// Always use this parser
+        #[arg(long)]
+        parser: Option<String>,
+
+        /// Write records that still fail to this dead-letter file
+        #[arg(long)]
+        dead_letter: Option<PathBuf>,
+
+        #[command(flatten)]
+        output: OutputArgs,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum SourceType {
+    File,
+    Network,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    Json,
+    Text,
+    Logfmt,
+    Cef,
+    Template,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OnError {
+    /// Retry transient read errors with backoff, skip bad records
+    Retry,
+    /// Keep reading after any error that isn't fatal
+    Skip,
+    /// Stop everything on the first read error
+    Fail,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Window {
+    /// Back to back windows of --window-size seconds
+    Tumbling,
+    /// Windows of --window-size seconds starting every --slide seconds
+    Sliding,
+    /// Windows that end after --window-size seconds without records
+    Session,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Overflow {
+    /// Wait for the output, slowing down the inputs
+    Block,
+    /// Drop the record that doesn't fit
+    DropNewest,
+    /// Drop the oldest waiting record to make room
+    DropOldest,
+    /// Keep every --sample-every record, drop the rest
+    Sample,
+    /// Write records to --spill-path until there is room
+    Spill,
+}
+
+#[derive(Debug, Args)]
+pub struct PipelineArgs {
+    /// Always use this parser instead of detecting the format (json, plain_text, cef, leef)
+    #[arg(long)]
+    parser: Option<String>,
+
+    /// Analytics window in seconds
+    #[arg(long, default_value_t = 100)]
+    window_size: i64,
+
+    /// How analytics group records into windows by their timestamp
+    #[arg(long, value_enum, default_value_t = Window::Tumbling)]
+    window: Window,
+
+    /// Seconds between the starts of sliding windows, --window-size when not given
+    #[arg(long)]
+    slide: Option<u64>,
+
+    /// Seconds a record may trail the latest timestamp and still count in analytics
+    #[arg(long, default_value_t = 0)]
+    allowed_lateness: u64,
+
+    /// Metadata number to track per host and service, repeatable (default cpu_usage, memory_usage, disk_usage, queue_depth)
+    #[arg(long = "metric")]
+    metrics: Vec<String>,
+
+    /// Append records that fail to parse to this file
+    #[arg(long)]
+    dead_letter: Option<PathBuf>,
+
+    /// Only keep records at this level or more severe
+    #[arg(long, value_parser = parse_level)]
+    level: Option<Level>,
+
+    /// Only keep records from this service (repeatable)
+    #[arg(long)]
+    service: Vec<String>,
+
+    /// Only keep records whose message contains this text
+    #[arg(long)]
+    grep: Option<String>,
+
+    /// Lines parsed together
+    #[arg(long, default_value_t = 100)]
+    batch_size: usize,
+
+    /// Longest a line waits for its batch to fill up, in milliseconds
+    #[arg(long, default_value_t = 200)]
+    max_latency_ms: u64,
+
+    /// Seconds to finish in-flight records after Ctrl-C or SIGTERM before dropping them
+    #[arg(long, default_value_t = 10)]
+    drain_timeout: u64,
+
+    /// What a read error does to its input
+    #[arg(long, value_enum, default_value_t = OnError::Retry)]
+    on_error: OnError,
+
+    /// What happens to parsed records while the output is full
+    #[arg(long, value_enum, default_value_t = Overflow::Block)]
+    overflow: Overflow,
+
+    /// Parsed records waiting for the output before it counts as full
+    #[arg(long, default_value_t = 100)]
+    queue_capacity: usize,
+
+    /// Also full once the waiting records take this many megabytes
+    #[arg(long)]
+    memory_budget_mb: Option<usize>,
+
+    /// With --overflow sample, every n-th record waits for room
+    #[arg(long, default_value_t = 10)]
+    sample_every: u32,
+
+    /// With --overflow spill, where records wait once the output is full
+    #[arg(long)]
+    spill_path: Option<PathBuf>,
+
+    /// Keep read lines in this directory until they are written out, lines that weren't are
+    /// delivered again on the next run with the same inputs
+    #[arg(long)]
+    spool_dir: Option<PathBuf>,
+}
+
+#[derive(Debug, Args)]
+pub struct OutputArgs {
+    #[arg(long, short = 'o', value_enum, default_value_t = OutputFormat::Text)]
+    output: OutputFormat,
+
+    /// Layout for --output template, e.g. "{timestamp} [{level}] {service_name}: {message}"
+    #[arg(long)]
+    template: Option<String>,
+
+    /// Never color the text output
+    #[arg(long)]
+    no_color: bool,
+}
+
+fn parse_level(level: &str) -> Result<Level, String> {
+    level.parse().map_err(|e: LogAnalyzerError| e.to_string())
+}
+
+impl OutputFormat {
+    fn formatter(self, template: Option<&str>, color: bool) -> Result<Box<dyn LogFormatter>, LogAnalyzerError> {
+        match self {
+            // colors only make sense on a terminal
+            OutputFormat::Text => Ok(Box::new(PlainTextFormatter::new().with_color(color && std::io::stdout().is_terminal()))),
+            OutputFormat::Json => formatter::from_name("json", None),
+            OutputFormat::Logfmt => formatter::from_name("logfmt", None),
+            OutputFormat::Cef => formatter::from_name("cef", None),
+            OutputFormat::Template => formatter::from_name("template", template),
+        }
+    }
+}
+
+impl OutputArgs {
+    fn formatter(&self) -> Result<Box<dyn LogFormatter>, LogAnalyzerError> {
+        self.output.formatter(self.template.as_deref(), !self.no_color)
+    }
+}
+
+impl PipelineArgs {
+    fn registry(&self) -> Result<ParserRegistry, LogAnalyzerError> {
+        let mut registry = ParserRegistry::builtin();
+        if let Some(parser) = &self.parser {
+            registry.pin(parser).map_err(|_| LogAnalyzerError::Config(format!(
+                "unknown parser '{}', expected one of {}", parser, registry.parser_names().join(", ")
+            )))?;
+        }
+        Ok(registry)
+    }
+
+    fn analytics(&self) -> Result<LogAnalytics, LogAnalyzerError> {
+        if self.window_size <= 0 {
+            return Err(LogAnalyzerError::Config("--window-size must be a positive number of seconds".to_string()));
+        }
+        let size = Duration::from_secs(self.window_size as u64);
+        let slide = self.slide.map(Duration::from_secs);
+        if slide.is_some_and(|slide| slide.is_zero() || slide > size) || (slide.is_some() && self.window != Window::Sliding) {
+            return Err(LogAnalyzerError::Config("--slide needs --window sliding and at most --window-size seconds".to_string()));
+        }
+        let windowing = match self.window {
+            Window::Tumbling => Windowing::Tumbling { size },
+            Window::Sliding => Windowing::Sliding { size, slide: slide.unwrap_or(size) },
+            Window::Session => Windowing::Session { gap: size },
+        };
+        let mut analytics = LogAnalytics::new(self.window_size)
+            .with_windowing(windowing)
+            .with_allowed_lateness(Duration::from_secs(self.allowed_lateness));
+        if !self.metrics.is_empty() {
+            analytics = analytics.with_metrics(self.metrics.clone());
+        }
+        Ok(analytics)
+    }
+
+    fn engine(&self) -> Result<Engine, LogAnalyzerError> {
+        let mut engine = Engine::new(Box::new(self.registry()?))
+            .with_analytics(self.analytics()?)
+            .with_batch_size(self.batch_size)
+            .with_max_latency(Duration::from_millis(self.max_latency_ms))
+            .with_supervision(match self.on_error {
+                OnError::Retry => SupervisionPolicy::default(),
+                OnError::Skip => SupervisionPolicy::Skip,
+                OnError::Fail => SupervisionPolicy::Fail,
+            })
+            .with_output_policy(self.output_policy()?);
+        if let Some(path) = &self.dead_letter {
+            engine = engine.with_dead_letter_sink(Box::new(FileDeadLetterSink::new(path)));
+        }
+        Ok(engine)
+    }
+
+    // every input gets its own spool, by position on the command line
+    fn spooled(&self, index: usize, source: Box<dyn LogSource>) -> Box<dyn LogSource> {
+        match &self.spool_dir {
+            Some(dir) => Box::new(SpooledSource::new(source, SpoolConfig::new(dir.join(format!("input-{}", index))))),
+            None => source,
+        }
+    }
+
+    fn output_policy(&self) -> Result<StagePolicy, LogAnalyzerError> {
+        let policy = match self.overflow {
+            Overflow::Block => OverflowPolicy::Block,
+            Overflow::DropNewest => OverflowPolicy::DropNewest,
+            Overflow::DropOldest => OverflowPolicy::DropOldest,
+            Overflow::Sample => OverflowPolicy::Sample(self.sample_every.max(1)),
+            Overflow::Spill => OverflowPolicy::Spill(self.spill_path.clone().ok_or_else(|| {
+                LogAnalyzerError::Config("--overflow spill needs --spill-path".to_string())
+            })?),
+        };
+        let mut stage = StagePolicy::new(policy).with_capacity(self.queue_capacity);
+        if let Some(memory_budget_mb) = self.memory_budget_mb {
+            stage = stage.with_memory_budget(memory_budget_mb * 1024 * 1024);
+        }
+        Ok(stage)
+    }
+
+    fn filter(&self) -> LogFilter {
+        let mut filter = LogFilter::new();
+        if let Some(level) = &self.level {
+            filter = filter.with_min_level(level.clone());
+        }
+        for service in &self.service {
+            filter = filter.with_service(service.clone());
+        }
+        if let Some(grep) = &self.grep {
+            filter = filter.with_contains(grep.clone());
+        }
+        filter
+    }
+}
+
+fn add_inputs(engine: &mut Engine, inputs: &[String], source_type: SourceType, pipeline: &PipelineArgs) {
+    for (index, input) in inputs.iter().enumerate() {
+        let source: Box<dyn LogSource> = match source_type {
+            SourceType::File => Box::new(FileLogSource::new(input)),
+            SourceType::Network => Box::new(NetworkLogSource::new(input.clone())),
+        };
+        engine.add_source(pipeline.spooled(index, source));
+    }
+}
+
+// writes every record that passes the filter, returns how many were written
+async fn write_records(rx: overflow::Receiver<ParsedLog>, filter: &LogFilter, formatter: &dyn LogFormatter, out: &mut dyn Write)
+    -> Result<usize, Box<dyn Error + Send + Sync>> {
+
+    // spooled inputs only let go of records once they are flushed
+    let mut rx = rx.with_manual_ack();
+    let mut written = 0;
+    while let Some(parsed_log) = rx.recv().await {
+        if filter.matches(&parsed_log) {
+            writeln!(out, "{}", formatter.format(&parsed_log)).map_err(LogAnalyzerError::Io)?;
+            written += 1;
+        }
+        if rx.is_empty() {
+            out.flush().map_err(LogAnalyzerError::Io)?;
+            rx.ack();
+        }
+    }
+    out.flush().map_err(LogAnalyzerError::Io)?;
+    rx.ack();
+    Ok(written)
+}
+
+// read errors and sources giving up go to stderr as they happen, sources that simply reached
+// their end don't
+fn log_events(mut events: broadcast::Receiver<EngineEvent>) {
+    tokio::spawn(async move {
+        loop {
+            match events.recv().await {
+                Ok(EngineEvent::SourceDone { state: SourceState::Finished | SourceState::Stopped, .. }) => {}
+                Ok(event) => eprintln!("{}", event),
+                Err(broadcast::error::RecvError::Lagged(missed)) => eprintln!("{} engine event(s) were not shown", missed),
+                Err(broadcast::error::RecvError::Closed) => break
+            }
+        }
+    });
+}
+
+// a failed engine is a runtime failure, otherwise records that were dead-lettered turn a
+// successful run into EXIT_DEAD_LETTERS
+fn finish(engine: &Engine) -> u8 {
+    report_overflow(&engine.overflow_stats());
+    if engine.has_failed() {
+        return EXIT_FAILURE;
+    }
+    report_dead_letters(&engine.dead_letter_stats())
+}
+
+// only stages that shed or spilled something
+fn report_overflow(stats: &[OverflowStats]) {
+    for stage in stats.iter().filter(|stage| stage.dropped() > 0 || stage.spilled > 0) {
+        eprintln!("overflow {}", stage);
+    }
+}
+
+// only counters that counted something, e.g. what redaction found
+fn report_transforms(counters: &[TransformCounter]) {
+    for counter in counters.iter().filter(|counter| counter.value > 0) {
+        eprintln!("transform {}", counter);
+    }
+}
+
+fn report_dead_letters(stats: &DeadLetterStats) -> u8 {
+    if stats.total == 0 {
+        return EXIT_OK;
+    }
+
+    let mut kinds = stats.by_kind.iter().collect::<Vec<_>>();
+    kinds.sort();
+    let kinds = kinds.iter().map(|(kind, count)| format!("{}={}", kind, count)).collect::<Vec<_>>().join(" ");
+    eprintln!("{} record(s) failed to parse ({})", stats.total, kinds);
+    EXIT_DEAD_LETTERS
+}
+
+// runs until the inputs are exhausted, or drains in-flight records on Ctrl-C / SIGTERM
+async fn run_pipeline(mut engine: Engine, args: &PipelineArgs, formatter: Box<dyn LogFormatter>, out: &mut dyn Write)
+    -> Result<u8, Box<dyn Error + Send + Sync>> {
+
+    log_events(engine.subscribe_events());
+    let rx = engine.run().await?;
+    let filter = args.filter();
+    let writer = write_records(rx, &filter, formatter.as_ref(), out);
+    tokio::pin!(writer);
+
+    tokio::select! {
+        result = &mut writer => {
+            result?;
+        }
+        _ = shutdown::signal() => {
+            let drain_timeout = Duration::from_secs(args.drain_timeout);
+            // the writer keeps reading while the engine drains, then sees the output close
+            let (mut report, written) = tokio::join!(
+                engine.shutdown(drain_timeout),
+                tokio::time::timeout(drain_timeout + Duration::from_secs(1), &mut writer)
+            );
+            match written {
+                Ok(result) => {
+                    result?;
+                    report.sinks_flushed = Some(true);
+                }
+                Err(_) => report.sinks_flushed = Some(false)
+            }
+
+            eprintln!("{}", report);
+            if !report.drained || report.sinks_flushed != Some(true) {
+                return Ok(EXIT_FAILURE);
+            }
+        }
+    }
+    Ok(finish(&engine))
+}
+
+async fn stats(mut engine: Engine, filter: LogFilter) -> Result<u8, Box<dyn Error + Send + Sync>> {
+    log_events(engine.subscribe_events());
+    // every window is reported, not just the last one
+    let windows = Arc::new(Mutex::new(Vec::new()));
+    let closed = windows.clone();
+    engine.analytics().lock().await.set_window_sink(move |window| closed.lock().unwrap().push(window));
+    let mut rx = engine.run().await?;
+
+    let mut total = 0;
+    let mut by_level = std::collections::BTreeMap::new();
+    let mut by_service = std::collections::BTreeMap::new();
+
+    while let Some(parsed_log) = rx.recv().await {
+        if !filter.matches(&parsed_log) {
+            continue;
+        }
+        total += 1;
+        let level = parsed_log.level.as_ref().map(Level::as_str).unwrap_or("none");
+        *by_level.entry(level).or_insert(0) += 1;
+        *by_service.entry(parsed_log.service_name.unwrap_or_else(|| "-".to_string())).or_insert(0) += 1;
+    }
+
+    let join = |counts: Vec<(String, usize)>| counts.iter()
+        .map(|(key, count)| format!("{}={}", key, count))
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    println!("records       {}", total);
+    println!("levels        {}", join(by_level.into_iter().map(|(k, v)| (k.to_string(), v)).collect()));
+    println!("services      {}", join(by_service.into_iter().collect()));
+
+    // every window closed when the input ended
+    let windows = std::mem::take(&mut *windows.lock().unwrap());
+    for window in &windows {
+        let mut errors = window.error_counts.clone().into_iter().collect::<Vec<_>>();
+        errors.sort();
+        let response = match window.response_time_percentiles {
+            Some((p50, p90, p99)) => format!("p50={} p90={} p99={}", p50, p90, p99),
+            None => "-".to_string()
+        };
+        println!("window        {} to {} records={} users={} response ms {} errors {}",
+            window.start.to_rfc3339(), window.end.to_rfc3339(), window.records, window.active_users, response,
+            if errors.is_empty() { "-".to_string() } else { join(errors) });
+        let mut usage = window.resource_usage.iter().collect::<Vec<_>>();
+        usage.sort_by_key(|(key, _)| *key);
+        for (key, summary) in usage {
+            println!("  resource    {} {}", key, summary);
+        }
+    }
+    let analytics = engine.analytics();
+    let analytics = analytics.lock().await;
+    println!("windows       {} ({}) late={} untimed={}", windows.len(), analytics.windowing(), analytics.late_records(), analytics.untimed_records());
+
+    let dead_letters = engine.dead_letter_stats();
+    println!("dead letters  {}", dead_letters.total);
+
+    let batches = engine.batch_metrics();
+    println!("batches       {} (full={} timer={} final={}) fill={:.0}% latency mean={:?} max={:?}",
+        batches.batches, batches.full_flushes, batches.timer_flushes, batches.final_flushes,
+        batches.fill_ratio * 100.0, batches.mean_latency, batches.max_latency);
+
+    Ok(finish(&engine))
+}
+
+async fn replay(dead_letter_file: PathBuf, parser: Option<String>, dead_letter: Option<PathBuf>, output: OutputArgs)
+    -> Result<u8, Box<dyn Error + Send + Sync>> {
+
+    let pipeline = PipelineArgs { parser, window_size: 100, window: Window::Tumbling, slide: None, allowed_lateness: 0, metrics: Vec::new(), dead_letter: None, level: None, service: Vec::new(), grep: None, batch_size: 100, max_latency_ms: 200, drain_timeout: 10, on_error: OnError::Retry,
+        overflow: Overflow::Block, queue_capacity: 100, memory_budget_mb: None, sample_every: 10, spill_path: None, spool_dir: None };
+    let registry = pipeline.registry()?;
+    let formatter = output.formatter()?;
+
+    let outcome = dead_letter::replay(&dead_letter_file, &registry).await?;
+
+    let mut stdout = std::io::stdout().lock();
+    for parsed_log in &outcome.recovered {
+        writeln!(stdout, "{}", formatter.format(parsed_log)).map_err(LogAnalyzerError::Io)?;
+    }
+
+    if outcome.failed.is_empty() {
+        return Ok(EXIT_OK);
+    }
+
+    eprintln!("{} record(s) recovered, {} still failing", outcome.recovered.len(), outcome.failed.len());
+    if let Some(path) = dead_letter {
+        let queue = dead_letter::DeadLetterQueue::with_sink(Box::new(FileDeadLetterSink::new(path)));
+        for failed in outcome.failed {
+            queue.report(failed).await;
+        }
+        queue.flush().await?;
+    }
+    Ok(EXIT_DEAD_LETTERS)
+}
+
+async fn dispatch(command: Command) -> Result<u8, Box<dyn Error + Send + Sync>> {
+    match command {
+        Command::Analyze { inputs, source_type, pipeline, output } => {
+            let mut engine = pipeline.engine()?;
+            add_inputs(&mut engine, &inputs, source_type, &pipeline);
+            run_pipeline(engine, &pipeline, output.formatter()?, &mut std::io::stdout().lock()).await
+        }
+        Command::Tail { files, from_start, pipeline, output } => {
+            let mut engine = pipeline.engine()?;
+            for (index, file) in files.into_iter().enumerate() {
+                let source = Box::new(FileLogSource::new(file).with_follow(true).with_start_at_end(!from_start));
+                engine.add_source(pipeline.spooled(index, source));
+            }
+            run_pipeline(engine, &pipeline, output.formatter()?, &mut std::io::stdout().lock()).await
+        }
+        Command::Stats { inputs, source_type, pipeline } => {
+            let mut engine = pipeline.engine()?;
+            add_inputs(&mut engine, &inputs, source_type, &pipeline);
+            stats(engine, pipeline.filter()).await
+        }
+        Command::Convert { inputs, source_type, to, template, out, pipeline } => {
+            let mut engine = pipeline.engine()?;
+            add_inputs(&mut engine, &inputs, source_type, &pipeline);
+            let formatter = to.formatter(template.as_deref(), false)?;
+
+            match out {
+                Some(path) => {
+                    let file = std::fs::File::create(path).map_err(LogAnalyzerError::Io)?;
+                    run_pipeline(engine, &pipeline, formatter, &mut std::io::BufWriter::new(file)).await
+                }
+                None => run_pipeline(engine, &pipeline, formatter, &mut std::io::stdout().lock()).await
+            }
+        }
+        Command::Serve { listen, pipeline, output } => {
+            let mut engine = pipeline.engine()?;
+            engine.add_source(pipeline.spooled(0, Box::new(ListenerLogSource::new(listen))));
+            run_pipeline(engine, &pipeline, output.formatter()?, &mut std::io::stdout().lock()).await
+        }
+        Command::Run { config, watch } => {
+            let pipeline = PipelineConfig::load(&config)?.build()?;
+            log_events(pipeline.engine().subscribe_events());
+            let outcome = if watch {
+                pipeline.run_with_reload(&config, Duration::from_secs(1)).await?
+            } else {
+                pipeline.run().await?
+            };
+
+            report_overflow(&outcome.overflow);
+            report_transforms(&outcome.transforms);
+            if let Some(report) = &outcome.shutdown {
+                eprintln!("{}", report);
+                if !report.drained || report.sinks_flushed != Some(true) {
+                    return Ok(EXIT_FAILURE);
+                }
+            }
+            if outcome.failed {
+                return Ok(EXIT_FAILURE);
+            }
+            Ok(report_dead_letters(&outcome.dead_letters))
+        }
+        Command::Check { config } => {
+            PipelineConfig::load(&config)?;
+            println!("{}: ok", config.display());
+            Ok(EXIT_OK)
+        }
+        Command::Replay { dead_letter_file, parser, dead_letter, output } => {
+            replay(dead_letter_file, parser, dead_letter, output).await
+        }
+    }
+}
+
+fn exit_code_for(error: &(dyn Error + 'static)) -> u8 {
+    match error.downcast_ref::<LogAnalyzerError>() {
+        Some(LogAnalyzerError::Config(_)) | Some(LogAnalyzerError::ParserNotFound) => EXIT_USAGE,
+        Some(LogAnalyzerError::Io(_)) | Some(LogAnalyzerError::NetworkError(_)) | Some(LogAnalyzerError::SourceNotInitialized) => EXIT_INPUT,
+        _ => EXIT_FAILURE
+    }
+}
+
+pub async fn run(cli: Cli) -> ExitCode {
+    match dispatch(cli.command).await {
+        Ok(code) => ExitCode::from(code),
+        Err(e) => {
+            eprintln!("error: {}", e);
+            ExitCode::from(exit_code_for(e.as_ref()))
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_subcommands_and_maps_exit_codes() {
+        let cli = Cli::try_parse_from(["loganalyzer", "convert", "--to", "logfmt", "--level", "warning", "a.log"]).unwrap();
+        match cli.command {
+            Command::Convert { inputs, to, pipeline, .. } => {
+                assert_eq!(inputs, vec!["a.log".to_string()]);
+                assert_eq!(to, OutputFormat::Logfmt);
+                assert_eq!(pipeline.level, Some(Level::Warn));
+            }
+            other => panic!("unexpected command {:?}", other),
+        }
+
+        assert!(Cli::try_parse_from(["loganalyzer", "analyze"]).is_err());
+        assert_eq!(exit_code_for(&LogAnalyzerError::Config("x".to_string())), EXIT_USAGE);
+        assert_eq!(exit_code_for(&LogAnalyzerError::NetworkError("x".to_string())), EXIT_INPUT);
+    }
+}
@@ -0,0 +1,1364 @@
+// Declarative pipeline configuration.
+//
+// A TOML file describes the whole pipeline, so a deployment doesn't need a recompile:
+//
+//   [[sources]]
+//   name = "app"
+//   type = "file"            # file | network | listener
+//   path = "/var/log/app.log"
+//   follow = true
+//   checkpoint = "/var/lib/loganalyzer/app.checkpoint"
+//                            # optional, file only, where reading stopped. A restart, or a
+//                            # reload that restarts the source, resumes there instead of reading
+//                            # the file again and repeating records already delivered
+//   parser = "json"          # optional, skips detection for this source
+//   on_error = "retry"       # retry | skip | fail, what a read error does to the source
+//   max_retries = 5          # retry only, failed attempts in a row before the source gives up
+//   retry_backoff_ms = 100   # retry only, first wait, doubled per attempt up to 5 seconds
+//   # listener sources also take an `overflow` table like the one below for their client lines
+//   spool = { dir = "/var/spool/loganalyzer/app", fsync = "interval" }
+//                            # optional, lines wait on disk until the sinks flushed them and are
+//                            # delivered again after a crash. fsync = always | interval | never,
+//                            # also fsync_interval_ms (1000), segment_mb (16) and max_mb (at least segment_mb)
+//
+//   [parsers]
+//   enabled = ["json", "cef", "plain_text"]
+//   sticky_after = 5
+//
+//   [[transforms]]            # applied in order, sources take a `transforms` list of their own
+//   type = "filter"           # filter | drop keep / drop what matches `level`, `services`, `contains`
+//   level = "warn"
+//
+//   [[transforms]]
+//   type = "rename"           # moves metadata values, paths are dotted: "http.status"
+//   fields = { usr = "user.name" }
+//                             # delete takes a list of paths, promote a table of path = record
+//                             # field (service_name, host, level, ...), coerce a table of
+//                             # path = string | integer | float | boolean
+//   # type = "defaults" sets `environment` and `host` where they're missing
+//   # type = "split" turns the array at `field` into one record per element, `merge = true`
+//   # puts the keys of object elements at the top of metadata
+//
+//   [[transforms]]
+//   type = "script"           # Rhai, see `transform/script.rs` for what a script can do
+//   path = "massage.rhai"     # or the script inline as `script = '''...'''`
+//   max_operations = 100000   # per record, a record whose script fails or runs out of
+//   timeout_ms = 50           # operations or time goes to the dead letters
+//
+//   [[transforms]]
+//   type = "redact"           # emails, IPs, card numbers, JWTs, AWS keys and bearer tokens in
+//   mode = "hash"             # `message` and metadata. mask | remove | hash, hash replaces a
+//   key_env = "REDACT_KEY"    # value by a keyed HMAC so it still correlates, `key` inline
+//   detectors = ["email", "ipv4", "credit_card"]   # optional, all of them when not given
+//   patterns = { employee = 'E-\d{5}' }   # a `secret` group limits what is redacted
+//   allow = ["client.ip"]     # paths left alone, `message` for the message
+//   deny = ["password"]       # paths redacted whatever they contain
+//
+//   [[transforms]]
+//   type = "lookup"           # joins records against a CSV (header line) or JSON table
+//   path = "hosts.csv"
+//   fields = { host = "hostname" }   # record field or metadata path = key column, a list
+//                             # when they're named the same. Key cells can be `*`, `web-*` or
+//                             # CIDR ranges, exact keys win, then the first matching row
+//   columns = ["datacenter", "team"]   # optional, every other column when not given
+//   target = "site"           # optional, metadata path the columns go under
+//   check_interval_ms = 5000  # the file is reloaded when it changed
+//
+//   [[transforms]]
+//   type = "geoip"            # country, city and location from a MaxMind City database, asn
+//   field = "client.ip"       # and as_org from an ASN one, either is optional
+//   city_db = "GeoLite2-City.mmdb"
+//   asn_db = "GeoLite2-ASN.mmdb"
+//   target = "geo"            # optional, where the result goes in metadata
+//   cache_size = 10000        # optional, addresses remembered
+//   # type = "user_agent" parses `field` into browser, os, device and bot under
+//   # `user_agent_info` (or `target`), also with `cache_size`
+//
+//   [[transforms]]
+//   type = "dedupe"           # drops repeats within a window and adds one "message repeated N
+//   mode = "near"             # times" record when it ends. exact | near, near ignores numbers,
+//                             # ids and addresses
+//   fields = ["service_name", "message"]   # optional, level, service_name, host and message
+//   window_ms = 60000         # by record time
+//   max_entries = 10000       # fingerprints tracked, the oldest window ends early beyond that
+//
+//   [[transforms]]
+//   type = "sample"           # share of records kept per level, "*" for the rest. Levels
+//   rates = { info = 0.1, debug = 0.01, trace = 0.01 }   # without a rate are kept whole
+//   service_rates = { checkout = { info = 1.0 } }         # go before `rates`
+//                             # kept records carry `metadata.sample_rate`, analytics count them
+//                             # 1 / rate times
+//
+//   [[transforms]]
+//   type = "rate_limit"       # a token bucket per key, records beyond it are dropped. In a
+//   rate = 100.0              # source's `transforms` it limits that source
+//   burst = 500               # optional, `rate` when not given
+//   fields = ["service_name"] # optional, the key, one bucket for everything when not given
+//   max_entries = 10000       # optional, buckets kept
+//
+//   [analytics]
+//   window_size = 300
+//   window = "sliding"       # tumbling | sliding | session, by record time. Session windows end
+//                            # after window_size seconds without records
+//   slide = 60               # sliding only, seconds between window starts
+//   allowed_lateness = 30    # seconds a record may trail the latest one and still count, later
+//                            # ones are counted as late. Open windows close when the input ends
+//   metrics = ["cpu_usage", "queue.depth"]
+//                            # metadata numbers tracked per host and service with min, max, avg,
+//                            # last and trend. cpu_usage, memory_usage, disk_usage and
+//                            # queue_depth when not given
+//
+//   [batching]
+//   size = 100               # lines parsed together
+//   max_latency_ms = 200     # a line never waits longer than this for its batch to fill
+//
+//   [overflow.output]        # parsed records waiting for a sink, unless the sink has its own
+//   policy = "drop_oldest"   # block | drop_newest | drop_oldest | sample | spill
+//   capacity = 1000          # records, 100 when not given
+//   memory_mb = 64           # optional, also full once the queued records weigh this much
+//   sample_every = 10        # sample only, every n-th record waits for room, the rest are dropped
+//   spill_path = "spill.ndjson"  # spill only, where records wait in order once the queue is full
+//
+//   [[sinks]]
+//   name = "console"
+//   type = "stdout"          # stdout | file
+//   format = "text"
+//   level = "error"          # optional, also `services` and `contains` like a filter transform
+//   overflow = { policy = "drop_oldest", capacity = 5000 }
+//                            # optional, every sink reads from its own queue. With a policy
+//                            # other than block a slow sink falls behind alone instead of
+//                            # holding up the sources and the other sinks
+//
+//   [dead_letter]
+//   path = "failed.ndjson"
+//
+//   [shutdown]
+//   drain_timeout = 10       # seconds to finish in-flight records on Ctrl-C or SIGTERM
+//
+// `PipelineConfig::parse` checks everything that can be checked without touching the outside
+// world and reports each problem as `<origin>:<line>:<column>: <message>`. A running pipeline can
+// pick up a changed file, see `reload`.
+
+use std::{collections::{BTreeMap, HashSet}, error::Error, ops::Range, path::{Path, PathBuf}, time::Duration};
+
+use serde::Deserialize;
+use tokio::task::JoinHandle;
+use toml::Spanned;
+
+use crate::analytics::{LogAnalytics, Windowing, DEFAULT_METRICS};
+use crate::dead_letter::{DeadLetterStats, FileDeadLetterSink};
+use crate::engine::{overflow, shutdown, Engine, OverflowPolicy, OverflowStats, ShutdownReport, StagePolicy, Subscription, SupervisionPolicy};
+use crate::error::LogAnalyzerError;
+use crate::filter::LogFilter;
+use crate::formatter;
+use crate::ingest::{file_source::FileLogSource, listener_source::ListenerLogSource, network_source::NetworkLogSource, spooled_source::SpooledSource, LogSource};
+use crate::parser::{registry::{ParserRegistry, BUILTIN_PARSERS}, Level, ParsedLog};
+use crate::sink::{FileSink, LogSink, StdoutSink};
+use crate::spool::{FsyncPolicy, SpoolConfig};
+use crate::transform::{redact::{DETECTORS, REDACT_MODES}, CoerceFields, Dedupe, DedupeMode, DeleteFields, GeoIp, LogField, Lookup, Predicate, PromoteFields, RateLimit, Redact, RedactMode, RenameFields, Sample, ScriptTransform, SetDefaults, SplitRecords, Transform, TransformChain, TransformCounter, UserAgent, ValueType};
+
+pub mod reload;
+
+const SOURCE_TYPES: [&str; 3] = ["file", "network", "listener"];
+const TRANSFORM_TYPES: [&str; 16] = ["filter", "drop", "rename", "delete", "promote", "defaults", "coerce", "split", "script", "redact", "lookup", "geoip", "user_agent", "dedupe", "sample", "rate_limit"];
+const SINK_TYPES: [&str; 2] = ["stdout", "file"];
+const ON_ERROR: [&str; 3] = ["retry", "skip", "fail"];
+const FSYNC_POLICIES: [&str; 3] = ["always", "interval", "never"];
+const OVERFLOW_POLICIES: [&str; 5] = ["block", "drop_newest", "drop_oldest", "sample", "spill"];
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PipelineConfig {
+    #[serde(default)]
+    pub sources: Vec<Spanned<SourceConfig>>,
+    #[serde(default)]
+    pub parsers: ParsersConfig,
+    #[serde(default)]
+    pub transforms: Vec<Spanned<TransformConfig>>,
+    #[serde(default)]
+    pub analytics: AnalyticsConfig,
+    #[serde(default)]
+    pub batching: BatchingConfig,
+    #[serde(default)]
+    pub sinks: Vec<Spanned<SinkConfig>>,
+    pub dead_letter: Option<DeadLetterConfig>,
+    #[serde(default)]
+    pub shutdown: ShutdownConfig,
+    #[serde(default)]
+    pub overflow: OverflowStagesConfig,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SourceConfig {
+    pub name: Spanned<String>,
+    #[serde(rename = "type")]
+    pub kind: Spanned<String>,
+    // file
+    pub path: Option<PathBuf>,
+    #[serde(default)]
+    pub follow: bool,
+    #[serde(default)]
+    pub start_at_end: bool,
+    pub checkpoint: Option<Spanned<PathBuf>>,
+    // network (connect) and listener (bind)
+    pub address: Option<String>,
+    pub parser: Option<Spanned<String>>,
+    pub on_error: Option<Spanned<String>>,
+    pub max_retries: Option<u32>,
+    pub retry_backoff_ms: Option<u64>,
+    // listener only
+    pub overflow: Option<Spanned<OverflowConfig>>,
+    pub spool: Option<SourceSpoolConfig>,
+    // applied to this source's records only, before the `[[transforms]]`
+    #[serde(default)]
+    pub transforms: Vec<Spanned<TransformConfig>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SourceSpoolConfig {
+    pub dir: PathBuf,
+    pub fsync: Option<Spanned<String>>,
+    pub fsync_interval_ms: Option<u64>,
+    pub segment_mb: Option<u64>,
+    pub max_mb: Option<Spanned<u64>>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ParsersConfig {
+    // every built-in parser when not given
+    pub enabled: Option<Vec<Spanned<String>>>,
+    pub pin: Option<Spanned<String>>,
+    pub sticky_after: Option<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TransformConfig {
+    #[serde(rename = "type")]
+    pub kind: Spanned<String>,
+    // filter and drop
+    pub level: Option<Spanned<String>>,
+    #[serde(default)]
+    pub services: Vec<String>,
+    pub contains: Option<String>,
+    // rename, promote and coerce take a table of metadata paths, delete a list of them
+    pub fields: Option<Spanned<FieldsConfig>>,
+    // defaults
+    pub environment: Option<String>,
+    pub host: Option<String>,
+    // split
+    pub field: Option<String>,
+    #[serde(default)]
+    pub merge: bool,
+    // script, inline or from a file
+    pub script: Option<Spanned<String>>,
+    pub path: Option<PathBuf>,
+    pub max_operations: Option<u64>,
+    pub timeout_ms: Option<u64>,
+    // redact, every built-in detector when `detectors` isn't given
+    pub detectors: Option<Vec<Spanned<String>>>,
+    #[serde(default)]
+    pub patterns: BTreeMap<String, Spanned<String>>,
+    pub mode: Option<Spanned<String>>,
+    // the HMAC key of the hash mode, or the environment variable holding it
+    pub key: Option<String>,
+    pub key_env: Option<String>,
+    #[serde(default)]
+    pub allow: Vec<String>,
+    #[serde(default)]
+    pub deny: Vec<String>,
+    // lookup, the table is `path` and `fields` maps record fields to its key columns
+    pub columns: Option<Vec<String>>,
+    pub target: Option<String>,
+    pub check_interval_ms: Option<u64>,
+    // geoip reads `field` with one or both databases, user_agent only needs the `field`. Both
+    // take `target` and cache this many results
+    pub city_db: Option<PathBuf>,
+    pub asn_db: Option<PathBuf>,
+    pub cache_size: Option<usize>,
+    // dedupe, with a list of `fields` and exact or near as the `mode`
+    pub window_ms: Option<u64>,
+    pub max_entries: Option<usize>,
+    // sample, level (or "*") = share kept, and the same per service_name
+    #[serde(default)]
+    pub rates: BTreeMap<String, Spanned<f64>>,
+    #[serde(default)]
+    pub service_rates: BTreeMap<String, BTreeMap<String, Spanned<f64>>>,
+    // rate_limit, records a second per key, the key being a list of `fields`
+    pub rate: Option<Spanned<f64>>,
+    pub burst: Option<f64>,
+}
+
+impl TransformConfig {
+    // the keys given besides `type`
+    fn keys(&self) -> Vec<&'static str> {
+        let given = [
+            ("level", self.level.is_some()),
+            ("services", !self.services.is_empty()),
+            ("contains", self.contains.is_some()),
+            ("fields", self.fields.is_some()),
+            ("environment", self.environment.is_some()),
+            ("host", self.host.is_some()),
+            ("field", self.field.is_some()),
+            ("merge", self.merge),
+            ("script", self.script.is_some()),
+            ("path", self.path.is_some()),
+            ("max_operations", self.max_operations.is_some()),
+            ("timeout_ms", self.timeout_ms.is_some()),
+            ("detectors", self.detectors.is_some()),
+            ("patterns", !self.patterns.is_empty()),
+            ("mode", self.mode.is_some()),
+            ("key", self.key.is_some()),
+            ("key_env", self.key_env.is_some()),
+            ("allow", !self.allow.is_empty()),
+            ("deny", !self.deny.is_empty()),
+            ("columns", self.columns.is_some()),
+            ("target", self.target.is_some()),
+            ("check_interval_ms", self.check_interval_ms.is_some()),
+            ("city_db", self.city_db.is_some()),
+            ("asn_db", self.asn_db.is_some()),
+            ("cache_size", self.cache_size.is_some()),
+            ("window_ms", self.window_ms.is_some()),
+            ("max_entries", self.max_entries.is_some()),
+            ("rates", !self.rates.is_empty()),
+            ("service_rates", !self.service_rates.is_empty()),
+            ("rate", self.rate.is_some()),
+            ("burst", self.burst.is_some()),
+        ];
+        given.into_iter().filter(|(_, given)| *given).map(|(key, _)| key).collect()
+    }
+}
+
+// the keys each transform type reads, anything else is a mistake rather than something to ignore
+fn transform_keys(kind: &str) -> &'static [&'static str] {
+    match kind {
+        "filter" | "drop" => &["level", "services", "contains"],
+        "rename" | "delete" | "promote" | "coerce" => &["fields"],
+        "defaults" => &["environment", "host"],
+        "split" => &["field", "merge"],
+        "script" => &["script", "path", "max_operations", "timeout_ms"],
+        "redact" => &["detectors", "patterns", "mode", "key", "key_env", "allow", "deny"],
+        "lookup" => &["path", "fields", "columns", "target", "check_interval_ms"],
+        "geoip" => &["field", "city_db", "asn_db", "target", "cache_size"],
+        "user_agent" => &["field", "target", "cache_size"],
+        "dedupe" => &["fields", "mode", "window_ms", "max_entries"],
+        "sample" => &["rates", "service_rates"],
+        "rate_limit" => &["rate", "burst", "fields", "max_entries"],
+        _ => &[]
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(untagged)]
+pub enum FieldsConfig {
+    List(Vec<String>),
+    Table(BTreeMap<String, String>),
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AnalyticsConfig {
+    // seconds, 100 when not given. The gap for session windows
+    pub window_size: Option<Spanned<i64>>,
+    // tumbling | sliding | session, tumbling when not given
+    pub window: Option<Spanned<String>>,
+    // seconds between the starts of sliding windows, `window_size` when not given
+    pub slide: Option<Spanned<i64>>,
+    // seconds records may come behind the latest timestamp and still count, 0 when not given
+    pub allowed_lateness: Option<u64>,
+    // metadata paths tracked as resource usage, `DEFAULT_METRICS` when not given
+    pub metrics: Option<Vec<String>>,
+}
+
+impl AnalyticsConfig {
+    pub fn windowing(&self) -> Result<Windowing, LogAnalyzerError> {
+        let seconds = |value: &Spanned<i64>| Duration::from_secs((*value.get_ref()).max(1) as u64);
+        let size = self.window_size.as_ref().map_or(Duration::from_secs(100), seconds);
+        let kind = self.window.as_ref().map_or("tumbling", |window| window.get_ref().as_str());
+        Windowing::from_name(kind, size, self.slide.as_ref().map(seconds))
+    }
+
+    pub fn allowed_lateness(&self) -> Duration {
+        Duration::from_secs(self.allowed_lateness.unwrap_or(0))
+    }
+
+    pub fn metrics(&self) -> Vec<String> {
+        self.metrics.clone().unwrap_or_else(|| DEFAULT_METRICS.iter().map(|metric| metric.to_string()).collect())
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BatchingConfig {
+    pub size: Option<Spanned<usize>>,
+    pub max_latency_ms: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SinkConfig {
+    pub name: Spanned<String>,
+    #[serde(rename = "type")]
+    pub kind: Spanned<String>,
+    pub path: Option<PathBuf>,
+    pub format: Option<Spanned<String>>,
+    pub template: Option<String>,
+    // only these records reach the sink, on top of the transforms
+    pub level: Option<Spanned<String>>,
+    #[serde(default)]
+    pub services: Vec<String>,
+    pub contains: Option<String>,
+    // the sink's own queue, `[overflow.output]` when not given
+    pub overflow: Option<Spanned<OverflowConfig>>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OverflowStagesConfig {
+    pub output: Option<Spanned<OverflowConfig>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OverflowConfig {
+    pub policy: Spanned<String>,
+    pub capacity: Option<Spanned<usize>>,
+    pub memory_mb: Option<usize>,
+    pub sample_every: Option<Spanned<u32>>,
+    pub spill_path: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DeadLetterConfig {
+    pub path: PathBuf,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ShutdownConfig {
+    // seconds, 10 when not given
+    pub drain_timeout: Option<u64>,
+}
+
+impl ShutdownConfig {
+    pub fn drain_timeout(&self) -> Duration {
+        Duration::from_secs(self.drain_timeout.unwrap_or(10))
+    }
+}
+
+// how a pipeline run ended
+#[derive(Debug, Clone)]
+pub struct RunOutcome {
+    pub dead_letters: DeadLetterStats,
+    // only when it was interrupted
+    pub shutdown: Option<ShutdownReport>,
+    // a source with on_error = "fail" stopped the pipeline
+    pub failed: bool,
+    // every queue between stages, see `Engine::overflow_stats`
+    pub overflow: Vec<OverflowStats>,
+    // see `Engine::transform_counters`
+    pub transforms: Vec<TransformCounter>,
+}
+
+// collects every problem in a file instead of stopping at the first one
+struct Problems<'a> {
+    origin: &'a str,
+    text: &'a str,
+    messages: Vec<String>,
+}
+
+impl<'a> Problems<'a> {
+    fn push(&mut self, span: Range<usize>, message: impl AsRef<str>) {
+        let (line, column) = line_column(self.text, span.start);
+        self.messages.push(format!("{}:{}:{}: {}", self.origin, line, column, message.as_ref()));
+    }
+
+    fn into_result(self) -> Result<(), LogAnalyzerError> {
+        if self.messages.is_empty() {
+            Ok(())
+        } else {
+            Err(LogAnalyzerError::Config(self.messages.join("\n")))
+        }
+    }
+}
+
+fn check_transform(problems: &mut Problems, transform: &Spanned<TransformConfig>) {
+    let span = transform.span();
+    let transform = transform.get_ref();
+    let kind = transform.kind.get_ref().as_str();
+    if TRANSFORM_TYPES.contains(&kind) {
+        let allowed = transform_keys(kind);
+        for key in transform.keys().into_iter().filter(|key| !allowed.contains(key)) {
+            problems.push(span.clone(), format!("`{}` doesn't apply to {} transforms", key, kind));
+        }
+    }
+    if let Some(level) = &transform.level {
+        if let Err(e) = level.get_ref().parse::<Level>() {
+            problems.push(level.span(), e.to_string());
+        }
+    }
+
+    let fields = transform.fields.as_ref();
+    match kind {
+        "filter" | "drop" => {}
+        "rename" | "promote" | "coerce" => match fields.map(|fields| (fields.span(), fields.get_ref())) {
+            Some((span, FieldsConfig::Table(fields))) => {
+                for target in fields.values() {
+                    let checked = match kind {
+                        "promote" => target.parse::<LogField>().err(),
+                        "coerce" => target.parse::<ValueType>().err(),
+                        _ => None
+                    };
+                    if let Some(e) = checked {
+                        problems.push(span.clone(), e.to_string());
+                    }
+                }
+            }
+            Some((span, FieldsConfig::List(_))) => problems.push(span, format!("{} transforms need a table of `fields`", kind)),
+            None => problems.push(span, format!("{} transforms need a table of `fields`", kind))
+        },
+        "delete" => match fields.map(|fields| (fields.span(), fields.get_ref())) {
+            Some((_, FieldsConfig::List(_))) => {}
+            Some((span, FieldsConfig::Table(_))) => problems.push(span, "delete transforms need a list of `fields`"),
+            None => problems.push(span, "delete transforms need a list of `fields`")
+        },
+        "defaults" if transform.environment.is_none() && transform.host.is_none() => {
+            problems.push(span, "defaults transforms need an `environment` or a `host`")
+        }
+        "defaults" => {}
+        "split" if transform.field.is_none() => problems.push(span, "split transforms need a `field`"),
+        "split" => {}
+        "script" => match (&transform.script, &transform.path) {
+            (Some(script), None) => if let Err(e) = ScriptTransform::new(script.get_ref()) {
+                problems.push(script.span(), e.to_string());
+            },
+            (None, Some(_)) => {}
+            _ => problems.push(span, "script transforms need either a `script` or a `path`")
+        },
+        "redact" => {
+            for detector in transform.detectors.iter().flatten() {
+                if !DETECTORS.contains(&detector.get_ref().as_str()) {
+                    problems.push(detector.span(), format!("unknown detector '{}', expected one of {}", detector.get_ref(), DETECTORS.join(", ")));
+                }
+            }
+            for (name, pattern) in &transform.patterns {
+                if let Err(e) = regex::Regex::new(pattern.get_ref()) {
+                    problems.push(pattern.span(), format!("pattern '{}': {}", name, e));
+                }
+            }
+            match transform.mode.as_ref() {
+                Some(mode) if !REDACT_MODES.contains(&mode.get_ref().as_str()) => {
+                    problems.push(mode.span(), format!("unknown redaction mode '{}', expected one of {}", mode.get_ref(), REDACT_MODES.join(", ")))
+                }
+                Some(mode) if mode.get_ref() == "hash" && transform.key.is_none() && transform.key_env.is_none() => {
+                    problems.push(mode.span(), "the hash redaction mode needs a `key` or a `key_env`")
+                }
+                _ => {}
+            }
+        }
+        "lookup" if transform.path.is_none() => problems.push(span, "lookup transforms need the `path` of a table"),
+        "lookup" if fields.is_none() => problems.push(span, "lookup transforms need the key `fields`"),
+        "lookup" => {}
+        "geoip" | "user_agent" if transform.field.is_none() => problems.push(span, format!("{} transforms need the `field` to read", kind)),
+        "geoip" if transform.city_db.is_none() && transform.asn_db.is_none() => problems.push(span, "geoip transforms need a `city_db` or an `asn_db`"),
+        "geoip" | "user_agent" => {}
+        "sample" => {
+            for (level, rate) in transform.rates.iter().chain(transform.service_rates.values().flatten()) {
+                if let Err(e) = sample_level(level) {
+                    problems.push(rate.span(), e.to_string());
+                }
+                if !(0.0..=1.0).contains(rate.get_ref()) {
+                    problems.push(rate.span(), "sample rates are between 0.0 and 1.0");
+                }
+            }
+        }
+        "rate_limit" => match &transform.rate {
+            Some(rate) if *rate.get_ref() <= 0.0 => problems.push(rate.span(), "rate must be above 0"),
+            Some(_) => {}
+            None => problems.push(span, "rate_limit transforms need a `rate`")
+        },
+        "dedupe" => {
+            if let Some((span, FieldsConfig::Table(_))) = fields.map(|fields| (fields.span(), fields.get_ref())) {
+                problems.push(span, "dedupe transforms need a list of `fields`");
+            }
+            if let Some(mode) = &transform.mode {
+                if let Err(e) = mode.get_ref().parse::<DedupeMode>() {
+                    problems.push(mode.span(), e.to_string());
+                }
+            }
+        }
+        kind => problems.push(transform.kind.span(), format!("unknown transform type '{}', expected one of {}", kind, TRANSFORM_TYPES.join(", ")))
+    }
+}
+
+// "*" is every level
+fn sample_level(level: &str) -> Result<Option<Level>, LogAnalyzerError> {
+    match level {
+        "*" => Ok(None),
+        level => level.parse().map(Some)
+    }
+}
+
+fn check_overflow(problems: &mut Problems, overflow: &Spanned<OverflowConfig>) {
+    let span = overflow.span();
+    let overflow = overflow.get_ref();
+    match overflow.policy.get_ref().as_str() {
+        "spill" if overflow.spill_path.is_none() => problems.push(span, "the spill policy needs a `spill_path`"),
+        policy if !OVERFLOW_POLICIES.contains(&policy) => {
+            problems.push(overflow.policy.span(), format!("unknown overflow policy '{}', expected one of {}", policy, OVERFLOW_POLICIES.join(", ")))
+        }
+        _ => {}
+    }
+    if let Some(capacity) = &overflow.capacity {
+        if *capacity.get_ref() == 0 {
+            problems.push(capacity.span(), "overflow capacity must be at least 1");
+        }
+    }
+    if let Some(sample_every) = &overflow.sample_every {
+        if *sample_every.get_ref() == 0 {
+            problems.push(sample_every.span(), "sample_every must be at least 1");
+        }
+    }
+}
+
+// 1-based, columns count characters
+fn line_column(text: &str, offset: usize) -> (usize, usize) {
+    let before = &text[..offset.min(text.len())];
+    let line = before.matches('\n').count() + 1;
+    let column = before.rsplit('\n').next().map(|l| l.chars().count()).unwrap_or(0) + 1;
+    (line, column)
+}
+
+impl PipelineConfig {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, LogAnalyzerError> {
+        let text = std::fs::read_to_string(path.as_ref())?;
+        Self::parse(&text, &path.as_ref().to_string_lossy())
+    }
+
+    // `origin` only appears in error messages, usually the file name
+    pub fn parse(text: &str, origin: &str) -> Result<Self, LogAnalyzerError> {
+        let config: PipelineConfig = toml::from_str(text).map_err(|e| {
+            let mut problems = Problems { origin, text, messages: Vec::new() };
+            problems.push(e.span().unwrap_or(0..0), e.message());
+            problems.into_result().unwrap_err()
+        })?;
+
+        config.validate(text, origin)?;
+        Ok(config)
+    }
+
+    fn validate(&self, text: &str, origin: &str) -> Result<(), LogAnalyzerError> {
+        let mut problems = Problems { origin, text, messages: Vec::new() };
+
+        if self.sources.is_empty() {
+            problems.push(0..0, "at least one [[sources]] entry is required");
+        }
+
+        let enabled: Vec<&str> = match &self.parsers.enabled {
+            Some(enabled) => enabled.iter().map(|p| p.get_ref().as_str()).collect(),
+            None => BUILTIN_PARSERS.to_vec(),
+        };
+        for parser in self.parsers.enabled.iter().flatten() {
+            if !BUILTIN_PARSERS.contains(&parser.get_ref().as_str()) {
+                problems.push(parser.span(), format!("unknown parser '{}', expected one of {}", parser.get_ref(), BUILTIN_PARSERS.join(", ")));
+            }
+        }
+        let check_parser = |problems: &mut Problems, parser: &Spanned<String>| {
+            if !enabled.contains(&parser.get_ref().as_str()) {
+                problems.push(parser.span(), format!("parser '{}' is not enabled, enabled parsers are {}", parser.get_ref(), enabled.join(", ")));
+            }
+        };
+        if let Some(pin) = &self.parsers.pin {
+            check_parser(&mut problems, pin);
+        }
+
+        let mut names = HashSet::new();
+        for source in &self.sources {
+            let span = source.span();
+            let source = source.get_ref();
+            if !names.insert(source.name.get_ref().as_str()) {
+                problems.push(source.name.span(), format!("duplicate source name '{}'", source.name.get_ref()));
+            }
+            match source.kind.get_ref().as_str() {
+                "file" if source.path.is_none() => problems.push(span, "file sources need a `path`"),
+                "network" | "listener" if source.address.is_none() => {
+                    problems.push(span, format!("{} sources need an `address`", source.kind.get_ref()))
+                }
+                kind if !SOURCE_TYPES.contains(&kind) => {
+                    problems.push(source.kind.span(), format!("unknown source type '{}', expected one of {}", kind, SOURCE_TYPES.join(", ")))
+                }
+                _ => {}
+            }
+            if let Some(parser) = &source.parser {
+                check_parser(&mut problems, parser);
+            }
+            if let Some(checkpoint) = &source.checkpoint {
+                if source.kind.get_ref() != "file" {
+                    problems.push(checkpoint.span(), "only file sources keep a `checkpoint`");
+                }
+            }
+            if let Some(overflow) = &source.overflow {
+                if source.kind.get_ref() == "listener" {
+                    check_overflow(&mut problems, overflow);
+                } else {
+                    problems.push(overflow.span(), "only listener sources queue records and take an `overflow` table");
+                }
+            }
+            if let Some(fsync) = source.spool.as_ref().and_then(|spool| spool.fsync.as_ref()) {
+                if !FSYNC_POLICIES.contains(&fsync.get_ref().as_str()) {
+                    problems.push(fsync.span(), format!("unknown fsync policy '{}', expected one of {}", fsync.get_ref(), FSYNC_POLICIES.join(", ")));
+                }
+            }
+            if let Some(spool) = &source.spool {
+                // segments are deleted whole, a cap below one segment can't be kept
+                let segment_mb = spool.segment_mb.unwrap_or(16);
+                if let Some(max_mb) = spool.max_mb.as_ref().filter(|max_mb| *max_mb.get_ref() < segment_mb) {
+                    problems.push(max_mb.span(), format!("spool max_mb must be at least segment_mb ({})", segment_mb));
+                }
+            }
+            if let Some(on_error) = &source.on_error {
+                if !ON_ERROR.contains(&on_error.get_ref().as_str()) {
+                    problems.push(on_error.span(), format!("unknown on_error '{}', expected one of {}", on_error.get_ref(), ON_ERROR.join(", ")));
+                } else if on_error.get_ref() != "retry" && (source.max_retries.is_some() || source.retry_backoff_ms.is_some()) {
+                    problems.push(on_error.span(), "`max_retries` and `retry_backoff_ms` only apply to on_error = \"retry\"");
+                }
+            }
+        }
+
+        for transform in self.transforms.iter().chain(self.sources.iter().flat_map(|source| &source.get_ref().transforms)) {
+            check_transform(&mut problems, transform);
+        }
+
+        if let Some(window_size) = &self.analytics.window_size {
+            if *window_size.get_ref() <= 0 {
+                problems.push(window_size.span(), "window_size must be a positive number of seconds");
+            }
+        }
+        if let Some(window) = &self.analytics.window {
+            if let Err(e) = Windowing::from_name(window.get_ref(), Duration::from_secs(1), None) {
+                problems.push(window.span(), e.to_string());
+            }
+        }
+        if let Some(slide) = &self.analytics.slide {
+            let window_size = self.analytics.window_size.as_ref().map_or(100, |w| *w.get_ref());
+            if self.analytics.window.as_ref().map(|w| w.get_ref().as_str()) != Some("sliding") {
+                problems.push(slide.span(), "`slide` only applies to window = \"sliding\"");
+            } else if *slide.get_ref() <= 0 || *slide.get_ref() > window_size {
+                problems.push(slide.span(), "slide must be a positive number of seconds, at most window_size");
+            }
+        }
+
+        if let Some(size) = &self.batching.size {
+            if *size.get_ref() == 0 {
+                problems.push(size.span(), "batching size must be at least 1");
+            }
+        }
+
+        if let Some(output) = &self.overflow.output {
+            check_overflow(&mut problems, output);
+        }
+
+        let mut names = HashSet::new();
+        for sink in &self.sinks {
+            let span = sink.span();
+            let sink = sink.get_ref();
+            if !names.insert(sink.name.get_ref().as_str()) {
+                problems.push(sink.name.span(), format!("duplicate sink name '{}'", sink.name.get_ref()));
+            }
+            match sink.kind.get_ref().as_str() {
+                "file" if sink.path.is_none() => problems.push(span, "file sinks need a `path`"),
+                kind if !SINK_TYPES.contains(&kind) => {
+                    problems.push(sink.kind.span(), format!("unknown sink type '{}', expected one of {}", kind, SINK_TYPES.join(", ")))
+                }
+                _ => {}
+            }
+            if let Some(format) = &sink.format {
+                if let Err(e) = formatter::from_name(format.get_ref(), sink.template.as_deref()) {
+                    problems.push(format.span(), e.to_string());
+                }
+            }
+            if let Some(level) = &sink.level {
+                if let Err(e) = level.get_ref().parse::<Level>() {
+                    problems.push(level.span(), e.to_string());
+                }
+            }
+            if let Some(overflow) = &sink.overflow {
+                check_overflow(&mut problems, overflow);
+            }
+        }
+
+        problems.into_result()
+    }
+
+    fn registry(&self) -> Result<ParserRegistry, LogAnalyzerError> {
+        let mut registry = match &self.parsers.enabled {
+            Some(enabled) => ParserRegistry::from_names(&enabled.iter().map(|p| p.get_ref().as_str()).collect::<Vec<_>>())?,
+            None => ParserRegistry::builtin(),
+        };
+        if let Some(sticky_after) = self.parsers.sticky_after {
+            registry = registry.with_sticky_after(sticky_after);
+        }
+        if let Some(pin) = &self.parsers.pin {
+            registry.pin(pin.get_ref())?;
+        }
+        for source in &self.sources {
+            let source = source.get_ref();
+            if let Some(parser) = &source.parser {
+                registry.pin_source(source.name.get_ref().clone(), parser.get_ref())?;
+            }
+        }
+        Ok(registry)
+    }
+
+    fn source(config: &SourceConfig) -> Box<dyn LogSource> {
+        let source = Self::input(config);
+        match &config.spool {
+            Some(spool) => Box::new(SpooledSource::new(source, Self::spool(spool))),
+            None => source,
+        }
+    }
+
+    fn spool(config: &SourceSpoolConfig) -> SpoolConfig {
+        let interval = Duration::from_millis(config.fsync_interval_ms.unwrap_or(1000));
+        let fsync = match config.fsync.as_ref().map(|f| f.get_ref().as_str()) {
+            Some("always") => FsyncPolicy::Always,
+            Some("never") => FsyncPolicy::Never,
+            _ => FsyncPolicy::Interval(interval),
+        };
+        let mut spool = SpoolConfig::new(&config.dir).with_fsync(fsync);
+        if let Some(segment_mb) = config.segment_mb {
+            spool = spool.with_segment_bytes(segment_mb * 1024 * 1024);
+        }
+        if let Some(max_mb) = &config.max_mb {
+            spool = spool.with_max_bytes(max_mb.get_ref() * 1024 * 1024);
+        }
+        spool
+    }
+
+    fn input(config: &SourceConfig) -> Box<dyn LogSource> {
+        let name = config.name.get_ref().clone();
+        let address = config.address.clone().unwrap_or_default();
+        match config.kind.get_ref().as_str() {
+            "network" => Box::new(NetworkLogSource::new(address).with_name(name)),
+            "listener" => {
+                let mut source = ListenerLogSource::new(address).with_name(name);
+                if let Some(overflow) = &config.overflow {
+                    source = source.with_overflow(Self::stage_policy(overflow.get_ref(), 1000));
+                }
+                Box::new(source)
+            }
+            _ => {
+                let mut source = FileLogSource::new(config.path.clone().unwrap_or_default())
+                    .with_name(name)
+                    .with_follow(config.follow)
+                    .with_start_at_end(config.start_at_end);
+                if let Some(checkpoint) = &config.checkpoint {
+                    source = source.with_checkpoint(checkpoint.get_ref());
+                }
+                Box::new(source)
+            }
+        }
+    }
+
+    fn stage_policy(config: &OverflowConfig, default_capacity: usize) -> StagePolicy {
+        let policy = match config.policy.get_ref().as_str() {
+            "drop_newest" => OverflowPolicy::DropNewest,
+            "drop_oldest" => OverflowPolicy::DropOldest,
+            "sample" => OverflowPolicy::Sample(config.sample_every.as_ref().map(|n| *n.get_ref()).unwrap_or(10)),
+            "spill" => OverflowPolicy::Spill(config.spill_path.clone().unwrap_or_default()),
+            _ => OverflowPolicy::Block,
+        };
+        let mut stage = StagePolicy::new(policy).with_capacity(config.capacity.as_ref().map(|c| *c.get_ref()).unwrap_or(default_capacity));
+        if let Some(memory_mb) = config.memory_mb {
+            stage = stage.with_memory_budget(memory_mb * 1024 * 1024);
+        }
+        stage
+    }
+
+    fn policy(config: &SourceConfig) -> SupervisionPolicy {
+        match config.on_error.as_ref().map(|o| o.get_ref().as_str()) {
+            Some("skip") => SupervisionPolicy::Skip,
+            Some("fail") => SupervisionPolicy::Fail,
+            _ => match SupervisionPolicy::default() {
+                SupervisionPolicy::Retry { max_retries, initial_backoff, max_backoff } => SupervisionPolicy::Retry {
+                    max_retries: config.max_retries.or(max_retries),
+                    initial_backoff: config.retry_backoff_ms.map(Duration::from_millis).unwrap_or(initial_backoff),
+                    max_backoff
+                },
+                policy => policy
+            }
+        }
+    }
+
+    fn sink(config: &SinkConfig) -> Result<Box<dyn LogSink>, LogAnalyzerError> {
+        let format = config.format.as_ref().map(|f| f.get_ref().as_str()).unwrap_or("json");
+        let formatter = formatter::from_name(format, config.template.as_deref())?;
+        let name = config.name.get_ref().clone();
+        Ok(match config.kind.get_ref().as_str() {
+            "file" => Box::new(FileSink::new(name, config.path.clone().unwrap_or_default(), formatter)),
+            _ => Box::new(StdoutSink::new(name, formatter)),
+        })
+    }
+
+    fn filter(level: &Option<Spanned<String>>, services: &[String], contains: &Option<String>) -> Result<LogFilter, LogAnalyzerError> {
+        let mut filter = LogFilter::new();
+        if let Some(level) = level {
+            filter = filter.with_min_level(level.get_ref().parse()?);
+        }
+        for service in services {
+            filter = filter.with_service(service.clone());
+        }
+        if let Some(contains) = contains {
+            filter = filter.with_contains(contains.clone());
+        }
+        Ok(filter)
+    }
+
+    fn transform(config: &TransformConfig) -> Result<Box<dyn Transform>, LogAnalyzerError> {
+        let (list, table) = match config.fields.as_ref().map(Spanned::get_ref) {
+            Some(FieldsConfig::List(list)) => (list.clone(), BTreeMap::new()),
+            Some(FieldsConfig::Table(table)) => (Vec::new(), table.clone()),
+            None => (Vec::new(), BTreeMap::new())
+        };
+        Ok(match config.kind.get_ref().as_str() {
+            "drop" => Box::new(Predicate::drop(Self::filter(&config.level, &config.services, &config.contains)?)),
+            "rename" => Box::new(table.into_iter().fold(RenameFields::new(), |rename, (from, to)| rename.with_rename(from, to))),
+            "delete" => Box::new(list.into_iter().fold(DeleteFields::new(), DeleteFields::with_field)),
+            "promote" => Box::new(table.into_iter().try_fold(PromoteFields::new(), |promote, (path, field)| Ok::<_, LogAnalyzerError>(promote.with_field(path, field.parse()?)))?),
+            "coerce" => Box::new(table.into_iter().try_fold(CoerceFields::new(), |coerce, (path, value_type)| Ok::<_, LogAnalyzerError>(coerce.with_field(path, value_type.parse()?)))?),
+            "defaults" => {
+                let mut defaults = SetDefaults::new();
+                if let Some(environment) = &config.environment {
+                    defaults = defaults.with_environment(environment.clone());
+                }
+                if let Some(host) = &config.host {
+                    defaults = defaults.with_host(host.clone());
+                }
+                Box::new(defaults)
+            }
+            "split" => Box::new(SplitRecords::new(config.field.clone().unwrap_or_default()).with_merge(config.merge)),
+            "script" => {
+                let mut script = match (&config.script, &config.path) {
+                    (Some(script), _) => ScriptTransform::new(script.get_ref())?,
+                    (None, path) => ScriptTransform::from_file(path.as_deref().unwrap_or(Path::new("")))?
+                };
+                if let Some(max_operations) = config.max_operations {
+                    script = script.with_max_operations(max_operations);
+                }
+                if let Some(timeout_ms) = config.timeout_ms {
+                    script = script.with_timeout(Duration::from_millis(timeout_ms));
+                }
+                Box::new(script)
+            }
+            "redact" => Box::new(Self::redact(config)?),
+            "lookup" => {
+                // a list names fields that are looked up in columns of the same name
+                let keys = match config.fields.as_ref().map(Spanned::get_ref) {
+                    Some(FieldsConfig::List(fields)) => fields.iter().map(|field| (field.clone(), field.clone())).collect(),
+                    _ => table.into_iter().collect()
+                };
+                let mut lookup = Lookup::new(config.path.clone().unwrap_or_default(), keys)?;
+                if let Some(columns) = &config.columns {
+                    lookup = lookup.with_columns(columns.clone());
+                }
+                if let Some(target) = &config.target {
+                    lookup = lookup.with_target(target.clone());
+                }
+                if let Some(check_interval_ms) = config.check_interval_ms {
+                    lookup = lookup.with_check_interval(Duration::from_millis(check_interval_ms));
+                }
+                Box::new(lookup)
+            }
+            "geoip" => {
+                let mut geoip = GeoIp::new(config.field.clone().unwrap_or_default());
+                if let Some(city_db) = &config.city_db {
+                    geoip = geoip.with_city_db(city_db)?;
+                }
+                if let Some(asn_db) = &config.asn_db {
+                    geoip = geoip.with_asn_db(asn_db)?;
+                }
+                if let Some(target) = &config.target {
+                    geoip = geoip.with_target(target.clone());
+                }
+                if let Some(cache_size) = config.cache_size {
+                    geoip = geoip.with_cache_size(cache_size);
+                }
+                Box::new(geoip)
+            }
+            "dedupe" => {
+                let mode = config.mode.as_ref().map_or(Ok(DedupeMode::Exact), |mode| mode.get_ref().parse())?;
+                let mut dedupe = Dedupe::new(mode);
+                if !list.is_empty() {
+                    dedupe = dedupe.with_fields(list);
+                }
+                if let Some(window_ms) = config.window_ms {
+                    dedupe = dedupe.with_window(Duration::from_millis(window_ms));
+                }
+                if let Some(max_entries) = config.max_entries {
+                    dedupe = dedupe.with_max_entries(max_entries);
+                }
+                Box::new(dedupe)
+            }
+            "sample" => {
+                let mut sample = Sample::new();
+                for (level, rate) in &config.rates {
+                    sample = sample.with_rate(sample_level(level)?, *rate.get_ref());
+                }
+                for (service, rates) in &config.service_rates {
+                    for (level, rate) in rates {
+                        sample = sample.with_service_rate(service.clone(), sample_level(level)?, *rate.get_ref());
+                    }
+                }
+                Box::new(sample)
+            }
+            "rate_limit" => {
+                let mut limit = RateLimit::new(config.rate.as_ref().map_or(1.0, |rate| *rate.get_ref())).with_key(list);
+                if let Some(burst) = config.burst {
+                    limit = limit.with_burst(burst);
+                }
+                if let Some(max_entries) = config.max_entries {
+                    limit = limit.with_max_keys(max_entries);
+                }
+                Box::new(limit)
+            }
+            "user_agent" => {
+                let mut user_agent = UserAgent::new(config.field.clone().unwrap_or_default());
+                if let Some(target) = &config.target {
+                    user_agent = user_agent.with_target(target.clone());
+                }
+                if let Some(cache_size) = config.cache_size {
+                    user_agent = user_agent.with_cache_size(cache_size);
+                }
+                Box::new(user_agent)
+            }
+            _ => Box::new(Predicate::keep(Self::filter(&config.level, &config.services, &config.contains)?))
+        })
+    }
+
+    fn redact(config: &TransformConfig) -> Result<Redact, LogAnalyzerError> {
+        // read when the pipeline is built, so the key never sits in the file
+        let key = match (&config.key, &config.key_env) {
+            (Some(key), _) => Some(key.clone()),
+            (None, Some(var)) => Some(std::env::var(var).map_err(|e| LogAnalyzerError::Config(format!("redaction key `{}`: {}", var, e)))?),
+            (None, None) => None
+        };
+        let mode = RedactMode::from_name(config.mode.as_ref().map_or("mask", |mode| mode.get_ref().as_str()), key.as_deref().map(str::as_bytes))?;
+        let detectors = match &config.detectors {
+            Some(detectors) => detectors.iter().map(|detector| detector.get_ref().as_str()).collect(),
+            None => DETECTORS.to_vec()
+        };
+
+        let mut redact = Redact::with_detectors(mode, &detectors)?;
+        for (name, pattern) in &config.patterns {
+            redact = redact.with_pattern(name.clone(), pattern.get_ref())?;
+        }
+        redact = config.allow.iter().fold(redact, |redact, path| redact.with_allowed(path.clone()));
+        Ok(config.deny.iter().fold(redact, |redact, path| redact.with_denied(path.clone())))
+    }
+
+    fn transforms(configs: &[Spanned<TransformConfig>]) -> Result<TransformChain, LogAnalyzerError> {
+        let mut chain = TransformChain::new();
+        for config in configs {
+            chain.push(Self::transform(config.get_ref())?);
+        }
+        Ok(chain)
+    }
+
+    fn subscription(&self, sink: Option<&SinkConfig>) -> Result<Subscription, LogAnalyzerError> {
+        let overflow = sink.and_then(|sink| sink.overflow.as_ref()).or(self.overflow.output.as_ref());
+        let policy = overflow.map(|overflow| Self::stage_policy(overflow.get_ref(), 100)).unwrap_or_else(|| StagePolicy::default().with_capacity(100));
+        let filter = match sink {
+            Some(sink) => Self::filter(&sink.level, &sink.services, &sink.contains)?,
+            None => LogFilter::new()
+        };
+        Ok(Subscription::new().with_policy(policy).with_filter(filter))
+    }
+
+    fn sinks(&self) -> Result<Vec<SinkSetup>, LogAnalyzerError> {
+        // without sinks the records still go somewhere visible
+        if self.sinks.is_empty() {
+            let sink = Box::new(StdoutSink::new("stdout", formatter::from_name("json", None)?));
+            return Ok(vec![SinkSetup { name: "stdout".to_string(), subscription: self.subscription(None)?, sink }]);
+        }
+        self.sinks.iter().map(|sink| {
+            let sink = sink.get_ref();
+            Ok(SinkSetup { name: sink.name.get_ref().clone(), subscription: self.subscription(Some(sink))?, sink: Self::sink(sink)? })
+        }).collect()
+    }
+
+    // nothing is opened or connected yet, that happens in `Pipeline::start`
+    pub fn build(&self) -> Result<Pipeline, LogAnalyzerError> {
+        let mut engine = Engine::new(Box::new(self.registry()?)).with_transforms(Self::transforms(&self.transforms)?);
+        let analytics = LogAnalytics::new(100)
+            .with_windowing(self.analytics.windowing()?)
+            .with_allowed_lateness(self.analytics.allowed_lateness())
+            .with_metrics(self.analytics.metrics());
+        engine = engine.with_analytics(analytics);
+        if let Some(size) = &self.batching.size {
+            engine = engine.with_batch_size(*size.get_ref());
+        }
+        if let Some(max_latency_ms) = self.batching.max_latency_ms {
+            engine = engine.with_max_latency(Duration::from_millis(max_latency_ms));
+        }
+        if let Some(dead_letter) = &self.dead_letter {
+            engine = engine.with_dead_letter_sink(Box::new(FileDeadLetterSink::new(&dead_letter.path)));
+        }
+        for source in &self.sources {
+            let name = source.get_ref().name.get_ref();
+            engine.set_source_policy(name.clone(), Self::policy(source.get_ref()));
+            engine.set_source_transforms(name.clone(), Self::transforms(&source.get_ref().transforms)?);
+            engine.add_named_source(name.clone(), Self::source(source.get_ref()));
+        }
+
+        Ok(Pipeline {
+            engine,
+            sinks: self.sinks()?,
+            config: self.clone(),
+            writers: Vec::new()
+        })
+    }
+}
+
+// a sink and what it subscribes to, before it's attached to the engine
+struct SinkSetup {
+    name: String,
+    subscription: Subscription,
+    sink: Box<dyn LogSink>
+}
+
+type SinkResult = Result<(), Box<dyn Error + Send + Sync>>;
+
+// a sink reading its own subscription, so a slow sink only holds up itself
+struct SinkWriter {
+    name: String,
+    task: JoinHandle<SinkResult>
+}
+
+async fn write_sink(mut rx: overflow::Receiver<ParsedLog>, mut sink: Box<dyn LogSink>) -> SinkResult {
+    while let Some(parsed_log) = rx.recv().await {
+        sink.write(&parsed_log).await?;
+
+        // spooled sources keep records until every sink has them flushed
+        if rx.is_empty() {
+            sink.flush().await?;
+            rx.ack();
+        }
+    }
+
+    sink.flush().await?;
+    rx.ack();
+    Ok(())
+}
+
+// an engine together with the configuration it was built from and where its output goes
+pub struct Pipeline {
+    engine: Engine,
+    // built by `build`, attached by `start`
+    sinks: Vec<SinkSetup>,
+    config: PipelineConfig,
+    writers: Vec<SinkWriter>
+}
+
+impl Pipeline {
+    pub fn engine(&self) -> &Engine {
+        &self.engine
+    }
+
+    pub fn config(&self) -> &PipelineConfig {
+        &self.config
+    }
+
+    pub fn sink_names(&self) -> Vec<String> {
+        self.sinks.iter().map(|setup| setup.name.clone()).chain(self.writers.iter().map(|writer| writer.name.clone())).collect()
+    }
+
+    // subscribes the sink and starts a task writing what it gets. `resubscribe` takes over from
+    // a running sink with the same name
+    fn attach(&mut self, setup: SinkSetup, resubscribe: bool) -> Result<(), LogAnalyzerError> {
+        let rx = match resubscribe {
+            true => self.engine.resubscribe(&setup.name, setup.subscription)?,
+            false => self.engine.subscribe(&setup.name, setup.subscription)?
+        };
+        let task = tokio::spawn(write_sink(rx.with_manual_ack(), setup.sink));
+        self.writers.push(SinkWriter { name: setup.name, task });
+        Ok(())
+    }
+
+    // starts the sources and a task per sink handing their records over
+    pub async fn start(&mut self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        for setup in std::mem::take(&mut self.sinks) {
+            self.attach(setup, false)?;
+        }
+        self.engine.start().await
+    }
+
+    // waits until every sink saw the end of its stream and wrote everything
+    pub async fn wait(&mut self) -> Result<DeadLetterStats, Box<dyn Error + Send + Sync>> {
+        for writer in std::mem::take(&mut self.writers) {
+            writer.task.await??;
+        }
+        Ok(self.engine.dead_letter_stats())
+    }
+
+    // drains the engine and the sinks within the configured `drain_timeout`
+    pub async fn shutdown(&mut self) -> Result<ShutdownReport, Box<dyn Error + Send + Sync>> {
+        let deadline = self.config.shutdown.drain_timeout();
+        let mut report = self.engine.shutdown(deadline).await;
+
+        // the sinks get what is left of the deadline, but at least a moment to flush
+        let remaining = deadline.saturating_sub(report.elapsed).max(Duration::from_secs(1));
+        let mut writers = std::mem::take(&mut self.writers);
+        let flushed = tokio::time::timeout(remaining, futures::future::join_all(writers.iter_mut().map(|writer| &mut writer.task))).await;
+        match flushed {
+            Ok(results) => {
+                for result in results {
+                    result??;
+                }
+                report.sinks_flushed = Some(true);
+            }
+            Err(_) => {
+                for writer in writers {
+                    writer.task.abort();
+                }
+                report.sinks_flushed = Some(false);
+            }
+        }
+        Ok(report)
+    }
+
+    // runs until every source is finished, or drains and stops on Ctrl-C / SIGTERM
+    pub async fn run(mut self) -> Result<RunOutcome, Box<dyn Error + Send + Sync>> {
+        self.start().await?;
+
+        let finished = tokio::select! {
+            result = futures::future::join_all(self.writers.iter_mut().map(|writer| &mut writer.task)) => Some(result),
+            _ = shutdown::signal() => None
+        };
+
+        let shutdown = match finished {
+            Some(results) => {
+                self.writers.clear();
+                for result in results {
+                    result??;
+                }
+                None
+            }
+            None => Some(self.shutdown().await?)
+        };
+        Ok(RunOutcome { dead_letters: self.engine.dead_letter_stats(), shutdown, failed: self.engine.has_failed(), overflow: self.engine.overflow_stats(), transforms: self.engine.transform_counters() })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn parses_a_complete_pipeline() {
+        let text = r#"
+[[sources]]
+name = "app"
+type = "file"
+path = "example.log"
+parser = "json"
+transforms = [{ type = "promote", fields = { svc = "service_name" } }]
+
+[parsers]
+enabled = ["json", "plain_text"]
+
+[[transforms]]
+type = "filter"
+level = "warning"
+
+[[transforms]]
+type = "delete"
+fields = ["password"]
+
+[analytics]
+window_size = 300
+window = "sliding"
+slide = 60
+allowed_lateness = 30
+metrics = ["queue.depth"]
+
+[[sinks]]
+name = "out"
+type = "file"
+path = "out.log"
+format = "logfmt"
+"#;
+        let config = PipelineConfig::parse(text, "pipeline.toml").unwrap();
+        assert_eq!(config.sources.len(), 1);
+        assert_eq!(config.analytics.window_size.as_ref().map(|w| *w.get_ref()), Some(300));
+        assert_eq!(config.analytics.windowing().unwrap(), Windowing::Sliding { size: Duration::from_secs(300), slide: Duration::from_secs(60) });
+        assert_eq!(config.analytics.allowed_lateness(), Duration::from_secs(30));
+        assert_eq!(config.analytics.metrics(), vec!["queue.depth".to_string()]);
+
+        assert_eq!(config.sources[0].get_ref().transforms.len(), 1);
+        assert_eq!(PipelineConfig::transforms(&config.transforms).unwrap().names(), vec!["keep", "delete"]);
+
+        let pipeline = config.build().unwrap();
+        assert_eq!(pipeline.sink_names(), vec!["out".to_string()]);
+    }
+
+    #[test]
+    fn reports_every_problem_with_its_line() {
+        let text = r#"
+[[sources]]
+name = "app"
+type = "fil"
+
+[[sources]]
+name = "app"
+type = "file"
+path = "a.log"
+parser = "cef"
+
+[parsers]
+enabled = ["json"]
+
+[analytics]
+window_size = 0
+
+[overflow.output]
+policy = "spill"
+
+[[transforms]]
+type = "promote"
+fields = ["svc"]
+
+[[transforms]]
+type = "redact"
+mode = "hash"
+
+[[sources]]
+name = "spooled"
+type = "file"
+path = "b.log"
+spool = { dir = "/tmp/spool", max_mb = 4 }
+
+[[transforms]]
+type = "delete"
+fields = ["a"]
+mode = "exact"
+"#;
+        let err = PipelineConfig::parse(text, "pipeline.toml").unwrap_err().to_string();
+        assert!(err.contains("pipeline.toml:4:8: unknown source type 'fil'"), "{}", err);
+        assert!(err.contains("pipeline.toml:7:8: duplicate source name 'app'"), "{}", err);
+        assert!(err.contains("pipeline.toml:10:10: parser 'cef' is not enabled"), "{}", err);
+        assert!(err.contains("pipeline.toml:16:15: window_size must be"), "{}", err);
+        assert!(err.contains("pipeline.toml:18:1: the spill policy needs a `spill_path`"), "{}", err);
+        assert!(err.contains("pipeline.toml:23:10: promote transforms need a table of `fields`"), "{}", err);
+        assert!(err.contains("pipeline.toml:27:8: the hash redaction mode needs a `key` or a `key_env`"), "{}", err);
+        assert!(err.contains("pipeline.toml:33:40: spool max_mb must be at least segment_mb (16)"), "{}", err);
+        assert!(err.contains("pipeline.toml:35:1: `mode` doesn't apply to delete transforms"), "{}", err);
+
+        let err = PipelineConfig::parse("[[sources]]\nname = 1\n", "p.toml").unwrap_err().to_string();
+        assert!(err.contains("p.toml:2:8:"), "{}", err);
+    }
+}
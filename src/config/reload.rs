@@ -0,0 +1,382 @@
+// Hot reload of a running pipeline.
+//
+// The new configuration is validated and its parser, transforms and sinks are built before
+// anything changes. Sources are matched by name: unchanged ones keep running, removed ones are
+// stopped after handing over what they already read, new ones are started and changed ones
+// restarted. Parser and transforms are swapped in one step between batches and analytics state
+// is kept. When a source can't be started the sources touched so far are put back and the running
+// configuration stays as it was.
+
+use std::{collections::HashMap, error::Error, fmt, path::{Path, PathBuf}, time::Duration};
+
+use tokio::sync::mpsc;
+
+use crate::engine::{shutdown, EngineEvent};
+use crate::error::LogAnalyzerError;
+use crate::ingest::LogSource;
+
+use super::{Pipeline, PipelineConfig, RunOutcome, SourceConfig};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReloadTrigger {
+    Signal,
+    FileChanged
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ReloadReport {
+    pub started: Vec<String>,
+    pub stopped: Vec<String>,
+    pub restarted: Vec<String>,
+    pub notes: Vec<String>
+}
+
+impl fmt::Display for ReloadReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "started [{}] stopped [{}] restarted [{}]", self.started.join(", "), self.stopped.join(", "), self.restarted.join(", "))?;
+        for note in &self.notes {
+            write!(f, "; {}", note)?;
+        }
+        Ok(())
+    }
+}
+
+// reports SIGHUP (on unix) and changes of the file's modification time
+pub fn watch<P: AsRef<Path>>(path: P, poll_interval: Duration) -> mpsc::Receiver<ReloadTrigger> {
+    let (tx, rx) = mpsc::channel(8);
+    let path: PathBuf = path.as_ref().to_owned();
+
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        if let Ok(mut hangup) = signal(SignalKind::hangup()) {
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                while hangup.recv().await.is_some() {
+                    if tx.send(ReloadTrigger::Signal).await.is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+    }
+
+    tokio::spawn(async move {
+        let modified = |path: &Path| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+        let mut last = modified(&path);
+
+        loop {
+            tokio::time::sleep(poll_interval).await;
+            let current = modified(&path);
+            if current.is_some() && current != last {
+                last = current;
+                if tx.send(ReloadTrigger::FileChanged).await.is_err() {
+                    break;
+                }
+            }
+        }
+    });
+
+    rx
+}
+
+async fn close_all(sources: Vec<(String, Box<dyn LogSource>)>) {
+    for (_, mut source) in sources {
+        let _ = source.close().await;
+    }
+}
+
+impl Pipeline {
+    // only valid after `start`
+    pub async fn reload(&mut self, new: PipelineConfig) -> Result<ReloadReport, Box<dyn Error + Send + Sync>> {
+        let current = self.config.clone();
+        let mut report = ReloadReport::default();
+
+        let registry = new.registry()?;
+        let transforms = PipelineConfig::transforms(&new.transforms)?;
+        let mut source_transforms = HashMap::new();
+        for source in &new.sources {
+            let source = source.get_ref();
+            source_transforms.insert(source.name.get_ref().as_str(), PipelineConfig::transforms(&source.transforms)?);
+        }
+        let analytics = (new.analytics.windowing()?, new.analytics.allowed_lateness(), new.analytics.metrics());
+        let analytics = (new.analytics != current.analytics).then_some(analytics);
+        // sinks without their own queue use `[overflow.output]`
+        let sinks = if new.sinks != current.sinks || new.overflow != current.overflow { Some(new.sinks()?) } else { None };
+
+        let old_sources: HashMap<&str, &SourceConfig> = current.sources.iter()
+            .map(|s| (s.get_ref().name.get_ref().as_str(), s.get_ref()))
+            .collect();
+        let new_names = new.sources.iter().map(|s| s.get_ref().name.get_ref().as_str()).collect::<Vec<_>>();
+
+        let mut added = Vec::new();
+        let mut changed = Vec::new();
+        for source in &new.sources {
+            let source = source.get_ref();
+            match old_sources.get(source.name.get_ref().as_str()) {
+                None => added.push(source),
+                Some(old) if *old != source => changed.push((*old, source)),
+                Some(_) => {}
+            }
+        }
+        let removed = current.sources.iter()
+            .map(|s| s.get_ref().name.get_ref().clone())
+            .filter(|name| !new_names.contains(&name.as_str()))
+            .collect::<Vec<_>>();
+
+        // new sources are opened before anything running is touched
+        let mut opened = Vec::new();
+        for config in added {
+            let name = config.name.get_ref().clone();
+            let mut source = PipelineConfig::source(config);
+            self.engine.set_source_policy(name.clone(), PipelineConfig::policy(config));
+            self.engine.set_source_transforms(name.clone(), source_transforms.remove(name.as_str()).unwrap_or_default());
+            if let Err(e) = source.init().await {
+                close_all(opened).await;
+                return Err(Box::new(LogAnalyzerError::Config(format!("source '{}' could not be started: {}", name, e))));
+            }
+            opened.push((name, source));
+        }
+
+        // a changed source usually needs what the old one holds (a port, a connection), so it's
+        // stopped first and put back if its replacement fails. A stopped file source stores its
+        // `checkpoint`, so the replacement, or the old one put back, resumes there. Without a
+        // checkpoint it reads from the configured position again and repeats what was delivered
+        let mut restarted: Vec<&SourceConfig> = Vec::new();
+        for (old, config) in changed {
+            let name = config.name.get_ref().clone();
+            let _ = self.engine.remove_source(&name).await;
+
+            let mut source = PipelineConfig::source(config);
+            if let Err(e) = source.init().await {
+                let _ = self.engine.start_source(name.clone(), PipelineConfig::source(old)).await;
+                for old in restarted {
+                    let name = old.name.get_ref().clone();
+                    let _ = self.engine.remove_source(&name).await;
+                    self.engine.set_source_policy(name.clone(), PipelineConfig::policy(old));
+                    // the running configuration was built before, so this can't fail
+                    self.engine.set_source_transforms(name.clone(), PipelineConfig::transforms(&old.transforms).unwrap_or_default());
+                    let _ = self.engine.start_source(name, PipelineConfig::source(old)).await;
+                }
+                close_all(opened).await;
+                return Err(Box::new(LogAnalyzerError::Config(format!("source '{}' could not be restarted: {}", name, e))));
+            }
+
+            self.engine.set_source_policy(name.clone(), PipelineConfig::policy(config));
+            self.engine.set_source_transforms(name.clone(), source_transforms.remove(name.as_str()).unwrap_or_default());
+            self.engine.spawn_source(name.clone(), source)?;
+            restarted.push(old);
+            report.restarted.push(name);
+        }
+
+        for (name, source) in opened {
+            self.engine.spawn_source(name.clone(), source)?;
+            report.started.push(name);
+        }
+
+        for name in removed {
+            // a source that already reached its end is not running anymore
+            let _ = self.engine.remove_source(&name).await;
+            report.stopped.push(name);
+        }
+
+        self.engine.replace_chain(Box::new(registry), Vec::new(), transforms);
+
+        if let Some((windowing, allowed_lateness, metrics)) = analytics {
+            self.engine.set_windowing(windowing, allowed_lateness).await;
+            self.engine.set_metrics(metrics).await;
+        }
+
+        if let Some(sinks) = sinks {
+            // the new sinks take over before the old ones finish what was queued for them, so
+            // no record is missed in between
+            let old = std::mem::take(&mut self.writers);
+            let names = sinks.iter().map(|setup| setup.name.clone()).collect::<Vec<_>>();
+            for setup in sinks {
+                self.attach(setup, true)?;
+            }
+            for writer in old {
+                if !names.contains(&writer.name) {
+                    let _ = self.engine.unsubscribe(&writer.name);
+                }
+                let _ = writer.task.await;
+            }
+            report.notes.push("sinks replaced".to_string());
+        }
+
+        if new.dead_letter != current.dead_letter {
+            report.notes.push("dead_letter changes take effect after a restart".to_string());
+        }
+        if new.batching != current.batching {
+            report.notes.push("batching changes take effect after a restart".to_string());
+        }
+
+        self.config = new;
+        Ok(report)
+    }
+
+    // like `run`, but applies changes to the file at `path` until interrupted with Ctrl-C or SIGTERM
+    pub async fn run_with_reload<P: AsRef<Path>>(mut self, path: P, poll_interval: Duration) -> Result<RunOutcome, Box<dyn Error + Send + Sync>> {
+        let path = path.as_ref();
+
+        // sources may all stop during a reload, the output has to stay open for their replacements
+        self.engine = self.engine.with_keep_open(true);
+        self.start().await?;
+
+        let mut triggers = watch(path, poll_interval);
+        let mut events = self.engine.subscribe_events();
+        loop {
+            tokio::select! {
+                // the output is kept open, so a failed engine has to be noticed here
+                Ok(EngineEvent::EngineFailed { .. }) = events.recv() => break,
+                // published as events, what to show of them is up to the subscriber
+                Some(trigger) = triggers.recv() => {
+                    let event = match PipelineConfig::load(path) {
+                        Ok(new) if new == self.config => continue,
+                        Ok(new) => match self.reload(new).await {
+                            Ok(report) => EngineEvent::Reloaded { path: path.to_path_buf(), trigger: format!("{:?}", trigger), changes: report.to_string() },
+                            Err(e) => EngineEvent::ReloadFailed { path: path.to_path_buf(), error: e.to_string() }
+                        },
+                        Err(e) => EngineEvent::ReloadFailed { path: path.to_path_buf(), error: e.to_string() }
+                    };
+                    self.engine.emit(event);
+                },
+                _ = shutdown::signal() => break
+            }
+        }
+
+        let shutdown = self.shutdown().await?;
+        Ok(RunOutcome {
+            dead_letters: self.engine.dead_letter_stats(),
+            shutdown: Some(shutdown),
+            failed: self.engine.has_failed(),
+            overflow: self.engine.overflow_stats(),
+            transforms: self.engine.transform_counters()
+        })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(sources: &str, level: &str, out: &Path) -> PipelineConfig {
+        PipelineConfig::parse(&config_text(sources, level, out), "test.toml").unwrap()
+    }
+
+    fn config_text(sources: &str, level: &str, out: &Path) -> String {
+        format!(r#"
+{}
+
+[[transforms]]
+type = "filter"
+level = "{}"
+
+[[sinks]]
+name = "out"
+type = "file"
+path = "{}"
+"#, sources, level, out.display())
+    }
+
+    fn checkpointed_source(name: &str, path: &Path, checkpoint: &Path, extra: &str) -> String {
+        format!("{}checkpoint = \"{}\"\n{}", file_source(name, path), checkpoint.display(), extra)
+    }
+
+    fn file_source(name: &str, path: &Path) -> String {
+        format!("[[sources]]\nname = \"{}\"\ntype = \"file\"\npath = \"{}\"\nfollow = true\n", name, path.display())
+    }
+
+    #[tokio::test]
+    async fn reload_applies_differences_and_rolls_back_failures() {
+        let dir = std::env::temp_dir().join(format!("loganalyzer-reload-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (a, b, out) = (dir.join("a.log"), dir.join("b.log"), dir.join("out.ndjson"));
+        std::fs::write(&a, "{\"level\":\"info\",\"message\":\"a\"}\n").unwrap();
+        std::fs::write(&b, "{\"level\":\"error\",\"message\":\"b\"}\n").unwrap();
+
+        let mut pipeline = config(&file_source("a", &a), "info", &out).build().unwrap();
+        pipeline.start().await.unwrap();
+        assert_eq!(pipeline.engine().running_sources(), vec!["a".to_string()]);
+
+        let report = pipeline.reload(config(&file_source("b", &b), "error", &out)).await.unwrap();
+        assert_eq!(report.started, vec!["b".to_string()]);
+        assert_eq!(report.stopped, vec!["a".to_string()]);
+        assert_eq!(pipeline.engine().running_sources(), vec!["b".to_string()]);
+
+        // a source that can't be opened leaves everything as it was
+        let broken = format!("{}{}", file_source("b", &b), file_source("c", &dir.join("missing.log")));
+        assert!(pipeline.reload(config(&broken, "info", &out)).await.is_err());
+        assert_eq!(pipeline.engine().running_sources(), vec!["b".to_string()]);
+        assert_eq!(pipeline.config().sources.len(), 1);
+
+        pipeline.engine.remove_source("b").await.unwrap();
+        pipeline.wait().await.unwrap();
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn restarted_file_sources_resume_from_their_checkpoint() {
+        let dir = std::env::temp_dir().join(format!("loganalyzer-reload-resume-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (a, checkpoint, out) = (dir.join("a.log"), dir.join("a.checkpoint"), dir.join("out.ndjson"));
+        std::fs::write(&a, "{\"level\":\"info\",\"message\":\"first\"}\n").unwrap();
+
+        let mut pipeline = config(&checkpointed_source("a", &a, &checkpoint, ""), "info", &out).build().unwrap();
+        pipeline.engine = pipeline.engine.with_keep_open(true);
+        pipeline.start().await.unwrap();
+        tokio::time::sleep(Duration::from_millis(300)).await;
+
+        // a changed source is restarted, and the rolled back one is put back, both after "first"
+        let changed = checkpointed_source("a", &a, &checkpoint, "on_error = \"skip\"\n");
+        let broken = format!("{}{}", changed, file_source("c", &dir.join("missing.log")));
+        assert!(pipeline.reload(config(&broken, "info", &out)).await.is_err());
+        let report = pipeline.reload(config(&changed, "info", &out)).await.unwrap();
+        assert_eq!(report.restarted, vec!["a".to_string()]);
+
+        std::fs::write(&a, "{\"level\":\"info\",\"message\":\"first\"}\n{\"level\":\"info\",\"message\":\"second\"}\n").unwrap();
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        pipeline.engine.remove_source("a").await.unwrap();
+        pipeline.engine.close_output();
+        pipeline.wait().await.unwrap();
+
+        let written = std::fs::read_to_string(&out).unwrap();
+        assert_eq!((written.matches("first").count(), written.matches("second").count()), (1, 1), "{}", written);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn watched_reloads_are_published_as_events() {
+        let dir = std::env::temp_dir().join(format!("loganalyzer-reload-events-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (a, out, path) = (dir.join("a.log"), dir.join("out.ndjson"), dir.join("pipeline.toml"));
+        std::fs::write(&a, "").unwrap();
+        std::fs::write(&path, config_text(&file_source("a", &a), "info", &out)).unwrap();
+
+        let pipeline = PipelineConfig::load(&path).unwrap().build().unwrap();
+        let mut events = pipeline.engine().subscribe_events();
+        let running = tokio::spawn(pipeline.run_with_reload(path.clone(), Duration::from_millis(20)));
+        let mut next_reload = async || loop {
+            let event = tokio::time::timeout(Duration::from_secs(5), events.recv()).await.unwrap().unwrap();
+            if matches!(event, EngineEvent::Reloaded { .. } | EngineEvent::ReloadFailed { .. }) {
+                return event;
+            }
+        };
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        std::fs::write(&path, "[[sources]]\nname = \"a\"\n").unwrap();
+        assert!(matches!(next_reload().await, EngineEvent::ReloadFailed { .. }));
+        std::fs::write(&path, config_text(&file_source("a", &a), "error", &out)).unwrap();
+        match next_reload().await {
+            EngineEvent::Reloaded { path: reloaded, .. } => assert_eq!(reloaded, path),
+            other => panic!("{:?}", other)
+        }
+
+        running.abort();
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
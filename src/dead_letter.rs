@@ -0,0 +1,233 @@
+use std::{collections::HashMap, error::Error, path::{Path, PathBuf}, sync::Mutex as StdMutex};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use futures::lock::Mutex;
+use serde::{Deserialize, Serialize};
+use tokio::{fs::{File, OpenOptions}, io::{AsyncBufReadExt, AsyncWriteExt, BufReader, BufWriter}, sync::mpsc};
+
+use crate::{error::{error_kind, LogAnalyzerError}, ingest::LogLine, parser::{LogParser, ParsedLog}};
+
+// a record that could not be processed, together with why
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DeadLetter {
+    pub line: LogLine,
+    pub parser: String,
+    pub error: String,
+    pub error_kind: String,
+    pub failed_at: DateTime<Utc>
+}
+
+impl DeadLetter {
+    // `parser` is the stage that was handling the line, replaced by the concrete parser when the
+    // error says which one failed
+    pub fn new(line: LogLine, parser: &str, error: &(dyn Error + 'static)) -> Self {
+        let parser = match error.downcast_ref::<LogAnalyzerError>() {
+            Some(LogAnalyzerError::ParseFailed { parser, .. }) => parser.clone(),
+            _ => parser.to_string()
+        };
+
+        Self {
+            line,
+            parser,
+            error: error.to_string(),
+            error_kind: error_kind(error).to_string(),
+            failed_at: Utc::now()
+        }
+    }
+}
+
+#[async_trait]
+pub trait DeadLetterSink : Send + Sync {
+    async fn send(&mut self, dead_letter: DeadLetter) -> Result<(), Box<dyn Error + Send + Sync>>;
+
+    async fn flush(&mut self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        Ok(())
+    }
+}
+
+// appends dead letters as newline delimited JSON, the file can later be fed to `replay`
+pub struct FileDeadLetterSink {
+    path: PathBuf,
+    writer: Option<BufWriter<File>>
+}
+
+impl FileDeadLetterSink {
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        Self { path: path.as_ref().to_owned(), writer: None }
+    }
+}
+
+#[async_trait]
+impl DeadLetterSink for FileDeadLetterSink {
+    async fn send(&mut self, dead_letter: DeadLetter) -> Result<(), Box<dyn Error + Send + Sync>> {
+        if self.writer.is_none() {
+            let file = OpenOptions::new().create(true).append(true).open(&self.path).await
+                .map_err(LogAnalyzerError::Io)?;
+            self.writer = Some(BufWriter::new(file));
+        }
+
+        let mut line = serde_json::to_vec(&dead_letter)?;
+        line.push(b'\n');
+
+        if let Some(writer) = &mut self.writer {
+            writer.write_all(&line).await.map_err(LogAnalyzerError::Io)?;
+        }
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        if let Some(writer) = &mut self.writer {
+            writer.flush().await.map_err(LogAnalyzerError::Io)?;
+        }
+        Ok(())
+    }
+}
+
+pub struct ChannelDeadLetterSink {
+    tx: mpsc::Sender<DeadLetter>
+}
+
+impl ChannelDeadLetterSink {
+    pub fn new(tx: mpsc::Sender<DeadLetter>) -> Self {
+        Self { tx }
+    }
+}
+
+#[async_trait]
+impl DeadLetterSink for ChannelDeadLetterSink {
+    async fn send(&mut self, dead_letter: DeadLetter) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.tx.send(dead_letter).await
+            .map_err(|_| LogAnalyzerError::SinkClosed("dead letter channel".to_string()))?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct DeadLetterStats {
+    pub total: usize,
+    pub by_source: HashMap<String, usize>,
+    pub by_kind: HashMap<String, usize>,
+    // dead letters the sink itself failed to accept
+    pub sink_errors: usize
+}
+
+// counts every failed record and forwards it to the configured sink, if any
+#[derive(Default)]
+pub struct DeadLetterQueue {
+    sink: Option<Mutex<Box<dyn DeadLetterSink>>>,
+    stats: StdMutex<DeadLetterStats>
+}
+
+impl DeadLetterQueue {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_sink(sink: Box<dyn DeadLetterSink>) -> Self {
+        Self { sink: Some(Mutex::new(sink)), stats: StdMutex::new(DeadLetterStats::default()) }
+    }
+
+    pub async fn report(&self, dead_letter: DeadLetter) {
+        {
+            let mut stats = self.stats.lock().unwrap();
+            stats.total += 1;
+            *stats.by_source.entry(dead_letter.line.source.clone()).or_insert(0) += 1;
+            *stats.by_kind.entry(dead_letter.error_kind.clone()).or_insert(0) += 1;
+        }
+
+        if let Some(sink) = &self.sink {
+            if sink.lock().await.send(dead_letter).await.is_err() {
+                self.stats.lock().unwrap().sink_errors += 1;
+            }
+        }
+    }
+
+    pub async fn flush(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        match &self.sink {
+            Some(sink) => sink.lock().await.flush().await,
+            None => Ok(())
+        }
+    }
+
+    pub fn stats(&self) -> DeadLetterStats {
+        self.stats.lock().unwrap().clone()
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ReplayOutcome {
+    pub recovered: Vec<ParsedLog>,
+    pub failed: Vec<DeadLetter>
+}
+
+// re-parses every record of a dead letter file, typically after the parser configuration was fixed
+pub async fn replay<P: AsRef<Path>>(path: P, parser: &dyn LogParser) -> Result<ReplayOutcome, Box<dyn Error + Send + Sync>> {
+    let file = File::open(path).await.map_err(LogAnalyzerError::Io)?;
+    let mut lines = BufReader::new(file).lines();
+    let mut outcome = ReplayOutcome::default();
+
+    while let Some(line) = lines.next_line().await.map_err(LogAnalyzerError::Io)? {
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let dead_letter = serde_json::from_str::<DeadLetter>(&line)?;
+        match parser.parse(&dead_letter.line) {
+            Ok(parsed_log) => outcome.recovered.push(parsed_log),
+            Err(e) => outcome.failed.push(DeadLetter::new(dead_letter.line, parser.name(), e.as_ref()))
+        }
+    }
+
+    Ok(outcome)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{json::JsonParser, plain_text::PlainTextParser, registry::ParserRegistry};
+
+    #[tokio::test]
+    async fn dead_letters_are_counted_and_replayed() {
+        let path = std::env::temp_dir().join(format!("loganalyzer-dead-letters-{}.ndjson", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let mut strict = ParserRegistry::new();
+        strict.register(JsonParser::new());
+        strict.pin("json").unwrap();
+
+        let queue = DeadLetterQueue::with_sink(Box::new(FileDeadLetterSink::new(&path)));
+        let line = LogLine { content: "not json at all".to_string(), source: "app.log".to_string(), timestamp: Utc::now() };
+
+        let err = strict.parse(&line).unwrap_err();
+        queue.report(DeadLetter::new(line, strict.name(), err.as_ref())).await;
+        queue.flush().await.unwrap();
+
+        let stats = queue.stats();
+        assert_eq!(stats.total, 1);
+        assert_eq!(stats.by_source["app.log"], 1);
+        assert_eq!(stats.by_kind["invalid_format"], 1);
+
+        // after adding a plain text parser the record can be recovered
+        let mut fixed = ParserRegistry::new();
+        fixed.register(JsonParser::new());
+        fixed.register(PlainTextParser::new());
+
+        let outcome = replay(&path, &fixed).await.unwrap();
+        assert_eq!(outcome.recovered.len(), 1);
+        assert_eq!(outcome.recovered[0].message, "not json at all");
+        assert!(outcome.failed.is_empty());
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn a_closed_channel_is_a_sink_error() {
+        let (tx, rx) = mpsc::channel(1);
+        drop(rx);
+        let line = LogLine { content: "x".to_string(), source: "app.log".to_string(), timestamp: Utc::now() };
+        let err = ChannelDeadLetterSink::new(tx).send(DeadLetter::new(line, "json", &LogAnalyzerError::ParserNotFound)).await.unwrap_err();
+        assert_eq!(err.downcast_ref::<LogAnalyzerError>().map(LogAnalyzerError::kind), Some("sink_closed"));
+    }
+}
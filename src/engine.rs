@@ -1,89 +1,1335 @@
-use std::{error::Error, sync::Arc};
+use std::{collections::HashMap, error::Error, sync::{atomic::{AtomicU64, AtomicUsize, Ordering}, Arc, RwLock, Weak}, time::{Duration, Instant}};
 
 
 use futures::lock::Mutex;
-use tokio::sync::mpsc;
+use tokio::{sync::{broadcast, watch}, task::JoinHandle};
 
-use crate::{analytics::LogAnalytics, ingest::LogSource, parser::{LogParser, ParsedLog}};
+use crate::{analytics::{LogAnalytics, Windowing}, dead_letter::{DeadLetter, DeadLetterQueue, DeadLetterSink, DeadLetterStats}, error::{error_class, is_disconnect, LogAnalyzerError}, filter::LogFilter, ingest::{LogLine, LogSource}, parser::{LogParser, ParsedLog}, transform::{TransformChain, TransformCounter, TransformError}};
+
+pub mod batching;
+pub mod handle;
+pub mod overflow;
+pub mod shutdown;
+pub mod subscribers;
+pub mod supervision;
+
+pub use batching::BatchMetrics;
+pub use handle::{SourceHandle, SourceState, SourceStats};
+pub use overflow::{OverflowPolicy, OverflowStats, StagePolicy};
+pub use shutdown::ShutdownReport;
+pub use subscribers::{SubscriberStats, Subscription};
+pub use supervision::{EngineEvent, ErrorAction, SupervisionPolicy};
+
+use batching::{Batch, BatchCounters, FlushReason, DEFAULT_BATCH_SIZE, DEFAULT_MAX_LATENCY};
+use handle::{Control, SourceControl};
+use overflow::{Ack, OverflowMonitor};
+use subscribers::{Delivery, Subscribers};
+
+// the parser and the filters and transforms applied after it, replaced as a whole so a batch
+// never sees a mix of an old parser and new filters
+struct Chain {
+    parser: Arc<dyn LogParser>,
+    filters: Vec<LogFilter>,
+    transforms: TransformChain
+}
+
+// chains a reload replaced, flushed by the next source task that finds no batch using them
+type Retired = Arc<std::sync::Mutex<Vec<Arc<Chain>>>>;
+
+struct RunningSource {
+    handle: SourceHandle,
+    task: Option<JoinHandle<()>>,
+    // a queue inside the source, e.g. between listener clients and the source
+    overflow: Option<OverflowMonitor>
+}
 
 pub struct Engine {
-    sources : Vec<Box<dyn LogSource>>,
-    parser_registry : Arc<Box<dyn LogParser>>,
-    analytics: Arc<Mutex<LogAnalytics>>
+    sources : Vec<(String, Box<dyn LogSource>)>,
+    chain : Arc<RwLock<Arc<Chain>>>,
+    retired: Retired,
+    analytics: Arc<Mutex<LogAnalytics>>,
+    dead_letters: Arc<DeadLetterQueue>,
+    running: HashMap<String, RunningSource>,
+    subscribers: Arc<Subscribers>,
+    // weak so the subscribers' streams end once every source task is done, unless `keep_open`
+    // holds it
+    delivery: Option<Weak<Delivery>>,
+    output_policy: StagePolicy,
+    keep_open: bool,
+    stop_when_deserted: bool,
+    delivery_guard: Option<Arc<Delivery>>,
+    // set when a shutdown runs out of time, records still waiting for the output are dropped
+    abandon: watch::Sender<bool>,
+    abandoned_records: Arc<AtomicU64>,
+    batch_size: usize,
+    max_latency: Duration,
+    batch_counters: Arc<BatchCounters>,
+    supervision: SupervisionPolicy,
+    source_policies: HashMap<String, SupervisionPolicy>,
+    source_transforms: HashMap<String, TransformChain>,
+    running_tasks: Arc<AtomicUsize>,
+    events: broadcast::Sender<EngineEvent>,
+    // set by a source with the `Fail` policy, every source stops
+    failed: watch::Sender<bool>
 }
 
-impl Engine {
-    pub fn new(parser_registry : Box<dyn LogParser>) -> Self {
-        Self { sources: Vec::new(), 
-            parser_registry: Arc::new(parser_registry) , 
-            analytics : Arc::new(Mutex::new(LogAnalytics::new(100)))}
+// everything a source task needs to turn its lines into parsed logs
+#[derive(Clone)]
+struct Pipeline {
+    chain: Arc<RwLock<Arc<Chain>>>,
+    retired: Retired,
+    analytics: Arc<Mutex<LogAnalytics>>,
+    dead_letters: Arc<DeadLetterQueue>,
+    delivery: Arc<Delivery>,
+    abandon: watch::Receiver<bool>,
+    abandoned_records: Arc<AtomicU64>,
+    batch_size: usize,
+    max_latency: Duration,
+    batch_counters: Arc<BatchCounters>,
+    policy: SupervisionPolicy,
+    stop_when_deserted: bool,
+    // applied right after parsing, before the engine's filters and transforms
+    transforms: TransformChain,
+    // sources whose task hasn't ended yet
+    running: Arc<AtomicUsize>,
+    events: broadcast::Sender<EngineEvent>,
+    failed: watch::Sender<bool>
+}
+
+// the line is gone by now, the record as JSON can still be replayed
+fn transform_dead_letter(source: &str, failure: TransformError) -> DeadLetter {
+    let line = LogLine {
+        content: failure.log.to_json().to_string(),
+        source: source.to_string(),
+        timestamp: failure.log.timestamp.unwrap_or_else(chrono::Utc::now)
+    };
+    DeadLetter::new(line, &format!("transform {}", failure.transform), failure.error.as_ref())
+}
+
+// what the transforms held back is handed out when a source ends, the shared transforms' only
+// when it was the last one running or a reload replaced them. What's due goes out on ticks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Flush {
+    Nothing,
+    Due,
+    Source,
+    Shared,
+    Replaced
+}
+
+impl Flush {
+    fn source(self) -> bool {
+        matches!(self, Flush::Source | Flush::Shared)
     }
 
-    pub fn add_source(&mut self, source : Box<dyn LogSource>) {
-        self.sources.push(source);
+    fn shared(self) -> bool {
+        matches!(self, Flush::Shared | Flush::Replaced)
     }
+}
 
-    pub async fn run(&mut self) -> Result<mpsc::Receiver<ParsedLog>, Box<dyn Error + Send + Sync>> {
+async fn engine_failed(failed: &mut watch::Receiver<bool>) {
+    let _ = failed.wait_for(|failed| *failed).await;
+}
 
-        let (tx, rx) = mpsc::channel(100);
+async fn deserted(deserted: &mut watch::Receiver<bool>) {
+    let _ = deserted.wait_for(|deserted| *deserted).await;
+}
 
-        for source in &mut self.sources {
-            source.init().await?;
+impl Pipeline {
+    // returns false once the records are abandoned
+    // `ack` confirms the lines of the batch, it goes out with the last record that passed
+    async fn process_batch(&self, batch: &mut Batch, reason: FlushReason, ack: Option<Ack>) -> bool {
+        let started = batch.take_started().unwrap_or_else(Instant::now);
+        let records = batch.lines.len();
+        let listening = self.parse_and_send(&mut batch.lines, ack).await;
+        self.batch_counters.record(records, reason, started.elapsed());
+        listening
+    }
+
+    async fn parse_and_send(&self, batch: &mut Vec<LogLine>, ack: Option<Ack>) -> bool {
+        // every line of a batch comes from the same source
+        let source = batch.first().map(|log_line| log_line.source.clone()).unwrap_or_default();
+
+        // a reload swaps the chain between batches, never in the middle of one
+        let chain = self.chain.read().unwrap().clone();
+
+        // parsing and transforms are CPU only, so they happen inline instead of a future per record
+        let mut parsed_logs = Vec::with_capacity(batch.len());
+        for log_line in batch.drain(..) {
+            match chain.parser.parse(&log_line) {
+                Ok(parsed_log) => parsed_logs.push(parsed_log),
+                Err(e) => self.dead_letters.report(self.redact(&chain, DeadLetter::new(log_line, chain.parser.name(), e.as_ref()))).await
+            }
         }
-        
+        self.transform_and_send(&source, &chain, parsed_logs, Flush::Nothing, ack).await
+    }
 
-        for mut source in std::mem::take(&mut self.sources) {
-            let tx_clone = tx.clone(); 
-            let parser_clone = self.parser_registry.clone();
-            let analytics_clone = self.analytics.clone();
+    async fn transform_and_send(&self, source: &str, chain: &Arc<Chain>, parsed_logs: Vec<ParsedLog>, flush: Flush, mut ack: Option<Ack>) -> bool {
+        let transforms = self.transforms.clone();
+        let transform = {
+            let chain = chain.clone();
+            move || {
+                let mut failed = Vec::new();
+                let mut parsed_logs = transforms.apply(parsed_logs, &mut failed);
+                if flush.source() {
+                    parsed_logs.extend(transforms.flush(&mut failed));
+                } else if flush == Flush::Due {
+                    parsed_logs.extend(transforms.tick(&mut failed));
+                }
+                parsed_logs.retain(|parsed_log| chain.filters.iter().all(|filter| filter.matches(parsed_log)));
+                let mut parsed_logs = chain.transforms.apply(parsed_logs, &mut failed);
+                if flush.shared() {
+                    parsed_logs.extend(chain.transforms.flush(&mut failed));
+                } else if flush == Flush::Due {
+                    parsed_logs.extend(chain.transforms.tick(&mut failed));
+                }
+                (parsed_logs, failed)
+            }
+        };
+        // scripts and lookups may take a while per record, that's no work for an async worker
+        let (parsed_logs, failed) = if self.transforms.is_empty() && chain.transforms.is_empty() {
+            transform()
+        } else {
+            match tokio::task::spawn_blocking(transform).await {
+                Ok(transformed) => transformed,
+                Err(e) => std::panic::resume_unwind(e.into_panic())
+            }
+        };
+        for failure in failed {
+            self.dead_letters.report(self.redact(chain, transform_dead_letter(source, failure))).await;
+        }
 
-            tokio::spawn(async move {
-                let mut batch = Vec::with_capacity(100);
+        {
+            let mut analytics = self.analytics.lock().await;
+            for parsed_log in &parsed_logs {
+                analytics.process_log(parsed_log.clone());
+            }
+            // the input ended, no more records can come for the open windows
+            if flush == Flush::Shared {
+                analytics.close_all();
+            }
+        }
 
-                while let Ok(Some(log_line)) = source.read_line().await {
+        // nothing left to deliver, everything was filtered or dead-lettered
+        let total = parsed_logs.len();
+        if total == 0 {
+            if let Some(ack) = ack {
+                ack.ack();
+            }
+            return true;
+        }
 
-                    batch.push(log_line);
+        let subscribers = &self.delivery.0;
+        let snapshot = subscribers.snapshot();
+        let mut abandon = self.abandon.clone();
+        for (sent, parsed_log) in parsed_logs.into_iter().enumerate() {
+            let ack = if sent + 1 == total { ack.take() } else { None };
+            tokio::select! {
+                _ = subscribers.deliver(&snapshot, parsed_log, ack) => {},
+                _ = abandon.wait_for(|abandon| *abandon) => {
+                    self.abandoned_records.fetch_add((total - sent) as u64, Ordering::Relaxed);
+                    return false;
+                }
+            }
+        }
 
-                    if batch.len() >= 100 {
+        true
+    }
 
-                        let futures = batch.iter()
-                        .map(|log_line| {
-                            let parser = parser_clone.clone();
-                            async move {
-                                parser.parse(log_line.clone()).await
-                            }
-                        }).collect::<Vec<_>>();
+    // runs every `max_latency`: hands out what replaced chains held back once no batch uses
+    // them anymore, and what the transforms held back that is due
+    async fn tick(&self, source: &str) -> bool {
+        let replaced: Vec<Arc<Chain>> = {
+            let mut retired = self.retired.lock().unwrap();
+            let (unused, in_use) = retired.drain(..).partition(|chain| Arc::strong_count(chain) == 1);
+            *retired = in_use;
+            unused
+        };
+        for chain in replaced {
+            if !self.transform_and_send(source, &chain, Vec::new(), Flush::Replaced, None).await {
+                return false;
+            }
+        }
+        let chain = self.chain.read().unwrap().clone();
+        self.transform_and_send(source, &chain, Vec::new(), Flush::Due, None).await
+    }
+
+    // dead letters keep the line or record as it came, before any redact transform got to it,
+    // so they get the same detectors. The error may quote the line too
+    fn redact(&self, chain: &Chain, mut dead_letter: DeadLetter) -> DeadLetter {
+        for text in [&mut dead_letter.line.content, &mut dead_letter.error] {
+            for transforms in [&self.transforms, &chain.transforms] {
+                if let Some(redacted) = transforms.redact(text) {
+                    *text = redacted;
+                }
+            }
+        }
+        dead_letter
+    }
 
-                        let results = futures::future::join_all(futures).await;
+    fn emit(&self, event: EngineEvent) {
+        // nobody subscribed is fine
+        let _ = self.events.send(event);
+    }
 
+    // reads until the source ends or a stop is requested. A batch is processed once it's full or
+    // its oldest line waited `max_latency`, and the last partial batch always. Read errors are
+    // handled by the source's supervision policy and published as events, a retry after a
+    // dropped connection reopens it with `init` first. With `stop_when_deserted` the source stops
+    // without a checkpoint once the last subscriber leaves, what it read since is read again by
+    // the next run
+    async fn drive(self, name: String, mut source: Box<dyn LogSource>, mut control: SourceControl) {
+        let mut batch = Batch::new(self.batch_size);
+        let mut failed = self.failed.subscribe();
+        let mut deserted_by = self.delivery.0.deserted();
+        // read errors in a row
+        let mut failures = 0;
+        let mut listening = true;
+        // false once every handle is dropped, the source then runs until its end
+        let mut controllable = true;
+        let mut current = Control::Run;
+        // the connection dropped, the next read opens it again first
+        let mut reconnect = false;
+        let mut next_tick = Instant::now() + self.max_latency;
 
-                        for res in results {
-                            if let Ok(parsed_log) = res {
-                                let analytics = analytics_clone.clone();
-                                let log_clone = parsed_log.clone();
-                                
-                                tokio::spawn(async move {
-                                    let analytics_lock = analytics.lock();
+        let end_state = loop {
+            if controllable {
+                current = *control.control.borrow_and_update();
+            }
+
+            match current {
+                Control::Stop => break SourceState::Stopped,
+                Control::Pause => {
+                    // hand over what was read before pausing
+                    if !batch.is_empty() {
+                        listening = self.process_batch(&mut batch, FlushReason::Final, source.take_ack()).await;
+                        if !listening {
+                            break SourceState::Stopped;
+                        }
+                    }
+                    control.state.send_replace(SourceState::Paused);
+                    if control.control.changed().await.is_err() {
+                        controllable = false;
+                        current = Control::Run;
+                    }
+                    continue;
+                }
+                Control::Run => {
+                    control.state.send_if_modified(|state| {
+                        let resumed = *state != SourceState::Running;
+                        *state = SourceState::Running;
+                        resumed
+                    });
+                }
+            }
 
-                                    analytics_lock.await.process_log(log_clone);
-                                });
+            let flush_at = batch.flush_at(self.max_latency);
+            let wake_at = flush_at.map_or(next_tick, |flush_at| flush_at.min(next_tick));
+            let mut retry_after = None;
 
-                                if tx_clone.send(parsed_log).await.is_err() {
-                                    break;
-                                }
-                            }   
+            tokio::select! {
+                _ = engine_failed(&mut failed) => break SourceState::Stopped,
+                _ = deserted(&mut deserted_by), if self.stop_when_deserted => {
+                    listening = false;
+                    break SourceState::Stopped;
+                },
+                changed = control.control.changed(), if controllable => {
+                    if changed.is_err() {
+                        controllable = false;
+                    }
+                },
+                _ = tokio::time::sleep_until(wake_at.into()) => {
+                    if flush_at.is_some_and(|flush_at| flush_at <= Instant::now()) {
+                        listening = self.process_batch(&mut batch, FlushReason::Timer, source.take_ack()).await;
+                        if !listening {
+                            break SourceState::Stopped;
                         }
+                    }
+                    if next_tick <= Instant::now() {
+                        listening = self.tick(&name).await;
+                        if !listening {
+                            break SourceState::Stopped;
+                        }
+                        next_tick = Instant::now() + self.max_latency;
+                    }
+                },
+                log_line = async {
+                    if reconnect {
+                        source.init().await?;
+                        reconnect = false;
+                    }
+                    source.read_line().await
+                } => match log_line {
+                    Ok(Some(log_line)) => {
+                        if failures > 0 {
+                            self.emit(EngineEvent::SourceRecovered { source: name.clone(), failures });
+                            failures = 0;
+                        }
+                        control.counters.record(&log_line);
+                        batch.push(log_line);
 
-                        batch.clear();
+                        if batch.is_full() {
+                            listening = self.process_batch(&mut batch, FlushReason::Full, source.take_ack()).await;
+                            if !listening {
+                                break SourceState::Stopped;
+                            }
+                        }
+                    }
+                    Ok(None) => break SourceState::Finished,
+                    Err(e) => {
+                        failures += 1;
+                        let class = error_class(e.as_ref());
+                        let action = self.policy.decide(class, failures);
+                        control.counters.error(e.to_string());
+                        self.emit(EngineEvent::SourceError { source: name.clone(), error: e.to_string(), class, action });
+
+                        match action {
+                            ErrorAction::Skip => {}
+                            ErrorAction::Retry { delay, .. } => {
+                                retry_after = Some(delay);
+                                reconnect = reconnect || is_disconnect(e.as_ref());
+                            }
+                            ErrorAction::StopSource => break SourceState::Failed,
+                            ErrorAction::StopEngine => {
+                                self.emit(EngineEvent::EngineFailed { source: name.clone(), error: e.to_string() });
+                                self.failed.send_replace(true);
+                                break SourceState::Failed;
+                            }
+                        }
+                    }
+                }
+            }
+
+            if let Some(delay) = retry_after {
+                // what was read before the error shouldn't wait for the retry
+                if !batch.is_empty() {
+                    listening = self.process_batch(&mut batch, FlushReason::Timer, source.take_ack()).await;
+                    if !listening {
+                        break SourceState::Stopped;
                     }
                 }
-                let _ = source.close().await;
-            });
+                tokio::select! {
+                    _ = tokio::time::sleep(delay) => {},
+                    _ = engine_failed(&mut failed) => break SourceState::Stopped,
+                    _ = deserted(&mut deserted_by), if self.stop_when_deserted => {
+                        listening = false;
+                        break SourceState::Stopped;
+                    },
+                    stop = async { control.control.wait_for(|control| *control == Control::Stop).await.is_ok() }, if controllable => {
+                        if stop {
+                            break SourceState::Stopped;
+                        }
+                        // every handle is gone, nobody can stop it anymore
+                        controllable = false;
+                    }
+                }
+            }
+        };
+
+        // the source is done, don't lose the last partial batch
+        if listening && !batch.is_empty() {
+            listening = self.process_batch(&mut batch, FlushReason::Final, source.take_ack()).await;
+        }
+        let last = self.running.fetch_sub(1, Ordering::AcqRel) == 1;
+        // replaced chains no batch uses anymore don't wait for another source's tick
+        if listening {
+            listening = self.tick(&name).await;
+        }
+        if listening {
+            let chain = self.chain.read().unwrap().clone();
+            let flush = if last { Flush::Shared } else { Flush::Source };
+            listening = self.transform_and_send(&name, &chain, Vec::new(), flush, None).await;
+        }
+
+        // only when everything read was handed over, otherwise the next run reads it again
+        if listening {
+            if let Err(e) = source.checkpoint().await {
+                control.counters.error(e.to_string());
+            }
+        }
+
+        let _ = self.dead_letters.flush().await;
+        if let Err(e) = source.close().await {
+            control.counters.error(e.to_string());
+        }
+        control.state.send_replace(end_state);
+        self.emit(EngineEvent::SourceDone { source: name, state: end_state });
+    }
+}
+
+impl Engine {
+    pub fn new(parser_registry : Box<dyn LogParser>) -> Self {
+        Self { sources: Vec::new(),
+            chain: Arc::new(RwLock::new(Arc::new(Chain { parser: Arc::from(parser_registry), filters: Vec::new(), transforms: TransformChain::new() }))),
+            retired: Retired::default(),
+            analytics : Arc::new(Mutex::new(LogAnalytics::new(100))),
+            dead_letters: Arc::new(DeadLetterQueue::new()),
+            running: HashMap::new(),
+            subscribers: Arc::new(Subscribers::default()),
+            delivery: None,
+            output_policy: StagePolicy::default(),
+            keep_open: false,
+            stop_when_deserted: false,
+            delivery_guard: None,
+            abandon: watch::channel(false).0,
+            abandoned_records: Arc::new(AtomicU64::new(0)),
+            batch_size: DEFAULT_BATCH_SIZE,
+            max_latency: DEFAULT_MAX_LATENCY,
+            batch_counters: Arc::new(BatchCounters::default()),
+            supervision: SupervisionPolicy::default(),
+            source_policies: HashMap::new(),
+            source_transforms: HashMap::new(),
+            running_tasks: Arc::new(AtomicUsize::new(0)),
+            events: broadcast::channel(256).0,
+            failed: watch::channel(false).0
+        }
+    }
+
+    // analytics window in seconds
+    pub fn with_window_size(mut self, window_size : i64) -> Self {
+        self.analytics = Arc::new(Mutex::new(LogAnalytics::new(window_size)));
+        self
+    }
+
+    // analytics with their windowing and lateness set up already
+    pub fn with_analytics(mut self, analytics : LogAnalytics) -> Self {
+        self.analytics = Arc::new(Mutex::new(analytics));
+        self
+    }
+
+    // records that fail parsing are always counted, with a sink they are also kept for replay
+    pub fn with_dead_letter_sink(mut self, sink : Box<dyn DeadLetterSink>) -> Self {
+        self.dead_letters = Arc::new(DeadLetterQueue::with_sink(sink));
+        self
+    }
+
+    // records have to pass every filter, anything else is dropped before analytics
+    pub fn with_filters(self, filters : Vec<LogFilter>) -> Self {
+        let chain = self.chain.read().unwrap().clone();
+        *self.chain.write().unwrap() = Arc::new(Chain { parser: chain.parser.clone(), filters, transforms: chain.transforms.clone() });
+        self
+    }
+
+    // applied to every source's records after the filters
+    pub fn with_transforms(self, transforms : TransformChain) -> Self {
+        let chain = self.chain.read().unwrap().clone();
+        *self.chain.write().unwrap() = Arc::new(Chain { parser: chain.parser.clone(), filters: chain.filters.clone(), transforms });
+        self
+    }
+
+    // applied to this source's records right after parsing, also when it's started later
+    pub fn with_source_transforms(mut self, name : impl Into<String>, transforms : TransformChain) -> Self {
+        self.set_source_transforms(name, transforms);
+        self
+    }
+
+    // applies the next time a source with this name is started
+    pub fn set_source_transforms(&mut self, name : impl Into<String>, transforms : TransformChain) {
+        self.source_transforms.insert(name.into(), transforms);
+    }
+
+    // lines parsed together, bigger batches mean less locking per record (at least 1)
+    pub fn with_batch_size(mut self, batch_size : usize) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    // longest time a line waits for its batch to fill up before it's processed anyway
+    pub fn with_max_latency(mut self, max_latency : Duration) -> Self {
+        self.max_latency = max_latency;
+        self
+    }
+
+    // what happens when parsed records come faster than the output `run` returns is read
+    pub fn with_output_policy(mut self, policy : StagePolicy) -> Self {
+        self.output_policy = policy;
+        self
+    }
+
+    // keep the subscribers' streams open after every source finished, so sources can still be
+    // started later
+    pub fn with_keep_open(mut self, keep_open : bool) -> Self {
+        self.keep_open = keep_open;
+        self
+    }
+
+    // stop the sources once the last subscriber is gone instead of reading for nobody. They stay
+    // stopped when a subscriber attaches later, until they're started again. Off by default, the
+    // sources keep reading and what nobody takes is counted in `undelivered_records`
+    pub fn with_stop_when_deserted(mut self, stop_when_deserted : bool) -> Self {
+        self.stop_when_deserted = stop_when_deserted;
+        self
+    }
+
+    // how sources react to read errors, unless `with_source_policy` says otherwise
+    pub fn with_supervision(mut self, policy : SupervisionPolicy) -> Self {
+        self.supervision = policy;
+        self
+    }
+
+    // policy for the source with this name, also when it's started later
+    pub fn with_source_policy(mut self, name : impl Into<String>, policy : SupervisionPolicy) -> Self {
+        self.set_source_policy(name, policy);
+        self
+    }
+
+    // applies the next time a source with this name is started
+    pub fn set_source_policy(&mut self, name : impl Into<String>, policy : SupervisionPolicy) {
+        self.source_policies.insert(name.into(), policy);
+    }
+
+    // read errors, recoveries and sources ending. A receiver that falls behind by more than 256
+    // events misses the oldest ones
+    pub fn subscribe_events(&self) -> broadcast::Receiver<EngineEvent> {
+        self.events.subscribe()
+    }
+
+    // for what happens around the engine, like configuration reloads
+    pub(crate) fn emit(&self, event: EngineEvent) {
+        // nobody subscribed is fine
+        let _ = self.events.send(event);
+    }
+
+    // true once a source with the `Fail` policy stopped the engine
+    pub fn has_failed(&self) -> bool {
+        *self.failed.borrow()
+    }
+
+    pub fn analytics(&self) -> Arc<Mutex<LogAnalytics>> {
+        self.analytics.clone()
+    }
+
+    pub fn dead_letter_stats(&self) -> DeadLetterStats {
+        self.dead_letters.stats()
+    }
+
+    pub fn batch_metrics(&self) -> BatchMetrics {
+        self.batch_counters.snapshot(self.batch_size)
+    }
+
+    // the subscribers' queues in the order they subscribed, then queues inside sources by
+    // source name
+    pub fn overflow_stats(&self) -> Vec<OverflowStats> {
+        let mut sources = self.running.iter()
+            .filter_map(|(name, running)| running.overflow.as_ref().map(|monitor| (name, monitor.stats())))
+            .collect::<Vec<_>>();
+        sources.sort_by(|a, b| a.0.cmp(b.0));
+        let subscribers = self.subscribers.stats().into_iter().map(|stats| stats.overflow);
+        subscribers.chain(sources.into_iter().map(|(_, stats)| stats)).collect()
+    }
+
+    // counters of the current transforms, the shared ones first and then the per-source ones by
+    // source name. Counters with the same transform and name are added up
+    pub fn transform_counters(&self) -> Vec<TransformCounter> {
+        let mut sources = self.source_transforms.iter().collect::<Vec<_>>();
+        sources.sort_by(|a, b| a.0.cmp(b.0));
+        let chain = self.chain.read().unwrap().clone();
 
+        let mut counters: Vec<TransformCounter> = Vec::new();
+        for counter in chain.transforms.counters().into_iter().chain(sources.into_iter().flat_map(|(_, transforms)| transforms.counters())) {
+            match counters.iter_mut().find(|known| known.transform == counter.transform && known.counter == counter.counter) {
+                Some(known) => known.value += counter.value,
+                None => counters.push(counter)
+            }
         }
+        counters
+    }
+
+    // a new stream of the records that pass the engine's filters and the subscription's, from
+    // the next batch on. Works before `run` as well as while sources are running
+    pub fn subscribe(&self, name : &str, subscription : Subscription) -> Result<overflow::Receiver<ParsedLog>, LogAnalyzerError> {
+        self.subscribers.add(name, subscription)
+    }
+
+    // like `subscribe`, but takes over from the subscriber with this name without a gap. The old
+    // receiver ends after what was already queued for it
+    pub fn resubscribe(&self, name : &str, subscription : Subscription) -> Result<overflow::Receiver<ParsedLog>, LogAnalyzerError> {
+        self.subscribers.replace(name, subscription)
+    }
+
+    // the receiver gets what was queued for it and then the end of the stream. With
+    // `with_stop_when_deserted` the sources stop when it was the last subscriber
+    pub fn unsubscribe(&self, name : &str) -> Result<SubscriberStats, LogAnalyzerError> {
+        self.subscribers.remove(name).ok_or_else(|| LogAnalyzerError::UnknownSubscriber(name.to_string()))
+    }
+
+    pub fn subscriber_stats(&self) -> Vec<SubscriberStats> {
+        self.subscribers.stats()
+    }
+
+    // records that passed the filters while there was no subscriber at all
+    pub fn undelivered_records(&self) -> u64 {
+        self.subscribers.undelivered()
+    }
+
+    pub fn add_source(&mut self, source : Box<dyn LogSource>) {
+        let name = format!("source-{}", self.sources.len() + self.running.len());
+        self.add_named_source(name, source);
+    }
+
+    // the name is how a running source is stopped again
+    pub fn add_named_source(&mut self, name : impl Into<String>, source : Box<dyn LogSource>) {
+        self.sources.push((name.into(), source));
+    }
+
+    // names of the sources that are reading or paused
+    pub fn running_sources(&self) -> Vec<String> {
+        self.sources().into_iter()
+            .filter(|handle| !handle.state().is_done())
+            .map(|handle| handle.name().to_string())
+            .collect()
+    }
+
+    // every source started since `run` and not removed, including the ones that are done
+    pub fn sources(&self) -> Vec<SourceHandle> {
+        let mut handles = self.running.values().map(|running| running.handle.clone()).collect::<Vec<_>>();
+        handles.sort_by(|a, b| a.name().cmp(b.name()));
+        handles
+    }
+
+    pub fn source(&self, name : &str) -> Option<SourceHandle> {
+        self.running.get(name).map(|running| running.handle.clone())
+    }
+
+    pub fn source_stats(&self) -> Vec<SourceStats> {
+        self.sources().iter().map(SourceHandle::stats).collect()
+    }
+
+    // swaps parser, filters and transforms for every source at once, batches already being
+    // parsed finish with the old ones. What the old transforms held back is flushed once those
+    // are done
+    pub fn replace_chain(&self, parser : Box<dyn LogParser>, filters : Vec<LogFilter>, transforms : TransformChain) {
+        let old = std::mem::replace(&mut *self.chain.write().unwrap(), Arc::new(Chain { parser: Arc::from(parser), filters, transforms }));
+        if !old.transforms.is_empty() {
+            self.retired.lock().unwrap().push(old);
+        }
+    }
+
+    pub async fn set_window_size(&self, window_size : i64) {
+        self.analytics.lock().await.set_window_size(window_size);
+    }
 
-        drop(tx);
+    // open windows keep their bounds, new ones follow the new windowing
+    pub async fn set_windowing(&self, windowing : Windowing, allowed_lateness : Duration) {
+        self.analytics.lock().await.set_windowing(windowing, allowed_lateness);
+    }
+
+    // metadata paths tracked as resource usage from the next record on
+    pub async fn set_metrics(&self, metrics : Vec<String>) {
+        self.analytics.lock().await.set_metrics(metrics);
+    }
 
+    // starts the sources with a subscriber called "output" that sees every record
+    pub async fn run(&mut self) -> Result<overflow::Receiver<ParsedLog>, Box<dyn Error + Send + Sync>> {
+        let rx = self.subscribe("output", Subscription::new().with_policy(self.output_policy.clone()))?;
+        self.start().await?;
         Ok(rx)
     }
-}
\ No newline at end of file
+
+    // starts the sources, records only go to the subscribers attached with `subscribe`
+    pub async fn start(&mut self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        for (_, source) in &mut self.sources {
+            source.init().await?;
+        }
+
+        let delivery = Arc::new(Delivery(self.subscribers.clone()));
+        self.delivery = Some(Arc::downgrade(&delivery));
+        if self.keep_open {
+            self.delivery_guard = Some(delivery.clone());
+        }
+
+        for (name, source) in std::mem::take(&mut self.sources) {
+            self.spawn_source(name, source)?;
+        }
+
+        Ok(())
+    }
+
+    // initializes the source and starts reading it, only after `run`. Sources added before `run`
+    // use `add_source`
+    pub async fn start_source(&mut self, name : impl Into<String>, mut source : Box<dyn LogSource>) -> Result<SourceHandle, Box<dyn Error + Send + Sync>> {
+        let name = name.into();
+        self.check_name(&name)?;
+        source.init().await?;
+        Ok(self.spawn_source(name, source)?)
+    }
+
+    // like `start_source` for a source that is already initialized
+    pub fn spawn_source(&mut self, name : impl Into<String>, source : Box<dyn LogSource>) -> Result<SourceHandle, LogAnalyzerError> {
+        let name = name.into();
+        self.check_name(&name)?;
+        let delivery = self.delivery.as_ref().and_then(Weak::upgrade).ok_or(LogAnalyzerError::EngineNotRunning)?;
+
+        let pipeline = Pipeline {
+            chain: self.chain.clone(),
+            retired: self.retired.clone(),
+            analytics: self.analytics.clone(),
+            dead_letters: self.dead_letters.clone(),
+            delivery,
+            abandon: self.abandon.subscribe(),
+            abandoned_records: self.abandoned_records.clone(),
+            batch_size: self.batch_size,
+            max_latency: self.max_latency,
+            batch_counters: self.batch_counters.clone(),
+            policy: self.source_policies.get(&name).unwrap_or(&self.supervision).clone(),
+            stop_when_deserted: self.stop_when_deserted,
+            transforms: self.source_transforms.get(&name).cloned().unwrap_or_default(),
+            running: self.running_tasks.clone(),
+            events: self.events.clone(),
+            failed: self.failed.clone()
+        };
+
+        let overflow = source.overflow_monitor();
+        let (handle, control) = SourceHandle::new(&name);
+        self.running_tasks.fetch_add(1, Ordering::AcqRel);
+        let task = tokio::spawn(pipeline.drive(name.clone(), source, control));
+        self.running.insert(name, RunningSource { handle: handle.clone(), task: Some(task), overflow });
+        Ok(handle)
+    }
+
+    // a name can be reused once its source is done
+    fn check_name(&self, name : &str) -> Result<(), LogAnalyzerError> {
+        match self.running.get(name) {
+            Some(running) if !running.handle.state().is_done() => {
+                Err(LogAnalyzerError::Config(format!("source '{}' is already running", name)))
+            }
+            _ => Ok(())
+        }
+    }
+
+    pub fn pause_source(&self, name : &str) -> Result<(), LogAnalyzerError> {
+        self.source(name).ok_or_else(|| LogAnalyzerError::UnknownSource(name.to_string()))?.pause();
+        Ok(())
+    }
+
+    pub fn resume_source(&self, name : &str) -> Result<(), LogAnalyzerError> {
+        self.source(name).ok_or_else(|| LogAnalyzerError::UnknownSource(name.to_string()))?.resume();
+        Ok(())
+    }
+
+    // returns once the source has handed over everything it read and is closed
+    pub async fn remove_source(&mut self, name : &str) -> Result<SourceStats, LogAnalyzerError> {
+        let running = self.running.remove(name).ok_or_else(|| LogAnalyzerError::UnknownSource(name.to_string()))?;
+        running.handle.stop();
+        if let Some(task) = running.task {
+            let _ = task.await;
+        }
+        Ok(running.handle.stats())
+    }
+
+    // waits until every source started so far is done, their handles stay available
+    pub async fn join(&mut self) -> Vec<SourceStats> {
+        for running in self.running.values_mut() {
+            if let Some(task) = running.task.take() {
+                let _ = task.await;
+            }
+        }
+        self.source_stats()
+    }
+
+    // lets the subscribers' streams end once the remaining sources finish
+    pub fn close_output(&mut self) {
+        self.delivery_guard = None;
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use std::collections::VecDeque;
+    use async_trait::async_trait;
+    use tokio::{io::AsyncWriteExt, net::{TcpListener, TcpStream}};
+    use crate::{ingest::{file_source::FileLogSource, network_source::NetworkLogSource}, parser::registry::ParserRegistry, transform::{Dedupe, DedupeMode, Redact, RedactMode, ScriptTransform}};
+
+    // plays back a script of lines and errors, then ends
+    struct ScriptedSource {
+        script: VecDeque<Result<&'static str, std::io::ErrorKind>>
+    }
+
+    #[async_trait]
+    impl LogSource for ScriptedSource {
+        async fn init(&mut self) -> Result<(), Box<dyn Error + Send + Sync>> {
+            Ok(())
+        }
+
+        async fn read_line(&mut self) -> Result<Option<LogLine>, Box<dyn Error + Send + Sync>> {
+            match self.script.pop_front() {
+                Some(Ok(content)) => Ok(Some(LogLine { content: content.to_string(), source: "scripted".to_string(), timestamp: chrono::Utc::now() })),
+                Some(Err(kind)) => Err(Box::new(LogAnalyzerError::Io(kind.into()))),
+                None => Ok(None)
+            }
+        }
+
+        async fn close(&mut self) -> Result<(), Box<dyn Error + Send + Sync>> {
+            Ok(())
+        }
+    }
+
+    fn scripted(script: Vec<Result<&'static str, std::io::ErrorKind>>) -> Box<dyn LogSource> {
+        Box::new(ScriptedSource { script: script.into() })
+    }
+
+    async fn wait_for_state(handle: &SourceHandle, state: SourceState) {
+        tokio::time::timeout(Duration::from_secs(2), async {
+            while handle.state() != state {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn sources_are_managed_through_handles() {
+        let dir = std::env::temp_dir().join(format!("loganalyzer-engine-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (first, late) = (dir.join("first.log"), dir.join("late.log"));
+        std::fs::write(&first, "one\ntwo\nthree\n").unwrap();
+        std::fs::write(&late, "").unwrap();
+
+        let mut engine = Engine::new(Box::new(ParserRegistry::builtin())).with_keep_open(true);
+        engine.add_named_source("first", Box::new(FileLogSource::new(&first)));
+        let mut rx = engine.run().await.unwrap();
+
+        let handle = engine.source("first").unwrap();
+        assert_eq!(handle.wait().await, SourceState::Finished);
+        let stats = handle.stats();
+        assert_eq!((stats.lines_read, stats.bytes_read), (3, 11));
+        assert!(stats.last_record_at.is_some());
+
+        // started after `run`, paused while the file grows, resumed to pick the line up
+        let late_handle = engine.start_source("late", Box::new(FileLogSource::new(&late).with_follow(true))).await.unwrap();
+        assert!(engine.start_source("late", Box::new(FileLogSource::new(&late))).await.is_err());
+        engine.pause_source("late").unwrap();
+        wait_for_state(&late_handle, SourceState::Paused).await;
+        std::fs::write(&late, "four\n").unwrap();
+        engine.resume_source("late").unwrap();
+        wait_for_state(&late_handle, SourceState::Running).await;
+
+        tokio::time::timeout(Duration::from_secs(2), async {
+            while late_handle.stats().lines_read == 0 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }).await.unwrap();
+        assert_eq!(engine.running_sources(), vec!["late".to_string()]);
+
+        // removing hands over the partial batch
+        let stats = engine.remove_source("late").await.unwrap();
+        assert_eq!((stats.state, stats.lines_read), (SourceState::Stopped, 1));
+        for expected in ["one", "two", "three", "four"] {
+            assert_eq!(rx.recv().await.unwrap().message, expected);
+        }
+        assert_eq!(engine.join().await.len(), 1);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn shutdown_drains_and_resumes_from_the_checkpoint() {
+        let dir = std::env::temp_dir().join(format!("loganalyzer-shutdown-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (log, checkpoint) = (dir.join("app.log"), dir.join("app.checkpoint"));
+        std::fs::write(&log, "one\ntwo\nthree\n").unwrap();
+
+        let mut engine = Engine::new(Box::new(ParserRegistry::builtin()));
+        engine.add_named_source("app", Box::new(FileLogSource::new(&log).with_follow(true).with_checkpoint(&checkpoint)));
+        let mut rx = engine.run().await.unwrap();
+        let consumer = tokio::spawn(async move {
+            let mut messages = Vec::new();
+            while let Some(parsed_log) = rx.recv().await {
+                messages.push(parsed_log.message);
+            }
+            messages
+        });
+
+        let handle = engine.source("app").unwrap();
+        while handle.stats().lines_read < 3 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let report = engine.shutdown(Duration::from_secs(2)).await;
+        assert!(report.drained);
+        // the consumer is ours, the engine can't tell whether it flushed
+        assert_eq!(report.sinks_flushed, None);
+        assert_eq!(report.closed, vec!["app".to_string()]);
+        assert_eq!(consumer.await.unwrap(), vec!["one", "two", "three"]);
+
+        // the next run only sees what was appended since
+        std::fs::write(&log, "one\ntwo\nthree\nfour\n").unwrap();
+        let mut engine = Engine::new(Box::new(ParserRegistry::builtin()));
+        engine.add_source(Box::new(FileLogSource::new(&log).with_checkpoint(&checkpoint)));
+        let mut rx = engine.run().await.unwrap();
+        assert_eq!(rx.recv().await.unwrap().message, "four");
+        assert!(rx.recv().await.is_none());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn shutdown_abandons_what_the_output_does_not_take() {
+        let log = std::env::temp_dir().join(format!("loganalyzer-abandon-{}.log", std::process::id()));
+        std::fs::write(&log, (0..250).map(|i| format!("line {}\n", i)).collect::<String>()).unwrap();
+
+        let mut engine = Engine::new(Box::new(ParserRegistry::builtin()));
+        engine.add_named_source("app", Box::new(FileLogSource::new(&log).with_follow(true)));
+        // nobody reads, so the second batch never fits into the output
+        let _rx = engine.run().await.unwrap();
+        while engine.source("app").unwrap().stats().lines_read < 200 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let report = engine.shutdown(Duration::from_millis(100)).await;
+        assert!(!report.drained);
+        assert_eq!(report.abandoned_records, 100);
+        assert_eq!(report.closed, vec!["app".to_string()]);
+        assert!(report.abandoned_sources.is_empty());
+
+        let _ = std::fs::remove_file(&log);
+    }
+
+    #[tokio::test]
+    async fn quiet_sources_are_flushed_by_the_timer() {
+        let log = std::env::temp_dir().join(format!("loganalyzer-batching-{}.log", std::process::id()));
+        std::fs::write(&log, "one\ntwo\nthree\n").unwrap();
+
+        let mut engine = Engine::new(Box::new(ParserRegistry::builtin()))
+            .with_batch_size(10)
+            .with_max_latency(Duration::from_millis(20));
+        engine.add_named_source("app", Box::new(FileLogSource::new(&log).with_follow(true)));
+        let mut rx = engine.run().await.unwrap();
+
+        // the source never ends, only the timer gets these out
+        for expected in ["one", "two", "three"] {
+            let parsed_log = tokio::time::timeout(Duration::from_secs(2), rx.recv()).await.unwrap().unwrap();
+            assert_eq!(parsed_log.message, expected);
+        }
+
+        let metrics = engine.batch_metrics();
+        assert_eq!((metrics.batches, metrics.records, metrics.timer_flushes), (1, 3, 1));
+        assert!((metrics.fill_ratio - 0.3).abs() < 1e-9);
+        assert!(metrics.max_latency >= Duration::from_millis(20));
+
+        engine.remove_source("app").await.unwrap();
+        let _ = std::fs::remove_file(&log);
+    }
+
+    // a network source "net" on an engine, and the server end the test writes its lines to
+    async fn network_engine(engine: Engine) -> (Engine, overflow::Receiver<ParsedLog>, TcpStream, TcpListener) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut engine = engine;
+        engine.add_named_source("net", Box::new(NetworkLogSource::new(listener.local_addr().unwrap().to_string())));
+        let rx = engine.run().await.unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        (engine, rx, server, listener)
+    }
+
+    async fn next_message(rx: &mut overflow::Receiver<ParsedLog>) -> String {
+        tokio::time::timeout(Duration::from_secs(2), rx.recv()).await.unwrap().unwrap().message
+    }
+
+    #[tokio::test]
+    async fn the_timer_does_not_cut_lines_in_half() {
+        let engine = Engine::new(Box::new(ParserRegistry::builtin())).with_batch_size(10).with_max_latency(Duration::from_millis(20));
+        let (_engine, mut rx, mut server, _) = network_engine(engine).await;
+
+        // "one" waits for the timer, which fires while "two" is half read
+        server.write_all(b"one\ntw").await.unwrap();
+        assert_eq!(next_message(&mut rx).await, "one");
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        server.write_all(b"o\n").await.unwrap();
+        assert_eq!(next_message(&mut rx).await, "two");
+    }
+
+    #[tokio::test]
+    async fn pausing_does_not_cut_lines_in_half() {
+        let (engine, mut rx, mut server, _) = network_engine(Engine::new(Box::new(ParserRegistry::builtin()))).await;
+        let handle = engine.source("net").unwrap();
+
+        // paused while "two" is half read, it comes out whole after resuming
+        server.write_all(b"one\ntw").await.unwrap();
+        while handle.stats().lines_read == 0 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        engine.pause_source("net").unwrap();
+        wait_for_state(&handle, SourceState::Paused).await;
+        assert_eq!(next_message(&mut rx).await, "one");
+
+        engine.resume_source("net").unwrap();
+        wait_for_state(&handle, SourceState::Running).await;
+        server.write_all(b"o\n").await.unwrap();
+        assert_eq!(next_message(&mut rx).await, "two");
+    }
+
+    #[tokio::test]
+    async fn a_retry_reconnects_a_dropped_connection() {
+        let retry = SupervisionPolicy::Retry { max_retries: Some(3), initial_backoff: Duration::from_millis(1), max_backoff: Duration::from_millis(5) };
+        let engine = Engine::new(Box::new(ParserRegistry::builtin())).with_supervision(retry);
+        let mut events = engine.subscribe_events();
+        let (engine, mut rx, mut server, listener) = network_engine(engine).await;
+
+        // a reset instead of a clean close
+        server.write_all(b"one\n").await.unwrap();
+        assert_eq!(next_message(&mut rx).await, "one");
+        server.set_zero_linger().unwrap();
+        drop(server);
+
+        let (mut server, _) = tokio::time::timeout(Duration::from_secs(2), listener.accept()).await.unwrap().unwrap();
+        server.write_all(b"two\n").await.unwrap();
+        assert_eq!(next_message(&mut rx).await, "two");
+        assert_eq!(engine.source("net").unwrap().state(), SourceState::Running);
+
+        let reset = std::iter::from_fn(|| events.try_recv().ok()).any(|event| matches!(event,
+            EngineEvent::SourceError { action: ErrorAction::Retry { .. }, .. }));
+        assert!(reset);
+    }
+
+    #[tokio::test]
+    async fn sources_stop_once_the_last_subscriber_leaves_if_asked() {
+        let engine = Engine::new(Box::new(ParserRegistry::builtin())).with_batch_size(1).with_stop_when_deserted(true);
+        let (engine, mut rx, mut server, _) = network_engine(engine).await;
+
+        server.write_all(b"one\n").await.unwrap();
+        assert_eq!(next_message(&mut rx).await, "one");
+        drop(rx);
+        // the next record finds nobody to take it
+        server.write_all(b"two\n").await.unwrap();
+        wait_for_state(&engine.source("net").unwrap(), SourceState::Stopped).await;
+    }
+
+    #[tokio::test]
+    async fn sources_keep_reading_for_a_later_subscriber() {
+        let engine = Engine::new(Box::new(ParserRegistry::builtin())).with_batch_size(1);
+        let (engine, mut rx, mut server, _) = network_engine(engine).await;
+
+        server.write_all(b"one\n").await.unwrap();
+        assert_eq!(next_message(&mut rx).await, "one");
+        engine.unsubscribe("output").unwrap();
+        server.write_all(b"two\n").await.unwrap();
+        tokio::time::timeout(Duration::from_secs(2), async {
+            while engine.undelivered_records() == 0 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }).await.unwrap();
+
+        let mut rx = engine.subscribe("late", Subscription::new()).unwrap();
+        server.write_all(b"three\n").await.unwrap();
+        assert_eq!(next_message(&mut rx).await, "three");
+        assert_eq!(engine.undelivered_records(), 1);
+        assert_eq!(engine.source("net").unwrap().state(), SourceState::Running);
+    }
+
+    #[tokio::test]
+    async fn dead_letters_are_redacted_too() {
+        let script = ScriptTransform::new(r#"if record.message.starts_with("break") { record.level = "loud"; }"#).unwrap();
+        let (tx, mut dead_letters) = tokio::sync::mpsc::channel(10);
+        let mut engine = Engine::new(Box::new(ParserRegistry::builtin()))
+            .with_source_transforms("app", TransformChain::new().with_transform(script))
+            .with_transforms(TransformChain::new().with_transform(Redact::new(RedactMode::Mask)))
+            .with_dead_letter_sink(Box::new(crate::dead_letter::ChannelDeadLetterSink::new(tx)));
+        engine.add_named_source("app", scripted(vec![Ok("break for ann@example.com")]));
+        let mut rx = engine.run().await.unwrap();
+        assert_eq!(rx.recv().await, None);
+
+        // the script failed before the redact transform saw the record
+        let dead_letter = dead_letters.recv().await.unwrap();
+        assert!(dead_letter.line.content.contains("break for [REDACTED:email]"), "{}", dead_letter.line.content);
+    }
+
+    // notes the thread each record is transformed on
+    struct Threads(Arc<std::sync::Mutex<Vec<std::thread::ThreadId>>>);
+
+    impl crate::transform::Transform for Threads {
+        fn name(&self) -> &'static str {
+            "threads"
+        }
+
+        fn apply(&self, log: ParsedLog, out: &mut Vec<ParsedLog>) -> Result<(), TransformError> {
+            self.0.lock().unwrap().push(std::thread::current().id());
+            out.push(log);
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn transforms_run_off_the_async_workers() {
+        let threads = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let mut engine = Engine::new(Box::new(ParserRegistry::builtin()))
+            .with_transforms(TransformChain::new().with_transform(Threads(threads.clone())));
+        engine.add_source(scripted(vec![Ok("one"), Ok("two")]));
+        let mut rx = engine.run().await.unwrap();
+        while rx.recv().await.is_some() {}
+
+        // the test's runtime has only this thread
+        let seen = threads.lock().unwrap();
+        assert_eq!(seen.len(), 2);
+        assert!(seen.iter().all(|thread| *thread != std::thread::current().id()));
+    }
+
+    #[tokio::test]
+    async fn records_a_transform_fails_on_are_dead_lettered() {
+        let log = std::env::temp_dir().join(format!("loganalyzer-transform-{}.log", std::process::id()));
+        std::fs::write(&log, "keep\nbreak\n").unwrap();
+
+        let script = ScriptTransform::new(r#"if record.message == "break" { record.level = "loud"; }"#).unwrap();
+        let mut engine = Engine::new(Box::new(ParserRegistry::builtin()))
+            .with_source_transforms("app", TransformChain::new().with_transform(script));
+        engine.add_named_source("app", Box::new(FileLogSource::new(&log)));
+        let mut rx = engine.run().await.unwrap();
+
+        assert_eq!(rx.recv().await.map(|parsed_log| parsed_log.message), Some("keep".to_string()));
+        assert_eq!(rx.recv().await, None);
+        let stats = engine.dead_letter_stats();
+        assert_eq!((stats.total, stats.by_kind.get("script")), (1, Some(&1)));
+
+        let _ = std::fs::remove_file(&log);
+    }
+
+    #[tokio::test]
+    async fn repeat_summaries_come_out_when_the_last_source_ends() {
+        let log = std::env::temp_dir().join(format!("loganalyzer-dedupe-{}.log", std::process::id()));
+        std::fs::write(&log, "disk full\ndisk full\ndisk full\n").unwrap();
+
+        let mut engine = Engine::new(Box::new(ParserRegistry::builtin()))
+            .with_transforms(TransformChain::new().with_transform(Dedupe::new(DedupeMode::Exact)));
+        engine.add_named_source("a", Box::new(FileLogSource::new(&log)));
+        engine.add_named_source("b", Box::new(FileLogSource::new(&log)));
+        let mut rx = engine.run().await.unwrap();
+
+        let mut messages = Vec::new();
+        while let Some(parsed_log) = rx.recv().await {
+            messages.push(parsed_log.message);
+        }
+        assert_eq!(messages.len(), 2, "{:?}", messages);
+        assert_eq!(messages[0], "disk full");
+        assert!(messages[1].starts_with("message repeated 5 times"), "{}", messages[1]);
+
+        let _ = std::fs::remove_file(&log);
+    }
+
+    // lines sent through a channel, ends once the sender is dropped
+    struct ChannelSource(tokio::sync::mpsc::UnboundedReceiver<&'static str>);
+
+    #[async_trait]
+    impl LogSource for ChannelSource {
+        async fn init(&mut self) -> Result<(), Box<dyn Error + Send + Sync>> {
+            Ok(())
+        }
+
+        async fn read_line(&mut self) -> Result<Option<LogLine>, Box<dyn Error + Send + Sync>> {
+            Ok(self.0.recv().await.map(|content| LogLine { content: content.to_string(), source: "channel".to_string(), timestamp: chrono::Utc::now() }))
+        }
+
+        async fn close(&mut self) -> Result<(), Box<dyn Error + Send + Sync>> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn repeat_summaries_come_out_on_ticks_and_reloads() {
+        let (lines, receiver) = tokio::sync::mpsc::unbounded_channel();
+        let mut engine = Engine::new(Box::new(ParserRegistry::builtin()))
+            .with_max_latency(Duration::from_millis(20))
+            .with_transforms(TransformChain::new().with_transform(Dedupe::new(DedupeMode::Exact).with_window(Duration::from_millis(100))));
+        engine.add_source(Box::new(ChannelSource(receiver)));
+        let mut rx = engine.run().await.unwrap();
+        let mut next = async || rx.recv().await.map(|parsed_log| parsed_log.message).unwrap_or_default();
+
+        // nothing comes after the repeats, the window ends on a tick
+        for _ in 0..3 {
+            lines.send("disk full").unwrap();
+        }
+        assert_eq!(next().await, "disk full");
+        let summary = next().await;
+        assert!(summary.starts_with("message repeated 2 times"), "{}", summary);
+
+        // the window is still open when a reload replaces the chain
+        engine.replace_chain(Box::new(ParserRegistry::builtin()), Vec::new(), TransformChain::new().with_transform(Dedupe::new(DedupeMode::Exact)));
+        lines.send("disk full").unwrap();
+        lines.send("disk full").unwrap();
+        assert_eq!(next().await, "disk full");
+        tokio::time::timeout(Duration::from_secs(2), async {
+            while !engine.transform_counters().iter().any(|counter| counter.counter == "suppressed" && counter.value == 1) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }).await.unwrap();
+        engine.replace_chain(Box::new(ParserRegistry::builtin()), Vec::new(), TransformChain::new());
+        let summary = next().await;
+        assert!(summary.starts_with("message repeated 1 time between"), "{}", summary);
+
+        drop(lines);
+        assert_eq!(next().await, "");
+    }
+
+    #[tokio::test]
+    async fn read_errors_follow_the_supervision_policy() {
+        use std::io::ErrorKind::{ConnectionReset, InvalidData, PermissionDenied};
+
+        let retry = SupervisionPolicy::Retry { max_retries: Some(2), initial_backoff: Duration::from_millis(1), max_backoff: Duration::from_millis(5) };
+        let mut engine = Engine::new(Box::new(ParserRegistry::builtin()))
+            .with_supervision(retry)
+            .with_source_policy("strict", SupervisionPolicy::Fail);
+        let mut events = engine.subscribe_events();
+        engine.add_named_source("flaky", scripted(vec![Ok("one"), Err(ConnectionReset), Err(InvalidData), Ok("two"), Err(PermissionDenied), Ok("never")]));
+        let mut rx = engine.run().await.unwrap();
+
+        let mut messages = Vec::new();
+        while let Some(parsed_log) = rx.recv().await {
+            messages.push(parsed_log.message);
+        }
+        // the reset is retried, the bad record skipped and the permission error ends the source
+        assert_eq!(messages, vec!["one", "two"]);
+        let stats = engine.source("flaky").unwrap().stats();
+        assert_eq!(stats.state, SourceState::Failed);
+        assert!(stats.last_error.unwrap().contains("permission"));
+
+        let mut actions = Vec::new();
+        while let Ok(event) = events.try_recv() {
+            match event {
+                EngineEvent::SourceError { action, .. } => actions.push(format!("{:?}", action)),
+                EngineEvent::SourceRecovered { failures, .. } => actions.push(format!("recovered {}", failures)),
+                _ => {}
+            }
+        }
+        assert_eq!(actions, vec!["Retry { attempt: 1, delay: 1ms }", "Skip", "recovered 2", "StopSource"]);
+        assert!(!engine.has_failed());
+
+        // one error of a source with the `Fail` policy stops its healthy neighbours too
+        let mut engine = Engine::new(Box::new(ParserRegistry::builtin()))
+            .with_source_policy("strict", SupervisionPolicy::Fail)
+            .with_keep_open(true);
+        let healthy = std::env::temp_dir().join(format!("loganalyzer-supervision-{}.log", std::process::id()));
+        std::fs::write(&healthy, "").unwrap();
+        engine.add_named_source("healthy", Box::new(FileLogSource::new(&healthy).with_follow(true)));
+        let _rx = engine.run().await.unwrap();
+        engine.start_source("strict", scripted(vec![Err(ConnectionReset)])).await.unwrap();
+
+        assert_eq!(tokio::time::timeout(Duration::from_secs(2), engine.source("healthy").unwrap().wait()).await.unwrap(), SourceState::Stopped);
+        assert!(engine.has_failed());
+
+        let _ = std::fs::remove_file(&healthy);
+    }
+
+    #[tokio::test]
+    async fn a_full_output_sheds_records_by_its_policy() {
+        let log = std::env::temp_dir().join(format!("loganalyzer-overflow-{}.log", std::process::id()));
+        std::fs::write(&log, (0..50).map(|i| format!("line {}\n", i)).collect::<String>()).unwrap();
+
+        let mut engine = Engine::new(Box::new(ParserRegistry::builtin()))
+            .with_output_policy(StagePolicy::new(OverflowPolicy::DropNewest).with_capacity(10));
+        engine.add_named_source("app", Box::new(FileLogSource::new(&log)));
+        // nobody reads until the source is done, with `Block` it would wait forever
+        let mut rx = engine.run().await.unwrap();
+        assert_eq!(tokio::time::timeout(Duration::from_secs(2), engine.source("app").unwrap().wait()).await.unwrap(), SourceState::Finished);
+
+        let stats = engine.overflow_stats();
+        assert_eq!(stats.len(), 1);
+        assert_eq!((stats[0].stage.as_str(), stats[0].dropped_newest, stats[0].peak), ("output", 40, 10));
+        let mut received = 0;
+        while rx.recv().await.is_some() {
+            received += 1;
+        }
+        assert_eq!(received, 10);
+
+        let _ = std::fs::remove_file(&log);
+    }
+}
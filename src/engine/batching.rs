@@ -0,0 +1,118 @@
+use std::{sync::atomic::{AtomicU64, Ordering}, time::{Duration, Instant}};
+
+use crate::ingest::LogLine;
+
+pub(crate) const DEFAULT_BATCH_SIZE: usize = 100;
+pub(crate) const DEFAULT_MAX_LATENCY: Duration = Duration::from_millis(200);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum FlushReason {
+    // the batch reached the batch size
+    Full,
+    // the oldest line waited for the max latency
+    Timer,
+    // the source ended, was paused or stopped
+    Final
+}
+
+// lines of one source waiting to be parsed together
+pub(crate) struct Batch {
+    pub(crate) lines: Vec<LogLine>,
+    started: Option<Instant>,
+    size: usize
+}
+
+impl Batch {
+    pub(crate) fn new(size: usize) -> Self {
+        Self { lines: Vec::with_capacity(size), started: None, size }
+    }
+
+    pub(crate) fn push(&mut self, log_line: LogLine) {
+        if self.lines.is_empty() {
+            self.started = Some(Instant::now());
+        }
+        self.lines.push(log_line);
+    }
+
+    pub(crate) fn is_full(&self) -> bool {
+        self.lines.len() >= self.size
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.lines.is_empty()
+    }
+
+    // when the oldest line has waited long enough
+    pub(crate) fn flush_at(&self, max_latency: Duration) -> Option<Instant> {
+        self.started.map(|started| started + max_latency)
+    }
+
+    pub(crate) fn take_started(&mut self) -> Option<Instant> {
+        self.started.take()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BatchMetrics {
+    pub batches: u64,
+    pub records: u64,
+    pub full_flushes: u64,
+    pub timer_flushes: u64,
+    pub final_flushes: u64,
+    // records per batch relative to the batch size, 1.0 when every batch was full
+    pub fill_ratio: f64,
+    // from the first line entering a batch until the batch was handed over
+    pub mean_latency: Duration,
+    pub max_latency: Duration
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct BatchCounters {
+    batches: AtomicU64,
+    records: AtomicU64,
+    full_flushes: AtomicU64,
+    timer_flushes: AtomicU64,
+    final_flushes: AtomicU64,
+    latency_micros: AtomicU64,
+    max_latency_micros: AtomicU64
+}
+
+impl BatchCounters {
+    pub(crate) fn record(&self, records: usize, reason: FlushReason, latency: Duration) {
+        let latency = latency.as_micros() as u64;
+        self.batches.fetch_add(1, Ordering::Relaxed);
+        self.records.fetch_add(records as u64, Ordering::Relaxed);
+        self.latency_micros.fetch_add(latency, Ordering::Relaxed);
+        self.max_latency_micros.fetch_max(latency, Ordering::Relaxed);
+
+        let flushes = match reason {
+            FlushReason::Full => &self.full_flushes,
+            FlushReason::Timer => &self.timer_flushes,
+            FlushReason::Final => &self.final_flushes,
+        };
+        flushes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self, batch_size: usize) -> BatchMetrics {
+        let batches = self.batches.load(Ordering::Relaxed);
+        let records = self.records.load(Ordering::Relaxed);
+        let (fill_ratio, mean_latency) = match batches {
+            0 => (0.0, Duration::ZERO),
+            n => (
+                records as f64 / (n as f64 * batch_size as f64),
+                Duration::from_micros(self.latency_micros.load(Ordering::Relaxed) / n)
+            )
+        };
+
+        BatchMetrics {
+            batches,
+            records,
+            full_flushes: self.full_flushes.load(Ordering::Relaxed),
+            timer_flushes: self.timer_flushes.load(Ordering::Relaxed),
+            final_flushes: self.final_flushes.load(Ordering::Relaxed),
+            fill_ratio,
+            mean_latency,
+            max_latency: Duration::from_micros(self.max_latency_micros.load(Ordering::Relaxed))
+        }
+    }
+}
@@ -0,0 +1,142 @@
+use std::sync::{atomic::{AtomicU64, Ordering}, Arc, Mutex};
+
+use chrono::{DateTime, Utc};
+use tokio::sync::watch;
+
+use crate::ingest::LogLine;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SourceState {
+    Running,
+    Paused,
+    // the source reported its end
+    Finished,
+    // reading failed, see `SourceStats::last_error`
+    Failed,
+    // removed through the engine or a handle
+    Stopped
+}
+
+impl SourceState {
+    pub fn is_done(self) -> bool {
+        matches!(self, SourceState::Finished | SourceState::Failed | SourceState::Stopped)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Control {
+    Run,
+    Pause,
+    Stop
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SourceStats {
+    pub name: String,
+    pub state: SourceState,
+    pub lines_read: u64,
+    // bytes of record content, without line endings
+    pub bytes_read: u64,
+    pub last_record_at: Option<DateTime<Utc>>,
+    pub last_error: Option<String>
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Counters {
+    lines_read: AtomicU64,
+    bytes_read: AtomicU64,
+    last_record_at: Mutex<Option<DateTime<Utc>>>,
+    last_error: Mutex<Option<String>>
+}
+
+impl Counters {
+    pub(crate) fn record(&self, log_line: &LogLine) {
+        self.lines_read.fetch_add(1, Ordering::Relaxed);
+        self.bytes_read.fetch_add(log_line.content.len() as u64, Ordering::Relaxed);
+        *self.last_record_at.lock().unwrap() = Some(Utc::now());
+    }
+
+    pub(crate) fn error(&self, error: String) {
+        *self.last_error.lock().unwrap() = Some(error);
+    }
+}
+
+// the task side of a handle
+pub(crate) struct SourceControl {
+    pub(crate) control: watch::Receiver<Control>,
+    pub(crate) state: watch::Sender<SourceState>,
+    pub(crate) counters: Arc<Counters>
+}
+
+// cheap to clone, stays usable (for its stats) after the source is done
+#[derive(Clone)]
+pub struct SourceHandle {
+    name: Arc<str>,
+    control: Arc<watch::Sender<Control>>,
+    state: watch::Receiver<SourceState>,
+    counters: Arc<Counters>
+}
+
+impl SourceHandle {
+    pub(crate) fn new(name: &str) -> (Self, SourceControl) {
+        let (control, control_rx) = watch::channel(Control::Run);
+        let (state_tx, state) = watch::channel(SourceState::Running);
+        let counters = Arc::new(Counters::default());
+
+        let handle = Self { name: Arc::from(name), control: Arc::new(control), state, counters: counters.clone() };
+        (handle, SourceControl { control: control_rx, state: state_tx, counters })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    // stops reading, records read so far are still processed
+    pub fn pause(&self) {
+        self.control.send_if_modified(|control| {
+            let pause = *control == Control::Run;
+            if pause {
+                *control = Control::Pause;
+            }
+            pause
+        });
+    }
+
+    pub fn resume(&self) {
+        self.control.send_if_modified(|control| {
+            let resume = *control == Control::Pause;
+            if resume {
+                *control = Control::Run;
+            }
+            resume
+        });
+    }
+
+    // `Engine::remove_source` also waits for the source to be closed
+    pub fn stop(&self) {
+        self.control.send_replace(Control::Stop);
+    }
+
+    pub fn state(&self) -> SourceState {
+        *self.state.borrow()
+    }
+
+    pub fn stats(&self) -> SourceStats {
+        SourceStats {
+            name: self.name.to_string(),
+            state: self.state(),
+            lines_read: self.counters.lines_read.load(Ordering::Relaxed),
+            bytes_read: self.counters.bytes_read.load(Ordering::Relaxed),
+            last_record_at: *self.counters.last_record_at.lock().unwrap(),
+            last_error: self.counters.last_error.lock().unwrap().clone()
+        }
+    }
+
+    // resolves once the source is finished, failed or stopped and closed
+    pub async fn wait(&self) -> SourceState {
+        let mut state = self.state.clone();
+        let done = state.wait_for(|state| state.is_done()).await.map(|state| *state);
+        // the task is gone without reporting, it can't be reading anymore
+        done.unwrap_or(SourceState::Stopped)
+    }
+}
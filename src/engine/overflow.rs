@@ -0,0 +1,798 @@
+// Bounded queues between pipeline stages with an explicit answer to "what happens when it's full".
+//
+// A queue is full when it holds `capacity` records or, with a memory budget, when the records it
+// holds weigh that many bytes. What a sender does then is its stage's `OverflowPolicy`. Every
+// record that doesn't make it is counted per stage, see `OverflowStats`.
+//
+// A record can carry an `Ack`, it runs once the receiver confirmed the record, or right away when
+// the record was dropped on purpose. Records that never made it out (the receiver went away, or the
+// spill file lost them) are not acknowledged, so a spool before the queue delivers them again.
+
+use std::{collections::VecDeque, fmt, fs::{File, OpenOptions}, io::{BufRead, BufReader, Seek, SeekFrom, Write}, path::PathBuf, sync::{atomic::{AtomicU64, AtomicUsize, Ordering}, Arc, Mutex, Weak}};
+
+use serde::{de::DeserializeOwned, Serialize};
+use tokio::sync::Notify;
+
+use crate::{ingest::LogLine, parser::ParsedLog};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    // senders wait for room, slowing down everything before this stage
+    Block,
+    // the record being sent is dropped
+    DropNewest,
+    // the oldest queued record makes room
+    DropOldest,
+    // every n-th record waits for room, the others are dropped
+    Sample(u32),
+    // records go to this file and come back in order once there is room again
+    Spill(PathBuf)
+}
+
+impl OverflowPolicy {
+    pub fn name(&self) -> &'static str {
+        match self {
+            OverflowPolicy::Block => "block",
+            OverflowPolicy::DropNewest => "drop_newest",
+            OverflowPolicy::DropOldest => "drop_oldest",
+            OverflowPolicy::Sample(_) => "sample",
+            OverflowPolicy::Spill(_) => "spill"
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StagePolicy {
+    pub policy: OverflowPolicy,
+    pub capacity: usize,
+    // bytes, estimated with `Weigh`
+    pub memory_budget: Option<usize>
+}
+
+impl StagePolicy {
+    pub fn new(policy: OverflowPolicy) -> Self {
+        Self { policy, capacity: 100, memory_budget: None }
+    }
+
+    // at least 1
+    pub fn with_capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity.max(1);
+        self
+    }
+
+    pub fn with_memory_budget(mut self, bytes: usize) -> Self {
+        self.memory_budget = Some(bytes);
+        self
+    }
+}
+
+impl Default for StagePolicy {
+    fn default() -> Self {
+        Self::new(OverflowPolicy::Block)
+    }
+}
+
+// confirms that a record, usually the last of a batch, was dealt with downstream
+pub struct Ack(Box<dyn FnOnce() + Send + Sync>);
+
+impl Ack {
+    pub fn new(confirm: impl FnOnce() + Send + Sync + 'static) -> Self {
+        Self(Box::new(confirm))
+    }
+
+    pub fn ack(self) {
+        (self.0)()
+    }
+
+    // `n` acks that confirm this one once every one of them was confirmed, right away for none
+    pub fn split(self, n: usize) -> Vec<Ack> {
+        if n == 0 {
+            self.ack();
+            return Vec::new();
+        }
+        let remaining = Arc::new(Mutex::new((n, Some(self))));
+        (0..n).map(|_| {
+            let remaining = remaining.clone();
+            Ack::new(move || {
+                let last = {
+                    let mut remaining = remaining.lock().unwrap();
+                    remaining.0 -= 1;
+                    if remaining.0 == 0 { remaining.1.take() } else { None }
+                };
+                if let Some(ack) = last {
+                    ack.ack();
+                }
+            })
+        }).collect()
+    }
+}
+
+fn ack_all(acks: impl IntoIterator<Item = Ack>) {
+    for ack in acks {
+        ack.ack();
+    }
+}
+
+// rough number of heap and inline bytes a queued record holds
+pub trait Weigh {
+    fn weight(&self) -> usize;
+}
+
+fn json_weight(value: &serde_json::Value) -> usize {
+    std::mem::size_of::<serde_json::Value>() + match value {
+        serde_json::Value::String(s) => s.len(),
+        serde_json::Value::Array(values) => values.iter().map(json_weight).sum(),
+        serde_json::Value::Object(map) => map.iter().map(|(k, v)| k.len() + json_weight(v)).sum(),
+        _ => 0
+    }
+}
+
+impl Weigh for ParsedLog {
+    fn weight(&self) -> usize {
+        let strings = [&self.service_name, &self.trace_id, &self.span_id, &self.host, &self.environment, &self.version];
+        std::mem::size_of::<ParsedLog>()
+            + self.message.len()
+            + strings.iter().map(|s| s.as_ref().map_or(0, String::len)).sum::<usize>()
+            + json_weight(&self.metadata)
+    }
+}
+
+impl Weigh for LogLine {
+    fn weight(&self) -> usize {
+        std::mem::size_of::<LogLine>() + self.content.len() + self.source.len()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct OverflowStats {
+    pub stage: String,
+    pub policy: &'static str,
+    pub queued: usize,
+    pub queued_bytes: usize,
+    // most records queued at once
+    pub peak: usize,
+    pub dropped_newest: u64,
+    pub dropped_oldest: u64,
+    pub sampled_out: u64,
+    pub spilled: u64,
+    // spilled records not read back yet
+    pub spill_pending: u64,
+    // records the spill file couldn't take or give back, they are dropped unacknowledged
+    pub spill_errors: u64
+}
+
+impl OverflowStats {
+    // every record this stage lost
+    pub fn dropped(&self) -> u64 {
+        self.dropped_newest + self.dropped_oldest + self.sampled_out + self.spill_errors
+    }
+}
+
+impl fmt::Display for OverflowStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({}): dropped {} (newest={} oldest={} sampled={} spill_errors={}), spilled {}, peak {}",
+            self.stage, self.policy, self.dropped(), self.dropped_newest, self.dropped_oldest, self.sampled_out,
+            self.spill_errors, self.spilled, self.peak)
+    }
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    peak: AtomicUsize,
+    dropped_newest: AtomicU64,
+    dropped_oldest: AtomicU64,
+    sampled_out: AtomicU64,
+    spilled: AtomicU64,
+    spill_errors: AtomicU64
+}
+
+// the spill file is appended to by senders and read from the front by the receiver, it's
+// truncated whenever everything in it was read back. Only used on blocking threads, under its own
+// lock and never under the queue's
+struct Spill {
+    writer: File,
+    reader: BufReader<File>
+}
+
+impl Spill {
+    fn open(path: &PathBuf) -> std::io::Result<Self> {
+        // opened twice, a cloned handle would share the position with the writer
+        let writer = OpenOptions::new().create(true).write(true).truncate(true).open(path)?;
+        Ok(Self { reader: BufReader::new(File::open(path)?), writer })
+    }
+
+    // a line that couldn't be written whole is cut off again, so the next one starts clean
+    fn push(&mut self, line: &[u8]) -> std::io::Result<()> {
+        let end = self.writer.stream_position()?;
+        self.writer.write_all(line).inspect_err(|_| {
+            let _ = self.writer.set_len(end).and_then(|_| self.writer.seek(SeekFrom::Start(end)));
+        })
+    }
+
+    // `None` for a line that couldn't be read back
+    fn pop(&mut self) -> Option<String> {
+        let mut line = String::new();
+        match self.reader.read_line(&mut line) {
+            Ok(0) | Err(_) => None,
+            Ok(_) => Some(line)
+        }
+    }
+
+    fn reset(&mut self) -> std::io::Result<()> {
+        self.writer.set_len(0)?;
+        self.writer.seek(SeekFrom::Start(0))?;
+        self.reader.seek(SeekFrom::Start(0))?;
+        Ok(())
+    }
+}
+
+struct State<T> {
+    items: VecDeque<(T, Option<Ack>)>,
+    // acks of the spilled records, in the same order
+    spill_acks: VecDeque<Option<Ack>>,
+    // records spilled or on their way to the file, not read back yet
+    spill_pending: u64,
+    // of those, the ones in the file
+    spill_written: u64,
+    // a line is being read back, it ends up in `read_back`
+    spill_reading: bool,
+    read_back: Option<(Option<String>, Option<Ack>)>,
+    bytes: usize,
+    senders: usize,
+    receiver: bool,
+    // counts sends that found the queue full, for `Sample`
+    overflowed: u64,
+    // acks the dropped receiver never confirmed, held until `release` says nobody will
+    abandoned: Vec<Ack>,
+    released: bool
+}
+
+impl<T> State<T> {
+    // every ack still waiting in the queue, the records themselves stay
+    fn take_acks(&mut self) -> Vec<Ack> {
+        let queued = self.items.iter_mut().filter_map(|(_, ack)| ack.take());
+        let spilled = self.spill_acks.iter_mut().chain(self.read_back.iter_mut().map(|(_, ack)| ack)).filter_map(Option::take);
+        let mut acks = queued.chain(spilled).collect::<Vec<_>>();
+        acks.append(&mut self.abandoned);
+        acks
+    }
+}
+
+struct Shared<T> {
+    stage: String,
+    policy: StagePolicy,
+    state: Mutex<State<T>>,
+    spill: Option<Mutex<Spill>>,
+    // one receiver, so a stored permit is never lost
+    readable: Notify,
+    writable: Notify,
+    counters: Counters
+}
+
+impl<T> Shared<T> {
+    fn is_full(&self, state: &State<T>) -> bool {
+        state.items.len() >= self.policy.capacity || self.policy.memory_budget.is_some_and(|budget| state.bytes >= budget)
+    }
+
+    fn stats(&self) -> OverflowStats {
+        let state = self.state.lock().unwrap();
+        OverflowStats {
+            stage: self.stage.clone(),
+            policy: self.policy.policy.name(),
+            queued: state.items.len(),
+            queued_bytes: state.bytes,
+            peak: self.counters.peak.load(Ordering::Relaxed),
+            dropped_newest: self.counters.dropped_newest.load(Ordering::Relaxed),
+            dropped_oldest: self.counters.dropped_oldest.load(Ordering::Relaxed),
+            sampled_out: self.counters.sampled_out.load(Ordering::Relaxed),
+            spilled: self.counters.spilled.load(Ordering::Relaxed),
+            spill_pending: state.spill_pending,
+            spill_errors: self.counters.spill_errors.load(Ordering::Relaxed)
+        }
+    }
+}
+
+trait StageStats: Send + Sync {
+    fn stats(&self) -> OverflowStats;
+}
+
+impl<T: Send> StageStats for Shared<T> {
+    fn stats(&self) -> OverflowStats {
+        Shared::stats(self)
+    }
+}
+
+// reads the counters of a queue without keeping it open, whatever it carries
+#[derive(Clone)]
+pub struct OverflowMonitor {
+    shared: Arc<dyn StageStats>
+}
+
+impl OverflowMonitor {
+    pub fn stats(&self) -> OverflowStats {
+        self.shared.stats()
+    }
+}
+
+// `Err` gives the record back, nobody will receive it anymore
+#[derive(Debug, PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
+impl<T> fmt::Display for SendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "the receiving stage is gone")
+    }
+}
+
+impl<T: fmt::Debug> std::error::Error for SendError<T> {}
+
+// like an mpsc channel, the receiver sees the end once every sender is dropped. Spilling needs a
+// writable file at `path`, that's the only way this fails
+pub fn channel<T>(stage: impl Into<String>, policy: StagePolicy) -> std::io::Result<(Sender<T>, Receiver<T>)> {
+    let spill = match &policy.policy {
+        OverflowPolicy::Spill(path) => Some(Mutex::new(Spill::open(path)?)),
+        _ => None
+    };
+    let shared = Arc::new(Shared {
+        stage: stage.into(),
+        state: Mutex::new(State {
+            items: VecDeque::new(), spill_acks: VecDeque::new(), spill_pending: 0, spill_written: 0, spill_reading: false, read_back: None,
+            bytes: 0, senders: 1, receiver: true, overflowed: 0, abandoned: Vec::new(), released: false
+        }),
+        spill,
+        policy,
+        readable: Notify::new(),
+        writable: Notify::new(),
+        counters: Counters::default()
+    });
+    Ok((Sender { shared: shared.clone() }, Receiver { shared, unacked: Vec::new(), manual_ack: false }))
+}
+
+pub struct Sender<T> {
+    shared: Arc<Shared<T>>
+}
+
+// doesn't keep the receiver's stream open
+pub struct WeakSender<T> {
+    shared: Weak<Shared<T>>
+}
+
+pub struct Receiver<T> {
+    shared: Arc<Shared<T>>,
+    // acks of records handed out but not confirmed yet
+    unacked: Vec<Ack>,
+    manual_ack: bool
+}
+
+// what `send` does with a record once it holds the lock
+enum Admit {
+    Push,
+    Wait,
+    Drop,
+    Spill
+}
+
+impl<T: Weigh + Serialize + Send + 'static> Sender<T> {
+    pub async fn send(&self, item: T) -> Result<(), SendError<T>> {
+        self.send_with_ack(item, None).await
+    }
+
+    // `ack` runs once the receiver confirms the record, see `Receiver::ack`
+    pub async fn send_with_ack(&self, item: T, ack: Option<Ack>) -> Result<(), SendError<T>> {
+        let shared = &self.shared;
+        // only the first look at a full queue decides whether a sampled record is kept
+        let mut sampled_in = false;
+
+        loop {
+            let notified = shared.writable.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+
+            let spill = {
+                let mut state = shared.state.lock().unwrap();
+                if !state.receiver {
+                    if state.released {
+                        drop(state);
+                        ack_all(ack);
+                    } else {
+                        state.abandoned.extend(ack);
+                    }
+                    return Err(SendError(item));
+                }
+
+                let spilling = state.spill_pending > 0;
+                let admit = if !shared.is_full(&state) && !spilling {
+                    Admit::Push
+                } else {
+                    match &shared.policy.policy {
+                        OverflowPolicy::Block => Admit::Wait,
+                        OverflowPolicy::DropNewest => {
+                            shared.counters.dropped_newest.fetch_add(1, Ordering::Relaxed);
+                            Admit::Drop
+                        }
+                        OverflowPolicy::DropOldest => {
+                            if let Some((oldest, oldest_ack)) = state.items.pop_front() {
+                                state.bytes -= oldest.weight();
+                                shared.counters.dropped_oldest.fetch_add(1, Ordering::Relaxed);
+                                ack_all(oldest_ack);
+                            }
+                            Admit::Push
+                        }
+                        OverflowPolicy::Sample(every) => {
+                            if !sampled_in {
+                                state.overflowed += 1;
+                                sampled_in = state.overflowed.is_multiple_of(u64::from((*every).max(1)));
+                            }
+                            if sampled_in {
+                                Admit::Wait
+                            } else {
+                                shared.counters.sampled_out.fetch_add(1, Ordering::Relaxed);
+                                Admit::Drop
+                            }
+                        }
+                        // once something is spilled everything after it is too, to keep the order
+                        OverflowPolicy::Spill(_) => Admit::Spill
+                    }
+                };
+
+                match admit {
+                    Admit::Push => {
+                        state.bytes += item.weight();
+                        state.items.push_back((item, ack));
+                        shared.counters.peak.fetch_max(state.items.len(), Ordering::Relaxed);
+                        drop(state);
+                        shared.readable.notify_one();
+                        return Ok(());
+                    }
+                    Admit::Drop => {
+                        drop(state);
+                        ack_all(ack);
+                        return Ok(());
+                    }
+                    Admit::Spill => {
+                        state.spill_pending += 1;
+                        true
+                    }
+                    Admit::Wait => false
+                }
+            };
+
+            if spill {
+                self.spill(&item, ack).await;
+                return Ok(());
+            }
+            notified.await;
+        }
+    }
+}
+
+impl<T: Send + 'static> Sender<T> {
+    // written on a blocking thread, a slow disk doesn't hold up the async workers or the queue
+    async fn spill(&self, item: &T, ack: Option<Ack>) where T: Serialize {
+        let shared = self.shared.clone();
+        let line = serde_json::to_vec(item).map(|mut line| {
+            line.push(b'\n');
+            line
+        });
+        let write = move || {
+            let mut spill = shared.spill.as_ref().expect("spill policy without a spill file").lock().unwrap();
+            let written = line.map_err(std::io::Error::from).and_then(|line| spill.push(&line));
+            let mut state = shared.state.lock().unwrap();
+            match written {
+                Ok(()) => {
+                    shared.counters.spilled.fetch_add(1, Ordering::Relaxed);
+                    state.spill_written += 1;
+                    state.spill_acks.push_back(ack);
+                    None
+                }
+                Err(_) => {
+                    shared.counters.spill_errors.fetch_add(1, Ordering::Relaxed);
+                    state.spill_pending -= 1;
+                    // not delivered, so not confirmed either unless nothing will be redelivered
+                    if state.released && !state.receiver {
+                        return ack;
+                    }
+                    state.abandoned.extend(ack);
+                    None
+                }
+            }
+        };
+        match tokio::task::spawn_blocking(write).await {
+            Ok(unspilled) => ack_all(unspilled),
+            Err(e) => std::panic::resume_unwind(e.into_panic())
+        }
+        self.shared.readable.notify_one();
+    }
+}
+
+impl<T> Sender<T> {
+    pub fn downgrade(&self) -> WeakSender<T> {
+        WeakSender { shared: Arc::downgrade(&self.shared) }
+    }
+
+    pub fn stats(&self) -> OverflowStats {
+        self.shared.stats()
+    }
+
+    // nothing will be redelivered to this stage, so what its receiver doesn't confirm no longer
+    // holds the acks back: they run once the receiver is dropped, or right away if it already is
+    pub fn release(&self) {
+        let acks = {
+            let mut state = self.shared.state.lock().unwrap();
+            state.released = true;
+            if state.receiver { Vec::new() } else { state.take_acks() }
+        };
+        ack_all(acks);
+    }
+}
+
+impl<T: Send + 'static> Sender<T> {
+    pub fn monitor(&self) -> OverflowMonitor {
+        OverflowMonitor { shared: self.shared.clone() }
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.shared.state.lock().unwrap().senders += 1;
+        Self { shared: self.shared.clone() }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let last = {
+            let mut state = self.shared.state.lock().unwrap();
+            state.senders -= 1;
+            state.senders == 0
+        };
+        if last {
+            self.shared.readable.notify_one();
+        }
+    }
+}
+
+impl<T> WeakSender<T> {
+    // `None` once every sender is gone, the receiver may already have seen the end
+    pub fn upgrade(&self) -> Option<Sender<T>> {
+        let shared = self.shared.upgrade()?;
+        {
+            let mut state = shared.state.lock().unwrap();
+            if state.senders == 0 {
+                return None;
+            }
+            state.senders += 1;
+        }
+        Some(Sender { shared })
+    }
+}
+
+impl<T> Clone for WeakSender<T> {
+    fn clone(&self) -> Self {
+        Self { shared: self.shared.clone() }
+    }
+}
+
+impl<T: Weigh + DeserializeOwned + Send + 'static> Receiver<T> {
+    // `None` once every sender is dropped and everything queued or spilled was received. Unless
+    // acks are manual, asking for the next record confirms the ones received before
+    pub async fn recv(&mut self) -> Option<T> {
+        if !self.manual_ack {
+            self.ack();
+        }
+
+        let shared = &self.shared;
+        loop {
+            {
+                let mut state = shared.state.lock().unwrap();
+                let entry = match state.items.pop_front() {
+                    Some((item, ack)) => {
+                        state.bytes -= item.weight();
+                        Some((Some(item), ack))
+                    }
+                    None => state.read_back.take()
+                        .map(|(line, ack)| (line.and_then(|line| serde_json::from_str(&line).ok()), ack))
+                };
+                if entry.is_none() && state.spill_written > 0 && !state.spill_reading {
+                    state.spill_written -= 1;
+                    state.spill_reading = true;
+                    Self::read_back(shared.clone());
+                }
+
+                match entry {
+                    Some((item, ack)) => {
+                        drop(state);
+                        shared.writable.notify_waiters();
+                        match item {
+                            Some(item) => {
+                                self.unacked.extend(ack);
+                                return Some(item);
+                            }
+                            // lost in the spill file, it's not coming back from there
+                            None => {
+                                shared.counters.spill_errors.fetch_add(1, Ordering::Relaxed);
+                                shared.state.lock().unwrap().abandoned.extend(ack);
+                                continue;
+                            }
+                        }
+                    }
+                    None if state.senders == 0 && state.spill_pending == 0 => return None,
+                    None => {}
+                }
+            }
+
+            shared.readable.notified().await;
+        }
+    }
+
+    // reads the next spilled line on a blocking thread into `read_back`, where the next `recv`
+    // finds it even if the one that asked was cancelled
+    fn read_back(shared: Arc<Shared<T>>) {
+        tokio::task::spawn_blocking(move || {
+            let mut spill = shared.spill.as_ref().expect("spilled without a spill file").lock().unwrap();
+            let line = spill.pop();
+            let mut state = shared.state.lock().unwrap();
+            let ack = state.spill_acks.pop_front().flatten();
+            state.read_back = Some((line, ack));
+            state.spill_reading = false;
+            state.spill_pending -= 1;
+            // nothing left in the file or on its way there
+            if state.spill_pending == 0 {
+                let _ = spill.reset();
+            }
+            drop(state);
+            drop(spill);
+            shared.readable.notify_one();
+        });
+    }
+}
+
+impl<T> Receiver<T> {
+    pub fn stats(&self) -> OverflowStats {
+        self.shared.stats()
+    }
+
+    // records only count as delivered once `ack` is called, e.g. after sinks flushed them
+    pub fn with_manual_ack(mut self) -> Self {
+        self.manual_ack = true;
+        self
+    }
+
+    // confirms every record received so far
+    pub fn ack(&mut self) {
+        ack_all(self.unacked.drain(..));
+    }
+
+    // nothing is waiting to be received right now
+    pub fn is_empty(&self) -> bool {
+        let state = self.shared.state.lock().unwrap();
+        state.items.is_empty() && state.read_back.is_none() && state.spill_pending == 0
+    }
+}
+
+impl<T: Send + 'static> Receiver<T> {
+    pub fn monitor(&self) -> OverflowMonitor {
+        OverflowMonitor { shared: self.shared.clone() }
+    }
+}
+
+// what wasn't confirmed stays unconfirmed, like after a crash, unless the sender released it
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let acks = {
+            let mut state = self.shared.state.lock().unwrap();
+            state.receiver = false;
+            state.abandoned.append(&mut self.unacked);
+            if state.released { state.take_acks() } else { Vec::new() }
+        };
+        self.shared.writable.notify_waiters();
+        ack_all(acks);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn line(content: &str) -> LogLine {
+        LogLine { content: content.to_string(), source: "test".to_string(), timestamp: chrono::Utc::now() }
+    }
+
+    async fn fill(tx: &Sender<LogLine>, count: usize) {
+        for i in 0..count {
+            tx.send(line(&i.to_string())).await.unwrap();
+        }
+    }
+
+    async fn drain(mut rx: Receiver<LogLine>) -> Vec<String> {
+        let mut received = Vec::new();
+        while let Some(log_line) = rx.recv().await {
+            received.push(log_line.content);
+        }
+        received
+    }
+
+    #[tokio::test]
+    async fn full_queues_shed_records_by_policy() {
+        let (tx, rx) = channel("newest", StagePolicy::new(OverflowPolicy::DropNewest).with_capacity(3)).unwrap();
+        fill(&tx, 5).await;
+        assert_eq!(tx.stats().dropped_newest, 2);
+        drop(tx);
+        assert_eq!(drain(rx).await, vec!["0", "1", "2"]);
+
+        let (tx, rx) = channel("oldest", StagePolicy::new(OverflowPolicy::DropOldest).with_capacity(3)).unwrap();
+        fill(&tx, 5).await;
+        assert_eq!(tx.stats().dropped_oldest, 2);
+        drop(tx);
+        assert_eq!(drain(rx).await, vec!["2", "3", "4"]);
+
+        // the budget fits two lines, the capacity wouldn't stop anything
+        let budget = line("0").weight() * 2;
+        let (tx, rx) = channel("budget", StagePolicy::new(OverflowPolicy::DropNewest).with_memory_budget(budget)).unwrap();
+        fill(&tx, 4).await;
+        let stats = tx.stats();
+        assert_eq!((stats.queued, stats.queued_bytes, stats.dropped()), (2, budget, 2));
+        drop(tx);
+        assert_eq!(drain(rx).await.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn blocking_and_sampling_senders_wait_for_room() {
+        let (tx, mut rx) = channel("sample", StagePolicy::new(OverflowPolicy::Sample(2)).with_capacity(1)).unwrap();
+        let sender = tokio::spawn(async move {
+            fill(&tx, 4).await;
+            tx.stats()
+        });
+
+        // "1" is dropped, "2" is the second overflow and waits until "0" was received
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(rx.recv().await.unwrap().content, "0");
+        let stats = sender.await.unwrap();
+        assert_eq!((stats.sampled_out, stats.peak), (2, 1));
+        assert_eq!(drain(rx).await, vec!["2"]);
+    }
+
+    #[tokio::test]
+    async fn spilled_records_come_back_in_order() {
+        let path = std::env::temp_dir().join(format!("loganalyzer-spill-{}.ndjson", std::process::id()));
+        let (tx, mut rx) = channel("spill", StagePolicy::new(OverflowPolicy::Spill(path.clone())).with_capacity(2)).unwrap();
+        fill(&tx, 5).await;
+        assert_eq!((tx.stats().spilled, tx.stats().spill_pending), (3, 3));
+
+        // room again, but the queue keeps spilling until the file is read back
+        assert_eq!(rx.recv().await.unwrap().content, "0");
+        tx.send(line("5")).await.unwrap();
+        drop(tx);
+
+        assert_eq!(drain(rx).await, vec!["1", "2", "3", "4", "5"]);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 0);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    // writes to /dev/full fail with "no space left"
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn records_the_spill_file_loses_stay_unacknowledged() {
+        let acked = Arc::new(AtomicUsize::new(0));
+        let counted = || {
+            let acked = acked.clone();
+            Some(Ack::new(move || {
+                acked.fetch_add(1, Ordering::Relaxed);
+            }))
+        };
+        let (tx, mut rx) = channel("full", StagePolicy::new(OverflowPolicy::Spill(PathBuf::from("/dev/full"))).with_capacity(1)).unwrap();
+        for content in ["0", "1", "2"] {
+            tx.send_with_ack(line(content), counted()).await.unwrap();
+        }
+        assert_eq!((tx.stats().spill_errors, tx.stats().spill_pending), (2, 0));
+        drop(tx);
+
+        assert_eq!(rx.recv().await.unwrap().content, "0");
+        assert_eq!(rx.recv().await, None);
+        // only the delivered one is confirmed, a spool would deliver "1" and "2" again
+        drop(rx);
+        assert_eq!(acked.load(Ordering::Relaxed), 1);
+    }
+}
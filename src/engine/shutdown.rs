@@ -0,0 +1,119 @@
+use std::{fmt, sync::atomic::Ordering, time::{Duration, Instant}};
+
+use tokio::task::JoinHandle;
+
+use super::Engine;
+
+// how long sources get to close after their in-flight records were abandoned
+const ABANDON_GRACE: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ShutdownReport {
+    // everything read was handed over before the deadline
+    pub drained: bool,
+    // sources that were closed, whether or not their records made it
+    pub closed: Vec<String>,
+    // sources that didn't even close after the grace period and were cancelled
+    pub abandoned_sources: Vec<String>,
+    // parsed records dropped because the output didn't take them in time
+    pub abandoned_records: u64,
+    // whether whoever reads the output (sinks) finished in time. The engine doesn't know, it
+    // leaves this `None` for the owner of the sinks to fill in once they're done
+    pub sinks_flushed: Option<bool>,
+    pub elapsed: Duration
+}
+
+impl fmt::Display for ShutdownReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.drained && self.sinks_flushed != Some(false) {
+            return write!(f, "shut down cleanly in {:?}, closed {} source(s)", self.elapsed, self.closed.len());
+        }
+
+        write!(f, "shut down after {:?}", self.elapsed)?;
+        if self.abandoned_records > 0 {
+            write!(f, ", abandoned {} record(s)", self.abandoned_records)?;
+        }
+        if !self.abandoned_sources.is_empty() {
+            write!(f, ", cancelled source(s) without closing: {}", self.abandoned_sources.join(", "))?;
+        }
+        if self.sinks_flushed == Some(false) {
+            write!(f, ", sinks were not flushed")?;
+        }
+        Ok(())
+    }
+}
+
+// resolves on Ctrl-C, or SIGTERM on unix
+pub async fn signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        if let Ok(mut terminate) = signal(SignalKind::terminate()) {
+            tokio::select! {
+                _ = tokio::signal::ctrl_c() => {}
+                _ = terminate.recv() => {}
+            }
+            return;
+        }
+    }
+
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+// true when every task finished within `limit`
+async fn wait_all(tasks: &mut [(String, JoinHandle<()>)], limit: Duration) -> bool {
+    let pending = tasks.iter_mut().filter(|(_, task)| !task.is_finished()).map(|(_, task)| task).collect::<Vec<_>>();
+    tokio::time::timeout(limit, futures::future::join_all(pending)).await.is_ok()
+}
+
+impl Engine {
+    // Stops every source from reading and lets what was already read go through parsing,
+    // analytics and out of the engine. Checkpoints are written and sources closed as they
+    // finish. Whatever is still in flight at `deadline` is dropped and counted. The output
+    // closes afterwards, so the consumer has to keep reading while this runs, and it reports
+    // whether it flushed in `sinks_flushed`.
+    pub async fn shutdown(&mut self, deadline: Duration) -> ShutdownReport {
+        let started = Instant::now();
+
+        self.delivery_guard = None;
+        self.sources.clear();
+        for running in self.running.values() {
+            running.handle.stop();
+        }
+
+        let mut tasks = self.running.iter_mut()
+            .filter_map(|(name, running)| running.task.take().map(|task| (name.clone(), task)))
+            .collect::<Vec<_>>();
+
+        let drained = wait_all(&mut tasks, deadline).await;
+        if !drained {
+            self.abandon.send_replace(true);
+            wait_all(&mut tasks, ABANDON_GRACE).await;
+        }
+
+        let mut abandoned_sources = Vec::new();
+        for (name, task) in &tasks {
+            if !task.is_finished() {
+                task.abort();
+                abandoned_sources.push(name.clone());
+            }
+        }
+        abandoned_sources.sort();
+
+        let closed = self.sources().into_iter()
+            .filter(|handle| handle.state().is_done() && !abandoned_sources.iter().any(|name| name == handle.name()))
+            .map(|handle| handle.name().to_string())
+            .collect();
+
+        ShutdownReport {
+            drained: drained && self.abandoned_records.load(Ordering::Relaxed) == 0,
+            closed,
+            abandoned_sources,
+            abandoned_records: self.abandoned_records.load(Ordering::Relaxed),
+            sinks_flushed: None,
+            elapsed: started.elapsed()
+        }
+    }
+}
+
@@ -0,0 +1,316 @@
+use std::{fmt, sync::{atomic::{AtomicU64, Ordering}, Arc, RwLock}};
+
+use tokio::sync::watch;
+
+use crate::{error::LogAnalyzerError, filter::LogFilter, parser::ParsedLog};
+
+use super::overflow::{self, Ack, OverflowMonitor, OverflowPolicy, OverflowStats, StagePolicy};
+
+// queue length of a subscriber that didn't pick one
+const DEFAULT_SUBSCRIBER_CAPACITY: usize = 1000;
+
+// what a subscriber wants to see and how its queue behaves when it falls behind
+#[derive(Debug, Clone, PartialEq)]
+pub struct Subscription {
+    filter: LogFilter,
+    policy: StagePolicy
+}
+
+impl Subscription {
+    // every record, the oldest ones are dropped when the subscriber can't keep up so it never
+    // holds up the sources or the other subscribers
+    pub fn new() -> Self {
+        Self {
+            filter: LogFilter::new(),
+            policy: StagePolicy::new(OverflowPolicy::DropOldest).with_capacity(DEFAULT_SUBSCRIBER_CAPACITY)
+        }
+    }
+
+    // applied on top of the engine's filters
+    pub fn with_filter(mut self, filter: LogFilter) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    // `Block` makes the sources wait for this subscriber, and with them every other subscriber
+    pub fn with_policy(mut self, policy: StagePolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+}
+
+impl Default for Subscription {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SubscriberStats {
+    pub name: String,
+    // handed to the subscriber's queue, it may still have dropped some by its policy
+    pub delivered: u64,
+    pub filtered_out: u64,
+    pub overflow: OverflowStats
+}
+
+impl fmt::Display for SubscriberStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "subscriber '{}': {} delivered, {} filtered out, {}", self.name, self.delivered, self.filtered_out, self.overflow)
+    }
+}
+
+pub(crate) struct Subscriber {
+    name: String,
+    filter: LogFilter,
+    tx: overflow::Sender<ParsedLog>,
+    delivered: AtomicU64,
+    filtered_out: AtomicU64
+}
+
+impl Subscriber {
+    fn stats(&self) -> SubscriberStats {
+        SubscriberStats {
+            name: self.name.clone(),
+            delivered: self.delivered.load(Ordering::Relaxed),
+            filtered_out: self.filtered_out.load(Ordering::Relaxed),
+            overflow: self.tx.stats()
+        }
+    }
+}
+
+#[derive(Default)]
+struct Registry {
+    subscribers: Vec<Arc<Subscriber>>,
+    // every source is done, the subscribers saw the end of their stream
+    closed: bool,
+    // what the subscribers looked like when their stream ended, their queues may still drain
+    ended: Vec<(SubscriberStats, OverflowMonitor)>
+}
+
+// the subscribers of an engine, attached and detached while sources are sending
+#[derive(Default)]
+pub(crate) struct Subscribers {
+    registry: RwLock<Registry>,
+    // records that passed the engine's filters while nobody was subscribed
+    undelivered: AtomicU64,
+    // set once the last subscriber left, sources of an engine that stops when deserted stop then
+    deserted: watch::Sender<bool>
+}
+
+impl Subscribers {
+    fn subscriber(name: &str, subscription: Subscription) -> Result<(Arc<Subscriber>, overflow::Receiver<ParsedLog>), LogAnalyzerError> {
+        let (tx, rx) = overflow::channel(name, subscription.policy).map_err(LogAnalyzerError::Io)?;
+        let subscriber = Subscriber { name: name.to_string(), filter: subscription.filter, tx, delivered: AtomicU64::new(0), filtered_out: AtomicU64::new(0) };
+        Ok((Arc::new(subscriber), rx))
+    }
+
+    pub(crate) fn add(&self, name: &str, subscription: Subscription) -> Result<overflow::Receiver<ParsedLog>, LogAnalyzerError> {
+        let mut registry = self.registry.write().unwrap();
+        if registry.closed {
+            return Err(LogAnalyzerError::EngineNotRunning);
+        }
+        if registry.subscribers.iter().any(|subscriber| subscriber.name == name) {
+            return Err(LogAnalyzerError::Config(format!("subscriber '{}' already exists", name)));
+        }
+        let (subscriber, rx) = Self::subscriber(name, subscription)?;
+        registry.subscribers.push(subscriber);
+        self.deserted.send_replace(false);
+        Ok(rx)
+    }
+
+    // swaps the subscriber in one step, so no record goes to neither of them. The old receiver
+    // still gets what was queued for it
+    pub(crate) fn replace(&self, name: &str, subscription: Subscription) -> Result<overflow::Receiver<ParsedLog>, LogAnalyzerError> {
+        let mut registry = self.registry.write().unwrap();
+        if registry.closed {
+            return Err(LogAnalyzerError::EngineNotRunning);
+        }
+        let (subscriber, rx) = Self::subscriber(name, subscription)?;
+        let old = match registry.subscribers.iter_mut().find(|old| old.name == name) {
+            Some(old) => Some(std::mem::replace(old, subscriber)),
+            None => {
+                registry.subscribers.push(subscriber);
+                self.deserted.send_replace(false);
+                None
+            }
+        };
+        drop(registry);
+        if let Some(old) = old {
+            old.tx.release();
+        }
+        Ok(rx)
+    }
+
+    pub(crate) fn remove(&self, name: &str) -> Option<SubscriberStats> {
+        let removed = {
+            let mut registry = self.registry.write().unwrap();
+            let index = registry.subscribers.iter().position(|subscriber| subscriber.name == name)?;
+            let removed = registry.subscribers.remove(index);
+            self.check_deserted(&registry);
+            removed
+        };
+        removed.tx.release();
+        Some(removed.stats())
+    }
+
+    fn remove_dead(&self, dead: &Arc<Subscriber>) {
+        {
+            let mut registry = self.registry.write().unwrap();
+            registry.subscribers.retain(|subscriber| !Arc::ptr_eq(subscriber, dead));
+            self.check_deserted(&registry);
+        }
+        dead.tx.release();
+    }
+
+    fn check_deserted(&self, registry: &Registry) {
+        if registry.subscribers.is_empty() && !registry.closed {
+            self.deserted.send_replace(true);
+        }
+    }
+
+    // true once the last subscriber was removed or dropped its receiver
+    pub(crate) fn deserted(&self) -> watch::Receiver<bool> {
+        self.deserted.subscribe()
+    }
+
+    // in the order they subscribed
+    pub(crate) fn stats(&self) -> Vec<SubscriberStats> {
+        let registry = self.registry.read().unwrap();
+        let ended = registry.ended.iter().map(|(stats, monitor)| SubscriberStats { overflow: monitor.stats(), ..stats.clone() });
+        registry.subscribers.iter().map(|subscriber| subscriber.stats()).chain(ended).collect()
+    }
+
+    pub(crate) fn undelivered(&self) -> u64 {
+        self.undelivered.load(Ordering::Relaxed)
+    }
+
+    // taken once per batch, subscribers attached meanwhile see the next one
+    pub(crate) fn snapshot(&self) -> Vec<Arc<Subscriber>> {
+        self.registry.read().unwrap().subscribers.clone()
+    }
+
+    // dropping the senders ends every subscriber's stream once it's drained
+    fn close(&self) {
+        let mut registry = self.registry.write().unwrap();
+        registry.closed = true;
+        let ended = registry.subscribers.drain(..).map(|subscriber| (subscriber.stats(), subscriber.tx.monitor())).collect::<Vec<_>>();
+        registry.ended = ended;
+    }
+
+    // hands the record to every subscriber whose filter matches, one after the other. `ack` is
+    // confirmed once all of them confirmed their copy. A subscriber that drops its receiver is
+    // removed, from then on what it didn't confirm counts as confirmed by it
+    pub(crate) async fn deliver(&self, subscribers: &[Arc<Subscriber>], parsed_log: ParsedLog, ack: Option<Ack>) {
+        let matching = subscribers.iter()
+            .filter(|subscriber| {
+                let matches = subscriber.filter.matches(&parsed_log);
+                if !matches {
+                    subscriber.filtered_out.fetch_add(1, Ordering::Relaxed);
+                }
+                matches
+            })
+            .collect::<Vec<_>>();
+        if matching.is_empty() && subscribers.is_empty() {
+            self.undelivered.fetch_add(1, Ordering::Relaxed);
+        }
+
+        let mut acks = match ack {
+            Some(ack) => ack.split(matching.len()).into_iter().map(Some).collect(),
+            None => (0..matching.len()).map(|_| None).collect::<Vec<_>>()
+        };
+        let mut parsed_log = Some(parsed_log);
+        for (index, subscriber) in matching.iter().enumerate() {
+            // the last one gets the record itself instead of a copy
+            let copy = if index + 1 == matching.len() { parsed_log.take() } else { parsed_log.clone() };
+            let Some(copy) = copy else { break };
+            match subscriber.tx.send_with_ack(copy, acks[index].take()).await {
+                Ok(()) => { subscriber.delivered.fetch_add(1, Ordering::Relaxed); }
+                Err(_) => self.remove_dead(subscriber)
+            }
+        }
+    }
+}
+
+// held by every source task, the subscribers' streams end when the last one is dropped
+pub(crate) struct Delivery(pub(crate) Arc<Subscribers>);
+
+impl Drop for Delivery {
+    fn drop(&mut self) {
+        self.0.close();
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Level;
+
+    #[tokio::test]
+    async fn each_subscriber_gets_what_its_filter_lets_through() {
+        let subscribers = Subscribers::default();
+        let mut all = subscribers.add("all", Subscription::new()).unwrap().with_manual_ack();
+        let errors = LogFilter::new().with_min_level(Level::Error);
+        let mut errors = subscribers.add("errors", Subscription::new().with_filter(errors)).unwrap().with_manual_ack();
+        assert!(subscribers.add("all", Subscription::new()).is_err());
+
+        let confirmed = Arc::new(AtomicU64::new(0));
+        let counter = confirmed.clone();
+        let ack = Ack::new(move || { counter.fetch_add(1, Ordering::Relaxed); });
+
+        let snapshot = subscribers.snapshot();
+        subscribers.deliver(&snapshot, ParsedLog::new("fine".to_string()).with_level(Level::Info), None).await;
+        subscribers.deliver(&snapshot, ParsedLog::new("broken".to_string()).with_level(Level::Error), Some(ack)).await;
+
+        assert_eq!(all.recv().await.map(|log| log.message), Some("fine".to_string()));
+        assert_eq!(all.recv().await.map(|log| log.message), Some("broken".to_string()));
+        assert_eq!(errors.recv().await.map(|log| log.message), Some("broken".to_string()));
+
+        // the source's ack waits for both copies
+        errors.ack();
+        assert_eq!(confirmed.load(Ordering::Relaxed), 0);
+        all.ack();
+        assert_eq!(confirmed.load(Ordering::Relaxed), 1);
+
+        assert!(subscribers.remove("errors").is_some());
+        let stats = subscribers.stats();
+        assert_eq!(stats.len(), 1);
+        assert_eq!((stats[0].name.as_str(), stats[0].delivered, stats[0].filtered_out), ("all", 2, 0));
+
+        // the end of the sources ends every stream
+        drop(snapshot);
+        drop(Delivery(Arc::new(subscribers)));
+        assert_eq!(all.recv().await, None);
+    }
+
+    #[tokio::test]
+    async fn a_subscriber_that_leaves_releases_its_acks() {
+        let subscribers = Subscribers::default();
+        let mut stays = subscribers.add("stays", Subscription::new()).unwrap().with_manual_ack();
+        let mut leaves = subscribers.add("leaves", Subscription::new()).unwrap().with_manual_ack();
+        let mut deserted = subscribers.deserted();
+
+        let confirmed = Arc::new(AtomicU64::new(0));
+        let counter = confirmed.clone();
+        let ack = Ack::new(move || { counter.fetch_add(1, Ordering::Relaxed); });
+        let snapshot = subscribers.snapshot();
+        subscribers.deliver(&snapshot, ParsedLog::new("one".to_string()), Some(ack)).await;
+
+        assert!(stays.recv().await.is_some());
+        stays.ack();
+        // received but never confirmed, the copy may still come back
+        assert!(leaves.recv().await.is_some());
+        drop(leaves);
+        assert_eq!(confirmed.load(Ordering::Relaxed), 0);
+
+        // the next record finds it gone, it doesn't hold the first one back anymore
+        subscribers.deliver(&snapshot, ParsedLog::new("two".to_string()), None).await;
+        assert_eq!(confirmed.load(Ordering::Relaxed), 1);
+        assert_eq!(subscribers.stats().len(), 1);
+        assert!(!*deserted.borrow_and_update());
+
+        subscribers.remove("stays");
+        assert!(*deserted.borrow_and_update());
+    }
+}
@@ -0,0 +1,153 @@
+use std::{fmt, path::PathBuf, time::Duration};
+
+use crate::error::ErrorClass;
+
+use super::SourceState;
+
+// a source that keeps failing to read is given up on after this many skipped errors in a row,
+// so a broken source can't spin forever
+const MAX_CONSECUTIVE_SKIPS: u32 = 1000;
+
+// what a source task does when `read_line` fails
+#[derive(Debug, Clone, PartialEq)]
+pub enum SupervisionPolicy {
+    // wait and read again after transient errors, doubling the wait up to `max_backoff`.
+    // Errors affecting a single record are skipped, fatal ones end the source
+    Retry {
+        // consecutive failed attempts before giving up, `None` retries forever
+        max_retries: Option<u32>,
+        initial_backoff: Duration,
+        max_backoff: Duration
+    },
+    // keep reading after every error that isn't fatal
+    Skip,
+    // any error ends the source and stops every other source of the engine
+    Fail
+}
+
+impl Default for SupervisionPolicy {
+    fn default() -> Self {
+        SupervisionPolicy::Retry {
+            max_retries: Some(5),
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(5)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorAction {
+    Retry { attempt: u32, delay: Duration },
+    Skip,
+    // the source ends as failed
+    StopSource,
+    // the source ends and takes the engine with it
+    StopEngine
+}
+
+impl SupervisionPolicy {
+    // `failures` counts the errors in a row, including this one
+    pub fn decide(&self, class: ErrorClass, failures: u32) -> ErrorAction {
+        match (self, class) {
+            (SupervisionPolicy::Fail, _) => ErrorAction::StopEngine,
+            (_, ErrorClass::Fatal) => ErrorAction::StopSource,
+            (_, _) if failures > MAX_CONSECUTIVE_SKIPS => ErrorAction::StopSource,
+            (SupervisionPolicy::Skip, _) => ErrorAction::Skip,
+            (SupervisionPolicy::Retry { .. }, ErrorClass::Record) => ErrorAction::Skip,
+            (SupervisionPolicy::Retry { max_retries, initial_backoff, max_backoff }, ErrorClass::Transient) => {
+                if max_retries.is_some_and(|max| failures > max) {
+                    return ErrorAction::StopSource;
+                }
+                let factor = 2u32.saturating_pow(failures.saturating_sub(1));
+                let delay = initial_backoff.saturating_mul(factor).min(*max_backoff);
+                ErrorAction::Retry { attempt: failures, delay }
+            }
+        }
+    }
+}
+
+// published on `Engine::subscribe_events`, nothing is lost silently anymore
+#[derive(Debug, Clone, PartialEq)]
+pub enum EngineEvent {
+    SourceError {
+        source: String,
+        error: String,
+        class: ErrorClass,
+        action: ErrorAction
+    },
+    // reading worked again after `failures` errors
+    SourceRecovered {
+        source: String,
+        failures: u32
+    },
+    SourceDone {
+        source: String,
+        state: SourceState
+    },
+    // a source with the `Fail` policy hit an error, every source is being stopped
+    EngineFailed {
+        source: String,
+        error: String
+    },
+    // the configuration file changed and was applied, `changes` says what that did
+    Reloaded {
+        path: PathBuf,
+        trigger: String,
+        changes: String
+    },
+    // the changed configuration was rejected or couldn't be applied, the previous one stays
+    ReloadFailed {
+        path: PathBuf,
+        error: String
+    }
+}
+
+impl fmt::Display for EngineEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EngineEvent::SourceError { source, error, action, .. } => {
+                write!(f, "source '{}': {}", source, error)?;
+                match action {
+                    ErrorAction::Retry { attempt, delay } => write!(f, ", retry {} in {:?}", attempt, delay),
+                    ErrorAction::Skip => write!(f, ", skipped"),
+                    ErrorAction::StopSource => write!(f, ", giving up"),
+                    ErrorAction::StopEngine => write!(f, ", stopping the engine")
+                }
+            }
+            EngineEvent::SourceRecovered { source, failures } => write!(f, "source '{}' recovered after {} error(s)", source, failures),
+            EngineEvent::SourceDone { source, state } => write!(f, "source '{}' is done ({:?})", source, state),
+            EngineEvent::EngineFailed { source, error } => write!(f, "engine failed because of source '{}': {}", source, error),
+            EngineEvent::Reloaded { path, trigger, changes } => write!(f, "reloaded {} ({}): {}", path.display(), trigger, changes),
+            // configuration problems come one per line
+            EngineEvent::ReloadFailed { path, error } => {
+                let separator = if error.contains('\n') { "\n" } else { " " };
+                write!(f, "reload of {} failed, the previous configuration stays active:{}{}", path.display(), separator, error)
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retry_backs_off_and_gives_up() {
+        let policy = SupervisionPolicy::Retry {
+            max_retries: Some(3),
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(250)
+        };
+
+        assert_eq!(policy.decide(ErrorClass::Transient, 1), ErrorAction::Retry { attempt: 1, delay: Duration::from_millis(100) });
+        assert_eq!(policy.decide(ErrorClass::Transient, 2), ErrorAction::Retry { attempt: 2, delay: Duration::from_millis(200) });
+        assert_eq!(policy.decide(ErrorClass::Transient, 3), ErrorAction::Retry { attempt: 3, delay: Duration::from_millis(250) });
+        assert_eq!(policy.decide(ErrorClass::Transient, 4), ErrorAction::StopSource);
+        assert_eq!(policy.decide(ErrorClass::Record, 1), ErrorAction::Skip);
+        assert_eq!(policy.decide(ErrorClass::Fatal, 1), ErrorAction::StopSource);
+
+        assert_eq!(SupervisionPolicy::Skip.decide(ErrorClass::Transient, 7), ErrorAction::Skip);
+        assert_eq!(SupervisionPolicy::Fail.decide(ErrorClass::Record, 1), ErrorAction::StopEngine);
+    }
+}
@@ -1,5 +1,18 @@
+use std::io::ErrorKind;
+
 use thiserror::Error;
 
+// how a failed read is treated by source supervision
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorClass {
+    // reading again, possibly after a pause, can work
+    Transient,
+    // only the record being read is affected
+    Record,
+    // the source can't continue
+    Fatal
+}
+
 #[derive(Debug, Error)]
 pub enum LogAnalyzerError { 
 
@@ -19,5 +32,128 @@ pub enum LogAnalyzerError {
     LogFromatInvalid(String),
 
     #[error("network error: {0}")]
-    NetworkError(String)
-}
\ No newline at end of file
+    NetworkError(String),
+
+    #[error("configuration error: {0}")]
+    Config(String),
+
+    #[error("encoding error: {0}")]
+    Encoding(String),
+
+    #[error("unknown source '{0}'")]
+    UnknownSource(String),
+
+    #[error("script error: {0}")]
+    Script(String),
+
+    #[error("unknown subscriber '{0}'")]
+    UnknownSubscriber(String),
+
+    #[error("{0} is closed")]
+    SinkClosed(String),
+
+    #[error("engine is not running or its output is closed")]
+    EngineNotRunning,
+
+    #[error("{parser} parser failed: {source}")]
+    ParseFailed {
+        parser: String,
+        #[source]
+        source: Box<dyn std::error::Error + Send + Sync>
+    }
+}
+
+impl LogAnalyzerError {
+    // short, stable identifier used when counting errors
+    pub fn kind(&self) -> &'static str {
+        match self {
+            LogAnalyzerError::Io(_) => "io",
+            LogAnalyzerError::Json(_) => "json",
+            LogAnalyzerError::ParserNotFound => "parser_not_found",
+            LogAnalyzerError::SourceNotInitialized => "source_not_initialized",
+            LogAnalyzerError::LogFromatInvalid(_) => "invalid_format",
+            LogAnalyzerError::NetworkError(_) => "network",
+            LogAnalyzerError::Config(_) => "config",
+            LogAnalyzerError::Encoding(_) => "encoding",
+            LogAnalyzerError::UnknownSource(_) => "unknown_source",
+            LogAnalyzerError::Script(_) => "script",
+            LogAnalyzerError::UnknownSubscriber(_) => "unknown_subscriber",
+            LogAnalyzerError::SinkClosed(_) => "sink_closed",
+            LogAnalyzerError::EngineNotRunning => "engine_not_running",
+            LogAnalyzerError::ParseFailed { source, .. } => error_kind(source.as_ref()),
+        }
+    }
+
+    pub fn class(&self) -> ErrorClass {
+        match self {
+            LogAnalyzerError::Io(e) => io_class(e),
+            LogAnalyzerError::NetworkError(_) => ErrorClass::Transient,
+            LogAnalyzerError::Json(_)
+            | LogAnalyzerError::LogFromatInvalid(_)
+            | LogAnalyzerError::Encoding(_)
+            | LogAnalyzerError::Script(_)
+            | LogAnalyzerError::ParseFailed { .. } => ErrorClass::Record,
+            _ => ErrorClass::Fatal
+        }
+    }
+
+    pub fn is_transient(&self) -> bool {
+        self.class() == ErrorClass::Transient
+    }
+}
+
+fn io_class(error: &std::io::Error) -> ErrorClass {
+    match error.kind() {
+        ErrorKind::Interrupted
+        | ErrorKind::WouldBlock
+        | ErrorKind::TimedOut
+        | ErrorKind::ConnectionReset
+        | ErrorKind::ConnectionAborted
+        | ErrorKind::ConnectionRefused
+        | ErrorKind::NotConnected
+        | ErrorKind::BrokenPipe
+        | ErrorKind::UnexpectedEof => ErrorClass::Transient,
+        ErrorKind::InvalidData => ErrorClass::Record,
+        _ => ErrorClass::Fatal
+    }
+}
+
+// errors after which a source's connection is gone, a retry has to open it again first
+pub fn is_disconnect(error: &(dyn std::error::Error + 'static)) -> bool {
+    let io = match error.downcast_ref::<LogAnalyzerError>() {
+        Some(LogAnalyzerError::NetworkError(_)) => return true,
+        Some(LogAnalyzerError::Io(e)) => Some(e),
+        Some(_) => None,
+        None => error.downcast_ref::<std::io::Error>()
+    };
+    io.is_some_and(|e| matches!(e.kind(),
+        ErrorKind::ConnectionReset
+        | ErrorKind::ConnectionAborted
+        | ErrorKind::ConnectionRefused
+        | ErrorKind::NotConnected
+        | ErrorKind::BrokenPipe
+        | ErrorKind::UnexpectedEof))
+}
+
+// kind of an arbitrary boxed error, "other" when it doesn't come from this crate
+pub fn error_kind(error: &(dyn std::error::Error + 'static)) -> &'static str {
+    match error.downcast_ref::<LogAnalyzerError>() {
+        Some(e) => e.kind(),
+        None if error.is::<serde_json::Error>() => "json",
+        None if error.is::<std::io::Error>() => "io",
+        None => "other"
+    }
+}
+
+// class of an arbitrary boxed error, errors from outside this crate are fatal unless they are
+// io errors
+pub fn error_class(error: &(dyn std::error::Error + 'static)) -> ErrorClass {
+    match error.downcast_ref::<LogAnalyzerError>() {
+        Some(e) => e.class(),
+        None => match error.downcast_ref::<std::io::Error>() {
+            Some(e) => io_class(e),
+            None if error.is::<serde_json::Error>() => ErrorClass::Record,
+            None => ErrorClass::Fatal
+        }
+    }
+}
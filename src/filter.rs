@@ -0,0 +1,72 @@
+use crate::parser::{Level, ParsedLog};
+
+// simple record predicate, all configured conditions have to match
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct LogFilter {
+    min_level: Option<Level>,
+    services: Vec<String>,
+    contains: Option<String>
+}
+
+impl LogFilter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // keep records at this level or more severe, records without a level are dropped
+    pub fn with_min_level(mut self, level: Level) -> Self {
+        self.min_level = Some(level);
+        self
+    }
+
+    // may be called several times, a record has to come from any of the services
+    pub fn with_service(mut self, service: impl Into<String>) -> Self {
+        self.services.push(service.into());
+        self
+    }
+
+    pub fn with_contains(mut self, needle: impl Into<String>) -> Self {
+        self.contains = Some(needle.into());
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self == &LogFilter::default()
+    }
+
+    pub fn matches(&self, log: &ParsedLog) -> bool {
+        if let Some(min_level) = &self.min_level {
+            let min = ParsedLog::default().with_level(min_level.clone()).severity_level();
+            if log.level.is_none() || log.severity_level() > min {
+                return false;
+            }
+        }
+
+        if !self.services.is_empty() && !log.service_name.as_ref().is_some_and(|s| self.services.contains(s)) {
+            return false;
+        }
+
+        match &self.contains {
+            Some(needle) => log.message.contains(needle.as_str()),
+            None => true
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn combines_conditions() {
+        let filter = LogFilter::new().with_min_level(Level::Warn).with_service("api").with_contains("timeout");
+
+        let log = ParsedLog::new("upstream timeout".to_string()).with_level(Level::Error).with_service("api");
+        assert!(filter.matches(&log));
+        assert!(!filter.matches(&log.clone().with_level(Level::Info)));
+        assert!(!filter.matches(&log.clone().with_service("web")));
+        assert!(!filter.matches(&ParsedLog::new("upstream timeout".to_string()).with_service("api")));
+        assert!(LogFilter::new().matches(&ParsedLog::default()));
+    }
+}
@@ -0,0 +1,63 @@
+use crate::error::LogAnalyzerError;
+use crate::parser::{Level, ParsedLog};
+
+pub mod json;
+pub mod plain_text;
+pub mod logfmt;
+pub mod cef;
+pub mod template;
+
+pub use cef::CEFFormatter;
+pub use json::JsonFormatter;
+pub use logfmt::LogfmtFormatter;
+pub use plain_text::PlainTextFormatter;
+pub use template::TemplateFormatter;
+
+// turns a parsed log into a single output line (without the trailing newline)
+pub trait LogFormatter : Send + Sync {
+    fn format(&self, log: &ParsedLog) -> String;
+}
+
+pub const FORMAT_NAMES: [&str; 5] = ["json", "text", "logfmt", "cef", "template"];
+
+// `template` is the only format that needs extra input, the template string itself
+pub fn from_name(name: &str, template: Option<&str>) -> Result<Box<dyn LogFormatter>, LogAnalyzerError> {
+    match name.to_lowercase().as_str() {
+        "json" | "ndjson" => Ok(Box::new(JsonFormatter)),
+        "text" | "plain" | "plain_text" => Ok(Box::new(PlainTextFormatter::new())),
+        "logfmt" => Ok(Box::new(LogfmtFormatter)),
+        "cef" => Ok(Box::new(CEFFormatter::default())),
+        "template" => {
+            let template = template.unwrap_or(TemplateFormatter::DEFAULT_TEMPLATE);
+            Ok(Box::new(TemplateFormatter::new(template)?))
+        }
+        other => Err(LogAnalyzerError::Config(format!(
+            "unknown output format '{}', expected one of {}", other, FORMAT_NAMES.join(", ")
+        )))
+    }
+}
+
+pub(crate) fn level_str(level: &Option<Level>) -> &'static str {
+    level.as_ref().map(Level::as_str).unwrap_or("-")
+}
+
+// scalar metadata as (dotted key, rendered value) pairs, nested objects are flattened
+pub(crate) fn flatten_metadata(metadata: &serde_json::Value) -> Vec<(String, String)> {
+    fn walk(prefix: &str, value: &serde_json::Value, out: &mut Vec<(String, String)>) {
+        match value {
+            serde_json::Value::Object(map) => {
+                for (key, value) in map {
+                    let key = if prefix.is_empty() { key.clone() } else { format!("{}.{}", prefix, key) };
+                    walk(&key, value, out);
+                }
+            }
+            serde_json::Value::String(s) => out.push((prefix.to_string(), s.clone())),
+            serde_json::Value::Null => {}
+            other => out.push((prefix.to_string(), other.to_string())),
+        }
+    }
+
+    let mut out = Vec::new();
+    walk("", metadata, &mut out);
+    out
+}
@@ -0,0 +1,156 @@
+use serde_json::Value;
+
+use crate::parser::{Level, ParsedLog};
+
+use super::{flatten_metadata, LogFormatter};
+
+// header fields written by the CEF / LEEF parsers, they are not repeated as extensions
+const HEADER_METADATA: [&str; 11] = [
+    "cef_version", "leef_version", "device_vendor", "device_product", "device_version", "signature_id",
+    "event_id", "name", "severity", "extensions", "syslog_header"
+];
+
+// Common Event Format, device fields fall back to the configured defaults when the record
+// didn't come from a CEF source
+pub struct CEFFormatter {
+    vendor: String,
+    product: String,
+    version: String
+}
+
+impl Default for CEFFormatter {
+    fn default() -> Self {
+        Self::new("loganalyzer", "loganalyzer", env!("CARGO_PKG_VERSION"))
+    }
+}
+
+impl CEFFormatter {
+    pub fn new(vendor: impl Into<String>, product: impl Into<String>, version: impl Into<String>) -> Self {
+        Self { vendor: vendor.into(), product: product.into(), version: version.into() }
+    }
+
+    fn severity(level: &Option<Level>) -> &'static str {
+        match level {
+            Some(Level::Trace) | Some(Level::Debug) => "1",
+            Some(Level::Info) => "3",
+            Some(Level::Warn) => "5",
+            Some(Level::Error) => "7",
+            Some(Level::Critical) => "9",
+            Some(Level::Fatal) => "10",
+            None => "Unknown",
+        }
+    }
+
+    fn escape_header(value: &str) -> String {
+        value.replace('\\', "\\\\").replace('|', "\\|")
+    }
+
+    fn escape_extension(value: &str) -> String {
+        value.replace('\\', "\\\\")
+            .replace('=', "\\=")
+            .replace('\n', "\\n")
+            .replace('\r', "\\r")
+    }
+
+    // extension keys are alphanumeric, anything else is replaced
+    fn extension_key(key: &str) -> String {
+        key.chars()
+            .map(|c| if c.is_ascii_alphanumeric() || c == '_' || c == '.' { c } else { '_' })
+            .collect()
+    }
+}
+
+impl LogFormatter for CEFFormatter {
+    fn format(&self, log: &ParsedLog) -> String {
+        let meta = |key: &str| log.metadata.get(key).and_then(Value::as_str);
+
+        let header = [
+            meta("device_vendor").unwrap_or(&self.vendor),
+            meta("device_product").or(log.service_name.as_deref()).unwrap_or(&self.product),
+            meta("device_version").or(log.version.as_deref()).unwrap_or(&self.version),
+            meta("signature_id").or(meta("event_id")).unwrap_or("log"),
+            meta("name").unwrap_or(&log.message),
+        ]
+        .map(CEFFormatter::escape_header)
+        .join("|");
+
+        let mut extensions = Vec::new();
+        if let Some(timestamp) = log.timestamp {
+            extensions.push(("rt".to_string(), timestamp.timestamp_millis().to_string()));
+        }
+        extensions.push(("msg".to_string(), log.message.clone()));
+        if let Some(host) = &log.host {
+            extensions.push(("dvchost".to_string(), host.clone()));
+        }
+
+        // extensions parsed from a CEF / LEEF record are written back as they were
+        if let Some(Value::Object(parsed)) = log.metadata.get("extensions") {
+            for (key, value) in parsed {
+                if !extensions.iter().any(|(existing, _)| existing == key) {
+                    let value = value.as_str().map(str::to_string).unwrap_or_else(|| value.to_string());
+                    extensions.push((key.clone(), value));
+                }
+            }
+        }
+
+        let other = flatten_metadata(&log.metadata).into_iter()
+            .filter(|(key, _)| !HEADER_METADATA.iter().any(|h| key == h || key.starts_with(&format!("{}.", h))));
+        extensions.extend(other);
+
+        let extensions = extensions.iter()
+            .map(|(key, value)| format!("{}={}", CEFFormatter::extension_key(key), CEFFormatter::escape_extension(value)))
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        format!("CEF:0|{}|{}|{}", header, CEFFormatter::severity(&log.level), extensions)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ingest::LogLine;
+    use crate::parser::{cef::CefParser, leef::LeefParser, LogParser};
+
+    #[test]
+    fn round_trips_through_the_cef_parser() {
+        let log = ParsedLog {
+            timestamp: Some(chrono::Utc::now()),
+            level: Some(Level::Error),
+            message: "login failed for user=bob | retry".to_string(),
+            metadata: serde_json::json!({ "src": "10.0.0.1" }),
+            service_name: Some("auth|svc".to_string()),
+            host: Some("web-1".to_string()),
+            ..ParsedLog::default()
+        };
+
+        let line = CEFFormatter::default().format(&log);
+        let parsed = CefParser::new().parse(&LogLine {
+            content: line,
+            source: "test".to_string(),
+            timestamp: chrono::Utc::now()
+        }).unwrap();
+
+        assert_eq!(parsed.message, log.message);
+        assert_eq!(parsed.level, Some(Level::Error));
+        assert_eq!(parsed.service_name.as_deref(), Some("auth|svc"));
+        assert_eq!(parsed.host.as_deref(), Some("web-1"));
+        assert_eq!(parsed.metadata["extensions"]["src"], "10.0.0.1");
+        assert_eq!(parsed.timestamp.map(|t| t.timestamp_millis()), log.timestamp.map(|t| t.timestamp_millis()));
+    }
+
+    #[test]
+    fn leef_header_fields_are_not_repeated() {
+        let parsed = LeefParser::new().parse(&LogLine {
+            content: "LEEF:2.0|Lancope|StealthWatch|1.0|41|^|src=10.0.0.1^dst=2.1.2.2".to_string(),
+            source: "test".to_string(),
+            timestamp: chrono::Utc::now()
+        }).unwrap();
+
+        let line = CEFFormatter::default().format(&parsed);
+        assert!(line.starts_with("CEF:0|Lancope|StealthWatch|1.0|41|"), "{}", line);
+        assert!(!line.contains("leef_version") && !line.contains("event_id"), "{}", line);
+        assert!(line.contains("src=10.0.0.1"), "{}", line);
+    }
+}
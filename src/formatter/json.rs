@@ -0,0 +1,12 @@
+use crate::parser::ParsedLog;
+
+use super::LogFormatter;
+
+// newline delimited JSON, one `ParsedLog::to_json` object per line
+pub struct JsonFormatter;
+
+impl LogFormatter for JsonFormatter {
+    fn format(&self, log: &ParsedLog) -> String {
+        log.to_json().to_string()
+    }
+}
@@ -0,0 +1,100 @@
+use crate::parser::ParsedLog;
+
+use super::{flatten_metadata, level_str, LogFormatter};
+
+// `key=value` pairs, values are quoted when they contain spaces, quotes or `=`
+pub struct LogfmtFormatter;
+
+impl LogfmtFormatter {
+    fn value(value: &str) -> String {
+        let needs_quotes = value.is_empty()
+            || value.chars().any(|c| c.is_whitespace() || c == '"' || c == '=' || c == '\\');
+
+        if !needs_quotes {
+            return value.to_string();
+        }
+
+        let mut quoted = String::with_capacity(value.len() + 2);
+        quoted.push('"');
+        for c in value.chars() {
+            match c {
+                '"' => quoted.push_str("\\\""),
+                '\\' => quoted.push_str("\\\\"),
+                '\n' => quoted.push_str("\\n"),
+                '\r' => quoted.push_str("\\r"),
+                '\t' => quoted.push_str("\\t"),
+                c => quoted.push(c),
+            }
+        }
+        quoted.push('"');
+        quoted
+    }
+
+    // logfmt keys can't contain spaces, quotes or `=`
+    fn key(key: &str) -> String {
+        key.chars()
+            .map(|c| if c.is_whitespace() || c == '"' || c == '=' { '_' } else { c })
+            .collect()
+    }
+}
+
+impl LogFormatter for LogfmtFormatter {
+    fn format(&self, log: &ParsedLog) -> String {
+        let mut pairs = Vec::new();
+
+        if let Some(timestamp) = log.timestamp {
+            pairs.push(("ts".to_string(), timestamp.to_rfc3339()));
+        }
+        if log.level.is_some() {
+            pairs.push(("level".to_string(), level_str(&log.level).to_string()));
+        }
+        pairs.push(("msg".to_string(), log.message.clone()));
+
+        let optional = [
+            ("service", &log.service_name),
+            ("trace_id", &log.trace_id),
+            ("span_id", &log.span_id),
+            ("host", &log.host),
+            ("env", &log.environment),
+            ("version", &log.version),
+        ];
+        for (key, value) in optional {
+            if let Some(value) = value {
+                pairs.push((key.to_string(), value.clone()));
+            }
+        }
+        if let Some(duration) = log.duration_ms {
+            pairs.push(("duration_ms".to_string(), duration.to_string()));
+        }
+
+        pairs.extend(flatten_metadata(&log.metadata));
+
+        pairs.iter()
+            .map(|(key, value)| format!("{}={}", LogfmtFormatter::key(key), LogfmtFormatter::value(value)))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Level;
+
+    #[test]
+    fn quotes_and_flattens() {
+        let log = ParsedLog {
+            level: Some(Level::Warn),
+            message: "disk \"/var\" almost full".to_string(),
+            metadata: serde_json::json!({ "disk": { "used": 91.5, "mount": "/var" }, "empty": "" }),
+            service_name: Some("storage".to_string()),
+            ..ParsedLog::default()
+        };
+
+        assert_eq!(
+            LogfmtFormatter.format(&log),
+            r#"level=warn msg="disk \"/var\" almost full" service=storage disk.mount=/var disk.used=91.5 empty="""#
+        );
+    }
+}
@@ -0,0 +1,77 @@
+use crate::parser::{Level, ParsedLog};
+
+use super::{flatten_metadata, level_str, LogFormatter};
+
+const RESET: &str = "\x1b[0m";
+const DIM: &str = "\x1b[2m";
+
+// human readable single line, optionally colored with ANSI escapes
+pub struct PlainTextFormatter {
+    color: bool
+}
+
+impl Default for PlainTextFormatter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PlainTextFormatter {
+    pub fn new() -> Self {
+        Self { color: true }
+    }
+
+    pub fn with_color(mut self, color: bool) -> Self {
+        self.color = color;
+        self
+    }
+
+    fn level_color(level: &Option<Level>) -> &'static str {
+        match level {
+            Some(Level::Fatal) | Some(Level::Critical) => "\x1b[1;31m",
+            Some(Level::Error) => "\x1b[31m",
+            Some(Level::Warn) => "\x1b[33m",
+            Some(Level::Info) => "\x1b[32m",
+            Some(Level::Debug) => "\x1b[34m",
+            Some(Level::Trace) | None => DIM,
+        }
+    }
+}
+
+impl LogFormatter for PlainTextFormatter {
+    fn format(&self, log: &ParsedLog) -> String {
+        let timestamp = log.timestamp
+            .map(|t| t.format("%Y-%m-%d %H:%M:%S%.3f").to_string())
+            .unwrap_or_else(|| "-".to_string());
+        let level = format!("{:<8}", level_str(&log.level).to_uppercase());
+
+        let mut line = if self.color {
+            format!("{}{}{} {}{}{}", DIM, timestamp, RESET, PlainTextFormatter::level_color(&log.level), level, RESET)
+        } else {
+            format!("{} {}", timestamp, level)
+        };
+
+        if let Some(service) = &log.service_name {
+            line.push_str(&format!(" [{}]", service));
+        }
+        line.push(' ');
+        line.push_str(&log.message);
+
+        let fields = flatten_metadata(&log.metadata);
+        if !fields.is_empty() {
+            let fields = fields.iter()
+                .map(|(key, value)| format!("{}={}", key, value))
+                .collect::<Vec<_>>()
+                .join(" ");
+
+            if self.color {
+                line.push_str(&format!(" {}{}{}", DIM, fields, RESET));
+            } else {
+                line.push(' ');
+                line.push_str(&fields);
+            }
+        }
+
+        line
+    }
+}
@@ -0,0 +1,142 @@
+use crate::error::LogAnalyzerError;
+use crate::parser::ParsedLog;
+
+use super::{level_str, LogFormatter};
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Literal(String),
+    Timestamp,
+    Level,
+    Message,
+    ServiceName,
+    TraceId,
+    SpanId,
+    DurationMs,
+    Host,
+    Environment,
+    Version,
+    // `{metadata.some.path}`
+    Metadata(Vec<String>)
+}
+
+// user supplied layout such as `{timestamp} [{level}] {service_name}: {message}`,
+// `{{` and `}}` produce literal braces and missing values render as `-`
+pub struct TemplateFormatter {
+    segments: Vec<Segment>
+}
+
+impl TemplateFormatter {
+    pub const DEFAULT_TEMPLATE: &'static str = "{timestamp} [{level}] {service_name}: {message}";
+
+    pub fn new(template: &str) -> Result<Self, LogAnalyzerError> {
+        let mut segments = Vec::new();
+        let mut literal = String::new();
+        let mut chars = template.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let mut name = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => name.push(c),
+                            None => return Err(LogAnalyzerError::Config(format!("unclosed placeholder '{{{}' in template", name)))
+                        }
+                    }
+
+                    if !literal.is_empty() {
+                        segments.push(Segment::Literal(std::mem::take(&mut literal)));
+                    }
+                    segments.push(TemplateFormatter::placeholder(name.trim())?);
+                }
+                '}' => return Err(LogAnalyzerError::Config("unmatched '}' in template, use '}}' for a literal brace".to_string())),
+                c => literal.push(c),
+            }
+        }
+
+        if !literal.is_empty() {
+            segments.push(Segment::Literal(literal));
+        }
+
+        Ok(Self { segments })
+    }
+
+    fn placeholder(name: &str) -> Result<Segment, LogAnalyzerError> {
+        let segment = match name {
+            "timestamp" => Segment::Timestamp,
+            "level" => Segment::Level,
+            "message" => Segment::Message,
+            "service_name" | "service" => Segment::ServiceName,
+            "trace_id" => Segment::TraceId,
+            "span_id" => Segment::SpanId,
+            "duration_ms" => Segment::DurationMs,
+            "host" => Segment::Host,
+            "environment" => Segment::Environment,
+            "version" => Segment::Version,
+            other => match other.strip_prefix("metadata.") {
+                Some(path) if !path.is_empty() => Segment::Metadata(path.split('.').map(str::to_string).collect()),
+                _ => return Err(LogAnalyzerError::Config(format!("unknown template placeholder '{{{}}}'", other)))
+            }
+        };
+        Ok(segment)
+    }
+}
+
+impl LogFormatter for TemplateFormatter {
+    fn format(&self, log: &ParsedLog) -> String {
+        let mut out = String::new();
+
+        for segment in &self.segments {
+            let value = match segment {
+                Segment::Literal(text) => Some(text.clone()),
+                Segment::Timestamp => log.timestamp.map(|t| t.to_rfc3339()),
+                Segment::Level => Some(level_str(&log.level).to_string()),
+                Segment::Message => Some(log.message.clone()),
+                Segment::ServiceName => log.service_name.clone(),
+                Segment::TraceId => log.trace_id.clone(),
+                Segment::SpanId => log.span_id.clone(),
+                Segment::DurationMs => log.duration_ms.map(|d| d.to_string()),
+                Segment::Host => log.host.clone(),
+                Segment::Environment => log.environment.clone(),
+                Segment::Version => log.version.clone(),
+                Segment::Metadata(path) => path.iter()
+                    .try_fold(&log.metadata, |value, key| value.get(key))
+                    .filter(|value| !value.is_null())
+                    .map(|value| value.as_str().map(str::to_string).unwrap_or_else(|| value.to_string())),
+            };
+            out.push_str(value.as_deref().unwrap_or("-"));
+        }
+
+        out
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Level;
+
+    #[test]
+    fn renders_fields_and_metadata() {
+        let formatter = TemplateFormatter::new("[{level}] {service_name}: {message} {{user={metadata.user.id}}} {host}").unwrap();
+        let log = ParsedLog::new("payment accepted".to_string())
+            .with_level(Level::Info)
+            .with_service("billing");
+        let log = ParsedLog { metadata: serde_json::json!({ "user": { "id": 42 } }), ..log };
+
+        assert_eq!(formatter.format(&log), "[info] billing: payment accepted {user=42} -");
+        assert!(TemplateFormatter::new("{nope}").is_err());
+        assert!(TemplateFormatter::new("{message").is_err());
+    }
+}
@@ -1,17 +1,48 @@
 use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
 use std::error::Error;
+use std::io;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt};
+
+use crate::engine::overflow::{Ack, OverflowMonitor};
 
 /* inner modules */
 pub mod file_source;
 pub mod network_source;
+pub mod listener_source;
+pub mod spooled_source;
 
-#[derive(Clone)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct LogLine {
     pub content: String,
     pub source: String,
     pub timestamp: chrono::DateTime<chrono::Utc>
 }
 
+// borrowed view of a line, this is what parsers work on so they can avoid copying the content
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LogLineRef<'a> {
+    pub content: &'a str,
+    pub source: &'a str,
+    pub timestamp: chrono::DateTime<chrono::Utc>
+}
+
+impl<'a> LogLineRef<'a> {
+    pub fn new(content: &'a str, source: &'a str, timestamp: chrono::DateTime<chrono::Utc>) -> Self {
+        Self { content, source, timestamp }
+    }
+
+    pub fn to_owned_line(&self) -> LogLine {
+        LogLine { content: self.content.to_string(), source: self.source.to_string(), timestamp: self.timestamp }
+    }
+}
+
+impl<'a> From<&'a LogLine> for LogLineRef<'a> {
+    fn from(log_line: &'a LogLine) -> Self {
+        Self { content: &log_line.content, source: &log_line.source, timestamp: log_line.timestamp }
+    }
+}
+
 #[async_trait]
 pub trait LogSource : Send + Sync {
     // some way to initlialise the log source
@@ -19,5 +50,43 @@ pub trait LogSource : Send + Sync {
 
     async fn read_line(&mut self) -> Result<Option<LogLine>, Box<dyn Error + Send + Sync>>;
 
+    // remember the position after the last line returned by `read_line`, called once everything
+    // read so far has been handed over. Sources that can't resume don't need it
+    async fn checkpoint(&mut self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        Ok(())
+    }
+
     async fn close(&mut self) -> Result<(), Box<dyn Error + Send + Sync>>;
-}
\ No newline at end of file
+
+    // counters of a queue the source buffers records in, asked for once after `init`
+    fn overflow_monitor(&self) -> Option<OverflowMonitor> {
+        None
+    }
+
+    // confirms the lines returned since the last call once the engine's consumer acknowledged
+    // them, for sources that keep lines until they were delivered
+    fn take_ack(&mut self) -> Option<Ack> {
+        None
+    }
+}
+
+// Moves what the reader has buffered, up to and including the next newline, into `line` and
+// returns how many bytes that was, 0 at the end of the input. Call it again until `line` ends with
+// a newline. Unlike `read_line` this is cancel safe: the only await is `fill_buf`, which loses
+// nothing when cancelled, and the bytes are in `line` as soon as it returns. Sources are read in a
+// `select!` with the batch timer and pause / stop requests, so they must not lose half a line
+pub(crate) async fn read_line_chunk<R: AsyncBufRead + Unpin>(reader: &mut R, line: &mut Vec<u8>) -> io::Result<usize> {
+    let available = reader.fill_buf().await?;
+    let used = match available.iter().position(|byte| *byte == b'\n') {
+        Some(newline) => newline + 1,
+        None => available.len()
+    };
+    line.extend_from_slice(&available[..used]);
+    reader.consume(used);
+    Ok(used)
+}
+
+// a line that isn't UTF-8 only costs that line
+pub(crate) fn line_text(line: Vec<u8>) -> io::Result<String> {
+    String::from_utf8(line).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
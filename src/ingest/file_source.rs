@@ -1,34 +1,110 @@
 use std::error::Error;
+use std::io::SeekFrom;
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 
-use tokio::io::AsyncBufReadExt;
+use tokio::io::AsyncSeekExt;
 use tokio::{fs::File, io::BufReader};
 
 use crate::error::LogAnalyzerError;
 
-use super::LogSource;
+use super::{line_text, read_line_chunk, LogSource};
 use super::LogLine;
 use async_trait;
 
+// a record starting with `{` may span several lines, but we give up waiting for the closing
+// brace after this many lines and hand over what we have
+const MAX_RECORD_LINES: usize = 1000;
+
 pub struct FileLogSource {
     path: PathBuf,
     reader: Option<BufReader<File>>,
-    buffer: String
+    buffer: String,
+    buffered_lines: usize,
+    // a line without its newline yet
+    partial: Vec<u8>,
+    follow: bool,
+    start_at_end: bool,
+    poll_interval: Duration,
+    name: Option<String>,
+    checkpoint_path: Option<PathBuf>,
+    // bytes read from the file so far, and the end of the last record handed out
+    position: u64,
+    record_end: u64
+}
+
+// what is stored in the checkpoint file
+#[derive(serde::Serialize, serde::Deserialize)]
+struct Checkpoint {
+    path: PathBuf,
+    offset: u64
 }
 
 impl FileLogSource {
-    pub fn new<P>(path: P) -> Self 
+    pub fn new<P>(path: P) -> Self
     where P: AsRef<Path> {
-        
-        Self 
+
+        Self
         { path: path.as_ref().to_owned(),
           reader: None,
-            buffer: String::new()
+            buffer: String::new(),
+            buffered_lines: 0,
+            partial: Vec::new(),
+            follow: false,
+            start_at_end: false,
+            poll_interval: Duration::from_millis(250),
+            name: None,
+            checkpoint_path: None,
+            position: 0,
+            record_end: 0
         }
     }
 
-    fn is_valid_json(content : &String) -> bool {
-        serde_json::from_str::<serde_json::Value>(&content).is_ok()
+    // keep the read position in this file so a restart resumes where the last run stopped,
+    // a file that got shorter than the stored position is read from the start again
+    pub fn with_checkpoint<P: AsRef<Path>>(mut self, checkpoint_path: P) -> Self {
+        self.checkpoint_path = Some(checkpoint_path.as_ref().to_owned());
+        self
+    }
+
+    async fn stored_offset(&self) -> Option<u64> {
+        let content = tokio::fs::read(self.checkpoint_path.as_ref()?).await.ok()?;
+        let checkpoint: Checkpoint = serde_json::from_slice(&content).ok()?;
+        (checkpoint.path == self.path).then_some(checkpoint.offset)
+    }
+
+    // reported as the source of every line instead of the path
+    pub fn with_name(mut self, name: impl Into<String>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    // keep waiting for new lines at the end of the file instead of finishing, like `tail -f`
+    pub fn with_follow(mut self, follow: bool) -> Self {
+        self.follow = follow;
+        self
+    }
+
+    // skip the existing content and only read what is appended after `init`
+    pub fn with_start_at_end(mut self, start_at_end: bool) -> Self {
+        self.start_at_end = start_at_end;
+        self
+    }
+
+    fn is_valid_json(content : &str) -> bool {
+        serde_json::from_str::<serde_json::Value>(content).is_ok()
+    }
+
+    fn take_record(&mut self) -> LogLine {
+        let content = std::mem::take(&mut self.buffer);
+        self.buffered_lines = 0;
+        self.record_end = self.position;
+
+        LogLine {
+            content : content.trim().to_string(),
+            source: self.name.clone().unwrap_or_else(|| self.path.to_string_lossy().to_string()),
+            timestamp: chrono::Utc::now()
+        }
     }
 }
 
@@ -37,47 +113,88 @@ impl LogSource for FileLogSource {
 
     async fn init(&mut self) -> Result<(), Box<dyn Error + Send + Sync>> {
 
-        let file = tokio::fs::File::open(&self.path).await
-            .map_err(|e| LogAnalyzerError::Io(e))?;
-
+        let mut file = tokio::fs::File::open(&self.path).await
+            .map_err(LogAnalyzerError::Io)?;
+
+        let len = file.metadata().await.map_err(LogAnalyzerError::Io)?.len();
+        let start = match self.stored_offset().await {
+            Some(offset) if offset <= len => offset,
+            _ if self.start_at_end => len,
+            _ => 0
+        };
+        if start > 0 {
+            file.seek(SeekFrom::Start(start)).await.map_err(LogAnalyzerError::Io)?;
+        }
+        self.position = start;
+        self.record_end = start;
 
         self.reader = Some(BufReader::new(file));
 
         Ok(())
     }
-    
+
     async fn read_line (&mut self) -> Result<Option<LogLine>, Box<dyn Error + Send + Sync>> {
 
+        loop {
+            let reader = self.reader.as_mut().ok_or(LogAnalyzerError::SourceNotInitialized)?;
+            let bytes_read = read_line_chunk(reader, &mut self.partial).await
+                .map_err(LogAnalyzerError::Io)?;
+            self.position += bytes_read as u64;
 
-        if let Some(reader) = &mut self.reader {
+            // the rest of the line is still to come
+            if bytes_read > 0 && !self.partial.ends_with(b"\n") {
+                continue;
+            }
 
-            loop {
-                let mut line = String::new();
-                let bytes_read =reader.read_line(&mut line).await
-                    .map_err(|e| LogAnalyzerError::Io(e))?;
+            if bytes_read == 0 {
+                // a partial line stays in `partial` until it is complete
+                if self.follow {
+                    tokio::time::sleep(self.poll_interval).await;
+                    continue;
+                }
 
-                if bytes_read == 0 {
+                // end of the file, whatever is buffered is the last record
+                let rest = line_text(std::mem::take(&mut self.partial)).map_err(LogAnalyzerError::Io)?;
+                self.buffer.push_str(&rest);
+                if self.buffer.trim().is_empty() {
                     return Ok(None);
                 }
+                return Ok(Some(self.take_record()));
+            }
 
-                self.buffer.push_str(&line);
-                if FileLogSource::is_valid_json(&self.buffer) {
-                    let content = std::mem::take(&mut self.buffer);
-                    return Ok(Some(LogLine {
-                        content : content.trim().to_string(),
-                        source: self.path.to_string_lossy().to_string(),
-                        timestamp: chrono::Utc::now()
-                    }));
-                }
+            let line = line_text(std::mem::take(&mut self.partial)).map_err(LogAnalyzerError::Io)?;
+            if self.buffer.is_empty() && line.trim().is_empty() {
+                continue;
             }
-        }
-        else {
-            Err(Box::new(LogAnalyzerError::SourceNotInitialized))
+
+            self.buffer.push_str(&line);
+            self.buffered_lines += 1;
+
+            // multi-line JSON, keep reading until the object is complete
+            let open_json = self.buffer.trim_start().starts_with('{') && !FileLogSource::is_valid_json(&self.buffer);
+            if open_json && self.buffered_lines < MAX_RECORD_LINES {
+                continue;
+            }
+
+            return Ok(Some(self.take_record()));
         }
     }
 
+    async fn checkpoint(&mut self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let Some(checkpoint_path) = &self.checkpoint_path else {
+            return Ok(());
+        };
+
+        // written next to the checkpoint and renamed, so a crash never leaves half a file
+        let checkpoint = Checkpoint { path: self.path.clone(), offset: self.record_end };
+        let tmp = checkpoint_path.with_extension("tmp");
+        tokio::fs::write(&tmp, serde_json::to_vec(&checkpoint).map_err(LogAnalyzerError::Json)?).await.map_err(LogAnalyzerError::Io)?;
+        tokio::fs::rename(&tmp, checkpoint_path).await.map_err(LogAnalyzerError::Io)?;
+        Ok(())
+    }
+
     async fn close(&mut self) -> Result<(), Box<dyn Error + Send + Sync>> {
         self.reader = None;
         Ok(())
     }
-}
\ No newline at end of file
+}
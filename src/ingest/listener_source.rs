@@ -0,0 +1,97 @@
+use std::error::Error;
+
+use async_trait::async_trait;
+use tokio::{io::{AsyncBufReadExt, BufReader}, net::TcpListener, task::JoinHandle};
+
+use super::{LogLine, LogSource};
+use crate::engine::overflow::{self, OverflowMonitor, StagePolicy};
+use crate::error::LogAnalyzerError;
+
+// accepts any number of TCP clients and reads newline delimited records from all of them,
+// the counterpart of `NetworkLogSource` which connects out to a single server
+pub struct ListenerLogSource {
+    address: String,
+    receiver: Option<overflow::Receiver<LogLine>>,
+    accept_task: Option<JoinHandle<()>>,
+    name: Option<String>,
+    overflow: StagePolicy
+}
+
+impl ListenerLogSource {
+    pub fn new(address : String) -> Self {
+        Self { address, receiver: None, accept_task: None, name: None, overflow: StagePolicy::default().with_capacity(1000) }
+    }
+
+    // lines from all clients wait here for the engine, by default clients are slowed down
+    // once 1000 are waiting
+    pub fn with_overflow(mut self, overflow : StagePolicy) -> Self {
+        self.overflow = overflow;
+        self
+    }
+
+    // reported as the source of every line instead of `tcp <peer>`, so all clients share one source
+    pub fn with_name(mut self, name : impl Into<String>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+}
+
+#[async_trait]
+impl LogSource for ListenerLogSource {
+
+    async fn init(&mut self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let listener = TcpListener::bind(&self.address)
+            .await
+            .map_err(|e| LogAnalyzerError::NetworkError(e.to_string()))?;
+
+        let stage = format!("listener {}", self.name.as_deref().unwrap_or(&self.address));
+        let (tx, rx) = overflow::channel(stage, self.overflow.clone()).map_err(LogAnalyzerError::Io)?;
+        let name = self.name.clone();
+
+        let accept_task = tokio::spawn(async move {
+            while let Ok((stream, peer)) = listener.accept().await {
+                let tx = tx.clone();
+                let source = name.clone().unwrap_or_else(|| format!("tcp {}", peer));
+
+                tokio::spawn(async move {
+                    let mut lines = BufReader::new(stream).lines();
+
+                    while let Ok(Some(line)) = lines.next_line().await {
+                        if line.trim().is_empty() {
+                            continue;
+                        }
+
+                        let log_line = LogLine { content: line.trim().to_string(), source: source.clone(), timestamp: chrono::Utc::now() };
+                        if tx.send(log_line).await.is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+
+        self.receiver = Some(rx);
+        self.accept_task = Some(accept_task);
+        Ok(())
+    }
+
+    // never finishes on its own while the listener is accepting, `close` stops it
+    async fn read_line(&mut self) -> Result<Option<LogLine>, Box<dyn Error + Send + Sync>> {
+        match &mut self.receiver {
+            Some(receiver) => Ok(receiver.recv().await),
+            None => Err(Box::new(LogAnalyzerError::SourceNotInitialized))
+        }
+    }
+
+    async fn close(&mut self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        if let Some(task) = self.accept_task.take() {
+            task.abort();
+        }
+        self.receiver = None;
+        Ok(())
+    }
+
+    fn overflow_monitor(&self) -> Option<OverflowMonitor> {
+        self.receiver.as_ref().map(overflow::Receiver::monitor)
+    }
+}
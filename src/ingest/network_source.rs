@@ -1,26 +1,40 @@
 use async_trait::async_trait;
-use tokio::{io::{AsyncBufReadExt, BufReader}, net::TcpStream};
+use tokio::{io::BufReader, net::TcpStream};
 
-use super::{LogSource, LogLine};
+use super::{line_text, read_line_chunk, LogSource, LogLine};
 use crate::error::LogAnalyzerError;
 use std::error::Error;
 
 pub struct NetworkLogSource {
     address: String,
     reader: Option<BufReader<TcpStream>>,
-    buffer: String
+    buffer: String,
+    // the line being read, kept here so a cancelled `read_line` doesn't lose it
+    line: Vec<u8>,
+    name: Option<String>
 }
 
 impl NetworkLogSource {
     pub fn new(address : String) -> Self {
         Self { address, 
             reader: None ,
-            buffer : String::new()
+            buffer : String::new(),
+            line: Vec::new(),
+            name: None
         }
     }
 
-    fn try_extracting_json(content: &String) -> Option<(String, String)> {
-        println!("Trying to extract JSON from: {}", content);
+    // reported as the source of every line instead of `network <address>`
+    pub fn with_name(mut self, name : impl Into<String>) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    fn source_name(&self) -> String {
+        self.name.clone().unwrap_or_else(|| format!("network {}", self.address))
+    }
+
+    fn try_extracting_json(content: &str) -> Option<(String, String)> {
         
         let mut depth = 0;
         let mut within_json_string = false;
@@ -43,13 +57,10 @@ impl NetworkLogSource {
             }
         }
 
-        if start_idx.is_none() {
-            println!("No JSON start found");
-            return None;
-        }
+        let start_idx = start_idx?;
 
         // start parsing from the opening brace
-        for (i, c) in normalized[start_idx.unwrap()..].chars().enumerate() {
+        for (i, c) in normalized[start_idx..].chars().enumerate() {
             current_json.push(c);
 
             if escape_char_next {
@@ -63,20 +74,15 @@ impl NetworkLogSource {
                 '{' if !within_json_string => depth += 1,
                 '}' if !within_json_string => {
                     depth -= 1;
-                    if depth == 0 {
-                        if serde_json::from_str::<serde_json::Value>(&current_json).is_ok() {
-                            let remainder = normalized[start_idx.unwrap() + i + 1..].to_string();
-                            println!("Valid JSON found: {}", current_json);
-                            println!("Remainder: {}", remainder);
-                            return Some((current_json, remainder));
-                        }
+                    if depth == 0 && serde_json::from_str::<serde_json::Value>(&current_json).is_ok() {
+                        let remainder = normalized[start_idx + i + 1..].to_string();
+                        return Some((current_json, remainder));
                     }
                 },
                 _ => {}
             }
         }
 
-        println!("No complete JSON found");
         None
 
     }
@@ -91,6 +97,8 @@ impl LogSource for NetworkLogSource {
             .await
             .map_err(|e| Box::new(LogAnalyzerError::NetworkError(e.to_string())))?;
 
+        // also a reconnect, the rest of a line from the old connection won't come
+        self.line.clear();
         self.reader = Some(BufReader::new(stream));
         Ok(())
     }
@@ -103,18 +111,18 @@ impl LogSource for NetworkLogSource {
             if !self.buffer.is_empty() {
                 if let Some((json, remainder)) = NetworkLogSource::try_extracting_json(&self.buffer) {
                     self.buffer = remainder;
-                    return Ok(Some(LogLine { content:json, source: format!("network {}", self.address), timestamp: chrono::Utc::now() }));
+                    return Ok(Some(LogLine { content:json, source: self.source_name(), timestamp: chrono::Utc::now() }));
                 }
             }
 
-            let mut line = String::new();
-            let bytes_read = reader.read_line(&mut line).await?;
+            while read_line_chunk(reader, &mut self.line).await? > 0 && !self.line.ends_with(b"\n") {}
+            let line = line_text(std::mem::take(&mut self.line))?;
 
-            if bytes_read == 0 {
+            if line.is_empty() {
                 // here we need to check if the buffer now contains any json
                 if !self.buffer.is_empty() {
                     let content = std::mem::take(&mut self.buffer);
-                    return Ok(Some(LogLine { content, source: format!("network {}", self.address), timestamp: chrono::Utc::now() }));
+                    return Ok(Some(LogLine { content, source: self.source_name(), timestamp: chrono::Utc::now() }));
                 }
                 return Ok(None);
             }
@@ -123,17 +131,16 @@ impl LogSource for NetworkLogSource {
             if let Some((json,remainder)) = NetworkLogSource::try_extracting_json(&self.buffer) {
                 // found a valid json , need to update the buffer
                 
-                println!("remainder: {}", remainder);
                 self.buffer = remainder;
 
-                return Ok(Some(LogLine { content: json, source: format!("network {}", self.address), timestamp: chrono::Utc::now() }));
+                return Ok(Some(LogLine { content: json, source: self.source_name(), timestamp: chrono::Utc::now() }));
             }
 
 
             // just a check here if the buffer content doesn't have any { or  } we can have it as a plain text
             if !line.contains('{') && !line.contains('}') {
                 self.buffer.clear();
-                return Ok(Some(LogLine { content: line.trim().to_string(), source: format!("network {}", self.address), timestamp: chrono::Utc::now() }));
+                return Ok(Some(LogLine { content: line.trim().to_string(), source: self.source_name(), timestamp: chrono::Utc::now() }));
             }
 
             Ok(None)
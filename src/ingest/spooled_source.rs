@@ -0,0 +1,182 @@
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use tokio::{sync::Notify, task::JoinHandle};
+
+use super::{LogLine, LogSource};
+use crate::engine::overflow::{Ack, OverflowMonitor};
+use crate::error::LogAnalyzerError;
+use crate::spool::{Spool, SpoolConfig, SpoolStats};
+
+type SourceError = Box<dyn Error + Send + Sync>;
+
+// Reads another source into a spool as fast as it can and hands out lines from the spool, so a
+// slow pipeline doesn't hold up the input and a crash doesn't lose what was read. Lines are only
+// removed from the spool once the engine's consumer acknowledged them, and whatever wasn't is read
+// again on the next start before anything new.
+pub struct SpooledSource {
+    source: Option<Box<dyn LogSource>>,
+    config: SpoolConfig,
+    spool: Option<Spool>,
+    ingest: Option<JoinHandle<Box<dyn LogSource>>>,
+    stop: Arc<Notify>,
+    // why the wrapped source stopped early, reported once the spool is drained
+    error: Arc<Mutex<Option<SourceError>>>,
+    // sequence numbers handed out since the last `take_ack`
+    unacked: Option<(u64, u64)>,
+    overflow: Option<OverflowMonitor>
+}
+
+impl SpooledSource {
+    pub fn new(source : Box<dyn LogSource>, config : SpoolConfig) -> Self {
+        Self { source: Some(source), config, spool: None, ingest: None, stop: Arc::new(Notify::new()), error: Arc::new(Mutex::new(None)), unacked: None, overflow: None }
+    }
+
+    // only after `init`
+    pub fn stats(&self) -> Option<SpoolStats> {
+        self.spool.as_ref().map(Spool::stats)
+    }
+}
+
+async fn ingest(mut source: Box<dyn LogSource>, spool: Spool, stop: Arc<Notify>, error: Arc<Mutex<Option<SourceError>>>) -> Box<dyn LogSource> {
+    // a line read from the source but not in the spool yet, the source's checkpoint must not move past it
+    let mut lost = false;
+
+    loop {
+        let log_line = tokio::select! {
+            log_line = source.read_line() => log_line,
+            _ = stop.notified() => break
+        };
+
+        match log_line {
+            Ok(Some(log_line)) => {
+                tokio::select! {
+                    appended = spool.append(&log_line) => if let Err(e) = appended {
+                        *error.lock().unwrap() = Some(Box::new(LogAnalyzerError::Io(e)));
+                        lost = true;
+                        break;
+                    },
+                    _ = stop.notified() => {
+                        lost = true;
+                        break;
+                    }
+                }
+            }
+            Ok(None) => break,
+            Err(e) => {
+                *error.lock().unwrap() = Some(e);
+                break;
+            }
+        }
+    }
+
+    let closing = spool.clone();
+    let _ = tokio::task::spawn_blocking(move || closing.close_input()).await;
+    // everything read is in the spool, the source doesn't have to deliver it again
+    if !lost {
+        let _ = source.checkpoint().await;
+    }
+    source
+}
+
+#[async_trait]
+impl LogSource for SpooledSource {
+
+    async fn init(&mut self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let mut source = self.source.take().ok_or(LogAnalyzerError::SourceNotInitialized)?;
+        source.init().await?;
+        self.overflow = source.overflow_monitor();
+        let spool = Spool::open(self.config.clone()).map_err(LogAnalyzerError::Io)?;
+
+        self.ingest = Some(tokio::spawn(ingest(source, spool.clone(), self.stop.clone(), self.error.clone())));
+        self.spool = Some(spool);
+        Ok(())
+    }
+
+    async fn read_line(&mut self) -> Result<Option<LogLine>, Box<dyn Error + Send + Sync>> {
+        let spool = self.spool.as_ref().ok_or(LogAnalyzerError::SourceNotInitialized)?;
+        match spool.read().await {
+            Some((seq, log_line)) => {
+                self.unacked = Some(self.unacked.map_or((seq, seq), |(first, _)| (first, seq)));
+                Ok(Some(log_line))
+            }
+            None => match self.error.lock().unwrap().take() {
+                Some(e) => Err(e),
+                None => Ok(None)
+            }
+        }
+    }
+
+    async fn checkpoint(&mut self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        match &self.spool {
+            Some(spool) => {
+                let spool = spool.clone();
+                let synced = tokio::task::spawn_blocking(move || spool.sync()).await.map_err(|e| LogAnalyzerError::Io(e.into()))?;
+                Ok(synced.map_err(LogAnalyzerError::Io)?)
+            }
+            None => Ok(())
+        }
+    }
+
+    async fn close(&mut self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        if let Some(ingest) = self.ingest.take() {
+            self.stop.notify_one();
+            if let Ok(mut source) = ingest.await {
+                source.close().await?;
+            }
+        }
+        Ok(())
+    }
+
+    fn overflow_monitor(&self) -> Option<OverflowMonitor> {
+        self.overflow.clone()
+    }
+
+    fn take_ack(&mut self) -> Option<Ack> {
+        let (first, last) = self.unacked.take()?;
+        let spool = self.spool.clone()?;
+        Some(Ack::new(move || spool.ack(first, last)))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{engine::Engine, ingest::file_source::FileLogSource, parser::registry::ParserRegistry};
+
+    async fn run(log: &std::path::Path, dir: &std::path::Path, acked: usize) -> Vec<String> {
+        let source = FileLogSource::new(log).with_checkpoint(dir.join("file.checkpoint"));
+        let mut engine = Engine::new(Box::new(ParserRegistry::builtin())).with_batch_size(1);
+        engine.add_named_source("app", Box::new(SpooledSource::new(Box::new(source), SpoolConfig::new(dir.join("spool")))));
+
+        let mut rx = engine.run().await.unwrap().with_manual_ack();
+        let mut messages = Vec::new();
+        while let Some(parsed_log) = rx.recv().await {
+            messages.push(parsed_log.message);
+            if messages.len() == acked {
+                rx.ack();
+            }
+        }
+        engine.join().await;
+        messages
+    }
+
+    #[tokio::test]
+    async fn records_are_delivered_again_until_acknowledged() {
+        let dir = std::env::temp_dir().join(format!("loganalyzer-spooled-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let log = dir.join("app.log");
+        std::fs::write(&log, "one\ntwo\nthree\nfour\nfive\n").unwrap();
+
+        // the consumer "crashes" after confirming two records
+        assert_eq!(run(&log, &dir, 2).await, vec!["one", "two", "three", "four", "five"]);
+        // the file is not read again, the spool still has what wasn't confirmed
+        assert_eq!(run(&log, &dir, 3).await, vec!["three", "four", "five"]);
+        assert!(run(&log, &dir, 0).await.is_empty());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
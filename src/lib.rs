@@ -0,0 +1,12 @@
+pub mod ingest;
+pub mod parser;
+pub mod engine;
+pub mod analytics;
+pub mod dead_letter;
+pub mod error;
+pub mod formatter;
+pub mod filter;
+pub mod transform;
+pub mod config;
+pub mod sink;
+pub mod spool;
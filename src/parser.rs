@@ -1,14 +1,21 @@
+use std::borrow::Cow;
 use std::error::Error;
-use std::any::Any;
 
-use crate::ingest::LogLine;
+use crate::ingest::{LogLine, LogLineRef};
 
 pub mod registry;
 pub mod plain_text;
 pub mod json;
+pub mod cef;
+pub mod leef;
+pub mod schema;
 
+pub use schema::SCHEMA_VERSION;
 
-#[derive(Debug,PartialEq, Clone)]
+
+// serialized with the versioned canonical schema described in `schema.rs`
+#[derive(Debug,PartialEq, Clone, serde::Deserialize)]
+#[serde(try_from = "schema::ParsedLogRecord")]
 pub struct ParsedLog {
     pub timestamp: Option<chrono::DateTime<chrono::Utc>>,
     pub level: Option<Level>,
@@ -23,7 +30,8 @@ pub struct ParsedLog {
     pub version: Option<String>,          // Application version
 }
 
-#[derive(Debug, PartialEq, Clone)]
+// serialized as its lowercase name, parsing also accepts the usual aliases (see `FromStr`)
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
 pub enum Level {
     Info,
     Error,
@@ -34,27 +42,40 @@ pub enum Level {
     Fatal      // For errors that cause the application to crash
 }
 
-#[async_trait::async_trait]
+// same fields as `ParsedLog` but string fields may borrow from the line that was parsed
+#[derive(Debug, PartialEq, Clone)]
+pub struct ParsedLogRef<'a> {
+    pub timestamp: Option<chrono::DateTime<chrono::Utc>>,
+    pub level: Option<Level>,
+    pub message: Cow<'a, str>,
+    pub metadata: serde_json::Value,
+    pub service_name: Option<Cow<'a, str>>,
+    pub trace_id: Option<Cow<'a, str>>,
+    pub span_id: Option<Cow<'a, str>>,
+    pub duration_ms: Option<f64>,
+    pub host: Option<Cow<'a, str>>,
+    pub environment: Option<Cow<'a, str>>,
+    pub version: Option<Cow<'a, str>>,
+}
+
+// parsing is pure CPU work, so parsers are synchronous and work on borrowed input
 pub trait LogParser : 'static + Send + Sync {
-    async fn parse(&self, log_line : LogLine) -> Result<ParsedLog, Box<dyn Error + Send + Sync>>;
-    fn as_any(&self) -> &dyn Any;
+    // unique name, used for pinning a parser and for reporting
+    fn name(&self) -> &'static str;
+
+    // confidence between 0.0 (not this format) and 1.0 (certainly this format), should be cheap
+    fn detect(&self, log_line : &LogLineRef<'_>) -> f32;
+
+    fn parse_ref<'a>(&self, log_line : &LogLineRef<'a>) -> Result<ParsedLogRef<'a>, Box<dyn Error + Send + Sync>>;
+
+    fn parse(&self, log_line : &LogLine) -> Result<ParsedLog, Box<dyn Error + Send + Sync>> {
+        self.parse_ref(&LogLineRef::from(log_line)).map(ParsedLogRef::into_owned)
+    }
 }
 
 impl ParsedLog {
     pub fn to_json(&self) -> serde_json::Value {
-        serde_json::json!({
-            "timestamp": self.timestamp.map(|t| t.to_rfc3339()),
-            "level": self.level.as_ref().map(|l| format!("{:?}", l)),
-            "message": self.message,
-            "metadata": self.metadata,
-            "service_name": self.service_name,
-            "trace_id": self.trace_id,
-            "span_id": self.span_id,
-            "duration_ms": self.duration_ms,
-            "host": self.host,
-            "environment": self.environment,
-            "version": self.version,
-        })
+        serde_json::to_value(self).expect("ParsedLog always serializes to JSON")
     }
 
     pub fn severity_level(&self) -> u8 {
@@ -89,6 +110,42 @@ impl Default for ParsedLog {
     }
 }
 
+impl Default for ParsedLogRef<'_> {
+    fn default() -> Self {
+        Self {
+            timestamp: None,
+            level: None,
+            message: Cow::Borrowed(""),
+            metadata: serde_json::Value::Object(serde_json::Map::new()),
+            service_name: None,
+            trace_id: None,
+            span_id: None,
+            duration_ms: None,
+            host: None,
+            environment: None,
+            version: None,
+        }
+    }
+}
+
+impl ParsedLogRef<'_> {
+    pub fn into_owned(self) -> ParsedLog {
+        ParsedLog {
+            timestamp: self.timestamp,
+            level: self.level,
+            message: self.message.into_owned(),
+            metadata: self.metadata,
+            service_name: self.service_name.map(Cow::into_owned),
+            trace_id: self.trace_id.map(Cow::into_owned),
+            span_id: self.span_id.map(Cow::into_owned),
+            duration_ms: self.duration_ms,
+            host: self.host.map(Cow::into_owned),
+            environment: self.environment.map(Cow::into_owned),
+            version: self.version.map(Cow::into_owned),
+        }
+    }
+}
+
 impl ParsedLog {
     pub fn new(message: String) -> Self {
         Self {
@@ -114,12 +171,3 @@ impl ParsedLog {
         self
     }
 }
-
-pub trait LogFormatter {
-    fn format(&self, log: &ParsedLog) -> String;
-}
-
-// Example implementations could be added for different formats:
-pub struct JsonFormatter;
-pub struct PlainTextFormatter;
-pub struct CEFFormatter;  // Common Event Format
\ No newline at end of file
@@ -0,0 +1,270 @@
+use std::{borrow::Cow, error::Error};
+
+use chrono::{DateTime, NaiveDateTime, TimeZone, Utc};
+use serde_json::{Map, Value};
+
+use crate::error::LogAnalyzerError;
+use crate::ingest::LogLineRef;
+
+use super::{Level, LogParser, ParsedLogRef};
+
+// CEF:Version|Device Vendor|Device Product|Device Version|Signature ID|Name|Severity|Extension
+const CEF_HEADER_FIELDS: usize = 7;
+
+pub struct CefParser;
+
+impl Default for CefParser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CefParser {
+    pub fn new() -> Self {
+        Self
+    }
+
+    // a CEF record may be prefixed by a syslog header, so we look for the marker anywhere in the line
+    pub fn is_cef(content: &str) -> bool {
+        content
+            .find("CEF:")
+            .map(|idx| split_header(&content[idx + 4..], CEF_HEADER_FIELDS).is_some())
+            .unwrap_or(false)
+    }
+
+    // CEF severity is either 0-10 or one of Low / Medium / High / Very-High
+    fn severity_level(severity: &str) -> Option<Level> {
+        match severity.trim().to_lowercase().as_str() {
+            "low" => Some(Level::Info),
+            "medium" => Some(Level::Warn),
+            "high" => Some(Level::Error),
+            "very-high" => Some(Level::Critical),
+            other => other.parse::<u8>().ok().and_then(severity_from_number),
+        }
+    }
+}
+
+// shared between CEF and LEEF, both use a 0-10 numeric severity scale
+pub(super) fn severity_from_number(severity: u8) -> Option<Level> {
+    match severity {
+        0..=3 => Some(Level::Info),
+        4..=6 => Some(Level::Warn),
+        7..=8 => Some(Level::Error),
+        9 => Some(Level::Critical),
+        10 => Some(Level::Fatal),
+        _ => None,
+    }
+}
+
+// splits `count - 1` pipe separated header fields (honouring `\|` and `\\`) and returns them
+// together with whatever follows the last pipe
+pub(super) fn split_header(content: &str, count: usize) -> Option<(Vec<String>, &str)> {
+    let mut fields = Vec::with_capacity(count);
+    let mut current = String::new();
+    let mut chars = content.char_indices();
+
+    while let Some((idx, c)) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some((_, escaped @ ('|' | '\\'))) => current.push(escaped),
+                Some((_, other)) => {
+                    current.push('\\');
+                    current.push(other);
+                }
+                None => current.push('\\'),
+            },
+            '|' => {
+                fields.push(std::mem::take(&mut current));
+                if fields.len() == count {
+                    return Some((fields, &content[idx + 1..]));
+                }
+            }
+            _ => current.push(c),
+        }
+    }
+
+    None
+}
+
+// parses `key=value key2=value with spaces` where `=`, `\` and newlines are escaped in values
+fn parse_extension(extension: &str) -> Map<String, Value> {
+    let bytes = extension.as_bytes();
+    let is_key_byte = |b: u8| b.is_ascii_alphanumeric() || matches!(b, b'_' | b'.' | b'-' | b'[' | b']');
+
+    // collect (key start, '=' position) for every unescaped '=' preceded by a complete key
+    let mut keys = Vec::new();
+    let mut escaped = false;
+    for (i, &b) in bytes.iter().enumerate() {
+        if escaped {
+            escaped = false;
+            continue;
+        }
+        match b {
+            b'\\' => escaped = true,
+            b'=' => {
+                let mut start = i;
+                while start > 0 && is_key_byte(bytes[start - 1]) {
+                    start -= 1;
+                }
+                if start < i && (start == 0 || bytes[start - 1] == b' ') {
+                    keys.push((start, i));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let mut map = Map::new();
+    for (n, &(start, eq)) in keys.iter().enumerate() {
+        let end = keys.get(n + 1).map(|&(next, _)| next).unwrap_or(bytes.len());
+        let key = &extension[start..eq];
+        let value = unescape_extension_value(extension[eq + 1..end].trim_end());
+        map.insert(key.to_string(), Value::String(value));
+    }
+
+    map
+}
+
+fn unescape_extension_value(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    let mut chars = value.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('=') => out.push('='),
+            Some('\\') => out.push('\\'),
+            Some('n') => out.push('\n'),
+            Some('r') => out.push('\r'),
+            Some(other) => {
+                out.push('\\');
+                out.push(other);
+            }
+            None => out.push('\\'),
+        }
+    }
+
+    out
+}
+
+// device timestamps are either epoch millis, RFC 3339 or the `MMM dd yyyy HH:mm:ss` family
+pub(super) fn parse_device_time(value: &str) -> Option<DateTime<Utc>> {
+    let value = value.trim();
+
+    if let Ok(millis) = value.parse::<i64>() {
+        return Utc.timestamp_millis_opt(millis).single();
+    }
+
+    if let Ok(dt) = DateTime::parse_from_rfc3339(value) {
+        return Some(dt.with_timezone(&Utc));
+    }
+
+    ["%b %d %Y %H:%M:%S%.f", "%b %d %Y %H:%M:%S"]
+        .iter()
+        .find_map(|fmt| NaiveDateTime::parse_from_str(value, fmt).ok())
+        .map(|naive| naive.and_utc())
+}
+
+impl LogParser for CefParser {
+    fn name(&self) -> &'static str {
+        "cef"
+    }
+
+    fn detect(&self, log_line: &LogLineRef<'_>) -> f32 {
+        let content = log_line.content.trim_start();
+        if !CefParser::is_cef(content) {
+            0.0
+        } else if content.starts_with("CEF:") {
+            1.0
+        } else {
+            0.95
+        }
+    }
+
+    fn parse_ref<'a>(&self, log_line: &LogLineRef<'a>) -> Result<ParsedLogRef<'a>, Box<dyn Error + Send + Sync>> {
+        let content = log_line.content.trim();
+        let start = content
+            .find("CEF:")
+            .ok_or_else(|| LogAnalyzerError::LogFromatInvalid("missing CEF: marker".to_string()))?;
+
+        let (header, extension) = split_header(&content[start + 4..], CEF_HEADER_FIELDS)
+            .ok_or_else(|| LogAnalyzerError::LogFromatInvalid("incomplete CEF header".to_string()))?;
+
+        let [cef_version, vendor, product, device_version, signature_id, name, severity] =
+            <[String; CEF_HEADER_FIELDS]>::try_from(header).expect("split_header returns exactly the requested fields");
+
+        let extensions = parse_extension(extension);
+
+        let timestamp = extensions.get("rt")
+            .and_then(|v| v.as_str())
+            .and_then(parse_device_time);
+
+        let message = extensions.get("msg")
+            .and_then(|v| v.as_str())
+            .unwrap_or(&name)
+            .to_string();
+
+        let host = extensions.get("dvchost")
+            .and_then(|v| v.as_str())
+            .map(|h| h.to_string());
+
+        let mut metadata = Map::new();
+        let syslog_header = content[..start].trim();
+        if !syslog_header.is_empty() {
+            metadata.insert("syslog_header".to_string(), Value::String(syslog_header.to_string()));
+        }
+        metadata.insert("cef_version".to_string(), Value::String(cef_version));
+        metadata.insert("device_vendor".to_string(), Value::String(vendor));
+        metadata.insert("device_product".to_string(), Value::String(product.clone()));
+        metadata.insert("device_version".to_string(), Value::String(device_version.clone()));
+        metadata.insert("signature_id".to_string(), Value::String(signature_id));
+        metadata.insert("name".to_string(), Value::String(name));
+        metadata.insert("severity".to_string(), Value::String(severity.clone()));
+        metadata.insert("extensions".to_string(), Value::Object(extensions));
+
+        Ok(ParsedLogRef {
+            timestamp,
+            level: CefParser::severity_level(&severity),
+            message: Cow::Owned(message),
+            metadata: Value::Object(metadata),
+            service_name: Some(Cow::Owned(product)),
+            host: host.map(Cow::Owned),
+            version: Some(Cow::Owned(device_version)),
+            ..ParsedLogRef::default()
+        })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ingest::LogLine;
+
+    #[test]
+    fn parse_cef_with_escapes() {
+        let parser = CefParser::new();
+
+        let content = r#"<134>Feb 12 10:10:10 fw01 CEF:0|Security|threat\|manager|1.0|100|worm successfully stopped|10|src=10.0.0.1 dst=2.1.2.2 msg=Detected a threat\=high\nNo action needed cs1Label=rule name cs1=block all rt=1739355010100"#;
+
+        let log_line = LogLine {
+            content: content.to_string(),
+            source: "test".to_string(),
+            timestamp: Utc::now()
+        };
+
+        let res = parser.parse(&log_line).unwrap();
+
+        assert_eq!(res.level, Some(Level::Fatal));
+        assert_eq!(res.message, "Detected a threat=high\nNo action needed");
+        assert_eq!(res.service_name.as_deref(), Some("threat|manager"));
+        assert_eq!(res.timestamp, Utc.timestamp_millis_opt(1739355010100).single());
+        assert_eq!(res.metadata["device_vendor"], "Security");
+        assert_eq!(res.metadata["syslog_header"], "<134>Feb 12 10:10:10 fw01");
+        assert_eq!(res.metadata["extensions"]["cs1"], "block all");
+        assert_eq!(res.metadata["extensions"]["dst"], "2.1.2.2");
+    }
+}
@@ -1,43 +1,98 @@
-use std::{any::Any, error::Error};
+use std::{borrow::Cow, error::Error};
 
-use serde_json::Value;
+use serde::{Deserialize, Deserializer};
+use serde_json::{Map, Value};
 
-use crate::ingest::LogLine;
+use crate::ingest::LogLineRef;
 use crate::error::LogAnalyzerError;
 
-use super::{Level, LogParser, ParsedLog};
+use super::{Level, LogParser, ParsedLogRef};
 
 pub struct JsonParser;
 
+impl Default for JsonParser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl JsonParser {
     pub fn new() -> Self {
         Self
     }
 
     fn level_parse(level_str : &str) -> Option<Level> {
-        match level_str.to_lowercase().as_str() {
-            "info" => Some(Level::Info),
-            "error" => Some(Level::Error),
-            "warn" | "warning" => Some(Level::Warn),
-            _ => None
-        }
+        level_str.parse().ok()
+    }
+
+    fn timestamp_parse(ts : &str) -> Option<chrono::DateTime<chrono::Utc>> {
+        chrono::DateTime::parse_from_rfc3339(ts).ok()
+            .map(|dt| dt.with_timezone(&chrono::Utc))
     }
 
-    
     fn normalize_json(content: &str) -> String {
         // Remove any leading/trailing whitespace and newlines
         content.trim()
-            .replace('\n', "")
-            .replace('\r', "")
+            .replace(['\n', '\r'], "")
             .replace("  ", " ")
     }
 }
 
-#[async_trait::async_trait]
+// the fields we lift out of a JSON record, borrowed straight from the input when they contain no escapes
+#[derive(Deserialize)]
+struct JsonRecord<'a> {
+    #[serde(borrow, default, deserialize_with = "borrowed_str")]
+    message: Option<Cow<'a, str>>,
+    #[serde(borrow, default, deserialize_with = "borrowed_str")]
+    level: Option<Cow<'a, str>>,
+    #[serde(borrow, default, deserialize_with = "borrowed_str")]
+    timestamp: Option<Cow<'a, str>>,
+    #[serde(flatten)]
+    metadata: Map<String, Value>
+}
+
+// serde only borrows a bare `Cow<str>`, inside an `Option` it would always allocate
+fn borrowed_str<'de, D>(deserializer: D) -> Result<Option<Cow<'de, str>>, D::Error>
+where D: Deserializer<'de> {
+    #[derive(Deserialize)]
+    struct Borrowed<'a>(#[serde(borrow)] Cow<'a, str>);
+
+    Option::<Borrowed<'de>>::deserialize(deserializer).map(|value| value.map(|b| b.0))
+}
+
 impl LogParser for JsonParser {
-    async fn parse(&self, log_line : LogLine) -> Result<ParsedLog, Box<dyn Error + Send + Sync>> {
+    fn name(&self) -> &'static str {
+        "json"
+    }
+
+    // we only look at the delimiters here, the actual parse happens once in `parse_ref`
+    fn detect(&self, log_line : &LogLineRef<'_>) -> f32 {
+        let content = log_line.content.trim();
+        match (content.starts_with('{'), content.ends_with('}')) {
+            (true, true) => 0.9,
+            (true, false) => 0.3,
+            _ => 0.0
+        }
+    }
+
+    fn parse_ref<'a>(&self, log_line : &LogLineRef<'a>) -> Result<ParsedLogRef<'a>, Box<dyn Error + Send + Sync>> {
+
+        // fast path, a single object whose well known fields are strings
+        if let Ok(record) = serde_json::from_str::<JsonRecord<'a>>(log_line.content) {
+            let timestamp = record.timestamp.as_deref()
+                                .and_then(JsonParser::timestamp_parse);
+
+            return Ok(ParsedLogRef {
+                timestamp,
+                level: record.level.as_deref().and_then(JsonParser::level_parse),
+                message: record.message.unwrap_or(Cow::Borrowed("")),
+                metadata: Value::Object(record.metadata),
+                ..ParsedLogRef::default()
+            });
+        }
+
         // Try to parse the normalized JSON string
-        let normalized = JsonParser::normalize_json(&log_line.content);
+        let normalized = JsonParser::normalize_json(log_line.content);
         let json_value = serde_json::from_str::<Value>(&normalized)
             .map_err(|e| LogAnalyzerError::LogFromatInvalid(e.to_string()))?;
 
@@ -54,9 +109,7 @@ impl LogParser for JsonParser {
 
         let timestamp = json_value.get("timestamp")
                             .and_then(|v| v.as_str())
-                            .and_then(|ts| chrono::DateTime::parse_from_rfc3339(ts).ok())
-                            .map(|dt| dt.with_timezone(&chrono::Utc))
-                            .or(Some(log_line.timestamp));
+                            .and_then(JsonParser::timestamp_parse);
 
 
 
@@ -69,30 +122,26 @@ impl LogParser for JsonParser {
             map.remove("timestamp");
         }
 
-        Ok(ParsedLog {
+        Ok(ParsedLogRef {
             timestamp,
             level,
-            message,
+            message: Cow::Owned(message),
             metadata,
-            ..ParsedLog::default()
+            ..ParsedLogRef::default()
         })
 
 
     }
-
-    fn as_any(&self) -> &dyn Any {
-        self
-    }
-    
 }
 
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::ingest::LogLine;
     
-    #[tokio::test]
-    async fn parse_valid_json() {
+    #[test]
+    fn parse_valid_json() {
         let parser = JsonParser::new();
 
         let json_content = r#"{
@@ -110,7 +159,7 @@ mod tests {
             timestamp : chrono::Utc::now()
         };
 
-        let res = parser.parse(log_line).await.unwrap();
+        let res = parser.parse(&log_line).unwrap();
 
         assert_eq!(res.message , "This is a test logging info".to_string());
         assert_eq!(res.level , Some(Level::Info));
@@ -125,4 +174,16 @@ mod tests {
 
 
     }
+
+    #[test]
+    fn message_borrows_from_input() {
+        let parser = JsonParser::new();
+        let content = r#"{"message": "no escapes here", "level": "warn", "service": "api"}"#;
+
+        let res = parser.parse_ref(&LogLineRef::new(content, "test", chrono::Utc::now())).unwrap();
+
+        assert!(matches!(res.message, Cow::Borrowed("no escapes here")));
+        assert_eq!(res.level, Some(Level::Warn));
+        assert_eq!(res.metadata["service"], "api");
+    }
 }
\ No newline at end of file
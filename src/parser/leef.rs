@@ -0,0 +1,179 @@
+use std::{borrow::Cow, error::Error};
+
+use serde_json::{Map, Value};
+
+use crate::error::LogAnalyzerError;
+use crate::ingest::LogLineRef;
+
+use super::cef::{parse_device_time, severity_from_number, split_header};
+use super::{LogParser, ParsedLogRef};
+
+// LEEF:1.0|Vendor|Product|Version|EventID|<tab separated attributes>
+// LEEF:2.0|Vendor|Product|Version|EventID|DelimiterCharacter|<attributes>
+const LEEF1_HEADER_FIELDS: usize = 5;
+const LEEF2_HEADER_FIELDS: usize = 6;
+
+pub struct LeefParser;
+
+impl Default for LeefParser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LeefParser {
+    pub fn new() -> Self {
+        Self
+    }
+
+    pub fn is_leef(content: &str) -> bool {
+        content
+            .find("LEEF:")
+            .map(|idx| split_header(&content[idx + 5..], LEEF1_HEADER_FIELDS).is_some())
+            .unwrap_or(false)
+    }
+
+    // the 2.0 delimiter is either a literal character or a hex code such as `x09` / `0x5E`
+    fn delimiter(spec: &str) -> Option<char> {
+        let spec = spec.trim();
+        let hex = spec.strip_prefix("0x")
+            .or_else(|| spec.strip_prefix("0X"))
+            .or_else(|| spec.strip_prefix('x'))
+            .or_else(|| spec.strip_prefix('X'));
+
+        match hex {
+            Some(code) if !code.is_empty() => u32::from_str_radix(code, 16).ok().and_then(char::from_u32),
+            _ => spec.chars().next(),
+        }
+    }
+
+    fn parse_attributes(attributes: &str, delimiter: char) -> Map<String, Value> {
+        attributes
+            .split(delimiter)
+            .filter_map(|pair| pair.split_once('='))
+            .filter(|(key, _)| !key.trim().is_empty())
+            .map(|(key, value)| (key.trim().to_string(), Value::String(value.to_string())))
+            .collect()
+    }
+}
+
+impl LogParser for LeefParser {
+    fn name(&self) -> &'static str {
+        "leef"
+    }
+
+    fn detect(&self, log_line: &LogLineRef<'_>) -> f32 {
+        let content = log_line.content.trim_start();
+        if !LeefParser::is_leef(content) {
+            0.0
+        } else if content.starts_with("LEEF:") {
+            1.0
+        } else {
+            0.95
+        }
+    }
+
+    fn parse_ref<'a>(&self, log_line: &LogLineRef<'a>) -> Result<ParsedLogRef<'a>, Box<dyn Error + Send + Sync>> {
+        let content = log_line.content.trim();
+        let start = content
+            .find("LEEF:")
+            .ok_or_else(|| LogAnalyzerError::LogFromatInvalid("missing LEEF: marker".to_string()))?;
+        let body = &content[start + 5..];
+
+        let (mut header, mut attributes) = split_header(body, LEEF1_HEADER_FIELDS)
+            .ok_or_else(|| LogAnalyzerError::LogFromatInvalid("incomplete LEEF header".to_string()))?;
+
+        let mut delimiter = '\t';
+        if header[0].starts_with('2') {
+            // LEEF 2.0 carries an extra (possibly empty) delimiter field
+            if let Some((header2, rest)) = split_header(body, LEEF2_HEADER_FIELDS) {
+                delimiter = LeefParser::delimiter(&header2[5]).unwrap_or('\t');
+                header = header2;
+                attributes = rest;
+            }
+        }
+
+        let leef_version = std::mem::take(&mut header[0]);
+        let vendor = std::mem::take(&mut header[1]);
+        let product = std::mem::take(&mut header[2]);
+        let device_version = std::mem::take(&mut header[3]);
+        let event_id = std::mem::take(&mut header[4]);
+
+        let attributes = LeefParser::parse_attributes(attributes, delimiter);
+
+        let level = attributes.get("sev")
+            .and_then(|v| v.as_str())
+            .and_then(|sev| sev.trim().parse::<u8>().ok())
+            .and_then(severity_from_number);
+
+        let timestamp = attributes.get("devTime")
+            .and_then(|v| v.as_str())
+            .and_then(parse_device_time);
+
+        let message = attributes.get("msg")
+            .and_then(|v| v.as_str())
+            .unwrap_or(&event_id)
+            .to_string();
+
+        let host = attributes.get("identHostName")
+            .and_then(|v| v.as_str())
+            .map(|h| h.to_string());
+
+        let mut metadata = Map::new();
+        let syslog_header = content[..start].trim();
+        if !syslog_header.is_empty() {
+            metadata.insert("syslog_header".to_string(), Value::String(syslog_header.to_string()));
+        }
+        metadata.insert("leef_version".to_string(), Value::String(leef_version));
+        metadata.insert("device_vendor".to_string(), Value::String(vendor));
+        metadata.insert("device_product".to_string(), Value::String(product.clone()));
+        metadata.insert("device_version".to_string(), Value::String(device_version.clone()));
+        metadata.insert("event_id".to_string(), Value::String(event_id));
+        metadata.insert("extensions".to_string(), Value::Object(attributes));
+
+        Ok(ParsedLogRef {
+            timestamp,
+            level,
+            message: Cow::Owned(message),
+            metadata: Value::Object(metadata),
+            service_name: Some(Cow::Owned(product)),
+            host: host.map(Cow::Owned),
+            version: Some(Cow::Owned(device_version)),
+            ..ParsedLogRef::default()
+        })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ingest::LogLine;
+    use crate::parser::Level;
+
+    #[test]
+    fn parse_leef_versions() {
+        let parser = LeefParser::new();
+
+        let leef1 = LogLine {
+            content: "LEEF:1.0|IBM|QRadar|7.5|Login Failed|src=10.0.0.1\tsev=7\tusrName=alice".to_string(),
+            source: "test".to_string(),
+            timestamp: chrono::Utc::now()
+        };
+        let res = parser.parse(&leef1).unwrap();
+        assert_eq!(res.level, Some(Level::Error));
+        assert_eq!(res.message, "Login Failed");
+        assert_eq!(res.metadata["extensions"]["usrName"], "alice");
+
+        let leef2 = LogLine {
+            content: "LEEF:2.0|Lancope|StealthWatch|1.0|41|^|src=10.0.1.8^dst=10.0.0.5^sev=5^msg=port scan".to_string(),
+            source: "test".to_string(),
+            timestamp: chrono::Utc::now()
+        };
+        let res = parser.parse(&leef2).unwrap();
+        assert_eq!(res.level, Some(Level::Warn));
+        assert_eq!(res.message, "port scan");
+        assert_eq!(res.metadata["device_vendor"], "Lancope");
+        assert_eq!(res.metadata["extensions"]["dst"], "10.0.0.5");
+    }
+}
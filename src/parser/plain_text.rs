@@ -1,10 +1,16 @@
-use super::{LogParser, ParsedLog};
-use crate::ingest::LogLine;
+use super::{LogParser, ParsedLogRef};
+use crate::ingest::LogLineRef;
+use std::borrow::Cow;
 use std::error::Error;
-use std::any::Any;
 
 pub struct PlainTextParser;
 
+impl Default for PlainTextParser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl PlainTextParser {
     pub fn new() -> Self {
         Self
@@ -12,20 +18,24 @@ impl PlainTextParser {
 }
 
 
-#[async_trait::async_trait]
 impl LogParser for PlainTextParser {
-    async fn parse(&self, log_line : LogLine) -> Result<ParsedLog, Box<dyn Error + Send + Sync>> {
+    fn name(&self) -> &'static str {
+        "plain_text"
+    }
 
-        Ok(ParsedLog {
-            timestamp : Some(chrono::Utc::now()),
+    // plain text accepts anything, so it should only win when nothing else matches
+    fn detect(&self, _log_line : &LogLineRef<'_>) -> f32 {
+        0.1
+    }
+
+    fn parse_ref<'a>(&self, log_line : &LogLineRef<'a>) -> Result<ParsedLogRef<'a>, Box<dyn Error + Send + Sync>> {
+
+        Ok(ParsedLogRef {
+            timestamp : None,
             level: None,
-            message: log_line.content,
+            message: Cow::Borrowed(log_line.content),
             metadata: serde_json::Value::Object(serde_json::Map::new()),
-            ..ParsedLog::default()
+            ..ParsedLogRef::default()
         })
     }
-
-    fn as_any(&self) -> &dyn Any {
-        self
-    }
 }
\ No newline at end of file
@@ -1,64 +1,267 @@
-use std::{any::Any, error::Error};
+use std::{collections::HashMap, error::Error, sync::{Arc, Mutex}};
 
-use crate::ingest::LogLine;
+use crate::ingest::LogLineRef;
 use crate::error::LogAnalyzerError;
 
-use super::{json::JsonParser, plain_text::PlainTextParser, LogParser, ParsedLog};
+use super::{cef::CefParser, json::JsonParser, leef::LeefParser, plain_text::PlainTextParser, LogParser, ParsedLogRef};
+
+// names of the parsers that ship with the crate, accepted by `ParserRegistry::from_names`
+pub const BUILTIN_PARSERS: [&str; 4] = ["plain_text", "json", "cef", "leef"];
+
+// number of consecutive successful parses by the same detected parser before a source is
+// considered to be of that format and we stop probing it
+const DEFAULT_STICKY_AFTER: usize = 5;
+
+#[derive(Debug, Clone, Copy)]
+struct SourceFormat {
+    parser: usize,
+    streak: usize,
+    sticky: bool
+}
 
 pub struct ParserRegistry {
-    parsers: Vec<Box<dyn LogParser>>
+    parsers: Vec<Box<dyn LogParser>>,
+    pinned: Option<usize>,
+    source_pins: HashMap<String, usize>,
+    sticky_after: usize,
+    // looked up once per line, the source's own lock is then held while its line is parsed
+    source_formats: Mutex<HashMap<String, Arc<Mutex<Option<SourceFormat>>>>>
+}
+
+impl Default for ParserRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl ParserRegistry {
     pub fn new() -> Self {
-        Self { parsers: Vec::new() }
+        Self { parsers: Vec::new(),
+            pinned: None,
+            source_pins: HashMap::new(),
+            sticky_after: DEFAULT_STICKY_AFTER,
+            source_formats: Mutex::new(HashMap::new())
+        }
+    }
+
+    // registry with the given built-in parsers, in that order
+    pub fn from_names<S: AsRef<str>>(names : &[S]) -> Result<Self, LogAnalyzerError> {
+        let mut registry = Self::new();
+        for name in names {
+            match name.as_ref() {
+                "plain_text" => registry.register(PlainTextParser::new()),
+                "json" => registry.register(JsonParser::new()),
+                "cef" => registry.register(CefParser::new()),
+                "leef" => registry.register(LeefParser::new()),
+                other => return Err(LogAnalyzerError::Config(format!(
+                    "unknown parser '{}', expected one of {}", other, BUILTIN_PARSERS.join(", ")
+                )))
+            }
+        }
+        Ok(registry)
+    }
+
+    pub fn builtin() -> Self {
+        Self::from_names(&BUILTIN_PARSERS).expect("built-in parser names are known")
     }
 
     pub fn register(&mut self, parser : impl LogParser) {
         self.parsers.push(Box::new(parser));
     }
 
+    pub fn parser_names(&self) -> Vec<&'static str> {
+        self.parsers.iter().map(|p| p.name()).collect()
+    }
+
+    // 0 disables the sticky format cache, every line is then probed
+    pub fn with_sticky_after(mut self, sticky_after : usize) -> Self {
+        self.sticky_after = sticky_after;
+        self
+    }
 
-    // this is basically trying to parse and check the logLine content to a json value
-    fn try_parse_json(content : &str) -> bool {
-        serde_json::from_str::<serde_json::Value>(content).is_ok()
+    // always use the named parser, skipping detection and the fallback chain
+    pub fn pin(&mut self, parser_name : &str) -> Result<(), LogAnalyzerError> {
+        self.pinned = Some(self.index_of(parser_name)?);
+        Ok(())
     }
 
-    fn select_parser(&self, log_line : &LogLine) -> Result<&Box<dyn LogParser>, LogAnalyzerError> {
+    // same as `pin` but only for lines coming from the given source
+    pub fn pin_source(&mut self, source : impl Into<String>, parser_name : &str) -> Result<(), LogAnalyzerError> {
+        let idx = self.index_of(parser_name)?;
+        self.source_pins.insert(source.into(), idx);
+        Ok(())
+    }
 
-        if ParserRegistry::try_parse_json(&log_line.content) {
-            println!("Parser selected : JsonParser");
-            self.parsers.iter().find(|p| p.as_any().is::<JsonParser>())
-            .ok_or(LogAnalyzerError::ParserNotFound)
+    // the format a source has settled on, if any
+    pub fn sticky_format(&self, source : &str) -> Option<&'static str> {
+        let format = self.source_formats.lock().unwrap().get(source)?.clone();
+        let format = *format.lock().unwrap();
+        format.filter(|format| format.sticky).map(|format| self.parsers[format.parser].name())
+    }
+
+    fn source_format(&self, source : &str) -> Arc<Mutex<Option<SourceFormat>>> {
+        let mut formats = self.source_formats.lock().unwrap();
+        match formats.get(source) {
+            Some(format) => format.clone(),
+            None => formats.entry(source.to_string()).or_default().clone()
         }
-        else {
-            println!("Parser selected : PlainTextParser");
-            self.parsers.iter().find(|p| p.as_any().is::<PlainTextParser>())
+    }
+
+    fn index_of(&self, parser_name : &str) -> Result<usize, LogAnalyzerError> {
+        self.parsers.iter()
+            .position(|p| p.name() == parser_name)
             .ok_or(LogAnalyzerError::ParserNotFound)
+    }
+
+    // parsers to try for the line, best candidate first
+    fn candidates(&self, log_line : &LogLineRef<'_>, format : Option<SourceFormat>) -> Vec<usize> {
+
+        if let Some(&idx) = self.source_pins.get(log_line.source).or(self.pinned.as_ref()) {
+            return vec![idx];
+        }
+
+        let sticky = format
+            .filter(|format| format.sticky)
+            .map(|format| format.parser);
+
+        if let Some(idx) = sticky {
+            // the remaining parsers are only scored if the sticky one fails
+            return vec![idx];
+        }
+
+        self.detect(log_line, None)
+    }
+
+    fn detect(&self, log_line : &LogLineRef<'_>, skip : Option<usize>) -> Vec<usize> {
+        let mut scored = self.parsers.iter()
+            .enumerate()
+            .filter(|(idx, _)| Some(*idx) != skip)
+            .map(|(idx, p)| (idx, p.detect(log_line)))
+            .filter(|(_, score)| *score > 0.0)
+            .collect::<Vec<_>>();
+
+        // stable sort keeps registration order for equal scores
+        scored.sort_by(|a, b| b.1.total_cmp(&a.1));
+        scored.into_iter().map(|(idx, _)| idx).collect()
+    }
+
+    fn record_success(&self, format : &mut Option<SourceFormat>, parser : usize, detected : usize) {
+        if self.sticky_after == 0 {
+            return;
+        }
+
+        // a fallback parser succeeding means detection was wrong for this line, start over
+        if parser != detected {
+            *format = None;
+            return;
+        }
+
+        let format = format.get_or_insert(SourceFormat { parser, streak: 0, sticky: false });
+
+        if format.parser != parser {
+            *format = SourceFormat { parser, streak: 0, sticky: false };
+        }
+
+        format.streak += 1;
+        if format.streak >= self.sticky_after {
+            format.sticky = true;
+        }
+    }
+
+    fn record_failure(&self, format : &mut Option<SourceFormat>, parser : usize) {
+        if format.is_some_and(|format| format.parser == parser) {
+            *format = None;
         }
     }
 }
 
 
-#[async_trait::async_trait]
 impl LogParser for ParserRegistry {
-    async fn parse(&self, log_line : LogLine) -> Result<ParsedLog, Box<dyn Error + Send + Sync>> {
-        let parser = self.select_parser(&log_line)?;
-        parser.parse(log_line).await
+    fn name(&self) -> &'static str {
+        "registry"
     }
 
-    fn as_any(&self) -> &dyn Any {
-        self
+    fn detect(&self, log_line : &LogLineRef<'_>) -> f32 {
+        self.parsers.iter()
+            .map(|p| p.detect(log_line))
+            .fold(0.0, f32::max)
+    }
+
+    fn parse_ref<'a>(&self, log_line : &LogLineRef<'a>) -> Result<ParsedLogRef<'a>, Box<dyn Error + Send + Sync>> {
+        let pinned = self.source_pins.contains_key(log_line.source) || self.pinned.is_some();
+        // pinned lines have no format to keep track of, the others hold their source's
+        let shared = (!pinned).then(|| self.source_format(log_line.source));
+        let mut guard = shared.as_ref().map(|format| format.lock().unwrap());
+        let mut candidates = self.candidates(log_line, guard.as_deref().copied().flatten());
+        let Some(&first) = candidates.first() else {
+            return Err(Box::new(LogAnalyzerError::ParserNotFound));
+        };
+
+        let mut last_error = None;
+        let mut attempt = 0;
+
+        while attempt < candidates.len() {
+            let idx = candidates[attempt];
+
+            match self.parsers[idx].parse_ref(log_line) {
+                Ok(parsed_log) => {
+                    if let Some(format) = guard.as_deref_mut() {
+                        self.record_success(format, idx, first);
+                    }
+                    return Ok(parsed_log);
+                }
+                Err(e) => {
+                    let e = LogAnalyzerError::ParseFailed { parser: self.parsers[idx].name().to_string(), source: e };
+                    if let Some(format) = guard.as_deref_mut() {
+                        self.record_failure(format, idx);
+
+                        // a sticky parser failing puts the source back into detection
+                        if attempt == 0 && candidates.len() == 1 {
+                            candidates.extend(self.detect(log_line, Some(idx)));
+                        }
+                    }
+                    last_error = Some(Box::new(e) as Box<dyn Error + Send + Sync>);
+                }
+            }
+
+            attempt += 1;
+        }
+
+        Err(last_error.unwrap_or_else(|| Box::new(LogAnalyzerError::ParserNotFound)))
     }
 }
 
 
 #[cfg(test)]
 mod tests {
-    use crate::ingest::{file_source::FileLogSource, LogSource};
+    use crate::ingest::{file_source::FileLogSource, LogLine, LogSource};
+    use crate::parser::{json::JsonParser, plain_text::PlainTextParser};
 
     use super::*;
 
+    // accepts `key=value` lines and fails on anything else
+    struct KeyValueParser;
+
+    impl LogParser for KeyValueParser {
+        fn name(&self) -> &'static str {
+            "key_value"
+        }
+
+        fn detect(&self, log_line : &LogLineRef<'_>) -> f32 {
+            if log_line.content.contains('=') { 0.8 } else { 0.0 }
+        }
+
+        fn parse_ref<'a>(&self, log_line : &LogLineRef<'a>) -> Result<ParsedLogRef<'a>, Box<dyn Error + Send + Sync>> {
+            let (_, message) = log_line.content.split_once("msg=")
+                .ok_or_else(|| LogAnalyzerError::LogFromatInvalid("no msg".to_string()))?;
+            Ok(ParsedLogRef { message: message.into(), ..ParsedLogRef::default() })
+        }
+    }
+
+    fn line(content : &str) -> LogLine {
+        LogLine { content: content.to_string(), source: "app.log".to_string(), timestamp: chrono::Utc::now() }
+    }
+
 
     #[tokio::test]
     async fn test_registry() {
@@ -67,15 +270,43 @@ mod tests {
         registry.register(JsonParser::new());
         registry.register(PlainTextParser::new());
 
-        
+
         let mut file = FileLogSource::new("./example.log");
         file.init().await.unwrap();
 
         while let Some(log_line) = file.read_line().await.unwrap() {
-            let res = registry.parse(log_line).await.unwrap();
+            let res = registry.parse(&log_line).unwrap();
 
             println!("The parsed log is: {:?}", res);
         }
     }
-    
-}
\ No newline at end of file
+
+    #[test]
+    fn test_detection_sticky_and_fallback() {
+
+        let mut registry = ParserRegistry::new().with_sticky_after(2);
+        registry.register(PlainTextParser::new());
+        registry.register(JsonParser::new());
+        registry.register(KeyValueParser);
+
+        // the custom parser wins detection over plain text
+        let res = registry.parse(&line("level=info msg=started")).unwrap();
+        assert_eq!(res.message, "started");
+        assert_eq!(registry.sticky_format("app.log"), None);
+
+        registry.parse(&line("level=info msg=ready")).unwrap();
+        assert_eq!(registry.sticky_format("app.log"), Some("key_value"));
+
+        // the sticky parser fails, the line falls back to plain text and the source is probed again
+        let res = registry.parse(&line("a line without any fields")).unwrap();
+        assert_eq!(res.message, "a line without any fields");
+        assert_eq!(registry.sticky_format("app.log"), None);
+
+        // pinning skips detection entirely
+        registry.pin_source("app.log", "plain_text").unwrap();
+        let res = registry.parse(&line("level=info msg=pinned")).unwrap();
+        assert_eq!(res.message, "level=info msg=pinned");
+        assert!(registry.pin("missing").is_err());
+    }
+
+}
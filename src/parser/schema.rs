@@ -0,0 +1,226 @@
+// Canonical record schema.
+//
+// Every `ParsedLog` is written as a map with these keys, in this order:
+//
+//   schema_version  u32, currently 1
+//   timestamp       RFC 3339 string in UTC, or null
+//   level           "trace" | "debug" | "info" | "warn" | "error" | "critical" | "fatal", or null
+//   message         string
+//   metadata        any JSON value, an object for every built-in parser
+//   service_name, trace_id, span_id
+//                   string or null
+//   duration_ms     number or null
+//   host, environment, version
+//                   string or null
+//
+// Readers accept records without `schema_version` (the pre-versioning `to_json` output, whose
+// levels were capitalised) as version 1, and reject versions newer than they know about.
+// The same schema is used for JSON and for the MessagePack encoding used when spooling.
+
+use std::{fmt, str::FromStr};
+
+use serde::{ser::SerializeStruct, Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::error::LogAnalyzerError;
+
+use super::{Level, ParsedLog};
+
+pub const SCHEMA_VERSION: u32 = 1;
+
+impl Level {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Level::Info => "info",
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Debug => "debug",
+            Level::Trace => "trace",
+            Level::Critical => "critical",
+            Level::Fatal => "fatal",
+        }
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+// case insensitive, with the aliases used by common logging libraries and syslog
+impl FromStr for Level {
+    type Err = LogAnalyzerError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "info" | "information" | "informational" | "notice" | "inf" | "i" => Ok(Level::Info),
+            "warn" | "warning" | "wrn" | "w" => Ok(Level::Warn),
+            "error" | "err" | "eror" | "e" => Ok(Level::Error),
+            "debug" | "dbg" | "d" => Ok(Level::Debug),
+            "trace" | "trc" | "verbose" | "t" => Ok(Level::Trace),
+            "critical" | "crit" | "alert" | "c" => Ok(Level::Critical),
+            "fatal" | "panic" | "emerg" | "emergency" | "f" => Ok(Level::Fatal),
+            other => Err(LogAnalyzerError::LogFromatInvalid(format!("unknown log level '{}'", other)))
+        }
+    }
+}
+
+impl Serialize for Level {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for Level {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let level = <std::borrow::Cow<'de, str>>::deserialize(deserializer)?;
+        level.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+impl Serialize for ParsedLog {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut record = serializer.serialize_struct("ParsedLog", 12)?;
+        record.serialize_field("schema_version", &SCHEMA_VERSION)?;
+        record.serialize_field("timestamp", &self.timestamp)?;
+        record.serialize_field("level", &self.level)?;
+        record.serialize_field("message", &self.message)?;
+        record.serialize_field("metadata", &self.metadata)?;
+        record.serialize_field("service_name", &self.service_name)?;
+        record.serialize_field("trace_id", &self.trace_id)?;
+        record.serialize_field("span_id", &self.span_id)?;
+        record.serialize_field("duration_ms", &self.duration_ms)?;
+        record.serialize_field("host", &self.host)?;
+        record.serialize_field("environment", &self.environment)?;
+        record.serialize_field("version", &self.version)?;
+        record.end()
+    }
+}
+
+// what is read off the wire before the version check
+#[derive(Deserialize)]
+pub struct ParsedLogRecord {
+    #[serde(default = "default_schema_version")]
+    schema_version: u32,
+    #[serde(default)]
+    timestamp: Option<chrono::DateTime<chrono::Utc>>,
+    #[serde(default)]
+    level: Option<Level>,
+    #[serde(default)]
+    message: String,
+    #[serde(default = "empty_metadata")]
+    metadata: serde_json::Value,
+    #[serde(default)]
+    service_name: Option<String>,
+    #[serde(default)]
+    trace_id: Option<String>,
+    #[serde(default)]
+    span_id: Option<String>,
+    #[serde(default)]
+    duration_ms: Option<f64>,
+    #[serde(default)]
+    host: Option<String>,
+    #[serde(default)]
+    environment: Option<String>,
+    #[serde(default)]
+    version: Option<String>,
+}
+
+fn default_schema_version() -> u32 {
+    1
+}
+
+fn empty_metadata() -> serde_json::Value {
+    serde_json::Value::Object(serde_json::Map::new())
+}
+
+impl TryFrom<ParsedLogRecord> for ParsedLog {
+    type Error = LogAnalyzerError;
+
+    fn try_from(record: ParsedLogRecord) -> Result<Self, Self::Error> {
+        if record.schema_version == 0 || record.schema_version > SCHEMA_VERSION {
+            return Err(LogAnalyzerError::Encoding(format!(
+                "unsupported schema_version {}, this build reads up to {}", record.schema_version, SCHEMA_VERSION
+            )));
+        }
+
+        Ok(ParsedLog {
+            timestamp: record.timestamp,
+            level: record.level,
+            message: record.message,
+            metadata: if record.metadata.is_null() { empty_metadata() } else { record.metadata },
+            service_name: record.service_name,
+            trace_id: record.trace_id,
+            span_id: record.span_id,
+            duration_ms: record.duration_ms,
+            host: record.host,
+            environment: record.environment,
+            version: record.version,
+        })
+    }
+}
+
+impl ParsedLog {
+    pub fn from_json(value: serde_json::Value) -> Result<Self, LogAnalyzerError> {
+        Ok(serde_json::from_value(value)?)
+    }
+
+    // compact binary form of the canonical schema, meant for spooling records between processes
+    pub fn to_msgpack(&self) -> Result<Vec<u8>, LogAnalyzerError> {
+        rmp_serde::to_vec_named(self).map_err(|e| LogAnalyzerError::Encoding(e.to_string()))
+    }
+
+    pub fn from_msgpack(bytes: &[u8]) -> Result<Self, LogAnalyzerError> {
+        rmp_serde::from_slice(bytes).map_err(|e| LogAnalyzerError::Encoding(e.to_string()))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> ParsedLog {
+        ParsedLog::new("order created".to_string())
+            .with_level(Level::Warn)
+            .with_service("orders")
+            .with_trace_context("trace-1", "span-1")
+    }
+
+    #[test]
+    fn round_trips_json_and_msgpack() {
+        let mut log = sample();
+        log.metadata = serde_json::json!({ "order_id": 17, "tags": ["a", "b"], "nested": { "ok": true } });
+        log.duration_ms = Some(12.5);
+
+        let json = log.to_json();
+        assert_eq!(json["schema_version"], SCHEMA_VERSION);
+        assert_eq!(json["level"], "warn");
+        assert_eq!(ParsedLog::from_json(json).unwrap(), log);
+
+        let bytes = log.to_msgpack().unwrap();
+        assert_eq!(ParsedLog::from_msgpack(&bytes).unwrap(), log);
+
+        // the keys come out in the order the schema lists them
+        let text = serde_json::to_string(&log).unwrap();
+        let keys = ["schema_version", "timestamp", "level", "message", "metadata", "service_name", "trace_id", "span_id", "duration_ms", "host", "environment", "version"];
+        let positions = keys.iter().map(|key| text.find(&format!("\"{}\":", key)).unwrap()).collect::<Vec<_>>();
+        assert!(positions.windows(2).all(|pair| pair[0] < pair[1]), "{}", text);
+    }
+
+    #[test]
+    fn reads_legacy_records_and_rejects_newer_versions() {
+        let legacy = serde_json::json!({ "level": "Info", "message": "hello", "metadata": null });
+        let log = ParsedLog::from_json(legacy).unwrap();
+        assert_eq!(log.level, Some(Level::Info));
+        assert!(log.metadata.is_object());
+
+        let future = serde_json::json!({ "schema_version": SCHEMA_VERSION + 1, "message": "hello" });
+        assert!(ParsedLog::from_json(future).is_err());
+
+        assert_eq!("WARNING".parse::<Level>().unwrap(), Level::Warn);
+        assert_eq!("emerg".parse::<Level>().unwrap(), Level::Fatal);
+        assert_eq!(Level::Critical.to_string(), "critical");
+        assert!("loud".parse::<Level>().is_err());
+    }
+}
@@ -0,0 +1,89 @@
+use std::{error::Error, io::Write, path::{Path, PathBuf}};
+
+use async_trait::async_trait;
+use tokio::io::AsyncWriteExt;
+
+use crate::{error::LogAnalyzerError, formatter::LogFormatter, parser::ParsedLog};
+
+// final destination of parsed records, one formatted line per record
+#[async_trait]
+pub trait LogSink : Send {
+    fn name(&self) -> &str;
+
+    async fn write(&mut self, log: &ParsedLog) -> Result<(), Box<dyn Error + Send + Sync>>;
+
+    async fn flush(&mut self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        Ok(())
+    }
+}
+
+pub struct StdoutSink {
+    name: String,
+    formatter: Box<dyn LogFormatter>
+}
+
+impl StdoutSink {
+    pub fn new(name: impl Into<String>, formatter: Box<dyn LogFormatter>) -> Self {
+        Self { name: name.into(), formatter }
+    }
+}
+
+#[async_trait]
+impl LogSink for StdoutSink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn write(&mut self, log: &ParsedLog) -> Result<(), Box<dyn Error + Send + Sync>> {
+        writeln!(std::io::stdout().lock(), "{}", self.formatter.format(log)).map_err(LogAnalyzerError::Io)?;
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        std::io::stdout().flush().map_err(LogAnalyzerError::Io)?;
+        Ok(())
+    }
+}
+
+// appends to the file, which is created on the first write
+pub struct FileSink {
+    name: String,
+    path: PathBuf,
+    formatter: Box<dyn LogFormatter>,
+    writer: Option<tokio::io::BufWriter<tokio::fs::File>>
+}
+
+impl FileSink {
+    pub fn new<P: AsRef<Path>>(name: impl Into<String>, path: P, formatter: Box<dyn LogFormatter>) -> Self {
+        Self { name: name.into(), path: path.as_ref().to_owned(), formatter, writer: None }
+    }
+}
+
+#[async_trait]
+impl LogSink for FileSink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn write(&mut self, log: &ParsedLog) -> Result<(), Box<dyn Error + Send + Sync>> {
+        if self.writer.is_none() {
+            let file = tokio::fs::OpenOptions::new().create(true).append(true).open(&self.path).await
+                .map_err(LogAnalyzerError::Io)?;
+            self.writer = Some(tokio::io::BufWriter::new(file));
+        }
+
+        let mut line = self.formatter.format(log);
+        line.push('\n');
+        if let Some(writer) = &mut self.writer {
+            writer.write_all(line.as_bytes()).await.map_err(LogAnalyzerError::Io)?;
+        }
+        Ok(())
+    }
+
+    async fn flush(&mut self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        if let Some(writer) = &mut self.writer {
+            writer.flush().await.map_err(LogAnalyzerError::Io)?;
+        }
+        Ok(())
+    }
+}
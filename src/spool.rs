@@ -0,0 +1,526 @@
+// Durable queue of log lines between ingestion and processing.
+//
+// Lines are appended to segment files in one directory, one JSON document per line, each file
+// named after the sequence number of its first line. The reader takes lines in order and
+// acknowledges ranges of sequence numbers once they were delivered, in any order. Everything
+// below the first unacknowledged line is recorded in `ack` next to the segments, and segments
+// holding only acknowledged lines are deleted. Acknowledging only updates memory, the next append,
+// read or sync writes it out. `append` and `read` do their file IO on tokio's blocking threads,
+// `sync` and `close_input` leave that to the caller. After a crash or restart every line that
+// wasn't acknowledged is read again, so delivery is at least once. A line torn by a crash while it
+// was written is cut off on recovery.
+
+use std::{collections::{BTreeMap, VecDeque}, fs::{File, OpenOptions}, io::{BufRead, BufReader, Write}, path::{Path, PathBuf}, sync::{Arc, Mutex}, time::{Duration, Instant}};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
+
+use crate::ingest::LogLine;
+
+const SEGMENT_EXTENSION: &str = "seg";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsyncPolicy {
+    // after every line, nothing acknowledged by `append` is lost
+    Always,
+    // at most once per interval, checked when appending. A crash loses at most that much
+    Interval(Duration),
+    // left to the operating system, only a process crash is survived
+    Never
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpoolConfig {
+    pub dir: PathBuf,
+    pub fsync: FsyncPolicy,
+    // a new segment is started once the current one is this big
+    pub segment_bytes: u64,
+    // appending waits while the unacknowledged lines take this much, at least `segment_bytes`
+    // to make sense
+    pub max_bytes: Option<u64>
+}
+
+impl SpoolConfig {
+    pub fn new<P: AsRef<Path>>(dir: P) -> Self {
+        Self {
+            dir: dir.as_ref().to_owned(),
+            fsync: FsyncPolicy::Interval(Duration::from_secs(1)),
+            segment_bytes: 16 * 1024 * 1024,
+            max_bytes: None
+        }
+    }
+
+    pub fn with_fsync(mut self, fsync: FsyncPolicy) -> Self {
+        self.fsync = fsync;
+        self
+    }
+
+    // at least 1
+    pub fn with_segment_bytes(mut self, segment_bytes: u64) -> Self {
+        self.segment_bytes = segment_bytes.max(1);
+        self
+    }
+
+    pub fn with_max_bytes(mut self, max_bytes: u64) -> Self {
+        self.max_bytes = Some(max_bytes);
+        self
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpoolStats {
+    pub segments: usize,
+    pub bytes: u64,
+    // lines appended since the spool was opened
+    pub appended: u64,
+    // lines found unacknowledged when the spool was opened
+    pub recovered: u64,
+    // lines appended but not acknowledged yet
+    pub pending: u64,
+    // lines that couldn't be read back and were skipped
+    pub corrupted: u64
+}
+
+// what is stored in the `ack` file
+#[derive(Serialize, Deserialize)]
+struct AckPosition {
+    acked: u64
+}
+
+struct Segment {
+    first_seq: u64,
+    path: PathBuf,
+    bytes: u64
+}
+
+struct State {
+    // the last one is written to
+    segments: VecDeque<Segment>,
+    writer: File,
+    next_seq: u64,
+    reader: Option<BufReader<File>>,
+    read_seq: u64,
+    // every line below is acknowledged
+    acked: u64,
+    // sizes of the lines from `acked` on that were read, to know what acknowledging frees
+    read_sizes: VecDeque<u64>,
+    // bytes of acknowledged lines in segments that are still there
+    acked_bytes: u64,
+    // a line read ahead by `read` and not taken yet, so a cancelled `read` loses nothing
+    ready: Option<(u64, LogLine)>,
+    // acknowledged ranges above `acked`, first -> last
+    completed: BTreeMap<u64, u64>,
+    // `acked` moved since the `ack` file was written
+    ack_dirty: bool,
+    bytes: u64,
+    last_sync: Instant,
+    closed: bool,
+    appended: u64,
+    recovered: u64,
+    corrupted: u64
+}
+
+struct Inner {
+    config: SpoolConfig,
+    state: Mutex<State>,
+    // one reader and one writer, so stored permits are never lost
+    appended: Notify,
+    freed: Notify
+}
+
+// the last handle going away writes out what was acknowledged since
+impl Drop for Inner {
+    fn drop(&mut self) {
+        if let Ok(state) = self.state.get_mut() {
+            persist_acks(&self.config.dir, state);
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Spool {
+    inner: Arc<Inner>
+}
+
+// records `acked` in the `ack` file and deletes the segments below it
+fn persist_acks(dir: &Path, state: &mut State) {
+    if !state.ack_dirty {
+        return;
+    }
+    state.ack_dirty = false;
+
+    let tmp = dir.join("ack.tmp");
+    let position = serde_json::to_vec(&AckPosition { acked: state.acked }).unwrap_or_default();
+    if std::fs::write(&tmp, position).is_ok() {
+        let _ = std::fs::rename(&tmp, dir.join("ack"));
+    }
+
+    // the segment being written is kept, the next line goes there
+    while state.segments.len() > 1 && state.segments[1].first_seq <= state.acked {
+        let segment = state.segments.pop_front().unwrap();
+        let _ = std::fs::remove_file(&segment.path);
+        state.bytes -= segment.bytes;
+        state.acked_bytes = state.acked_bytes.saturating_sub(segment.bytes);
+    }
+}
+
+fn segment_path(dir: &Path, first_seq: u64) -> PathBuf {
+    dir.join(format!("{:020}.{}", first_seq, SEGMENT_EXTENSION))
+}
+
+// number of complete lines, a torn or unreadable last line is cut off
+fn recover_segment(path: &Path) -> std::io::Result<u64> {
+    let content = std::fs::read(path)?;
+    let mut lines = 0;
+    let mut valid = 0;
+    for line in content.split_inclusive(|b| *b == b'\n') {
+        if !line.ends_with(b"\n") || serde_json::from_slice::<LogLine>(line).is_err() {
+            break;
+        }
+        lines += 1;
+        valid += line.len();
+    }
+    if valid < content.len() {
+        OpenOptions::new().write(true).open(path)?.set_len(valid as u64)?;
+    }
+    Ok(lines)
+}
+
+impl Spool {
+    pub fn open(config: SpoolConfig) -> std::io::Result<Self> {
+        std::fs::create_dir_all(&config.dir)?;
+        let dir = config.dir.clone();
+
+        let mut acked = std::fs::read(dir.join("ack")).ok()
+            .and_then(|content| serde_json::from_slice::<AckPosition>(&content).ok())
+            .map_or(0, |position| position.acked);
+
+        let mut first_seqs = std::fs::read_dir(&dir)?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == SEGMENT_EXTENSION))
+            .filter_map(|path| path.file_stem()?.to_str()?.parse::<u64>().ok())
+            .collect::<Vec<_>>();
+        first_seqs.sort_unstable();
+
+        let mut segments = VecDeque::new();
+        let mut next_seq = acked;
+        for (i, first_seq) in first_seqs.iter().enumerate() {
+            let path = segment_path(&dir, *first_seq);
+            let end = match first_seqs.get(i + 1) {
+                Some(next) => *next,
+                None => first_seq + recover_segment(&path)?
+            };
+            if end <= acked {
+                std::fs::remove_file(&path)?;
+                continue;
+            }
+            let bytes = std::fs::metadata(&path)?.len();
+            segments.push_back(Segment { first_seq: *first_seq, path, bytes });
+            next_seq = end;
+        }
+        if let Some(first) = segments.front() {
+            acked = acked.max(first.first_seq);
+        }
+
+        // appending always goes to a fresh segment, unless the last one is still empty
+        if segments.back().is_none_or(|last| last.first_seq != next_seq) {
+            segments.push_back(Segment { first_seq: next_seq, path: segment_path(&dir, next_seq), bytes: 0 });
+        }
+        let active = segments.back().unwrap();
+        let writer = OpenOptions::new().create(true).append(true).open(&active.path)?;
+
+        // the reader starts at the first unacknowledged line
+        let mut reader = None;
+        let mut acked_bytes = 0;
+        if let Some(segment) = segments.iter().rev().find(|segment| segment.first_seq <= acked) {
+            let mut file = BufReader::new(File::open(&segment.path)?);
+            let mut line = Vec::new();
+            for _ in segment.first_seq..acked {
+                line.clear();
+                acked_bytes += file.read_until(b'\n', &mut line)? as u64;
+            }
+            reader = Some(file);
+        }
+
+        let state = State {
+            bytes: segments.iter().map(|segment| segment.bytes).sum(),
+            segments,
+            writer,
+            next_seq,
+            reader,
+            read_seq: acked,
+            acked,
+            read_sizes: VecDeque::new(),
+            acked_bytes,
+            ready: None,
+            completed: BTreeMap::new(),
+            ack_dirty: false,
+            last_sync: Instant::now(),
+            closed: false,
+            appended: 0,
+            recovered: next_seq - acked,
+            corrupted: 0
+        };
+
+        Ok(Self { inner: Arc::new(Inner { config, state: Mutex::new(state), appended: Notify::new(), freed: Notify::new() }) })
+    }
+
+    // returns the line's sequence number, waits while the spool is at `max_bytes`
+    pub async fn append(&self, log_line: &LogLine) -> std::io::Result<u64> {
+        let mut line = serde_json::to_vec(log_line)?;
+        line.push(b'\n');
+
+        loop {
+            let spool = self.clone();
+            let attempt = line.clone();
+            match tokio::task::spawn_blocking(move || spool.try_append(&attempt)).await? {
+                Some(appended) => return appended,
+                None => self.inner.freed.notified().await
+            }
+        }
+    }
+
+    // None while the spool is full
+    fn try_append(&self, line: &[u8]) -> Option<std::io::Result<u64>> {
+        let mut state = self.inner.state.lock().unwrap();
+        persist_acks(&self.inner.config.dir, &mut state);
+        // acknowledged lines don't count, even while their segment is still there
+        let full = self.inner.config.max_bytes.is_some_and(|max| state.bytes - state.acked_bytes >= max);
+        // an empty spool always takes a line, however big
+        if full && state.acked != state.next_seq {
+            return None;
+        }
+        Some(self.write(&mut state, line))
+    }
+
+    fn write(&self, state: &mut State, line: &[u8]) -> std::io::Result<u64> {
+        let config = &self.inner.config;
+        let active = state.segments.back().unwrap();
+        if active.bytes >= config.segment_bytes {
+            if config.fsync != FsyncPolicy::Never {
+                state.writer.sync_data()?;
+            }
+            let path = segment_path(&config.dir, state.next_seq);
+            state.writer = OpenOptions::new().create(true).append(true).open(&path)?;
+            state.segments.push_back(Segment { first_seq: state.next_seq, path, bytes: 0 });
+        }
+
+        state.writer.write_all(line)?;
+        match config.fsync {
+            FsyncPolicy::Always => state.writer.sync_data()?,
+            FsyncPolicy::Interval(interval) if state.last_sync.elapsed() >= interval => {
+                state.writer.sync_data()?;
+                state.last_sync = Instant::now();
+            }
+            _ => {}
+        }
+
+        state.segments.back_mut().unwrap().bytes += line.len() as u64;
+        state.bytes += line.len() as u64;
+        let seq = state.next_seq;
+        state.next_seq += 1;
+        state.appended += 1;
+        self.inner.appended.notify_one();
+        Ok(seq)
+    }
+
+    // the next line in order, `None` once `close_input` was called and everything was read
+    pub async fn read(&self) -> Option<(u64, LogLine)> {
+        loop {
+            let caught_up = {
+                let mut state = self.inner.state.lock().unwrap();
+                if let Some(ready) = state.ready.take() {
+                    return Some(ready);
+                }
+                if state.read_seq >= state.next_seq && state.closed {
+                    return None;
+                }
+                state.read_seq >= state.next_seq
+            };
+            if caught_up {
+                self.inner.appended.notified().await;
+                continue;
+            }
+
+            // a line read there is kept in `ready` if this future is gone by then
+            let spool = self.clone();
+            let _ = tokio::task::spawn_blocking(move || spool.read_ahead()).await;
+        }
+    }
+
+    // moves the next line into `ready`, if there is one on disk
+    fn read_ahead(&self) {
+        let mut state = self.inner.state.lock().unwrap();
+        persist_acks(&self.inner.config.dir, &mut state);
+        while state.ready.is_none() && state.read_seq < state.next_seq {
+            let seq = state.read_seq;
+            let mut line = Vec::new();
+            let read = state.reader.as_mut().map(|reader| reader.read_until(b'\n', &mut line));
+
+            match read {
+                Some(Ok(n)) if n > 0 => {
+                    state.read_seq += 1;
+                    state.read_sizes.push_back(n as u64);
+                    match serde_json::from_slice(&line) {
+                        Ok(log_line) => state.ready = Some((seq, log_line)),
+                        // it won't get better, let it count as delivered
+                        Err(_) => {
+                            state.corrupted += 1;
+                            self.complete(&mut state, seq, seq);
+                        }
+                    }
+                }
+                // the end of a segment, the next one starts with `read_seq`
+                _ => {
+                    let next = state.segments.iter().find(|segment| segment.first_seq == seq).map(|segment| segment.path.clone());
+                    match next.map(File::open) {
+                        Some(Ok(file)) => state.reader = Some(BufReader::new(file)),
+                        _ => break
+                    }
+                }
+            }
+        }
+    }
+
+    // marks `first..=last` as delivered, lines can be acknowledged in any order
+    pub fn ack(&self, first: u64, last: u64) {
+        let mut state = self.inner.state.lock().unwrap();
+        self.complete(&mut state, first, last);
+    }
+
+    fn complete(&self, state: &mut State, first: u64, last: u64) {
+        if last < state.acked {
+            return;
+        }
+        state.completed.insert(first.max(state.acked), last);
+
+        let before = state.acked;
+        while let Some(last) = state.completed.remove(&state.acked) {
+            state.acked = last + 1;
+        }
+        if state.acked == before {
+            return;
+        }
+        for _ in before..state.acked {
+            state.acked_bytes += state.read_sizes.pop_front().unwrap_or(0);
+        }
+        state.ack_dirty = true;
+        self.inner.freed.notify_one();
+    }
+
+    // nothing more is appended, the reader gets `None` after the last line
+    pub fn close_input(&self) {
+        let mut state = self.inner.state.lock().unwrap();
+        state.closed = true;
+        let _ = self.sync_locked(&mut state);
+        self.inner.appended.notify_one();
+    }
+
+    pub fn sync(&self) -> std::io::Result<()> {
+        let mut state = self.inner.state.lock().unwrap();
+        self.sync_locked(&mut state)
+    }
+
+    fn sync_locked(&self, state: &mut State) -> std::io::Result<()> {
+        persist_acks(&self.inner.config.dir, state);
+        if self.inner.config.fsync == FsyncPolicy::Never {
+            return Ok(());
+        }
+        state.writer.sync_data()?;
+        state.last_sync = Instant::now();
+        Ok(())
+    }
+
+    pub fn stats(&self) -> SpoolStats {
+        let state = self.inner.state.lock().unwrap();
+        SpoolStats {
+            segments: state.segments.len(),
+            bytes: state.bytes,
+            appended: state.appended,
+            recovered: state.recovered,
+            pending: state.next_seq - state.acked,
+            corrupted: state.corrupted
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn line(content: &str) -> LogLine {
+        LogLine { content: content.to_string(), source: "test".to_string(), timestamp: chrono::Utc::now() }
+    }
+
+    #[tokio::test]
+    async fn unacknowledged_lines_survive_a_restart() {
+        let dir = std::env::temp_dir().join(format!("loganalyzer-spool-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        // small segments, so acknowledging deletes some
+        let config = SpoolConfig::new(&dir).with_fsync(FsyncPolicy::Always).with_segment_bytes(200);
+
+        let spool = Spool::open(config.clone()).unwrap();
+        for i in 0..10 {
+            spool.append(&line(&format!("line {}", i))).await.unwrap();
+        }
+        let segments = spool.stats().segments;
+        assert!(segments > 2);
+
+        let mut read = Vec::new();
+        for _ in 0..6 {
+            read.push(spool.read().await.unwrap());
+        }
+        assert_eq!(read[5].1.content, "line 5");
+        // out of order, only 0..=3 count until 4 is confirmed too
+        spool.ack(5, 5);
+        spool.ack(0, 3);
+        spool.sync().unwrap();
+        let stats = spool.stats();
+        assert_eq!(stats.pending, 6);
+        assert!(stats.segments < segments);
+        drop(spool);
+
+        // a crash in the middle of writing leaves half a line
+        let last = std::fs::read_dir(&dir).unwrap().filter_map(|e| e.ok()).map(|e| e.path())
+            .filter(|p| p.extension().is_some_and(|e| e == "seg")).max().unwrap();
+        OpenOptions::new().append(true).open(&last).unwrap().write_all(b"{\"content\":\"tor").unwrap();
+
+        let spool = Spool::open(config).unwrap();
+        assert_eq!(spool.stats().recovered, 6);
+        spool.append(&line("line 10")).await.unwrap();
+        spool.close_input();
+
+        let mut contents = Vec::new();
+        while let Some((_, log_line)) = spool.read().await {
+            contents.push(log_line.content);
+        }
+        assert_eq!(contents, vec!["line 4", "line 5", "line 6", "line 7", "line 8", "line 9", "line 10"]);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn acknowledged_lines_free_the_active_segment() {
+        let dir = std::env::temp_dir().join(format!("loganalyzer-spool-full-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let config = SpoolConfig::new(&dir).with_fsync(FsyncPolicy::Never).with_segment_bytes(1000).with_max_bytes(200);
+
+        let spool = Spool::open(config).unwrap();
+        for i in 0..3 {
+            spool.append(&line(&format!("line {}", i))).await.unwrap();
+            let (seq, _) = spool.read().await.unwrap();
+            spool.ack(seq, seq);
+        }
+        // everything so far is acknowledged but still in the one segment, it must not count
+        assert!(spool.stats().bytes >= 200);
+        for i in 3..5 {
+            let appended = tokio::time::timeout(Duration::from_secs(2), spool.append(&line(&format!("line {}", i)))).await;
+            assert!(appended.is_ok(), "appending waited on acknowledged lines");
+        }
+        assert_eq!(spool.stats().pending, 2);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,308 @@
+use std::{error::Error, fmt, sync::Arc};
+
+use serde_json::{Map, Value};
+
+use crate::filter::LogFilter;
+use crate::parser::ParsedLog;
+
+pub mod dedupe;
+pub mod fields;
+pub mod geoip;
+pub mod lookup;
+pub mod redact;
+pub mod sample;
+pub mod script;
+pub mod split;
+pub mod user_agent;
+
+pub use dedupe::{Dedupe, DedupeMode};
+pub use fields::{CoerceFields, DeleteFields, LogField, PromoteFields, RenameFields, SetDefaults, ValueType};
+pub use geoip::GeoIp;
+pub use lookup::Lookup;
+pub use redact::{Redact, RedactMode};
+pub use sample::{RateLimit, Sample};
+pub use script::ScriptTransform;
+pub use split::SplitRecords;
+pub use user_agent::UserAgent;
+
+// changes parsed records between parsing and analytics. Transforms are synchronous work run once
+// per record, the engine calls them on tokio's blocking threads
+pub trait Transform : Send + Sync {
+    fn name(&self) -> &'static str;
+
+    // pushes what becomes of `log` to `out`: nothing drops it, several records split it. A
+    // record the transform fails on is handed back with the error and dead-lettered
+    fn apply(&self, log: ParsedLog, out: &mut Vec<ParsedLog>) -> Result<(), TransformError>;
+
+    // what the transform counted so far, e.g. redactions per detector
+    fn counters(&self) -> Vec<(String, u64)> {
+        Vec::new()
+    }
+
+    // hands out records the transform held back, like repeat summaries, once no more records
+    // are coming
+    fn flush(&self, _out: &mut Vec<ParsedLog>) {}
+
+    // hands out held back records that are due although no record came to trigger them, the
+    // engine calls it every `max_latency`
+    fn tick(&self, _out: &mut Vec<ParsedLog>) {}
+
+    // redacts raw text that leaves the pipeline without becoming a record, like the lines and
+    // records kept as dead letters. None when there's nothing to change
+    fn redact(&self, _text: &str) -> Option<String> {
+        None
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TransformCounter {
+    pub transform: &'static str,
+    pub counter: String,
+    pub value: u64
+}
+
+impl fmt::Display for TransformCounter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}: {}", self.transform, self.counter, self.value)
+    }
+}
+
+#[derive(Debug)]
+pub struct TransformError {
+    pub transform: &'static str,
+    pub log: Box<ParsedLog>,
+    pub error: Box<dyn Error + Send + Sync>
+}
+
+impl TransformError {
+    pub fn new(transform: &'static str, log: ParsedLog, error: impl Into<Box<dyn Error + Send + Sync>>) -> Self {
+        Self { transform, log: Box::new(log), error: error.into() }
+    }
+}
+
+impl fmt::Display for TransformError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} transform failed: {}", self.transform, self.error)
+    }
+}
+
+// keeps or drops the records a filter matches
+pub struct Predicate {
+    filter: LogFilter,
+    keep: bool
+}
+
+impl Predicate {
+    pub fn keep(filter: LogFilter) -> Self {
+        Self { filter, keep: true }
+    }
+
+    pub fn drop(filter: LogFilter) -> Self {
+        Self { filter, keep: false }
+    }
+}
+
+impl Transform for Predicate {
+    fn name(&self) -> &'static str {
+        if self.keep { "keep" } else { "drop" }
+    }
+
+    fn apply(&self, log: ParsedLog, out: &mut Vec<ParsedLog>) -> Result<(), TransformError> {
+        if self.filter.matches(&log) == self.keep {
+            out.push(log);
+        }
+        Ok(())
+    }
+}
+
+// transforms applied one after the other, cheap to clone and share between sources
+#[derive(Clone, Default)]
+pub struct TransformChain {
+    transforms: Vec<Arc<dyn Transform>>
+}
+
+impl TransformChain {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_transform(mut self, transform: impl Transform + 'static) -> Self {
+        self.transforms.push(Arc::new(transform));
+        self
+    }
+
+    pub fn push(&mut self, transform: Box<dyn Transform>) {
+        self.transforms.push(Arc::from(transform));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.transforms.is_empty()
+    }
+
+    // every transform's `redact` in turn
+    pub fn redact(&self, text: &str) -> Option<String> {
+        let mut redacted: Option<String> = None;
+        for transform in &self.transforms {
+            if let Some(text) = transform.redact(redacted.as_deref().unwrap_or(text)) {
+                redacted = Some(text);
+            }
+        }
+        redacted
+    }
+
+    pub fn names(&self) -> Vec<&'static str> {
+        self.transforms.iter().map(|transform| transform.name()).collect()
+    }
+
+    pub fn counters(&self) -> Vec<TransformCounter> {
+        self.transforms.iter()
+            .flat_map(|transform| transform.counters().into_iter()
+                .map(|(counter, value)| TransformCounter { transform: transform.name(), counter, value }))
+            .collect()
+    }
+
+    // records a transform dropped or failed on don't reach the ones after it, the failed ones
+    // end up in `failed`
+    pub fn apply(&self, logs: Vec<ParsedLog>, failed: &mut Vec<TransformError>) -> Vec<ParsedLog> {
+        let mut logs = logs;
+        for transform in &self.transforms {
+            let mut out = Vec::with_capacity(logs.len());
+            for log in logs {
+                if let Err(e) = transform.apply(log, &mut out) {
+                    failed.push(e);
+                }
+            }
+            logs = out;
+        }
+        logs
+    }
+
+    // what the transforms held back, each transform's passing through the ones after it
+    pub fn flush(&self, failed: &mut Vec<TransformError>) -> Vec<ParsedLog> {
+        self.held_back(failed, |transform, out| transform.flush(out))
+    }
+
+    // what the transforms held back that is due by now, passed on like `flush`
+    pub fn tick(&self, failed: &mut Vec<TransformError>) -> Vec<ParsedLog> {
+        self.held_back(failed, |transform, out| transform.tick(out))
+    }
+
+    fn held_back(&self, failed: &mut Vec<TransformError>, hand_out: impl Fn(&dyn Transform, &mut Vec<ParsedLog>)) -> Vec<ParsedLog> {
+        let mut logs = Vec::new();
+        for transform in &self.transforms {
+            let mut out = Vec::with_capacity(logs.len());
+            for log in logs {
+                if let Err(e) = transform.apply(log, &mut out) {
+                    failed.push(e);
+                }
+            }
+            hand_out(transform.as_ref(), &mut out);
+            logs = out;
+        }
+        logs
+    }
+}
+
+// metadata is addressed with dotted paths, `http.status` is the key `status` of the object `http`
+
+pub(crate) fn get_path<'a>(metadata: &'a Value, path: &str) -> Option<&'a Value> {
+    path.split('.').try_fold(metadata, |value, key| value.get(key))
+}
+
+pub(crate) fn get_path_mut<'a>(metadata: &'a mut Value, path: &str) -> Option<&'a mut Value> {
+    path.split('.').try_fold(metadata, |value, key| value.get_mut(key))
+}
+
+pub(crate) fn take_path(metadata: &mut Value, path: &str) -> Option<Value> {
+    let (parent, key) = match path.rsplit_once('.') {
+        Some((parent, key)) => (get_path_mut(metadata, parent)?, key),
+        None => (metadata, path)
+    };
+    parent.as_object_mut()?.remove(key)
+}
+
+// a record field like `host` or `service_name`, any other name is a metadata path. Transforms
+// resolve their names once when they're built, not per record
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum FieldRef {
+    Field(LogField),
+    Path(String)
+}
+
+impl FieldRef {
+    pub(crate) fn new(name: &str) -> Self {
+        match name.parse::<LogField>() {
+            Ok(field) => FieldRef::Field(field),
+            Err(_) => FieldRef::Path(name.to_string())
+        }
+    }
+
+    pub(crate) fn text(&self, log: &ParsedLog) -> Option<String> {
+        match self {
+            FieldRef::Field(field) => field.get(log),
+            FieldRef::Path(path) => get_path(&log.metadata, path).and_then(fields::text)
+        }
+    }
+}
+
+// objects on the way are created, anything else in the way is replaced by one
+pub(crate) fn set_path(metadata: &mut Value, path: &str, new: Value) {
+    let mut value = metadata;
+    let mut keys = path.split('.').peekable();
+    while let Some(key) = keys.next() {
+        if !value.is_object() {
+            *value = Value::Object(Map::new());
+        }
+        let object = value.as_object_mut().expect("made an object above");
+        if keys.peek().is_none() {
+            object.insert(key.to_string(), new);
+            return;
+        }
+        value = object.entry(key).or_insert_with(|| Value::Object(Map::new()));
+    }
+}
+
+
+// runs one transform on one record, for the transforms' tests
+#[cfg(test)]
+pub(crate) mod testing {
+    use super::*;
+
+    // every record the transform made of `log`
+    pub(crate) fn apply_all(transform: &dyn Transform, log: ParsedLog) -> Result<Vec<ParsedLog>, TransformError> {
+        let mut out = Vec::new();
+        transform.apply(log, &mut out).map(|_| out)
+    }
+
+    // the one record a transform that neither fails nor drops made of `log`
+    pub(crate) fn apply(transform: &dyn Transform, log: ParsedLog) -> ParsedLog {
+        apply_all(transform, log).unwrap().pop().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Level;
+    use serde_json::json;
+
+    #[test]
+    fn the_chain_applies_transforms_in_order() {
+        let chain = TransformChain::new()
+            .with_transform(Predicate::drop(LogFilter::new().with_contains("health")))
+            .with_transform(RenameFields::new().with_rename("usr", "user.name"))
+            .with_transform(Predicate::keep(LogFilter::new().with_min_level(Level::Warn)));
+
+        let logs = vec![
+            ParsedLog { metadata: json!({ "usr": "ann" }), ..ParsedLog::new("login failed".to_string()).with_level(Level::Error) },
+            ParsedLog::new("health check".to_string()).with_level(Level::Error),
+            ParsedLog::new("login".to_string()).with_level(Level::Info)
+        ];
+        let mut failed = Vec::new();
+        let logs = chain.apply(logs, &mut failed);
+
+        assert_eq!(chain.names(), vec!["drop", "rename", "keep"]);
+        assert_eq!((logs.len(), failed.len()), (1, 0));
+        assert_eq!(logs[0].metadata, json!({ "user": { "name": "ann" } }));
+    }
+}
@@ -0,0 +1,309 @@
+use std::{collections::{hash_map::DefaultHasher, HashMap, VecDeque}, hash::{Hash, Hasher}, str::FromStr, sync::{atomic::{AtomicU64, Ordering}, Mutex}, time::{Duration, Instant}};
+
+use chrono::{DateTime, Utc};
+use regex::Regex;
+use serde_json::json;
+
+use super::{set_path, FieldRef, Transform, TransformError};
+use crate::error::LogAnalyzerError;
+use crate::parser::ParsedLog;
+
+pub const DEFAULT_DEDUPE_FIELDS: [&str; 4] = ["level", "service_name", "host", "message"];
+pub const DEFAULT_DEDUPE_WINDOW: Duration = Duration::from_secs(60);
+pub const DEFAULT_MAX_ENTRIES: usize = 10_000;
+pub const DEDUPE_MODES: [&str; 2] = ["exact", "near"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DedupeMode {
+    // the fields have to be equal
+    Exact,
+    // numbers, hex ids, UUIDs and IP addresses don't count, so "user 17 timed out after
+    // 3012ms" repeats "user 4 timed out after 2990ms"
+    Near
+}
+
+impl FromStr for DedupeMode {
+    type Err = LogAnalyzerError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "exact" => Ok(DedupeMode::Exact),
+            "near" => Ok(DedupeMode::Near),
+            other => Err(LogAnalyzerError::Config(format!("unknown dedupe mode '{}', expected one of {}", other, DEDUPE_MODES.join(", "))))
+        }
+    }
+}
+
+// the first record of a window and what was suppressed after it
+struct Seen {
+    first: ParsedLog,
+    first_at: DateTime<Utc>,
+    last_at: DateTime<Utc>,
+    repeats: u64
+}
+
+#[derive(Default)]
+struct State {
+    seen: HashMap<u64, Seen>,
+    // fingerprints in the order their windows started, windows end in about that order too
+    windows: VecDeque<u64>,
+    // the latest record time so far and when it came, windows end by record time so replayed
+    // logs dedupe like live ones
+    now: Option<(DateTime<Utc>, Instant)>
+}
+
+// Suppresses repeats of a record within a window. The first record of a fingerprint passes, the
+// ones after it within `window` are dropped and counted, and when the window ends a summary
+// "message repeated N times between t1 and t2: ..." follows, a copy of the first record with the
+// count in `metadata.dedupe`. Windows end by record time as later records come in, by the clock
+// on the engine's ticks while none do, or when the last source ends. A record without a timestamp
+// belongs to the latest time seen. At most `max_entries` fingerprints are tracked, the oldest
+// window is closed early to make room
+pub struct Dedupe {
+    fields: Vec<FieldRef>,
+    mode: DedupeMode,
+    window: chrono::Duration,
+    max_entries: usize,
+    masks: Vec<Regex>,
+    state: Mutex<State>,
+    suppressed: AtomicU64,
+    summaries: AtomicU64
+}
+
+impl Dedupe {
+    pub fn new(mode: DedupeMode) -> Self {
+        Self {
+            fields: DEFAULT_DEDUPE_FIELDS.iter().map(|field| FieldRef::new(field)).collect(),
+            mode,
+            window: chrono::Duration::from_std(DEFAULT_DEDUPE_WINDOW).expect("a minute fits"),
+            max_entries: DEFAULT_MAX_ENTRIES,
+            // most specific first, an IP address would otherwise become four numbers
+            masks: [
+                r"(?i)\b[0-9a-f]{8}-[0-9a-f]{4}-[0-9a-f]{4}-[0-9a-f]{4}-[0-9a-f]{12}\b",
+                r"\b\d{1,3}(?:\.\d{1,3}){3}\b",
+                r"(?i)\b(?:0x)?[0-9a-f]*\d[0-9a-f]*[a-f][0-9a-f]*\b|(?i)\b(?:0x)?[0-9a-f]*[a-f][0-9a-f]*\d[0-9a-f]*\b",
+                r"\d+(?:\.\d+)?"
+            ].iter().map(|pattern| Regex::new(pattern).expect("mask patterns compile")).collect(),
+            state: Mutex::new(State::default()),
+            suppressed: AtomicU64::new(0),
+            summaries: AtomicU64::new(0)
+        }
+    }
+
+    // record fields like `message` or metadata paths that make up the fingerprint
+    pub fn with_fields(mut self, fields: Vec<String>) -> Self {
+        self.fields = fields.iter().map(|field| FieldRef::new(field)).collect();
+        self
+    }
+
+    pub fn with_window(mut self, window: Duration) -> Self {
+        self.window = chrono::Duration::from_std(window).unwrap_or(chrono::TimeDelta::MAX);
+        self
+    }
+
+    // at least 1
+    pub fn with_max_entries(mut self, max_entries: usize) -> Self {
+        self.max_entries = max_entries.max(1);
+        self
+    }
+
+    fn mask(&self, value: String) -> String {
+        let masked = self.masks.iter().fold(value, |value, mask| mask.replace_all(&value, "#").into_owned());
+        masked.split_whitespace().collect::<Vec<_>>().join(" ").to_lowercase()
+    }
+
+    fn fingerprint(&self, log: &ParsedLog) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        for field in &self.fields {
+            let value = field.text(log);
+            match (self.mode, value) {
+                (DedupeMode::Near, Some(value)) => Some(self.mask(value)).hash(&mut hasher),
+                (_, value) => value.hash(&mut hasher)
+            }
+        }
+        hasher.finish()
+    }
+
+    fn summary(&self, fingerprint: u64, seen: Seen) -> ParsedLog {
+        let mut summary = seen.first;
+        summary.message = format!("message repeated {} {} between {} and {}: {}",
+            seen.repeats, if seen.repeats == 1 { "time" } else { "times" }, seen.first_at.to_rfc3339(), seen.last_at.to_rfc3339(), summary.message);
+        summary.timestamp = Some(seen.last_at);
+        set_path(&mut summary.metadata, "dedupe", json!({
+            "repeats": seen.repeats,
+            "first_seen": seen.first_at.to_rfc3339(),
+            "last_seen": seen.last_at.to_rfc3339(),
+            "fingerprint": format!("{:016x}", fingerprint)
+        }));
+        self.summaries.fetch_add(1, Ordering::Relaxed);
+        summary
+    }
+
+    // ends the oldest window, with a summary if anything was suppressed in it
+    fn close_oldest(&self, state: &mut State, out: &mut Vec<ParsedLog>) {
+        if let Some(fingerprint) = state.windows.pop_front() {
+            if let Some(seen) = state.seen.remove(&fingerprint) {
+                if seen.repeats > 0 {
+                    out.push(self.summary(fingerprint, seen));
+                }
+            }
+        }
+    }
+
+    fn close_ended(&self, state: &mut State, now: DateTime<Utc>, out: &mut Vec<ParsedLog>) {
+        while let Some(oldest) = state.windows.front().and_then(|fingerprint| state.seen.get(fingerprint)) {
+            if oldest.first_at + self.window > now {
+                break;
+            }
+            self.close_oldest(state, out);
+        }
+    }
+}
+
+impl Transform for Dedupe {
+    fn name(&self) -> &'static str {
+        "dedupe"
+    }
+
+    fn apply(&self, log: ParsedLog, out: &mut Vec<ParsedLog>) -> Result<(), TransformError> {
+        let fingerprint = self.fingerprint(&log);
+        let mut state = self.state.lock().unwrap();
+        // the clock only before any record had a time, it would end every window of a replay
+        let at = log.timestamp.or(state.now.map(|(now, _)| now)).unwrap_or_else(Utc::now);
+
+        let now = match state.now {
+            Some((now, _)) if now >= at => now,
+            _ => {
+                state.now = Some((at, Instant::now()));
+                at
+            }
+        };
+        self.close_ended(&mut state, now, out);
+
+        if let Some(seen) = state.seen.get_mut(&fingerprint) {
+            seen.repeats += 1;
+            seen.last_at = seen.last_at.max(at);
+            self.suppressed.fetch_add(1, Ordering::Relaxed);
+            return Ok(());
+        }
+
+        if state.seen.len() >= self.max_entries {
+            self.close_oldest(&mut state, out);
+        }
+        state.seen.insert(fingerprint, Seen { first: log.clone(), first_at: at, last_at: at, repeats: 0 });
+        state.windows.push_back(fingerprint);
+        out.push(log);
+        Ok(())
+    }
+
+    // with no records coming record time stands still, so it's moved on as far as the clock did
+    // since the latest one
+    fn tick(&self, out: &mut Vec<ParsedLog>) {
+        let mut state = self.state.lock().unwrap();
+        if let Some((now, seen_at)) = state.now {
+            let now = now + chrono::Duration::from_std(seen_at.elapsed()).unwrap_or(chrono::TimeDelta::zero());
+            self.close_ended(&mut state, now, out);
+        }
+    }
+
+    fn flush(&self, out: &mut Vec<ParsedLog>) {
+        let mut state = self.state.lock().unwrap();
+        while !state.windows.is_empty() {
+            self.close_oldest(&mut state, out);
+        }
+    }
+
+    fn counters(&self) -> Vec<(String, u64)> {
+        vec![
+            ("suppressed".to_string(), self.suppressed.load(Ordering::Relaxed)),
+            ("summaries".to_string(), self.summaries.load(Ordering::Relaxed))
+        ]
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(seconds: i64, message: &str) -> ParsedLog {
+        ParsedLog { timestamp: DateTime::from_timestamp(1_700_000_000 + seconds, 0), ..ParsedLog::new(message.to_string()) }
+    }
+
+    fn messages(logs: &[ParsedLog]) -> Vec<&str> {
+        logs.iter().map(|log| log.message.as_str()).collect()
+    }
+
+    #[test]
+    fn repeats_are_summarised_when_the_window_ends() {
+        let dedupe = Dedupe::new(DedupeMode::Near).with_window(Duration::from_secs(10));
+        let mut out = Vec::new();
+        for (seconds, message) in [(0, "user 17 timed out after 3012ms"), (1, "user 4 timed out after 2990ms"), (2, "disk full"),
+                                   (3, "user 9 timed out after 3001ms"), (12, "user 5 timed out after 10ms")] {
+            dedupe.apply(at(seconds, message), &mut out).unwrap();
+        }
+
+        assert_eq!(messages(&out), vec![
+            "user 17 timed out after 3012ms",
+            "disk full",
+            "message repeated 2 times between 2023-11-14T22:13:20+00:00 and 2023-11-14T22:13:23+00:00: user 17 timed out after 3012ms",
+            "user 5 timed out after 10ms"
+        ]);
+        assert_eq!(out[2].metadata["dedupe"]["repeats"], 2);
+
+        // nothing repeated "disk full", so its window ends without a summary
+        out.clear();
+        dedupe.apply(at(13, "user 6 timed out after 11ms"), &mut out).unwrap();
+        dedupe.flush(&mut out);
+        assert_eq!(out.len(), 1);
+        assert!(out[0].message.starts_with("message repeated 1 time between"), "{}", out[0].message);
+        assert_eq!(dedupe.counters(), vec![("suppressed".to_string(), 3), ("summaries".to_string(), 2)]);
+    }
+
+    #[test]
+    fn exact_mode_and_bounded_memory() {
+        let dedupe = Dedupe::new(DedupeMode::Exact).with_max_entries(2);
+        let mut out = Vec::new();
+        for message in ["user 1 failed", "user 2 failed", "user 1 failed", "user 3 failed", "user 1 failed"] {
+            dedupe.apply(at(0, message), &mut out).unwrap();
+        }
+        // "user 3" makes room by closing the oldest window, so the next "user 1" passes again
+        assert_eq!(messages(&out), vec![
+            "user 1 failed",
+            "user 2 failed",
+            "message repeated 1 time between 2023-11-14T22:13:20+00:00 and 2023-11-14T22:13:20+00:00: user 1 failed",
+            "user 3 failed",
+            "user 1 failed"
+        ]);
+    }
+
+    #[test]
+    fn windows_end_on_ticks_while_nothing_comes() {
+        let dedupe = Dedupe::new(DedupeMode::Exact).with_window(Duration::from_millis(50));
+        let mut out = Vec::new();
+        for _ in 0..3 {
+            dedupe.apply(at(0, "disk full"), &mut out).unwrap();
+        }
+        dedupe.tick(&mut out);
+        assert_eq!(messages(&out), vec!["disk full"]);
+
+        std::thread::sleep(Duration::from_millis(60));
+        dedupe.tick(&mut out);
+        assert_eq!(out.len(), 2);
+        assert!(out[1].message.starts_with("message repeated 2 times"), "{}", out[1].message);
+    }
+
+    #[test]
+    fn untimed_records_keep_to_the_replayed_time() {
+        let dedupe = Dedupe::new(DedupeMode::Exact).with_window(Duration::from_secs(10));
+        let mut out = Vec::new();
+        let continuation = ParsedLog { timestamp: None, ..ParsedLog::new("    at Worker.run(Worker.java:42)".to_string()) };
+        for log in [at(0, "disk full"), continuation, at(1, "disk full"), at(2, "disk full")] {
+            dedupe.apply(log, &mut out).unwrap();
+        }
+        dedupe.flush(&mut out);
+
+        assert_eq!(out.len(), 3, "{:?}", messages(&out));
+        assert!(out[2].message.starts_with("message repeated 2 times between 2023-11-14T22:13:20+00:00"), "{}", out[2].message);
+    }
+}
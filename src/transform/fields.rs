@@ -0,0 +1,369 @@
+use std::str::FromStr;
+
+use serde_json::Value;
+
+use super::{get_path, get_path_mut, set_path, take_path, Transform, TransformError};
+use crate::error::LogAnalyzerError;
+use crate::parser::ParsedLog;
+
+// moves metadata values to another path, also between nested objects. Missing keys are skipped
+#[derive(Debug, Default, Clone)]
+pub struct RenameFields {
+    renames: Vec<(String, String)>
+}
+
+impl RenameFields {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_rename(mut self, from: impl Into<String>, to: impl Into<String>) -> Self {
+        self.renames.push((from.into(), to.into()));
+        self
+    }
+}
+
+impl Transform for RenameFields {
+    fn name(&self) -> &'static str {
+        "rename"
+    }
+
+    fn apply(&self, mut log: ParsedLog, out: &mut Vec<ParsedLog>) -> Result<(), TransformError> {
+        for (from, to) in &self.renames {
+            if let Some(value) = take_path(&mut log.metadata, from) {
+                set_path(&mut log.metadata, to, value);
+            }
+        }
+        out.push(log);
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct DeleteFields {
+    paths: Vec<String>
+}
+
+impl DeleteFields {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_field(mut self, path: impl Into<String>) -> Self {
+        self.paths.push(path.into());
+        self
+    }
+}
+
+impl Transform for DeleteFields {
+    fn name(&self) -> &'static str {
+        "delete"
+    }
+
+    fn apply(&self, mut log: ParsedLog, out: &mut Vec<ParsedLog>) -> Result<(), TransformError> {
+        for path in &self.paths {
+            take_path(&mut log.metadata, path);
+        }
+        out.push(log);
+        Ok(())
+    }
+}
+
+// the typed fields of `ParsedLog` a metadata value can be promoted to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogField {
+    Timestamp,
+    Level,
+    Message,
+    ServiceName,
+    TraceId,
+    SpanId,
+    DurationMs,
+    Host,
+    Environment,
+    Version
+}
+
+pub const LOG_FIELDS: [&str; 10] = ["timestamp", "level", "message", "service_name", "trace_id", "span_id", "duration_ms", "host", "environment", "version"];
+
+impl FromStr for LogField {
+    type Err = LogAnalyzerError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "timestamp" => Ok(LogField::Timestamp),
+            "level" => Ok(LogField::Level),
+            "message" => Ok(LogField::Message),
+            "service_name" | "service" => Ok(LogField::ServiceName),
+            "trace_id" => Ok(LogField::TraceId),
+            "span_id" => Ok(LogField::SpanId),
+            "duration_ms" => Ok(LogField::DurationMs),
+            "host" => Ok(LogField::Host),
+            "environment" => Ok(LogField::Environment),
+            "version" => Ok(LogField::Version),
+            other => Err(LogAnalyzerError::Config(format!("unknown record field '{}', expected one of {}", other, LOG_FIELDS.join(", "))))
+        }
+    }
+}
+
+pub(crate) fn text(value: &Value) -> Option<String> {
+    match value {
+        Value::String(s) => Some(s.clone()),
+        Value::Number(n) => Some(n.to_string()),
+        Value::Bool(b) => Some(b.to_string()),
+        _ => None
+    }
+}
+
+fn number(value: &Value) -> Option<f64> {
+    match value {
+        Value::Number(n) => n.as_f64(),
+        Value::String(s) => s.trim().parse().ok(),
+        _ => None
+    }
+}
+
+impl LogField {
+    // the field as text, None when it isn't set
+    pub(crate) fn get(self, log: &ParsedLog) -> Option<String> {
+        match self {
+            LogField::Timestamp => log.timestamp.map(|timestamp| timestamp.to_rfc3339()),
+            LogField::Level => log.level.as_ref().map(|level| level.as_str().to_string()),
+            LogField::Message => Some(log.message.clone()),
+            LogField::DurationMs => log.duration_ms.map(|duration| duration.to_string()),
+            LogField::ServiceName => log.service_name.clone(),
+            LogField::TraceId => log.trace_id.clone(),
+            LogField::SpanId => log.span_id.clone(),
+            LogField::Host => log.host.clone(),
+            LogField::Environment => log.environment.clone(),
+            LogField::Version => log.version.clone()
+        }
+    }
+
+    // false when the value doesn't fit the field, the record is left as it was
+    fn set(self, log: &mut ParsedLog, value: &Value) -> bool {
+        match self {
+            LogField::Timestamp => {
+                let timestamp = match value {
+                    Value::String(s) => chrono::DateTime::parse_from_rfc3339(s).ok().map(|dt| dt.with_timezone(&chrono::Utc)),
+                    // seconds since the epoch, fractions allowed
+                    Value::Number(n) => n.as_f64().and_then(|secs| chrono::DateTime::from_timestamp_millis((secs * 1000.0) as i64)),
+                    _ => None
+                };
+                timestamp.map(|timestamp| log.timestamp = Some(timestamp)).is_some()
+            }
+            LogField::Level => value.as_str().and_then(|s| s.parse().ok()).map(|level| log.level = Some(level)).is_some(),
+            LogField::Message => text(value).map(|message| log.message = message).is_some(),
+            LogField::DurationMs => number(value).map(|duration| log.duration_ms = Some(duration)).is_some(),
+            LogField::ServiceName => text(value).map(|s| log.service_name = Some(s)).is_some(),
+            LogField::TraceId => text(value).map(|s| log.trace_id = Some(s)).is_some(),
+            LogField::SpanId => text(value).map(|s| log.span_id = Some(s)).is_some(),
+            LogField::Host => text(value).map(|s| log.host = Some(s)).is_some(),
+            LogField::Environment => text(value).map(|s| log.environment = Some(s)).is_some(),
+            LogField::Version => text(value).map(|s| log.version = Some(s)).is_some()
+        }
+    }
+}
+
+// lifts metadata values into typed fields, e.g. `svc` into `service_name`. A promoted value is
+// removed from metadata, one that doesn't convert stays there
+#[derive(Debug, Default, Clone)]
+pub struct PromoteFields {
+    fields: Vec<(String, LogField)>
+}
+
+impl PromoteFields {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_field(mut self, path: impl Into<String>, field: LogField) -> Self {
+        self.fields.push((path.into(), field));
+        self
+    }
+}
+
+impl Transform for PromoteFields {
+    fn name(&self) -> &'static str {
+        "promote"
+    }
+
+    fn apply(&self, mut log: ParsedLog, out: &mut Vec<ParsedLog>) -> Result<(), TransformError> {
+        for (path, field) in &self.fields {
+            let promoted = match get_path(&log.metadata, path) {
+                Some(value) => {
+                    let value = value.clone();
+                    field.set(&mut log, &value)
+                }
+                None => false
+            };
+            if promoted {
+                take_path(&mut log.metadata, path);
+            }
+        }
+        out.push(log);
+        Ok(())
+    }
+}
+
+// fills `environment` and `host` on records that don't have them
+#[derive(Debug, Default, Clone)]
+pub struct SetDefaults {
+    environment: Option<String>,
+    host: Option<String>
+}
+
+impl SetDefaults {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_environment(mut self, environment: impl Into<String>) -> Self {
+        self.environment = Some(environment.into());
+        self
+    }
+
+    pub fn with_host(mut self, host: impl Into<String>) -> Self {
+        self.host = Some(host.into());
+        self
+    }
+}
+
+impl Transform for SetDefaults {
+    fn name(&self) -> &'static str {
+        "defaults"
+    }
+
+    fn apply(&self, mut log: ParsedLog, out: &mut Vec<ParsedLog>) -> Result<(), TransformError> {
+        if log.environment.is_none() {
+            log.environment = self.environment.clone();
+        }
+        if log.host.is_none() {
+            log.host = self.host.clone();
+        }
+        out.push(log);
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValueType {
+    String,
+    Integer,
+    Float,
+    Boolean
+}
+
+pub const VALUE_TYPES: [&str; 4] = ["string", "integer", "float", "boolean"];
+
+impl FromStr for ValueType {
+    type Err = LogAnalyzerError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "string" => Ok(ValueType::String),
+            "integer" | "int" => Ok(ValueType::Integer),
+            "float" | "number" => Ok(ValueType::Float),
+            "boolean" | "bool" => Ok(ValueType::Boolean),
+            other => Err(LogAnalyzerError::Config(format!("unknown value type '{}', expected one of {}", other, VALUE_TYPES.join(", "))))
+        }
+    }
+}
+
+impl ValueType {
+    fn coerce(self, value: &Value) -> Option<Value> {
+        match self {
+            ValueType::String => text(value).map(Value::String),
+            ValueType::Integer => match value {
+                Value::Number(n) if n.is_i64() || n.is_u64() => Some(value.clone()),
+                Value::Bool(b) => Some(Value::from(*b as i64)),
+                // "12.0" and 12.0 are integers, 12.5 is not
+                _ => number(value).filter(|n| n.fract() == 0.0).map(|n| Value::from(n as i64))
+            },
+            ValueType::Float => number(value).and_then(serde_json::Number::from_f64).map(Value::Number),
+            ValueType::Boolean => match value {
+                Value::Bool(_) => Some(value.clone()),
+                Value::Number(n) => n.as_f64().map(|n| Value::Bool(n != 0.0)),
+                Value::String(s) => match s.trim().to_lowercase().as_str() {
+                    "true" | "yes" | "on" | "1" => Some(Value::Bool(true)),
+                    "false" | "no" | "off" | "0" => Some(Value::Bool(false)),
+                    _ => None
+                },
+                _ => None
+            }
+        }
+    }
+}
+
+// converts metadata values, e.g. a status code logged as "404" into 404. Values that don't
+// convert are left as they are
+#[derive(Debug, Default, Clone)]
+pub struct CoerceFields {
+    fields: Vec<(String, ValueType)>
+}
+
+impl CoerceFields {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_field(mut self, path: impl Into<String>, value_type: ValueType) -> Self {
+        self.fields.push((path.into(), value_type));
+        self
+    }
+}
+
+impl Transform for CoerceFields {
+    fn name(&self) -> &'static str {
+        "coerce"
+    }
+
+    fn apply(&self, mut log: ParsedLog, out: &mut Vec<ParsedLog>) -> Result<(), TransformError> {
+        for (path, value_type) in &self.fields {
+            if let Some(value) = get_path_mut(&mut log.metadata, path) {
+                if let Some(coerced) = value_type.coerce(value) {
+                    *value = coerced;
+                }
+            }
+        }
+        out.push(log);
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transform::testing::apply;
+    use crate::parser::Level;
+    use serde_json::json;
+
+    #[test]
+    fn metadata_is_promoted_and_coerced() {
+        let log = ParsedLog { metadata: json!({ "svc": "api", "lvl": "WARNING", "took": "12.5", "http": { "status": "404", "ok": "no" }, "ts": "yesterday" }), ..ParsedLog::default() };
+
+        let promote = PromoteFields::new()
+            .with_field("svc", LogField::ServiceName)
+            .with_field("lvl", LogField::Level)
+            .with_field("took", LogField::DurationMs)
+            .with_field("ts", LogField::Timestamp);
+        let coerce = CoerceFields::new().with_field("http.status", ValueType::Integer).with_field("http.ok", ValueType::Boolean);
+        let log = apply(&coerce, apply(&promote, log));
+
+        assert_eq!((log.service_name.as_deref(), log.level, log.duration_ms), (Some("api"), Some(Level::Warn), Some(12.5)));
+        // not a timestamp, so it stays where it was
+        assert_eq!(log.metadata, json!({ "http": { "status": 404, "ok": false }, "ts": "yesterday" }));
+    }
+
+    #[test]
+    fn defaults_only_fill_missing_fields() {
+        let defaults = SetDefaults::new().with_environment("production").with_host("web-1");
+        let log = apply(&defaults, ParsedLog { host: Some("web-2".to_string()), ..ParsedLog::default() });
+        assert_eq!((log.environment.as_deref(), log.host.as_deref()), (Some("production"), Some("web-2")));
+
+        let delete = DeleteFields::new().with_field("user.password").with_field("missing");
+        let log = apply(&delete, ParsedLog { metadata: json!({ "user": { "name": "ann", "password": "x" } }), ..ParsedLog::default() });
+        assert_eq!(log.metadata, json!({ "user": { "name": "ann" } }));
+    }
+}
@@ -0,0 +1,317 @@
+use std::{net::{IpAddr, SocketAddr}, num::NonZeroUsize, path::Path, sync::{atomic::{AtomicU64, Ordering}, Mutex}};
+
+use lru::LruCache;
+use maxminddb::{geoip2, MaxMindDBError, Reader};
+use serde_json::{Map, Value};
+
+use super::{set_path, FieldRef, Transform, TransformError};
+use crate::error::LogAnalyzerError;
+use crate::parser::ParsedLog;
+
+pub const DEFAULT_CACHE_SIZE: usize = 10_000;
+
+// `1.2.3.4`, `1.2.3.4:5678`, `[2001:db8::1]:443`, or the first of `X-Forwarded-For` style lists
+pub(crate) fn client_ip(value: &str) -> Option<IpAddr> {
+    let first = value.split(',').next()?.trim();
+    first.parse::<IpAddr>().ok().or_else(|| first.parse::<SocketAddr>().ok().map(|address| address.ip()))
+}
+
+fn open(path: &Path) -> Result<Reader<Vec<u8>>, LogAnalyzerError> {
+    Reader::open_readfile(path).map_err(|e| LogAnalyzerError::Config(format!("GeoIP database {}: {}", path.display(), e)))
+}
+
+// Adds where a client IP comes from, read from local MaxMind `.mmdb` files: `country` (ISO
+// code), `country_name`, `city`, `latitude`, `longitude` and `time_zone` from a City database,
+// `asn` and `as_org` from an ASN database. Either is optional. Results are cached per address,
+// addresses the databases don't know (private ranges, for one) are left alone
+pub struct GeoIp {
+    field: FieldRef,
+    target: String,
+    city: Option<Reader<Vec<u8>>>,
+    asn: Option<Reader<Vec<u8>>>,
+    cache: Mutex<LruCache<IpAddr, Option<Value>>>,
+    enriched: AtomicU64,
+    missed: AtomicU64,
+    cache_hits: AtomicU64,
+    lookup_errors: AtomicU64
+}
+
+impl GeoIp {
+    // `field` is a record field or a metadata path, the result goes to `geo` in metadata
+    pub fn new(field: impl Into<String>) -> Self {
+        Self {
+            field: FieldRef::new(&field.into()),
+            target: "geo".to_string(),
+            city: None,
+            asn: None,
+            cache: Mutex::new(LruCache::new(NonZeroUsize::new(DEFAULT_CACHE_SIZE).expect("not zero"))),
+            enriched: AtomicU64::new(0),
+            missed: AtomicU64::new(0),
+            cache_hits: AtomicU64::new(0),
+            lookup_errors: AtomicU64::new(0)
+        }
+    }
+
+    pub fn with_city_db(mut self, path: &Path) -> Result<Self, LogAnalyzerError> {
+        self.city = Some(open(path)?);
+        Ok(self)
+    }
+
+    pub fn with_asn_db(mut self, path: &Path) -> Result<Self, LogAnalyzerError> {
+        self.asn = Some(open(path)?);
+        Ok(self)
+    }
+
+    pub fn with_target(mut self, target: impl Into<String>) -> Self {
+        self.target = target.into();
+        self
+    }
+
+    // addresses remembered, at least 1
+    pub fn with_cache_size(mut self, cache_size: usize) -> Self {
+        self.cache = Mutex::new(LruCache::new(NonZeroUsize::new(cache_size.max(1)).expect("at least 1")));
+        self
+    }
+
+    fn locate(&self, ip: IpAddr) -> Option<Value> {
+        let mut geo = Map::new();
+        let mut insert = |key: &str, value: Option<Value>| {
+            if let Some(value) = value {
+                geo.insert(key.to_string(), value);
+            }
+        };
+
+        if let Some(city) = self.city.as_ref().and_then(|reader| self.found(reader.lookup::<geoip2::City>(ip))) {
+            let english = |names: Option<std::collections::BTreeMap<&str, &str>>| names.and_then(|names| names.get("en").map(|name| Value::from(*name)));
+            if let Some(country) = city.country {
+                insert("country", country.iso_code.map(Value::from));
+                insert("country_name", english(country.names));
+            }
+            insert("city", city.city.and_then(|city| english(city.names)));
+            if let Some(location) = city.location {
+                insert("latitude", location.latitude.map(Value::from));
+                insert("longitude", location.longitude.map(Value::from));
+                insert("time_zone", location.time_zone.map(Value::from));
+            }
+        }
+        if let Some(asn) = self.asn.as_ref().and_then(|reader| self.found(reader.lookup::<geoip2::Asn>(ip))) {
+            insert("asn", asn.autonomous_system_number.map(Value::from));
+            insert("as_org", asn.autonomous_system_organization.map(Value::from));
+        }
+        (!geo.is_empty()).then_some(Value::Object(geo))
+    }
+
+    // an address that isn't in the database is the normal case, anything else is counted
+    fn found<T>(&self, result: Result<T, MaxMindDBError>) -> Option<T> {
+        match result {
+            Ok(found) => Some(found),
+            Err(MaxMindDBError::AddressNotFoundError(_)) => None,
+            Err(_) => {
+                self.lookup_errors.fetch_add(1, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+}
+
+impl Transform for GeoIp {
+    fn name(&self) -> &'static str {
+        "geoip"
+    }
+
+    fn apply(&self, mut log: ParsedLog, out: &mut Vec<ParsedLog>) -> Result<(), TransformError> {
+        let geo = self.field.text(&log).as_deref().and_then(client_ip).and_then(|ip| {
+            if let Some(cached) = self.cache.lock().unwrap().get(&ip) {
+                self.cache_hits.fetch_add(1, Ordering::Relaxed);
+                return cached.clone();
+            }
+            // looked up without the lock, two threads may both miss the same address
+            let geo = self.locate(ip);
+            self.cache.lock().unwrap().put(ip, geo.clone());
+            geo
+        });
+
+        match geo {
+            Some(geo) => {
+                set_path(&mut log.metadata, &self.target, geo);
+                self.enriched.fetch_add(1, Ordering::Relaxed);
+            }
+            None => {
+                self.missed.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        out.push(log);
+        Ok(())
+    }
+
+    fn counters(&self) -> Vec<(String, u64)> {
+        vec![
+            ("enriched".to_string(), self.enriched.load(Ordering::Relaxed)),
+            ("missed".to_string(), self.missed.load(Ordering::Relaxed)),
+            ("cache_hits".to_string(), self.cache_hits.load(Ordering::Relaxed)),
+            ("lookup_errors".to_string(), self.lookup_errors.load(Ordering::Relaxed))
+        ]
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transform::testing::apply;
+    use serde_json::json;
+    use std::path::PathBuf;
+
+    // just enough of the MaxMind DB format to write a database holding one IPv4 network
+    enum Data {
+        Str(&'static str),
+        Double(f64),
+        U16(u16),
+        U32(u32),
+        U64(u64),
+        Map(Vec<(&'static str, Data)>),
+        Array(Vec<Data>)
+    }
+
+    fn encode(data: &Data, out: &mut Vec<u8>) {
+        // type in the top 3 bits and the size in the rest, types past 7 and sizes past 28 in
+        // a byte of their own
+        let head = |kind: u8, size: usize, out: &mut Vec<u8>| {
+            let (small, extra) = if size < 29 { (size as u8, None) } else { (29, Some(u8::try_from(size - 29).unwrap())) };
+            if kind < 8 {
+                out.push(kind << 5 | small);
+            } else {
+                out.extend_from_slice(&[small, kind - 7]);
+            }
+            out.extend(extra);
+        };
+        match data {
+            Data::Str(text) => {
+                head(2, text.len(), out);
+                out.extend_from_slice(text.as_bytes());
+            }
+            Data::Double(number) => {
+                head(3, 8, out);
+                out.extend_from_slice(&number.to_be_bytes());
+            }
+            Data::U16(number) => {
+                head(5, 2, out);
+                out.extend_from_slice(&number.to_be_bytes());
+            }
+            Data::U32(number) => {
+                head(6, 4, out);
+                out.extend_from_slice(&number.to_be_bytes());
+            }
+            Data::U64(number) => {
+                head(9, 8, out);
+                out.extend_from_slice(&number.to_be_bytes());
+            }
+            Data::Map(entries) => {
+                head(7, entries.len(), out);
+                for (key, value) in entries {
+                    encode(&Data::Str(key), out);
+                    encode(value, out);
+                }
+            }
+            Data::Array(items) => {
+                head(11, items.len(), out);
+                for item in items {
+                    encode(item, out);
+                }
+            }
+        }
+    }
+
+    // one search tree node per prefix bit with 24 bit records, the branch off the network
+    // leads to `prefix` (not found), the last node to the record at the start of the data
+    fn mmdb(name: &str, network: [u8; 4], prefix: usize, record: Data) -> PathBuf {
+        let mut file = Vec::new();
+        for bit in 0..prefix {
+            let next = if bit + 1 < prefix { bit + 1 } else { prefix + 16 };
+            let (left, right) = if (network[bit / 8] >> (7 - bit % 8)) & 1 == 1 { (prefix, next) } else { (next, prefix) };
+            file.extend_from_slice(&(left as u32).to_be_bytes()[1..]);
+            file.extend_from_slice(&(right as u32).to_be_bytes()[1..]);
+        }
+        file.extend_from_slice(&[0; 16]);
+        encode(&record, &mut file);
+        file.extend_from_slice(b"\xAB\xCD\xEFMaxMind.com");
+        let metadata = Data::Map(vec![
+            ("binary_format_major_version", Data::U16(2)),
+            ("binary_format_minor_version", Data::U16(0)),
+            ("build_epoch", Data::U64(1_700_000_000)),
+            ("database_type", Data::Str("Test")),
+            ("description", Data::Map(vec![("en", Data::Str("test fixture"))])),
+            ("ip_version", Data::U16(4)),
+            ("languages", Data::Array(vec![Data::Str("en")])),
+            ("node_count", Data::U32(prefix as u32)),
+            ("record_size", Data::U16(24))
+        ]);
+        encode(&metadata, &mut file);
+
+        let path = std::env::temp_dir().join(format!("geoip-{}-{}.mmdb", name, std::process::id()));
+        std::fs::write(&path, file).unwrap();
+        path
+    }
+
+    #[test]
+    fn client_ips_come_in_many_shapes() {
+        assert_eq!(client_ip("203.0.113.9"), Some("203.0.113.9".parse().unwrap()));
+        assert_eq!(client_ip("203.0.113.9:5678"), Some("203.0.113.9".parse().unwrap()));
+        assert_eq!(client_ip("[2001:db8::1]:443"), Some("2001:db8::1".parse().unwrap()));
+        assert_eq!(client_ip(" 198.51.100.1, 10.0.0.1"), Some("198.51.100.1".parse().unwrap()));
+        assert_eq!(client_ip("unknown"), None);
+
+        // without a database nothing is found, and the record passes unchanged
+        let geoip = GeoIp::new("client.ip");
+        let mut out = Vec::new();
+        geoip.apply(ParsedLog { metadata: json!({ "client": { "ip": "203.0.113.9" } }), ..ParsedLog::default() }, &mut out).unwrap();
+        assert_eq!(out[0].metadata, json!({ "client": { "ip": "203.0.113.9" } }));
+        assert!(GeoIp::new("ip").with_city_db(Path::new("/nonexistent/GeoLite2-City.mmdb")).is_err());
+    }
+
+    #[test]
+    fn addresses_are_located_from_city_and_asn_databases() {
+        let city = mmdb("city", [89, 160, 20, 0], 24, Data::Map(vec![
+            ("country", Data::Map(vec![("iso_code", Data::Str("SE")), ("names", Data::Map(vec![("en", Data::Str("Sweden"))]))])),
+            ("city", Data::Map(vec![("names", Data::Map(vec![("en", Data::Str("Linköping"))]))])),
+            ("location", Data::Map(vec![("latitude", Data::Double(58.4167)), ("longitude", Data::Double(15.6167)), ("time_zone", Data::Str("Europe/Stockholm"))]))
+        ]));
+        let asn = mmdb("asn", [89, 160, 0, 0], 16, Data::Map(vec![
+            ("autonomous_system_number", Data::U32(29518)),
+            ("autonomous_system_organization", Data::Str("Bredband2 AB"))
+        ]));
+        // a record that isn't a city is an error, not a miss
+        let broken = mmdb("broken", [89, 160, 20, 0], 24, Data::Str("not a city"));
+
+        let geoip = GeoIp::new("ip").with_city_db(&city).unwrap().with_asn_db(&asn).unwrap();
+        let log = |ip: &str| ParsedLog { metadata: json!({ "ip": ip }), ..ParsedLog::default() };
+        assert_eq!(apply(&geoip, log("89.160.20.128")).metadata["geo"], json!({
+            "country": "SE",
+            "country_name": "Sweden",
+            "city": "Linköping",
+            "latitude": 58.4167,
+            "longitude": 15.6167,
+            "time_zone": "Europe/Stockholm",
+            "asn": 29518,
+            "as_org": "Bredband2 AB"
+        }));
+        // in the ASN network but not the city's
+        assert_eq!(apply(&geoip, log("89.160.99.1")).metadata["geo"], json!({ "asn": 29518, "as_org": "Bredband2 AB" }));
+        assert_eq!(apply(&geoip, log("10.0.0.1")).metadata.get("geo"), None);
+        apply(&geoip, log("89.160.20.128"));
+        assert_eq!(geoip.counters(), vec![
+            ("enriched".to_string(), 3),
+            ("missed".to_string(), 1),
+            ("cache_hits".to_string(), 1),
+            ("lookup_errors".to_string(), 0)
+        ]);
+
+        let geoip = GeoIp::new("ip").with_city_db(&broken).unwrap();
+        assert_eq!(apply(&geoip, log("89.160.20.128")).metadata.get("geo"), None);
+        assert_eq!(geoip.counters()[3], ("lookup_errors".to_string(), 1));
+
+        for path in [city, asn, broken] {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+}
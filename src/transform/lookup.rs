@@ -0,0 +1,371 @@
+use std::{collections::HashMap, net::IpAddr, path::{Path, PathBuf}, sync::{atomic::{AtomicU64, Ordering}, Arc, Mutex, RwLock}, time::{Duration, Instant, SystemTime}};
+
+use ipnet::IpNet;
+use regex::Regex;
+use serde_json::{Map, Value};
+
+use super::{fields::text, set_path, FieldRef, Transform, TransformError};
+use crate::error::LogAnalyzerError;
+use crate::parser::ParsedLog;
+
+pub const DEFAULT_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+// what a key cell of the table matches
+enum KeyPattern {
+    Exact(String),
+    // `*` alone
+    Any,
+    // `web-*`, `?` is one character
+    Wildcard(Regex),
+    // `10.0.0.0/8`, matches addresses inside the range
+    Cidr(IpNet)
+}
+
+impl KeyPattern {
+    fn parse(cell: &str) -> Self {
+        if cell == "*" {
+            return KeyPattern::Any;
+        }
+        if let Ok(net) = cell.parse::<IpNet>() {
+            return KeyPattern::Cidr(net);
+        }
+        if cell.contains(['*', '?']) {
+            let pattern = regex::escape(cell).replace(r"\*", ".*").replace(r"\?", ".");
+            return KeyPattern::Wildcard(Regex::new(&format!("^{}$", pattern)).expect("escaped patterns compile"));
+        }
+        KeyPattern::Exact(cell.to_string())
+    }
+
+    fn matches(&self, value: &str) -> bool {
+        match self {
+            KeyPattern::Exact(exact) => exact == value,
+            KeyPattern::Any => true,
+            KeyPattern::Wildcard(pattern) => pattern.is_match(value),
+            KeyPattern::Cidr(net) => value.parse::<IpAddr>().is_ok_and(|ip| net.contains(&ip))
+        }
+    }
+}
+
+struct Row {
+    keys: Vec<KeyPattern>,
+    columns: Map<String, Value>
+}
+
+// rows whose keys are all exact are found by hash, the others are tried in file order
+#[derive(Default)]
+struct Table {
+    rows: Vec<Row>,
+    exact: HashMap<Vec<String>, usize>,
+    patterns: Vec<usize>
+}
+
+impl Table {
+    // CSV with a header line, or a JSON array of objects for files ending in `.json`
+    fn load(path: &Path, key_columns: &[String]) -> Result<Self, LogAnalyzerError> {
+        let error = |e: &dyn std::fmt::Display| LogAnalyzerError::Config(format!("lookup table {}: {}", path.display(), e));
+        let records = if path.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("json")) {
+            let text = std::fs::read_to_string(path).map_err(LogAnalyzerError::Io)?;
+            match serde_json::from_str::<Value>(&text).map_err(|e| error(&e))? {
+                Value::Array(records) => records.into_iter()
+                    .map(|record| match record {
+                        Value::Object(record) => Ok(record),
+                        other => Err(error(&format!("rows have to be objects, not {}", other)))
+                    })
+                    .collect::<Result<Vec<_>, _>>()?,
+                _ => return Err(error(&"expected an array of objects"))
+            }
+        } else {
+            let mut reader = csv::ReaderBuilder::new().trim(csv::Trim::All).from_path(path).map_err(|e| error(&e))?;
+            let headers = reader.headers().map_err(|e| error(&e))?.clone();
+            let mut records = Vec::new();
+            for record in reader.records() {
+                let record = record.map_err(|e| error(&e))?;
+                // an empty cell is a column the row has nothing for
+                records.push(headers.iter().zip(record.iter())
+                    .filter(|(_, cell)| !cell.is_empty())
+                    .map(|(header, cell)| (header.to_string(), Value::String(cell.to_string())))
+                    .collect::<Map<_, _>>());
+            }
+            records
+        };
+
+        let mut table = Table::default();
+        for (index, mut columns) in records.into_iter().enumerate() {
+            let cells = key_columns.iter()
+                .map(|column| columns.remove(column).as_ref().and_then(text)
+                    .ok_or_else(|| error(&format!("row {} has no key column '{}'", index + 1, column))))
+                .collect::<Result<Vec<_>, _>>()?;
+            let keys = cells.iter().map(|cell| KeyPattern::parse(cell)).collect::<Vec<_>>();
+
+            if keys.iter().all(|key| matches!(key, KeyPattern::Exact(_))) {
+                // the first row with a key wins, like the first matching pattern does
+                table.exact.entry(cells).or_insert(table.rows.len());
+            } else {
+                table.patterns.push(table.rows.len());
+            }
+            table.rows.push(Row { keys, columns });
+        }
+        Ok(table)
+    }
+
+    fn find(&self, values: &[String]) -> Option<&Row> {
+        if let Some(&index) = self.exact.get(values) {
+            return Some(&self.rows[index]);
+        }
+        self.patterns.iter()
+            .map(|&index| &self.rows[index])
+            .find(|row| row.keys.iter().zip(values).all(|(key, value)| key.matches(value)))
+    }
+}
+
+// when and what the table file was at the last check, a changed size counts as a change even
+// within the file system's timestamp resolution
+struct Loaded {
+    checked: Instant,
+    modified: Option<(SystemTime, u64)>,
+    // a check is running in the background
+    checking: bool
+}
+
+fn modified(path: &Path) -> Option<(SystemTime, u64)> {
+    std::fs::metadata(path).ok().and_then(|metadata| Some((metadata.modified().ok()?, metadata.len())))
+}
+
+// Joins records against a reference table, e.g. `host` to datacenter and owning team. The
+// record's key fields are looked up in the table's key columns and the other columns of the
+// matching row are written to metadata. Key cells can be `*`, wildcards like `web-*` or CIDR
+// ranges like `10.0.0.0/8`; exact keys win, then the first matching row in the file, so narrower
+// ranges go first. The file is checked for changes every few seconds and reloaded in the
+// background, records meanwhile use the table they have. A table that fails to load leaves the
+// previous one in place and counts as a reload error
+pub struct Lookup {
+    // record field or metadata path, and the table column it is looked up in
+    keys: Vec<FieldRef>,
+    columns: Option<Vec<String>>,
+    target: Option<String>,
+    check_interval: Duration,
+    table: Arc<TableFile>,
+    matched: AtomicU64,
+    missed: AtomicU64
+}
+
+// the table and its file, shared with the background checks
+struct TableFile {
+    path: PathBuf,
+    key_columns: Vec<String>,
+    table: RwLock<Arc<Table>>,
+    loaded: Mutex<Loaded>,
+    reloads: AtomicU64,
+    reload_errors: AtomicU64
+}
+
+impl TableFile {
+    fn check(&self) {
+        let current = modified(&self.path);
+        let changed = {
+            let mut loaded = self.loaded.lock().unwrap();
+            let changed = current.is_some() && current != loaded.modified;
+            // a half-written file is loaded again with the write that completes it
+            loaded.modified = current;
+            if !changed {
+                loaded.checking = false;
+            }
+            changed
+        };
+        if !changed {
+            return;
+        }
+
+        match Table::load(&self.path, &self.key_columns) {
+            Ok(table) => {
+                *self.table.write().unwrap() = Arc::new(table);
+                self.reloads.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(_) => {
+                self.reload_errors.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.loaded.lock().unwrap().checking = false;
+    }
+}
+
+impl Lookup {
+    // a key field is a record field like `host` or `service_name`, anything else is a metadata path
+    pub fn new(path: impl Into<PathBuf>, keys: Vec<(String, String)>) -> Result<Self, LogAnalyzerError> {
+        let path = path.into();
+        if keys.is_empty() {
+            return Err(LogAnalyzerError::Config(format!("lookup table {}: no key fields", path.display())));
+        }
+        let (keys, key_columns): (Vec<_>, Vec<_>) = keys.into_iter().map(|(field, column)| (FieldRef::new(&field), column)).unzip();
+        let modified = modified(&path);
+        let table = Table::load(&path, &key_columns)?;
+        Ok(Self {
+            keys,
+            columns: None,
+            target: None,
+            check_interval: DEFAULT_CHECK_INTERVAL,
+            table: Arc::new(TableFile {
+                path,
+                key_columns,
+                table: RwLock::new(Arc::new(table)),
+                loaded: Mutex::new(Loaded { checked: Instant::now(), modified, checking: false }),
+                reloads: AtomicU64::new(0),
+                reload_errors: AtomicU64::new(0)
+            }),
+            matched: AtomicU64::new(0),
+            missed: AtomicU64::new(0)
+        })
+    }
+
+    // only these columns are copied, all but the key columns when not given
+    pub fn with_columns(mut self, columns: Vec<String>) -> Self {
+        self.columns = Some(columns);
+        self
+    }
+
+    // the metadata path the columns are written under, the top level when not given
+    pub fn with_target(mut self, target: impl Into<String>) -> Self {
+        self.target = Some(target.into());
+        self
+    }
+
+    // how often the file is checked for changes
+    pub fn with_check_interval(mut self, check_interval: Duration) -> Self {
+        self.check_interval = check_interval;
+        self
+    }
+
+    // starts a check once the interval is up and none is running, on tokio's blocking threads
+    // when there is a runtime
+    fn refresh(&self) {
+        {
+            let Ok(mut loaded) = self.table.loaded.try_lock() else {
+                return;
+            };
+            if loaded.checking || loaded.checked.elapsed() < self.check_interval {
+                return;
+            }
+            loaded.checked = Instant::now();
+            loaded.checking = true;
+        }
+
+        let table = self.table.clone();
+        match tokio::runtime::Handle::try_current() {
+            Ok(runtime) => drop(runtime.spawn_blocking(move || table.check())),
+            Err(_) => table.check()
+        }
+    }
+}
+
+impl Transform for Lookup {
+    fn name(&self) -> &'static str {
+        "lookup"
+    }
+
+    fn apply(&self, mut log: ParsedLog, out: &mut Vec<ParsedLog>) -> Result<(), TransformError> {
+        self.refresh();
+        let values = self.keys.iter().map(|field| field.text(&log)).collect::<Option<Vec<_>>>();
+        let table = self.table.table.read().unwrap().clone();
+
+        match values.as_deref().and_then(|values| table.find(values)) {
+            Some(row) => {
+                for (column, value) in &row.columns {
+                    if self.columns.as_ref().is_some_and(|columns| !columns.contains(column)) {
+                        continue;
+                    }
+                    let path = match &self.target {
+                        Some(target) => format!("{}.{}", target, column),
+                        None => column.clone()
+                    };
+                    set_path(&mut log.metadata, &path, value.clone());
+                }
+                self.matched.fetch_add(1, Ordering::Relaxed);
+            }
+            None => {
+                self.missed.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        out.push(log);
+        Ok(())
+    }
+
+    fn counters(&self) -> Vec<(String, u64)> {
+        vec![
+            ("matched".to_string(), self.matched.load(Ordering::Relaxed)),
+            ("missed".to_string(), self.missed.load(Ordering::Relaxed)),
+            ("reloads".to_string(), self.table.reloads.load(Ordering::Relaxed)),
+            ("reload_errors".to_string(), self.table.reload_errors.load(Ordering::Relaxed))
+        ]
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transform::testing::apply;
+    use serde_json::json;
+
+    fn client(ip: &str) -> ParsedLog {
+        ParsedLog { metadata: json!({ "client": { "ip": ip } }), ..ParsedLog::new("request".to_string()) }
+    }
+
+    #[test]
+    fn exact_wildcard_and_cidr_keys() {
+        let path = std::env::temp_dir().join(format!("loganalyzer-lookup-{}.csv", std::process::id()));
+        std::fs::write(&path, "host,ip,datacenter,team\n\
+                               web-1,*,fra1,web\n\
+                               web-*,*,ams3,web\n\
+                               *,10.1.0.0/16,fra1,\n\
+                               *,10.0.0.0/8,internal,platform\n").unwrap();
+        let lookup = Lookup::new(&path, vec![("host".to_string(), "host".to_string()), ("client.ip".to_string(), "ip".to_string())]).unwrap()
+            .with_target("site");
+
+        let log = apply(&lookup, ParsedLog { host: Some("web-1".to_string()), ..client("1.2.3.4") });
+        assert_eq!(log.metadata["site"], json!({ "datacenter": "fra1", "team": "web" }));
+        let log = apply(&lookup, ParsedLog { host: Some("web-7".to_string()), ..client("1.2.3.4") });
+        assert_eq!(log.metadata["site"]["datacenter"], "ams3");
+        // the narrower range comes first, and an empty cell isn't written
+        let log = apply(&lookup, ParsedLog { host: Some("db-1".to_string()), ..client("10.1.2.3") });
+        assert_eq!(log.metadata["site"], json!({ "datacenter": "fra1" }));
+        let log = apply(&lookup, ParsedLog { host: Some("db-1".to_string()), ..client("192.168.0.1") });
+        assert!(log.metadata.get("site").is_none());
+
+        assert_eq!(lookup.counters()[..2], [("matched".to_string(), 3), ("missed".to_string(), 1)]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    // the checks run in the background, one may still be looking at the file from before the
+    // last write
+    async fn wait_for(lookup: &Lookup, counter: &str, value: u64) {
+        let counted = || lookup.counters().into_iter().any(|(name, count)| name == counter && count >= value);
+        tokio::time::timeout(Duration::from_secs(2), async {
+            while !counted() {
+                lookup.refresh();
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        }).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn a_changed_table_is_reloaded() {
+        let path = std::env::temp_dir().join(format!("loganalyzer-lookup-{}.json", std::process::id()));
+        std::fs::write(&path, r#"[{ "code": "E42", "runbook": "https://runbooks/e42" }]"#).unwrap();
+        let lookup = Lookup::new(&path, vec![("error.code".to_string(), "code".to_string())]).unwrap()
+            .with_check_interval(Duration::ZERO);
+        let error = |code: &str| ParsedLog { metadata: json!({ "error": { "code": code } }), ..ParsedLog::default() };
+
+        assert_eq!(apply(&lookup, error("E42")).metadata["runbook"], "https://runbooks/e42");
+        std::fs::write(&path, r#"[{ "code": "E42", "runbook": "https://runbooks/e42-v2" }, { "code": "E43", "runbook": "https://runbooks/e43" }]"#).unwrap();
+        wait_for(&lookup, "reloads", 1).await;
+        assert_eq!(apply(&lookup, error("E42")).metadata["runbook"], "https://runbooks/e42-v2");
+
+        // a broken file keeps the last good table
+        std::fs::write(&path, "[{").unwrap();
+        wait_for(&lookup, "reload_errors", 1).await;
+        assert_eq!(apply(&lookup, error("E43")).metadata["runbook"], "https://runbooks/e43");
+        // a check may also have caught the file truncated in the middle of the write
+        assert_eq!(lookup.counters()[2], ("reloads".to_string(), 1));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,267 @@
+use std::{collections::{hash_map::DefaultHasher, HashMap}, hash::{Hash, Hasher}, num::NonZeroUsize, sync::{atomic::{AtomicU64, Ordering}, Mutex}, time::Instant};
+
+use lru::LruCache;
+use serde_json::Value;
+
+use super::{get_path, set_path, FieldRef, Transform, TransformError};
+use crate::parser::{Level, ParsedLog};
+
+pub const DEFAULT_MAX_KEYS: usize = 10_000;
+
+// where the rate a record was kept at goes, analytics count such a record 1 / rate times
+pub const SAMPLE_RATE_FIELD: &str = "sample_rate";
+
+// the rate earlier sampling kept the record at, 1 when it wasn't sampled
+pub fn sample_rate(log: &ParsedLog) -> f64 {
+    get_path(&log.metadata, SAMPLE_RATE_FIELD)
+        .and_then(Value::as_f64)
+        .filter(|rate| *rate > 0.0 && *rate <= 1.0)
+        .unwrap_or(1.0)
+}
+
+// splitmix64, good enough to pick records and without a dependency
+fn next_random(state: &AtomicU64) -> u64 {
+    let mut z = state.fetch_add(0x9e37_79b9_7f4a_7c15, Ordering::Relaxed).wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+// below `rate` for that share of the inputs, 0.0 to 1.0
+fn unit(random: u64) -> f64 {
+    (random >> 11) as f64 / (1u64 << 53) as f64
+}
+
+// Keeps a share of the records by level, and per `service_name` where a service has rates of its
+// own, e.g. all errors, 10% of info and 1% of debug. Levels without a rate are kept whole. Records
+// of one trace are kept or dropped together. A kept record that was sampled carries its rate in
+// `metadata.sample_rate`, multiplied with the rate of any sampling before
+pub struct Sample {
+    // (service, level) -> rate, None stands for every service or level
+    rates: HashMap<(Option<String>, Option<Level>), f64>,
+    random: AtomicU64,
+    kept: AtomicU64,
+    sampled_out: AtomicU64
+}
+
+impl Default for Sample {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Sample {
+    pub fn new() -> Self {
+        let seed = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_nanos() as u64);
+        Self { rates: HashMap::new(), random: AtomicU64::new(seed), kept: AtomicU64::new(0), sampled_out: AtomicU64::new(0) }
+    }
+
+    // the same seed picks the same records
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.random = AtomicU64::new(seed);
+        self
+    }
+
+    // None for records of any level, or without one. Rates are clamped to 0.0 to 1.0
+    pub fn with_rate(self, level: Option<Level>, rate: f64) -> Self {
+        self.with_rates(None, level, rate)
+    }
+
+    // a service's rates go before the general ones, level by level
+    pub fn with_service_rate(self, service: impl Into<String>, level: Option<Level>, rate: f64) -> Self {
+        self.with_rates(Some(service.into()), level, rate)
+    }
+
+    fn with_rates(mut self, service: Option<String>, level: Option<Level>, rate: f64) -> Self {
+        self.rates.insert((service, level), rate.clamp(0.0, 1.0));
+        self
+    }
+
+    fn rate(&self, log: &ParsedLog) -> f64 {
+        let service = log.service_name.clone();
+        let level = log.level.clone();
+        [(service.clone(), level.clone()), (service, None), (None, level), (None, None)].iter()
+            .find_map(|key| self.rates.get(key))
+            .copied()
+            .unwrap_or(1.0)
+    }
+}
+
+impl Transform for Sample {
+    fn name(&self) -> &'static str {
+        "sample"
+    }
+
+    fn apply(&self, mut log: ParsedLog, out: &mut Vec<ParsedLog>) -> Result<(), TransformError> {
+        let rate = self.rate(&log);
+        if rate >= 1.0 {
+            self.kept.fetch_add(1, Ordering::Relaxed);
+            out.push(log);
+            return Ok(());
+        }
+
+        let random = match &log.trace_id {
+            Some(trace_id) => {
+                let mut hasher = DefaultHasher::new();
+                trace_id.hash(&mut hasher);
+                hasher.finish()
+            }
+            None => next_random(&self.random)
+        };
+        if unit(random) >= rate {
+            self.sampled_out.fetch_add(1, Ordering::Relaxed);
+            return Ok(());
+        }
+
+        let rate = sample_rate(&log) * rate;
+        set_path(&mut log.metadata, SAMPLE_RATE_FIELD, Value::from(rate));
+        self.kept.fetch_add(1, Ordering::Relaxed);
+        out.push(log);
+        Ok(())
+    }
+
+    fn counters(&self) -> Vec<(String, u64)> {
+        vec![
+            ("kept".to_string(), self.kept.load(Ordering::Relaxed)),
+            ("sampled_out".to_string(), self.sampled_out.load(Ordering::Relaxed))
+        ]
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant
+}
+
+// Token buckets per key, e.g. per `service_name`: each key passes `rate` records a second and
+// bursts of up to `burst`, the records beyond that are dropped. Without key fields there is one
+// bucket for everything; as a source's own transform that makes it a limit per source. At most
+// `max_keys` buckets are kept, the least recently used one goes first
+pub struct RateLimit {
+    rate: f64,
+    burst: f64,
+    fields: Vec<FieldRef>,
+    buckets: Mutex<LruCache<Vec<Option<String>>, Bucket>>,
+    passed: AtomicU64,
+    limited: AtomicU64
+}
+
+impl RateLimit {
+    // records a second, and a burst of as many
+    pub fn new(rate: f64) -> Self {
+        let rate = rate.max(0.0);
+        Self {
+            rate,
+            burst: rate.max(1.0),
+            fields: Vec::new(),
+            buckets: Mutex::new(LruCache::new(NonZeroUsize::new(DEFAULT_MAX_KEYS).expect("not zero"))),
+            passed: AtomicU64::new(0),
+            limited: AtomicU64::new(0)
+        }
+    }
+
+    // at least 1
+    pub fn with_burst(mut self, burst: f64) -> Self {
+        self.burst = burst.max(1.0);
+        self
+    }
+
+    // record fields like `service_name` or metadata paths, each combination gets its own bucket
+    pub fn with_key(mut self, fields: Vec<String>) -> Self {
+        self.fields = fields.iter().map(|field| FieldRef::new(field)).collect();
+        self
+    }
+
+    // at least 1
+    pub fn with_max_keys(mut self, max_keys: usize) -> Self {
+        self.buckets = Mutex::new(LruCache::new(NonZeroUsize::new(max_keys.max(1)).expect("at least 1")));
+        self
+    }
+
+    fn take(&self, key: Vec<Option<String>>, now: Instant) -> bool {
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.get_or_insert_mut(key, || Bucket { tokens: self.burst, updated: now });
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+impl Transform for RateLimit {
+    fn name(&self) -> &'static str {
+        "rate_limit"
+    }
+
+    fn apply(&self, log: ParsedLog, out: &mut Vec<ParsedLog>) -> Result<(), TransformError> {
+        let key = self.fields.iter().map(|field| field.text(&log)).collect();
+        if self.take(key, Instant::now()) {
+            self.passed.fetch_add(1, Ordering::Relaxed);
+            out.push(log);
+        } else {
+            self.limited.fetch_add(1, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    fn counters(&self) -> Vec<(String, u64)> {
+        vec![
+            ("passed".to_string(), self.passed.load(Ordering::Relaxed)),
+            ("limited".to_string(), self.limited.load(Ordering::Relaxed))
+        ]
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn levels_are_sampled_at_their_rates() {
+        let sample = Sample::new().with_seed(7)
+            .with_rate(Some(Level::Info), 0.1)
+            .with_rate(Some(Level::Debug), 0.01)
+            .with_service_rate("checkout", Some(Level::Info), 0.5);
+
+        let mut out = Vec::new();
+        for (level, service) in [(Level::Error, "api"), (Level::Info, "api"), (Level::Debug, "api"), (Level::Info, "checkout")] {
+            for _ in 0..10_000 {
+                let log = ParsedLog { service_name: Some(service.to_string()), ..ParsedLog::new("m".to_string()).with_level(level.clone()) };
+                sample.apply(log, &mut out).unwrap();
+            }
+        }
+
+        let kept = |level: Level, service: &str| out.iter().filter(|log| log.level == Some(level.clone()) && log.service_name.as_deref() == Some(service)).count();
+        assert_eq!(kept(Level::Error, "api"), 10_000);
+        assert!((900..1100).contains(&kept(Level::Info, "api")), "{}", kept(Level::Info, "api"));
+        assert!((60..140).contains(&kept(Level::Debug, "api")), "{}", kept(Level::Debug, "api"));
+        assert!((4700..5300).contains(&kept(Level::Info, "checkout")), "{}", kept(Level::Info, "checkout"));
+
+        // errors weren't sampled, so they don't carry a rate
+        let debug = out.iter().find(|log| log.level == Some(Level::Debug)).unwrap();
+        assert_eq!((sample_rate(&out[0]), sample_rate(debug)), (1.0, 0.01));
+        assert!(out[0].metadata.get(SAMPLE_RATE_FIELD).is_none());
+    }
+
+    #[test]
+    fn buckets_refill_per_key() {
+        let limit = RateLimit::new(10.0).with_burst(2.0).with_key(vec!["service_name".to_string()]);
+        let start = Instant::now();
+        let key = |service: &str| vec![Some(service.to_string())];
+
+        assert!(limit.take(key("api"), start) && limit.take(key("api"), start));
+        assert!(!limit.take(key("api"), start));
+        // another key has a bucket of its own
+        assert!(limit.take(key("web"), start));
+        // a tenth of a second later one more token is there
+        assert!(limit.take(key("api"), start + Duration::from_millis(100)));
+        assert!(!limit.take(key("api"), start + Duration::from_millis(100)));
+    }
+}
@@ -0,0 +1,256 @@
+use std::{cell::Cell, path::Path, sync::{atomic::{AtomicU64, Ordering}, Arc}, time::{Duration, Instant}};
+
+use rhai::{Dynamic, Scope, AST};
+use serde_json::{json, Map, Value};
+
+use super::{Transform, TransformError};
+use crate::error::LogAnalyzerError;
+use crate::parser::ParsedLog;
+
+pub const DEFAULT_MAX_OPERATIONS: u64 = 100_000;
+pub const DEFAULT_SCRIPT_TIMEOUT: Duration = Duration::from_millis(50);
+
+// checking the clock on every operation would cost more than the script itself
+const CLOCK_EVERY: u64 = 256;
+
+thread_local! {
+    // scripts run to completion on the thread that started them, so one deadline per thread is
+    // enough even with every source task sharing the same transform
+    static DEADLINE: Cell<Option<Instant>> = const { Cell::new(None) };
+}
+
+// Runs a Rhai script per record. The record is the object map `record` with the fields of a
+// parsed log (`message`, `level`, `timestamp` as RFC 3339, ..., `metadata`), the script changes
+// it in place. What the script returns decides what happens next:
+//   nothing or `true`  the changed `record` goes on
+//   `false`            the record is dropped
+//   a map              replaces the record
+//   an array of maps   one record each, none for an empty array
+// Scripts can't reach the file system or the network, `print` and `debug` calls are only counted
+// (see `counters`), there's no terminal to write to in a running pipeline. A script that
+// fails, runs out of operations or time, or returns something that isn't a record sends the
+// record to the dead letters.
+pub struct ScriptTransform {
+    engine: rhai::Engine,
+    ast: AST,
+    timeout: Duration,
+    output: Arc<Output>
+}
+
+// calls of `print` and `debug`
+#[derive(Default)]
+struct Output {
+    print: AtomicU64,
+    debug: AtomicU64
+}
+
+impl ScriptTransform {
+    pub fn new(script: &str) -> Result<Self, LogAnalyzerError> {
+        let output = Arc::new(Output::default());
+        let engine = Self::engine(DEFAULT_MAX_OPERATIONS, output.clone());
+        let ast = engine.compile(script).map_err(|e| LogAnalyzerError::Script(e.to_string()))?;
+        Ok(Self { engine, ast, timeout: DEFAULT_SCRIPT_TIMEOUT, output })
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self, LogAnalyzerError> {
+        let script = std::fs::read_to_string(path).map_err(LogAnalyzerError::Io)?;
+        Self::new(&script).map_err(|e| LogAnalyzerError::Script(format!("{}: {}", path.display(), e)))
+    }
+
+    // operations one call may take, roughly one per expression evaluated
+    pub fn with_max_operations(mut self, max_operations: u64) -> Self {
+        self.engine.set_max_operations(max_operations);
+        self
+    }
+
+    // wall clock time one call may take
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    fn engine(max_operations: u64, output: Arc<Output>) -> rhai::Engine {
+        let mut engine = rhai::Engine::new();
+        engine.set_max_operations(max_operations)
+            .set_max_call_levels(32)
+            .set_max_expr_depths(64, 32)
+            .set_max_string_size(1024 * 1024)
+            .set_max_array_size(10_000)
+            .set_max_map_size(10_000);
+        let printed = output.clone();
+        engine.on_print(move |_| { printed.print.fetch_add(1, Ordering::Relaxed); });
+        engine.on_debug(move |_, _, _| { output.debug.fetch_add(1, Ordering::Relaxed); });
+        engine.on_progress(|operations| {
+            if !operations.is_multiple_of(CLOCK_EVERY) {
+                return None;
+            }
+            match DEADLINE.get() {
+                Some(deadline) if Instant::now() > deadline => Some(Dynamic::from("timeout")),
+                _ => None
+            }
+        });
+        engine
+    }
+
+    fn run(&self, log: &ParsedLog) -> Result<Vec<ParsedLog>, LogAnalyzerError> {
+        let record = rhai::serde::to_dynamic(to_record(log)).map_err(|e| LogAnalyzerError::Script(e.to_string()))?;
+        let mut scope = Scope::new();
+        scope.push("record", record);
+
+        DEADLINE.set(Some(Instant::now() + self.timeout));
+        let result = self.engine.eval_ast_with_scope::<Dynamic>(&mut scope, &self.ast);
+        DEADLINE.set(None);
+        let result = result.map_err(|e| match *e {
+            rhai::EvalAltResult::ErrorTerminated(..) => LogAnalyzerError::Script(format!("took longer than {:?}", self.timeout)),
+            e => LogAnalyzerError::Script(e.to_string())
+        })?;
+
+        let records = if result.is_unit() || result.as_bool() == Ok(true) {
+            vec![scope.get_value::<Dynamic>("record").unwrap_or_default()]
+        } else if result.as_bool() == Ok(false) {
+            Vec::new()
+        } else if result.is_array() {
+            result.into_array().unwrap_or_default()
+        } else {
+            vec![result]
+        };
+
+        records.into_iter().map(|record| {
+            let record = rhai::serde::from_dynamic::<Value>(&record).map_err(|e| LogAnalyzerError::Script(e.to_string()))?;
+            from_record(record)
+        }).collect()
+    }
+}
+
+impl Transform for ScriptTransform {
+    fn name(&self) -> &'static str {
+        "script"
+    }
+
+    fn apply(&self, log: ParsedLog, out: &mut Vec<ParsedLog>) -> Result<(), TransformError> {
+        match self.run(&log) {
+            Ok(records) => {
+                out.extend(records);
+                Ok(())
+            }
+            Err(e) => Err(TransformError::new(self.name(), log, e))
+        }
+    }
+
+    fn counters(&self) -> Vec<(String, u64)> {
+        vec![
+            ("print".to_string(), self.output.print.load(Ordering::Relaxed)),
+            ("debug".to_string(), self.output.debug.load(Ordering::Relaxed))
+        ]
+    }
+}
+
+fn to_record(log: &ParsedLog) -> Value {
+    json!({
+        "timestamp": log.timestamp.map(|timestamp| timestamp.to_rfc3339()),
+        "level": log.level.as_ref().map(|level| level.as_str()),
+        "message": log.message,
+        "metadata": log.metadata,
+        "service_name": log.service_name,
+        "trace_id": log.trace_id,
+        "span_id": log.span_id,
+        "duration_ms": log.duration_ms,
+        "host": log.host,
+        "environment": log.environment,
+        "version": log.version
+    })
+}
+
+fn text(record: &mut Map<String, Value>, field: &str) -> Result<Option<String>, LogAnalyzerError> {
+    match record.remove(field) {
+        None | Some(Value::Null) => Ok(None),
+        Some(Value::String(s)) => Ok(Some(s)),
+        Some(other) => Err(LogAnalyzerError::Script(format!("`{}` has to be a string, not {}", field, other)))
+    }
+}
+
+fn from_record(record: Value) -> Result<ParsedLog, LogAnalyzerError> {
+    let Value::Object(mut record) = record else {
+        return Err(LogAnalyzerError::Script(format!("a record has to be a map, not {}", record)));
+    };
+
+    let timestamp = text(&mut record, "timestamp")?
+        .map(|timestamp| chrono::DateTime::parse_from_rfc3339(&timestamp)
+            .map(|timestamp| timestamp.with_timezone(&chrono::Utc))
+            .map_err(|e| LogAnalyzerError::Script(format!("`timestamp` '{}': {}", timestamp, e))))
+        .transpose()?;
+    let level = text(&mut record, "level")?
+        .map(|level| level.parse().map_err(|e: LogAnalyzerError| LogAnalyzerError::Script(format!("`level`: {}", e))))
+        .transpose()?;
+    let duration_ms = match record.remove("duration_ms") {
+        None | Some(Value::Null) => None,
+        Some(Value::Number(n)) => n.as_f64(),
+        Some(other) => return Err(LogAnalyzerError::Script(format!("`duration_ms` has to be a number, not {}", other)))
+    };
+
+    let log = ParsedLog {
+        timestamp,
+        level,
+        message: text(&mut record, "message")?.unwrap_or_default(),
+        metadata: record.remove("metadata").unwrap_or(Value::Null),
+        service_name: text(&mut record, "service_name")?,
+        trace_id: text(&mut record, "trace_id")?,
+        span_id: text(&mut record, "span_id")?,
+        duration_ms,
+        host: text(&mut record, "host")?,
+        environment: text(&mut record, "environment")?,
+        version: text(&mut record, "version")?
+    };
+    match record.keys().next() {
+        Some(unknown) => Err(LogAnalyzerError::Script(format!("unknown record field '{}'", unknown))),
+        None => Ok(log)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transform::testing::apply_all;
+    use crate::parser::Level;
+
+    #[test]
+    fn scripts_change_drop_and_emit_records() {
+        let script = ScriptTransform::new(r#"
+            print(record.message);
+            if record.message.contains("health") { return false; }
+            record.metadata.customer = "acme";
+            if record.level == "error" { record.service_name = "billing"; }
+            if record.metadata.items != () {
+                return record.metadata.items.map(|item| #{ message: item, level: "info" });
+            }
+        "#).unwrap();
+
+        let logs = apply_all(&script, ParsedLog { metadata: json!({ "id": 7 }), ..ParsedLog::new("paid".to_string()).with_level(Level::Error) }).unwrap();
+        assert_eq!(logs[0].metadata, json!({ "id": 7, "customer": "acme" }));
+        assert_eq!((logs[0].service_name.as_deref(), logs[0].level.clone()), (Some("billing"), Some(Level::Error)));
+
+        assert!(apply_all(&script, ParsedLog::new("health check".to_string())).unwrap().is_empty());
+
+        let logs = apply_all(&script, ParsedLog { metadata: json!({ "items": ["a", "b"] }), ..ParsedLog::default() }).unwrap();
+        assert_eq!(logs.iter().map(|log| log.message.as_str()).collect::<Vec<_>>(), vec!["a", "b"]);
+        assert_eq!(script.counters(), vec![("print".to_string(), 3), ("debug".to_string(), 0)]);
+    }
+
+    #[test]
+    fn failing_and_runaway_scripts_hand_the_record_back() {
+        let failing = ScriptTransform::new("record.level = \"loud\";").unwrap();
+        let failed = apply_all(&failing, ParsedLog::new("one".to_string())).unwrap_err();
+        assert_eq!(failed.log.message, "one");
+        assert!(failed.error.to_string().contains("unknown log level"), "{}", failed.error);
+
+        let runaway = ScriptTransform::new("loop {}").unwrap().with_max_operations(1000);
+        assert!(apply_all(&runaway, ParsedLog::new("two".to_string())).is_err());
+
+        let slow = ScriptTransform::new("loop {}").unwrap().with_max_operations(0).with_timeout(Duration::from_millis(20));
+        let failed = apply_all(&slow, ParsedLog::new("three".to_string())).unwrap_err();
+        assert!(failed.error.to_string().contains("took longer"), "{}", failed.error);
+
+        assert!(ScriptTransform::new("let x = ;").is_err());
+    }
+}
@@ -0,0 +1,83 @@
+use serde_json::Value;
+
+use super::{get_path, set_path, take_path, Transform, TransformError};
+use crate::parser::ParsedLog;
+
+// turns a record carrying an array into one record per element, e.g. a batch of events logged
+// as one line. Each copy has the element at the array's path, objects are merged into metadata
+// with `merge`. Records without a non-empty array there pass unchanged
+#[derive(Debug, Clone)]
+pub struct SplitRecords {
+    path: String,
+    merge: bool
+}
+
+impl SplitRecords {
+    pub fn new(path: impl Into<String>) -> Self {
+        Self { path: path.into(), merge: false }
+    }
+
+    // the keys of an object element go to the top level of metadata instead of the array's path
+    pub fn with_merge(mut self, merge: bool) -> Self {
+        self.merge = merge;
+        self
+    }
+}
+
+impl Transform for SplitRecords {
+    fn name(&self) -> &'static str {
+        "split"
+    }
+
+    fn apply(&self, log: ParsedLog, out: &mut Vec<ParsedLog>) -> Result<(), TransformError> {
+        let elements = match get_path(&log.metadata, &self.path) {
+            Some(Value::Array(elements)) if !elements.is_empty() => elements.clone(),
+            _ => {
+                out.push(log);
+                return Ok(());
+            }
+        };
+
+        for element in elements {
+            let mut copy = log.clone();
+            match element {
+                Value::Object(fields) if self.merge => {
+                    take_path(&mut copy.metadata, &self.path);
+                    for (key, value) in fields {
+                        set_path(&mut copy.metadata, &key, value);
+                    }
+                }
+                element => set_path(&mut copy.metadata, &self.path, element)
+            }
+            out.push(copy);
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn one_record_per_element() {
+        let log = ParsedLog { metadata: json!({ "batch": 7, "events": [{ "id": 1 }, { "id": 2 }] }), ..ParsedLog::new("events".to_string()) };
+
+        let mut out = Vec::new();
+        SplitRecords::new("events").apply(log.clone(), &mut out).unwrap();
+        assert_eq!(out.iter().map(|log| log.metadata.clone()).collect::<Vec<_>>(), vec![
+            json!({ "batch": 7, "events": { "id": 1 } }),
+            json!({ "batch": 7, "events": { "id": 2 } })
+        ]);
+
+        out.clear();
+        SplitRecords::new("events").with_merge(true).apply(log, &mut out).unwrap();
+        assert_eq!(out[1].metadata, json!({ "batch": 7, "id": 2 }));
+
+        out.clear();
+        SplitRecords::new("missing").apply(ParsedLog::new("single".to_string()), &mut out).unwrap();
+        assert_eq!(out.len(), 1);
+    }
+}
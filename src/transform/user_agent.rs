@@ -0,0 +1,139 @@
+use std::{num::NonZeroUsize, sync::{atomic::{AtomicU64, Ordering}, Mutex}};
+
+use lru::LruCache;
+use serde_json::{Map, Value};
+use woothee::{parser::Parser, woothee::VALUE_UNKNOWN};
+
+use super::{geoip::DEFAULT_CACHE_SIZE, set_path, FieldRef, Transform, TransformError};
+use crate::parser::ParsedLog;
+
+// Parses a user-agent string into `browser`, `browser_version`, `os`, `os_version`, `device`
+// (pc, smartphone, mobilephone, appliance, crawler or misc), `vendor` and `bot`. The rules are
+// compiled in, nothing is fetched. Parsed strings are cached, access logs repeat a handful of them
+pub struct UserAgent {
+    field: FieldRef,
+    target: String,
+    parser: Parser,
+    cache: Mutex<LruCache<String, Option<Value>>>,
+    parsed: AtomicU64,
+    unknown: AtomicU64,
+    cache_hits: AtomicU64
+}
+
+impl UserAgent {
+    // `field` is a record field or a metadata path, the result goes to `user_agent_info`
+    pub fn new(field: impl Into<String>) -> Self {
+        Self {
+            field: FieldRef::new(&field.into()),
+            target: "user_agent_info".to_string(),
+            parser: Parser::new(),
+            cache: Mutex::new(LruCache::new(NonZeroUsize::new(DEFAULT_CACHE_SIZE).expect("not zero"))),
+            parsed: AtomicU64::new(0),
+            unknown: AtomicU64::new(0),
+            cache_hits: AtomicU64::new(0)
+        }
+    }
+
+    pub fn with_target(mut self, target: impl Into<String>) -> Self {
+        self.target = target.into();
+        self
+    }
+
+    // user agents remembered, at least 1
+    pub fn with_cache_size(mut self, cache_size: usize) -> Self {
+        self.cache = Mutex::new(LruCache::new(NonZeroUsize::new(cache_size.max(1)).expect("at least 1")));
+        self
+    }
+
+    fn parse(&self, agent: &str) -> Option<Value> {
+        let result = self.parser.parse(agent)?;
+        if result.category == VALUE_UNKNOWN && result.name == VALUE_UNKNOWN {
+            return None;
+        }
+
+        let mut info = Map::new();
+        let known = [
+            ("browser", result.name),
+            ("browser_version", result.version),
+            ("os", result.os),
+            ("os_version", result.os_version.as_ref()),
+            ("device", result.category),
+            ("vendor", result.vendor)
+        ];
+        for (key, value) in known {
+            if value != VALUE_UNKNOWN && !value.is_empty() {
+                info.insert(key.to_string(), Value::from(value));
+            }
+        }
+        info.insert("bot".to_string(), Value::Bool(result.category == "crawler"));
+        Some(Value::Object(info))
+    }
+}
+
+impl Transform for UserAgent {
+    fn name(&self) -> &'static str {
+        "user_agent"
+    }
+
+    fn apply(&self, mut log: ParsedLog, out: &mut Vec<ParsedLog>) -> Result<(), TransformError> {
+        let info = self.field.text(&log).and_then(|agent| {
+            if let Some(cached) = self.cache.lock().unwrap().get(&agent) {
+                self.cache_hits.fetch_add(1, Ordering::Relaxed);
+                return cached.clone();
+            }
+            let info = self.parse(&agent);
+            self.cache.lock().unwrap().put(agent, info.clone());
+            info
+        });
+
+        match info {
+            Some(info) => {
+                set_path(&mut log.metadata, &self.target, info);
+                self.parsed.fetch_add(1, Ordering::Relaxed);
+            }
+            None => {
+                self.unknown.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        out.push(log);
+        Ok(())
+    }
+
+    fn counters(&self) -> Vec<(String, u64)> {
+        vec![
+            ("parsed".to_string(), self.parsed.load(Ordering::Relaxed)),
+            ("unknown".to_string(), self.unknown.load(Ordering::Relaxed)),
+            ("cache_hits".to_string(), self.cache_hits.load(Ordering::Relaxed))
+        ]
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transform::testing::apply;
+    use serde_json::json;
+
+    fn parse(user_agent: &UserAgent, agent: &str) -> Value {
+        let log = apply(user_agent, ParsedLog { metadata: json!({ "http": { "user_agent": agent } }), ..ParsedLog::default() });
+        log.metadata.get("ua").cloned().unwrap_or(Value::Null)
+    }
+
+    #[test]
+    fn browsers_and_bots_are_told_apart() {
+        let user_agent = UserAgent::new("http.user_agent").with_target("ua");
+        let chrome = "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/120.0.0.0 Safari/537.36";
+
+        let info = parse(&user_agent, chrome);
+        assert_eq!((info["browser"].as_str(), info["os"].as_str(), info["device"].as_str(), info["bot"].as_bool()),
+                   (Some("Chrome"), Some("Windows 10"), Some("pc"), Some(false)));
+        assert_eq!(parse(&user_agent, chrome), info);
+
+        let info = parse(&user_agent, "Mozilla/5.0 (compatible; Googlebot/2.1; +http://www.google.com/bot.html)");
+        assert_eq!((info["browser"].as_str(), info["bot"].as_bool()), (Some("Googlebot"), Some(true)));
+        assert_eq!(parse(&user_agent, "curl-ish/0.1 nothing known"), Value::Null);
+
+        assert_eq!(user_agent.counters(), vec![("parsed".to_string(), 3), ("unknown".to_string(), 1), ("cache_hits".to_string(), 1)]);
+    }
+}